@@ -0,0 +1,473 @@
+use std::io::Read;
+use std::rc::Rc;
+
+use anyhow::Result;
+
+use crate::compiler::compile;
+use crate::conversion::{ToLua, ToLuaMulti};
+use crate::debug::check_code;
+use crate::eval::{LuaNumber, TValue, Value};
+use crate::func::{NativeFn, Proto};
+use crate::heap::{Gc, LuaString};
+use crate::iolib::EACCES;
+use crate::table::Table;
+use crate::undump::Undump;
+use crate::userdata::Userdata;
+use crate::vm::{LuaState, type_name};
+
+/// first bytes of a precompiled chunk
+pub(crate) const LUA_SIGNATURE: &[u8] = b"\x1bLua";
+/// reference of nil, which is never stored
+pub const LUA_REFNIL: i64 = -1;
+/// slot heading the list of free references of a table
+const FREELIST_REF: i64 = 0;
+
+/// Helpers for native functions, in the spirit of lapi.c/lauxlib.c.
+/// Arguments are numbered from 1 like in the C API.
+impl LuaState {
+    /// number of values in the current frame
+    pub(crate) fn get_top(&self) -> usize {
+        self.top - self.base_ci.last().unwrap().base
+    }
+    /// shrink or grow (with nils) the current frame to `n` values
+    pub(crate) fn set_top(&mut self, n: usize) {
+        let base = self.base_ci.last().unwrap().base;
+        let new_top = base + n;
+        self.check_stack(n);
+        for i in self.top..new_top {
+            self.stack[i] = TValue::nil();
+        }
+        self.top = new_top;
+    }
+    /// stack index of argument `n`
+    pub(crate) fn arg_index(&self, n: usize) -> usize {
+        self.base_ci.last().unwrap().base + n - 1
+    }
+    /// argument `n`, nil when absent
+    pub(crate) fn arg(&self, n: usize) -> TValue {
+        let i = self.arg_index(n);
+        if i < self.top {
+            self.stack[i]
+        } else {
+            TValue::nil()
+        }
+    }
+    /// insert `val` at argument position `n`, shifting the values above
+    pub(crate) fn insert_arg(&mut self, n: usize, val: TValue) {
+        let i = self.arg_index(n);
+        self.push(val);
+        for j in (i + 1..self.top).rev() {
+            self.stack[j] = self.stack[j - 1];
+        }
+        self.stack[i] = val;
+    }
+
+    /// error prefixed with the position of the calling Lua code (luaL_error)
+    pub(crate) fn error(&mut self, msg: String) -> anyhow::Error {
+        let msg = format!("{}{}", self.where_(1), msg);
+        let value = self.intern(msg.as_bytes());
+        self.error_value(value)
+    }
+    /// "bad argument #n to 'f' (extramsg)" (luaL_argerror)
+    pub(crate) fn arg_error(&mut self, narg: usize, extramsg: &str) -> anyhow::Error {
+        let ci = self.base_ci.len() - 1;
+        let mut narg = narg;
+        let name = match self.func_name(ci) {
+            Some(("method", name)) => {
+                narg -= 1;
+                if narg == 0 {
+                    return self.error(format!("calling '{name}' on bad self ({extramsg})"));
+                }
+                name
+            }
+            Some((_, name)) => name,
+            None => "?".to_string(),
+        };
+        self.error(format!("bad argument #{narg} to '{name}' ({extramsg})"))
+    }
+    /// "X expected, got Y" (luaL_typerror)
+    pub(crate) fn type_error_arg(&mut self, narg: usize, tname: &str) -> anyhow::Error {
+        let got = if self.arg_index(narg) < self.top {
+            type_name(&self.arg(narg))
+        } else {
+            "no value"
+        };
+        self.arg_error(narg, &format!("{tname} expected, got {got}"))
+    }
+    pub(crate) fn check_any(&mut self, narg: usize) -> Result<TValue> {
+        if self.arg_index(narg) >= self.top {
+            return Err(self.arg_error(narg, "value expected"));
+        }
+        Ok(self.arg(narg))
+    }
+    pub(crate) fn check_table(&mut self, narg: usize) -> Result<Gc<Table>> {
+        match self.arg(narg).value() {
+            Value::Table(t) => Ok(t),
+            _ => Err(self.type_error_arg(narg, "table")),
+        }
+    }
+    pub(crate) fn check_number(&mut self, narg: usize) -> Result<LuaNumber> {
+        match self.to_number(&self.arg(narg)) {
+            Some(Value::Integer(n)) => Ok(n as LuaNumber),
+            Some(Value::Number(n)) => Ok(n),
+            _ => Err(self.type_error_arg(narg, "number")),
+        }
+    }
+    /// number argument truncated to an integer (luaL_checkinteger)
+    pub(crate) fn check_integer(&mut self, narg: usize) -> Result<i64> {
+        Ok(self.check_number(narg)? as i64)
+    }
+    pub(crate) fn opt_integer(&mut self, narg: usize, default: i64) -> Result<i64> {
+        match self.arg(narg).value() {
+            Value::Nil => Ok(default),
+            _ => self.check_integer(narg),
+        }
+    }
+    pub(crate) fn opt_number(&mut self, narg: usize, default: LuaNumber) -> Result<LuaNumber> {
+        match self.arg(narg).value() {
+            Value::Nil => Ok(default),
+            _ => self.check_number(narg),
+        }
+    }
+
+    /// string argument, converting numbers in place (luaL_checklstring)
+    pub(crate) fn check_string(&mut self, narg: usize) -> Result<Gc<LuaString>> {
+        let val = self.arg(narg);
+        match val.value() {
+            Value::String(s) => Ok(s),
+            Value::Integer(_) | Value::Number(_) => {
+                let bytes = self.to_str_bytes(&val).unwrap();
+                let s = self.intern(&bytes);
+                let i = self.arg_index(narg);
+                self.stack[i] = s;
+                match s.value() {
+                    Value::String(s) => Ok(s),
+                    _ => unreachable!(),
+                }
+            }
+            _ => Err(self.type_error_arg(narg, "string")),
+        }
+    }
+    pub(crate) fn opt_string(&mut self, narg: usize) -> Result<Option<Gc<LuaString>>> {
+        match self.arg(narg).value() {
+            Value::Nil => Ok(None),
+            _ => self.check_string(narg).map(Some),
+        }
+    }
+    /// index in `opts` of the string argument `narg`, `def` when absent (luaL_checkoption)
+    pub(crate) fn check_option(
+        &mut self,
+        narg: usize,
+        def: Option<&str>,
+        opts: &[&str],
+    ) -> Result<usize> {
+        let name = match (self.arg(narg).value(), def) {
+            (Value::Nil, Some(def)) => def.to_string(),
+            _ => {
+                let s = self.check_string(narg)?;
+                String::from_utf8_lossy(self.str_bytes(s)).into_owned()
+            }
+        };
+        match opts.iter().position(|o| *o == name) {
+            Some(i) => Ok(i),
+            None => Err(self.arg_error(narg, &format!("invalid option '{name}'"))),
+        }
+    }
+
+    /// string form of `val`, through its `__tostring` metamethod if it has one
+    pub(crate) fn tostring(&mut self, val: TValue) -> Result<TValue> {
+        let tm = self.get_metafield(&val, "__tostring");
+        if !matches!(tm.value(), Value::Nil) {
+            let func = self.top;
+            self.push(tm);
+            self.push(val);
+            self.call(func, 1)?;
+            self.top -= 1;
+            return Ok(self.stack[self.top]);
+        }
+        let s = match val.value() {
+            Value::String(_) => return Ok(val),
+            Value::Nil => "nil".to_string(),
+            Value::Boolean(b) => b.to_string(),
+            Value::Integer(_) | Value::Number(_) => {
+                let bytes = self.to_str_bytes(&val).unwrap();
+                return Ok(self.intern(&bytes));
+            }
+            Value::LuaClosure(c) => format!("function: 0x{:08x}", c.index()),
+            Value::NativeClosure(c) => format!("function: 0x{:08x}", c.index()),
+            other => other.to_string(),
+        };
+        Ok(self.intern(s.as_bytes()))
+    }
+
+    /// field `event` of the metatable of `val`, nil if absent (luaL_getmetafield)
+    pub(crate) fn get_metafield(&mut self, val: &TValue, event: &str) -> TValue {
+        match self.get_metatable(val) {
+            Some(mt) => {
+                let key = self.global.heap.intern(event.as_bytes());
+                self.global.heap.get(mt).get_str(key)
+            }
+            None => TValue::nil(),
+        }
+    }
+    /// set the metatable of a table or userdata, or the shared one of other types
+    pub(crate) fn set_metatable(&mut self, val: &TValue, mt: Option<Gc<Table>>) {
+        match val.value() {
+            Value::Table(t) => self.global.heap.get_mut(t).metatable = mt,
+            Value::UserData(u) => self.global.heap.get_mut(u).metatable = mt,
+            _ => self.global.mt[val.lua_type() as usize] = mt,
+        }
+    }
+    /// new userdata owning `data`
+    pub(crate) fn new_userdata(&mut self, data: Box<dyn std::any::Any>) -> Gc<Userdata> {
+        let env = self.global.globals;
+        self.global.heap.alloc_userdata(data, env)
+    }
+
+    pub fn get_global(&mut self, name: &str) -> TValue {
+        let key = self.global.heap.intern(name.as_bytes());
+        self.global.heap.get(self.global.globals).get_str(key)
+    }
+    /// set a global without the checks of the host API `set_global`
+    pub(crate) fn raw_set_global(&mut self, name: &str, val: impl ToLua) {
+        let val = val.to_lua(self);
+        let key = self.global.heap.intern(name.as_bytes());
+        let globals = self.global.globals;
+        self.global.heap.get_mut(globals).set_str(key, val);
+    }
+    pub(crate) fn register(&mut self, name: &str, func: NativeFn) {
+        let f = self.new_native(func);
+        self.raw_set_global(name, f);
+    }
+    /// Add `funcs` to the module `libname`: the table in `package.loaded`, or
+    /// else the global table of that (possibly dotted) name, created if
+    /// needed and recorded as loaded (luaL_register).
+    pub(crate) fn register_lib(&mut self, libname: &str, funcs: &[(&str, NativeFn)]) -> Gc<Table> {
+        let loaded = self.loaded_table();
+        let key = self.global.heap.intern(libname.as_bytes());
+        let lib = match self.global.heap.get(loaded).get_str(key).value() {
+            Value::Table(lib) => lib,
+            _ => {
+                let globals = self.global.globals;
+                let Ok(lib) = self.find_table(globals, libname) else {
+                    panic!("name conflict for module '{libname}'");
+                };
+                self.global
+                    .heap
+                    .get_mut(loaded)
+                    .set_str(key, TValue::table(lib));
+                lib
+            }
+        };
+        for (name, func) in funcs {
+            let key = self.global.heap.intern(name.as_bytes());
+            let f = self.new_native(*func);
+            self.global.heap.get_mut(lib).set_str(key, f);
+        }
+        lib
+    }
+    /// Store `val` in `t` under a new integer key and return it, reusing the
+    /// keys released by `unreference` (luaL_ref). Nil is not stored and gets
+    /// `LUA_REFNIL`.
+    pub(crate) fn reference(&mut self, t: Gc<Table>, val: TValue) -> i64 {
+        if let Value::Nil = val.value() {
+            return LUA_REFNIL;
+        }
+        let table = self.global.heap.get_mut(t);
+        let r = match table.get_int(FREELIST_REF).as_number() {
+            Some(free) if free > 0.0 => {
+                let next = table.get_int(free as i64);
+                table.set_int(FREELIST_REF, next);
+                free as i64
+            }
+            _ => table.length() + 1,
+        };
+        table.set_int(r, val);
+        r
+    }
+    /// free the reference `r` of `t` (luaL_unref)
+    pub(crate) fn unreference(&mut self, t: Gc<Table>, r: i64) {
+        if r > FREELIST_REF {
+            let table = self.global.heap.get_mut(t);
+            let free = table.get_int(FREELIST_REF);
+            table.set_int(r, free);
+            table.set_int(FREELIST_REF, TValue::number(r as LuaNumber));
+        }
+    }
+    /// `package.loaded`, kept in the registry as `_LOADED`
+    pub(crate) fn loaded_table(&mut self) -> Gc<Table> {
+        let key = self.global.heap.intern(b"_LOADED");
+        let registry = self.global.registry;
+        if let Value::Table(t) = self.global.heap.get(registry).get_str(key).value() {
+            return t;
+        }
+        let t = self.new_table();
+        self.global
+            .heap
+            .get_mut(registry)
+            .set_str(key, TValue::table(t));
+        t
+    }
+    /// The table at the dotted path `name` from `root`, creating missing
+    /// levels (luaL_findtable). A non-table on the way is returned as the
+    /// conflicting part of the name.
+    pub(crate) fn find_table(&mut self, root: Gc<Table>, name: &str) -> Result<Gc<Table>, String> {
+        let mut t = root;
+        for (i, part) in name.split('.').enumerate() {
+            let key = self.global.heap.intern(part.as_bytes());
+            t = match self.global.heap.get(t).get_str(key).value() {
+                Value::Table(next) => next,
+                Value::Nil => {
+                    let next = self.new_table();
+                    self.global
+                        .heap
+                        .get_mut(t)
+                        .set_str(key, TValue::table(next));
+                    next
+                }
+                _ => {
+                    let end = name.split('.').take(i + 1).map(str::len).sum::<usize>() + i;
+                    return Err(name[..end].to_string());
+                }
+            };
+        }
+        Ok(t)
+    }
+    /// Make `require(name)` call `open` with the module name, for host
+    /// modules loaded on demand. Needs the package library.
+    pub fn preload<R, F>(&mut self, name: &str, open: F)
+    where
+        R: ToLuaMulti,
+        F: Fn(&mut LuaState, String) -> Result<R> + 'static,
+    {
+        let package = self.get_global("package");
+        let Value::Table(package) = package.value() else {
+            panic!("preload of '{name}' without the package library");
+        };
+        let key = self.global.heap.intern(b"preload");
+        let Value::Table(preload) = self.global.heap.get(package).get_str(key).value() else {
+            panic!("'package.preload' must be a table");
+        };
+        let key = self.global.heap.intern(name.as_bytes());
+        let f = self.create_function(open);
+        self.global.heap.get_mut(preload).set_str(key, f);
+    }
+    /// change the environment of a function; false if `f` has none
+    pub(crate) fn set_fenv(&mut self, f: &TValue, env: Gc<Table>) -> bool {
+        match f.value() {
+            Value::LuaClosure(c) => self.global.heap.get_mut(c).env = env,
+            Value::NativeClosure(c) => self.global.heap.get_mut(c).env = env,
+            _ => return false,
+        }
+        true
+    }
+    /// upvalue `n` of the running native closure
+    pub(crate) fn upvalue(&self, n: usize) -> TValue {
+        let func = self.stack[self.base_ci.last().unwrap().func];
+        match func.value() {
+            Value::NativeClosure(c) => self.global.heap.get(c).upvalues[n - 1],
+            _ => TValue::nil(),
+        }
+    }
+    pub(crate) fn set_upvalue(&mut self, n: usize, val: TValue) {
+        let func = self.stack[self.base_ci.last().unwrap().func];
+        if let Value::NativeClosure(c) = func.value() {
+            self.global.heap.get_mut(c).upvalues[n - 1] = val;
+        }
+    }
+
+    /// allow or refuse precompiled chunks in `load_buffer` and `load_file`
+    pub fn set_binary_chunks(&mut self, allow: bool) {
+        self.global.binary_chunks = allow;
+    }
+    /// Compile source or undump a precompiled chunk, told apart by the
+    /// signature, into a function (luaL_loadbuffer). Errors are Lua errors
+    /// with the message as value.
+    pub(crate) fn load_buffer(&mut self, buf: &[u8], chunkname: &str) -> Result<TValue> {
+        if !buf.starts_with(LUA_SIGNATURE) {
+            return match compile(buf, chunkname) {
+                Ok(chunk) => Ok(self.load(&chunk)),
+                Err(msg) => {
+                    let msg = self.intern(msg.as_bytes());
+                    Err(self.error_value(msg))
+                }
+            };
+        }
+        if !self.global.binary_chunks {
+            let msg = self.intern(b"attempt to load a binary chunk");
+            return Err(self.error_value(msg));
+        }
+        let res = Undump::new(buf.to_vec()).undump().and_then(|(_, chunk)| {
+            let proto = Proto::from_chunk(&chunk, "=?", &mut self.global.heap);
+            if !check_code(&proto) {
+                anyhow::bail!("bad code");
+            }
+            Ok(proto)
+        });
+        match res {
+            Ok(proto) => Ok(self.new_lua_closure(Rc::new(proto))),
+            Err(why) => {
+                let name = if let Some(name) = chunkname.strip_prefix(['@', '=']) {
+                    name
+                } else if chunkname.as_bytes().starts_with(LUA_SIGNATURE) {
+                    "binary string"
+                } else {
+                    chunkname
+                };
+                let msg = format!("{name}: {why} in precompiled chunk");
+                let msg = self.intern(msg.as_bytes());
+                Err(self.error_value(msg))
+            }
+        }
+    }
+    /// Load the file `filename`, or standard input, as a chunk named
+    /// "@filename" or "=stdin" (luaL_loadfile). A first line starting with
+    /// `#` is skipped. Files are refused when the libraries were opened
+    /// without `read_files`.
+    pub fn load_file(&mut self, filename: Option<&str>) -> Result<TValue> {
+        let (chunkname, data) = match filename {
+            Some(name) if !self.global.read_files => (
+                format!("@{name}"),
+                Err(std::io::Error::from_raw_os_error(EACCES)),
+            ),
+            Some(name) => (format!("@{name}"), std::fs::read(name)),
+            None => {
+                let mut data = Vec::new();
+                let res = std::io::stdin().lock().read_to_end(&mut data);
+                ("=stdin".to_string(), res.map(|_| data))
+            }
+        };
+        let mut data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                let what = if e.kind() == std::io::ErrorKind::IsADirectory {
+                    "read"
+                } else {
+                    "open"
+                };
+                let reason = e.to_string();
+                let reason = reason
+                    .rfind(" (os error")
+                    .map_or(&reason[..], |i| &reason[..i]);
+                let msg = format!("cannot {what} {}: {reason}", &chunkname[1..]);
+                let msg = self.intern(msg.as_bytes());
+                return Err(self.error_value(msg));
+            }
+        };
+        if data.first() == Some(&b'#') {
+            // keep the newline so that line numbers stay right, unless the
+            // rest is a precompiled chunk
+            let end = data.iter().position(|&c| c == b'\n').unwrap_or(data.len());
+            let keep = if data[end..]
+                .get(1..)
+                .is_some_and(|rest| rest.starts_with(LUA_SIGNATURE))
+            {
+                end + 1
+            } else {
+                end
+            };
+            data.drain(..keep);
+        }
+        self.load_buffer(&data, &chunkname)
+    }
+}
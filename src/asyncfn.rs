@@ -0,0 +1,253 @@
+//! Host functions returning futures.
+//!
+//! `call_async` runs a Lua function in a coroutine of its own. An async host
+//! function called from it makes its future and yields; the coroutine is
+//! resumed with the results once the future is ready, so the script reads
+//! like a plain call.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+
+use crate::conversion::{FromLuaMulti, ToLuaMulti};
+use crate::error::LuaError;
+use crate::eval::{TValue, Value};
+use crate::heap::Gc;
+use crate::host::host_error;
+use crate::thread::{Thread, ThreadStatus};
+use crate::vm::LuaState;
+
+/// results of a finished future, converted once the state is available again
+type AsyncResults = Box<dyn FnOnce(&mut LuaState) -> Vec<TValue>>;
+type HostFuture = Pin<Box<dyn Future<Output = Result<AsyncResults>>>>;
+/// An async host function, kept in a userdata upvalue of `call_async_host`.
+type AsyncHostFn = Rc<dyn Fn(&mut LuaState, Vec<TValue>) -> Result<HostFuture>>;
+
+/// future of a suspended coroutine, with the position of its call for errors
+struct PendingCall {
+    future: HostFuture,
+    position: String,
+}
+
+#[derive(Default)]
+pub(crate) struct AsyncState {
+    /// coroutine being resumed by `call_async`
+    thread: Option<Gc<Thread>>,
+    /// future left by the async function which made `thread` yield
+    pending: Option<PendingCall>,
+}
+
+/// Native function starting the `AsyncHostFn` in its first upvalue.
+fn call_async_host(state: &mut LuaState) -> Result<usize> {
+    let f = match state.upvalue(1).value() {
+        Value::UserData(u) => state
+            .global
+            .heap
+            .get(u)
+            .data
+            .downcast_ref::<AsyncHostFn>()
+            .cloned(),
+        _ => None,
+    };
+    let f = f.expect("async host function upvalue");
+    if state.global.async_state.thread != Some(state.current) {
+        return Err(state.error("async function called outside call_async".to_string()));
+    }
+    let args = state.stack[state.arg_index(1)..state.top].to_vec();
+    let future = f(state, args).map_err(|e| host_error(state, e))?;
+    let position = state.where_(1);
+    let n = state.yield_(0)?;
+    state.global.async_state.pending = Some(PendingCall { future, position });
+    Ok(n)
+}
+
+/// Future ready on its second poll, letting other tasks run in between.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+impl LuaState {
+    /// Lua function starting the future returned by `f` and waiting for its
+    /// results. It only works in a function run by `call_async`, and not
+    /// across `pcall`, metamethods or iterators, which cannot yield. The
+    /// arguments are converted like those of `create_function`.
+    pub fn create_async_function<A, R, F, Fut>(&mut self, f: F) -> TValue
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti + 'static,
+        F: Fn(A) -> Fut + 'static,
+        Fut: Future<Output = Result<R>> + 'static,
+    {
+        let f: AsyncHostFn = Rc::new(move |state, args| {
+            let future = f(A::from_lua_multi(args, state)?);
+            Ok(Box::pin(async move {
+                let results = future.await?;
+                Ok(
+                    Box::new(move |state: &mut LuaState| results.to_lua_multi(state))
+                        as AsyncResults,
+                )
+            }) as HostFuture)
+        });
+        let ud = self.new_userdata(Box::new(f));
+        self.new_native_closure(call_async_host, vec![TValue::userdata(ud)])
+    }
+
+    /// set the global `name` to the function made by `create_async_function`
+    pub fn register_async_function<A, R, F, Fut>(&mut self, name: &str, f: F)
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti + 'static,
+        F: Fn(A) -> Fut + 'static,
+        Fut: Future<Output = Result<R>> + 'static,
+    {
+        let func = self.create_async_function(f);
+        self.raw_set_global(name, func);
+    }
+
+    /// Call `f` in a new coroutine, suspending it while the futures of its
+    /// async host functions are pending. A plain `coroutine.yield` in it
+    /// lets other tasks run and returns nothing. An error of a future is
+    /// returned with the position of the call which made it.
+    pub async fn call_async<A: ToLuaMulti, R: FromLuaMulti>(
+        &mut self,
+        f: &TValue,
+        args: A,
+    ) -> Result<R, LuaError> {
+        let mut args = args.to_lua_multi(self);
+        self.check_live(&[*f])?;
+        self.check_live(&args)?;
+        self.start_deadline();
+        let co = self.new_thread(*f);
+        loop {
+            let saved = self.global.async_state.thread.replace(co);
+            let res = self.resume(co, &args);
+            self.global.async_state.thread = saved;
+            let results = res?;
+            if let Some(call) = self.global.async_state.pending.take() {
+                args = match call.future.await {
+                    Ok(results) => results(self),
+                    Err(e) => return Err(self.async_error(e, &call.position)),
+                };
+            } else if self.thread_status(co) == ThreadStatus::Dead {
+                return R::from_lua_multi(results, self).map_err(|e| self.error_object(e));
+            } else {
+                YieldNow(false).await;
+                args = Vec::new();
+            }
+        }
+    }
+
+    fn async_error(&mut self, e: anyhow::Error, position: &str) -> LuaError {
+        match e.downcast::<LuaError>() {
+            Ok(e) => e,
+            Err(e) => {
+                let msg = format!("{position}{e}");
+                LuaError::new(self.intern(msg.as_bytes()), msg)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::task::Waker;
+
+    use super::*;
+    use crate::iolib::SystemAccess;
+    use pretty_assertions::assert_eq;
+
+    /// future pending until the test puts a value in its slot
+    struct Slot<T>(Rc<RefCell<Option<T>>>);
+
+    impl<T> Future for Slot<T> {
+        type Output = T;
+        fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<T> {
+            match self.0.borrow_mut().take() {
+                Some(v) => Poll::Ready(v),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    fn poll<F: Future>(future: &mut Pin<&mut F>) -> Poll<F::Output> {
+        future
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    /// output of a future ready on its first poll
+    fn ready<F: Future>(future: F) -> F::Output {
+        match poll(&mut std::pin::pin!(future)) {
+            Poll::Ready(v) => v,
+            Poll::Pending => panic!("future still pending"),
+        }
+    }
+
+    #[test]
+    fn test_call_async() {
+        let mut lua = LuaState::new();
+        lua.open_libs(SystemAccess::SAFE);
+        let slot: Rc<RefCell<Option<Result<String>>>> = Rc::default();
+        let calls = Rc::new(Cell::new(0));
+        let (s, c) = (slot.clone(), calls.clone());
+        lua.register_async_function("http_get", move |url: String| {
+            c.set(c.get() + 1);
+            let body = Slot(s.clone());
+            async move { Ok(format!("{url}: {}", body.await?)) }
+        });
+        let f = lua
+            .load_chunk(
+                "local a = http_get('/a') coroutine.yield() return a .. ', ' .. http_get('/b')",
+                "=script",
+            )
+            .unwrap();
+
+        {
+            let mut future = std::pin::pin!(lua.call_async::<_, String>(&f, ()));
+            assert!(poll(&mut future).is_pending());
+            assert!(poll(&mut future).is_pending());
+            assert_eq!(calls.get(), 1);
+            *slot.borrow_mut() = Some(Ok("one".to_string()));
+            // the coroutine.yield gives a turn to the other tasks
+            assert!(poll(&mut future).is_pending());
+            assert!(poll(&mut future).is_pending());
+            assert_eq!(calls.get(), 2);
+            *slot.borrow_mut() = Some(Ok("two".to_string()));
+            match poll(&mut future) {
+                Poll::Ready(res) => assert_eq!(res.unwrap(), "/a: one, /b: two"),
+                Poll::Pending => panic!("call_async still pending"),
+            }
+        }
+
+        *slot.borrow_mut() = Some(Err(anyhow::anyhow!("connection refused")));
+        let err = ready(lua.call_async::<_, String>(&f, ())).unwrap_err();
+        assert_eq!(err.to_string(), "script:1: connection refused");
+
+        // async functions need the coroutine of call_async
+        let err = lua.exec::<()>("http_get('/c')").unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("async function called outside call_async")
+        );
+        let f = lua
+            .load_chunk("return pcall(http_get, '/d')", "=script")
+            .unwrap();
+        let (ok, msg) = ready(lua.call_async::<_, (bool, String)>(&f, ())).unwrap();
+        assert!(!ok);
+        assert_eq!(msg, "attempt to yield across metamethod/C-call boundary");
+    }
+}
@@ -0,0 +1,832 @@
+use std::io::Write;
+
+use anyhow::Result;
+
+use crate::eval::{LuaNumber, TValue, Value};
+use crate::heap::Gc;
+use crate::thread::Thread;
+use crate::vm::{LUA_MULTRET, LUAI_MAXCSTACK, LuaState, is_false, type_name};
+
+/// print(...)
+fn lua_print(state: &mut LuaState) -> Result<usize> {
+    let n = state.get_top();
+    let tostring = state.get_global("tostring");
+    let mut out = Vec::new();
+    for i in 1..=n {
+        let arg = state.arg(i);
+        let func = state.top;
+        state.push(tostring);
+        state.push(arg);
+        state.call(func, 1)?;
+        state.top -= 1;
+        let s = state.stack[state.top];
+        let Some(bytes) = state.to_str_bytes(&s) else {
+            return Err(state.error("'tostring' must return a string to 'print'".to_string()));
+        };
+        if i > 1 {
+            out.push(b'\t');
+        }
+        out.extend_from_slice(&bytes);
+    }
+    out.push(b'\n');
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(&out)?;
+    stdout.flush()?;
+    Ok(0)
+}
+
+/// tostring(v)
+fn lua_tostring(state: &mut LuaState) -> Result<usize> {
+    let v = state.check_any(1)?;
+    let s = state.tostring(v)?;
+    state.push(s);
+    Ok(1)
+}
+
+/// digits of `bytes` in `base`, surrounded by optional spaces (strtoul)
+fn str2int(bytes: &[u8], base: u32) -> Option<LuaNumber> {
+    let s = bytes.trim_ascii();
+    let (neg, digits) = match s.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        _ => (false, s),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: LuaNumber = 0.0;
+    for &b in digits {
+        let d = (b as char).to_digit(base)?;
+        n = n * base as LuaNumber + d as LuaNumber;
+    }
+    Some(if neg { -n } else { n })
+}
+
+/// tonumber(e [, base])
+fn lua_tonumber(state: &mut LuaState) -> Result<usize> {
+    let base = state.opt_number(2, 10.0)? as i64;
+    let res = if base == 10 {
+        let v = state.check_any(1)?;
+        state.to_number(&v).map(TValue::from)
+    } else {
+        let s = state.check_string(1)?;
+        if !(2..=36).contains(&base) {
+            return Err(state.arg_error(2, "base out of range"));
+        }
+        str2int(state.str_bytes(s), base as u32).map(TValue::number)
+    };
+    state.push(res.unwrap_or_else(TValue::nil));
+    Ok(1)
+}
+
+/// assert(v [, message])
+fn lua_assert(state: &mut LuaState) -> Result<usize> {
+    let v = state.check_any(1)?;
+    if is_false(&v) {
+        let msg = match state.opt_string(2)? {
+            Some(s) => String::from_utf8_lossy(state.str_bytes(s)).into_owned(),
+            None => "assertion failed!".to_string(),
+        };
+        return Err(state.error(msg));
+    }
+    Ok(state.get_top())
+}
+
+/// next(table [, index])
+fn lua_next(state: &mut LuaState) -> Result<usize> {
+    let t = state.check_table(1)?;
+    state.set_top(2);
+    let key = state.arg(2);
+    match state.global.heap.get(t).next(&key) {
+        Ok(Some((k, v))) => {
+            state.push(k);
+            state.push(v);
+            Ok(2)
+        }
+        Ok(None) => {
+            state.push(TValue::nil());
+            Ok(1)
+        }
+        Err(e) => Err(state.runtime_error(e.to_string())),
+    }
+}
+
+/// pairs(t): next, t, nil
+fn lua_pairs(state: &mut LuaState) -> Result<usize> {
+    let t = state.check_table(1)?;
+    state.push(state.upvalue(1));
+    state.push(TValue::table(t));
+    state.push(TValue::nil());
+    Ok(3)
+}
+
+/// iterator returned by ipairs
+fn ipairs_aux(state: &mut LuaState) -> Result<usize> {
+    let t = state.check_table(1)?;
+    let i = state.check_number(2)? as i64 + 1;
+    let v = state.global.heap.get(t).get_int(i);
+    if matches!(v.value(), Value::Nil) {
+        return Ok(0);
+    }
+    state.push(TValue::number(i as LuaNumber));
+    state.push(v);
+    Ok(2)
+}
+
+/// ipairs(t): ipairs_aux, t, 0
+fn lua_ipairs(state: &mut LuaState) -> Result<usize> {
+    let t = state.check_table(1)?;
+    state.push(state.upvalue(1));
+    state.push(TValue::table(t));
+    state.push(TValue::number(0.0));
+    Ok(3)
+}
+
+/// rawequal(v1, v2)
+fn lua_rawequal(state: &mut LuaState) -> Result<usize> {
+    let a = state.check_any(1)?;
+    let b = state.check_any(2)?;
+    let eq = state.raw_equal(&a, &b);
+    state.push(TValue::boolean(eq));
+    Ok(1)
+}
+
+/// rawget(table, index)
+fn lua_rawget(state: &mut LuaState) -> Result<usize> {
+    let t = state.check_table(1)?;
+    let k = state.check_any(2)?;
+    let v = state.global.heap.get(t).get(&k);
+    state.push(v);
+    Ok(1)
+}
+
+/// rawset(table, index, value)
+fn lua_rawset(state: &mut LuaState) -> Result<usize> {
+    let t = state.check_table(1)?;
+    let k = state.check_any(2)?;
+    let v = state.check_any(3)?;
+    if let Err(e) = state.global.heap.get_mut(t).set(k, v) {
+        return Err(state.runtime_error(e.to_string()));
+    }
+    state.set_top(1);
+    Ok(1)
+}
+
+/// select(index, ...)
+fn lua_select(state: &mut LuaState) -> Result<usize> {
+    let n = state.get_top() as i64;
+    if let Value::String(s) = state.arg(1).value()
+        && state.str_bytes(s).first() == Some(&b'#')
+    {
+        state.push(TValue::number((n - 1) as LuaNumber));
+        return Ok(1);
+    }
+    let mut i = state.check_number(1)? as i64;
+    if i < 0 {
+        i += n;
+    } else if i > n {
+        i = n;
+    }
+    if i < 1 {
+        return Err(state.arg_error(1, "index out of range"));
+    }
+    Ok((n - i) as usize)
+}
+
+/// unpack(list [, i [, j]])
+fn lua_unpack(state: &mut LuaState) -> Result<usize> {
+    let t = state.check_table(1)?;
+    let i = state.opt_number(2, 1.0)? as i64;
+    let j = match state.arg(3).value() {
+        Value::Nil => state.global.heap.get(t).length(),
+        _ => state.check_number(3)? as i64,
+    };
+    if i > j {
+        return Ok(0);
+    }
+    let n = (j as i128 - i as i128 + 1) as usize;
+    if n >= LUAI_MAXCSTACK {
+        return Err(state.error("too many results to unpack".to_string()));
+    }
+    state.check_stack(n);
+    for k in i..=j {
+        let v = state.global.heap.get(t).get_int(k);
+        state.push(v);
+    }
+    Ok(n)
+}
+
+/// error(message [, level])
+fn lua_error(state: &mut LuaState) -> Result<usize> {
+    let level = state.opt_number(2, 1.0)? as i64;
+    state.set_top(1);
+    let mut msg = state.arg(1);
+    if let Value::String(s) = msg.value()
+        && level > 0
+    {
+        let mut bytes = state.where_(level as usize).into_bytes();
+        bytes.extend_from_slice(state.str_bytes(s));
+        msg = state.intern(&bytes);
+    }
+    Err(state.error_value(msg))
+}
+
+/// pcall(f, ...)
+fn lua_pcall(state: &mut LuaState) -> Result<usize> {
+    state.check_any(1)?;
+    let func = state.arg_index(1);
+    match state.pcall(func, LUA_MULTRET, None) {
+        Ok(()) => {
+            state.insert_arg(1, TValue::boolean(true));
+            Ok(state.get_top())
+        }
+        Err(e) if e.is_uncatchable() => Err(e.into()),
+        Err(e) => {
+            state.push(TValue::boolean(false));
+            state.push(e.value());
+            Ok(2)
+        }
+    }
+}
+
+/// xpcall(f, msgh)
+fn lua_xpcall(state: &mut LuaState) -> Result<usize> {
+    state.check_any(2)?;
+    state.set_top(2);
+    // put the message handler under the function to be called
+    let base = state.arg_index(1);
+    state.stack.swap(base, base + 1);
+    let status = match state.pcall(base + 1, LUA_MULTRET, Some(base)) {
+        Ok(()) => true,
+        Err(e) if e.is_uncatchable() => return Err(e.into()),
+        Err(e) => {
+            state.push(e.value());
+            false
+        }
+    };
+    state.stack[base] = TValue::boolean(status);
+    Ok(state.get_top())
+}
+
+/// the function loaded by `res`, or nil and the error message (load_aux)
+pub(crate) fn load_aux(state: &mut LuaState, res: Result<TValue>) -> usize {
+    match res {
+        Ok(f) => {
+            state.push(f);
+            1
+        }
+        Err(e) => {
+            let e = state.error_object(e);
+            state.push(TValue::nil());
+            state.push(e.value());
+            2
+        }
+    }
+}
+
+/// pieces returned by the reader function in argument 1, up to nil or ""
+pub(crate) fn read_chunk(state: &mut LuaState) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    loop {
+        let func = state.top;
+        state.push(state.arg(1));
+        state.pcall(func, 1, None)?;
+        state.top -= 1;
+        let piece = state.stack[state.top];
+        match piece.value() {
+            Value::Nil => return Ok(buf),
+            Value::String(s) if state.str_bytes(s).is_empty() => return Ok(buf),
+            Value::String(s) => buf.extend_from_slice(state.str_bytes(s)),
+            _ => return Err(state.error("reader function must return a string".to_string())),
+        }
+    }
+}
+
+/// load(func [, chunkname])
+fn lua_load(state: &mut LuaState) -> Result<usize> {
+    let chunkname = match state.opt_string(2)? {
+        Some(s) => String::from_utf8_lossy(state.str_bytes(s)).into_owned(),
+        None => "=(load)".to_string(),
+    };
+    if !matches!(
+        state.arg(1).value(),
+        Value::LuaClosure(_) | Value::NativeClosure(_)
+    ) {
+        return Err(state.type_error_arg(1, "function"));
+    }
+    // errors of the reader are returned like compilation errors
+    let res = read_chunk(state).and_then(|buf| state.load_buffer(&buf, &chunkname));
+    Ok(load_aux(state, res))
+}
+
+/// loadstring(s [, chunkname])
+fn lua_loadstring(state: &mut LuaState) -> Result<usize> {
+    let s = state.check_string(1)?;
+    let chunkname = match state.opt_string(2)? {
+        Some(name) => state.str_bytes(name),
+        None => state.str_bytes(s),
+    };
+    let chunkname = String::from_utf8_lossy(chunkname).into_owned();
+    let buf = state.str_bytes(s).to_vec();
+    let res = state.load_buffer(&buf, &chunkname);
+    Ok(load_aux(state, res))
+}
+
+fn opt_filename(state: &mut LuaState) -> Result<Option<String>> {
+    Ok(state
+        .opt_string(1)?
+        .map(|s| String::from_utf8_lossy(state.str_bytes(s)).into_owned()))
+}
+
+/// loadfile([filename])
+fn lua_loadfile(state: &mut LuaState) -> Result<usize> {
+    let filename = opt_filename(state)?;
+    let res = state.load_file(filename.as_deref());
+    Ok(load_aux(state, res))
+}
+
+/// dofile([filename]): run the file and return all its results
+fn lua_dofile(state: &mut LuaState) -> Result<usize> {
+    let filename = opt_filename(state)?;
+    let n = state.get_top();
+    let f = state.load_file(filename.as_deref())?;
+    let func = state.top;
+    state.push(f);
+    state.call(func, LUA_MULTRET)?;
+    Ok(state.get_top() - n)
+}
+
+/// type(v)
+fn lua_type(state: &mut LuaState) -> Result<usize> {
+    let v = state.check_any(1)?;
+    let name = state.intern(type_name(&v).as_bytes());
+    state.push(name);
+    Ok(1)
+}
+
+/// collectgarbage([opt [, arg]])
+fn lua_collectgarbage(state: &mut LuaState) -> Result<usize> {
+    const OPTS: [&str; 7] = [
+        "stop",
+        "restart",
+        "collect",
+        "count",
+        "step",
+        "setpause",
+        "setstepmul",
+    ];
+    let o = state.check_option(1, Some("collect"), &OPTS)?;
+    let ex = state.opt_number(2, 0.0)?.max(0.0) as usize;
+    let res = match OPTS[o] {
+        "stop" => {
+            state.gc_stop();
+            0.0
+        }
+        "restart" => {
+            state.gc_restart();
+            0.0
+        }
+        "collect" => {
+            state.gc_collect()?;
+            0.0
+        }
+        "count" => state.gc_count() as f64 / 1024.0,
+        "step" => {
+            let done = state.gc_step(ex)?;
+            state.push(TValue::boolean(done));
+            return Ok(1);
+        }
+        "setpause" => state.gc_set_pause(ex) as f64,
+        _ => state.gc_set_stepmul(ex) as f64,
+    };
+    state.push(TValue::number(res));
+    Ok(1)
+}
+
+/// gcinfo(): kilobytes in use, like collectgarbage("count") without the fraction
+fn lua_gcinfo(state: &mut LuaState) -> Result<usize> {
+    let kb = state.gc_count() / 1024;
+    state.push(TValue::int_or_float(kb as i128));
+    Ok(1)
+}
+
+/// function at argument 1, or the one running at the stack level given there
+/// (getfunc of lbaselib.c); nil for a level lost to a tail call
+fn get_func(state: &mut LuaState, opt: bool) -> Result<TValue> {
+    let f = state.arg(1);
+    if matches!(f.value(), Value::LuaClosure(_) | Value::NativeClosure(_)) {
+        return Ok(f);
+    }
+    let level = if opt {
+        state.opt_integer(1, 1)?
+    } else {
+        state.check_integer(1)?
+    };
+    if level < 0 {
+        return Err(state.arg_error(1, "level must be non-negative"));
+    }
+    match state.get_stack(level as usize) {
+        None => Err(state.arg_error(1, "invalid level")),
+        Some(0) => Err(state.error(format!(
+            "no function environment for tail call at level {level}"
+        ))),
+        Some(ci) => Ok(state.stack[state.base_ci[ci].func]),
+    }
+}
+
+/// getfenv([f]): environment of a Lua function, the globals for natives
+fn lua_getfenv(state: &mut LuaState) -> Result<usize> {
+    let f = get_func(state, true)?;
+    let env = match f.value() {
+        Value::LuaClosure(c) => state.global.heap.get(c).env,
+        _ => state.global.globals,
+    };
+    state.push(TValue::table(env));
+    Ok(1)
+}
+
+/// setfenv(f, table): level 0 replaces the globals
+fn lua_setfenv(state: &mut LuaState) -> Result<usize> {
+    let env = state.check_table(2)?;
+    let f = get_func(state, false)?;
+    if state.arg(1).as_number() == Some(0.0) {
+        state.global.globals = env;
+        return Ok(0);
+    }
+    if !matches!(f.value(), Value::LuaClosure(_)) || !state.set_fenv(&f, env) {
+        return Err(state.error("'setfenv' cannot change environment of given object".to_string()));
+    }
+    state.push(f);
+    Ok(1)
+}
+
+/// getmetatable(object)
+fn lua_getmetatable(state: &mut LuaState) -> Result<usize> {
+    let obj = state.check_any(1)?;
+    let res = match state.get_metatable(&obj) {
+        None => TValue::nil(),
+        Some(mt) => {
+            // a `__metatable` field hides the real metatable
+            let protected = state.get_metafield(&obj, "__metatable");
+            match protected.value() {
+                Value::Nil => TValue::table(mt),
+                _ => protected,
+            }
+        }
+    };
+    state.push(res);
+    Ok(1)
+}
+
+/// setmetatable(table, metatable)
+fn lua_setmetatable(state: &mut LuaState) -> Result<usize> {
+    let t = state.check_table(1)?;
+    let mt = match state.arg(2).value() {
+        Value::Nil => None,
+        Value::Table(mt) => Some(mt),
+        _ => return Err(state.type_error_arg(2, "nil or table")),
+    };
+    let obj = TValue::table(t);
+    if !matches!(state.get_metafield(&obj, "__metatable").value(), Value::Nil) {
+        return Err(state.error("cannot change a protected metatable".to_string()));
+    }
+    state.set_metatable(&obj, mt);
+    state.set_top(1);
+    Ok(1)
+}
+
+/// newproxy([boolean | proxy]): an empty userdata, optionally with a fresh
+/// metatable or sharing the metatable of another proxy
+fn lua_newproxy(state: &mut LuaState) -> Result<usize> {
+    state.set_top(1);
+    let u = state.new_userdata(Box::new(()));
+    let proxy = TValue::userdata(u);
+    state.push(proxy);
+    let arg = state.arg(1);
+    if is_false(&arg) {
+        return Ok(1);
+    }
+    // metatables created by newproxy, as keys of a weak table
+    let valid = match state.upvalue(1).value() {
+        Value::Table(t) => t,
+        _ => unreachable!("newproxy without its table"),
+    };
+    let is_valid = |state: &LuaState, mt| {
+        let key = TValue::table(mt);
+        !is_false(&state.global.heap.get(valid).get(&key))
+    };
+    let mt = match (arg.value(), state.get_metatable(&arg)) {
+        (Value::Boolean(_), _) => {
+            let mt = state.new_table();
+            let key = TValue::table(mt);
+            let yes = TValue::boolean(true);
+            state.global.heap.get_mut(valid).set(key, yes)?;
+            mt
+        }
+        (_, Some(mt)) if is_valid(state, mt) => mt,
+        _ => return Err(state.arg_error(1, "boolean or proxy expected")),
+    };
+    state.set_metatable(&proxy, Some(mt));
+    Ok(1)
+}
+
+fn check_co(state: &mut LuaState, narg: usize) -> Result<Gc<Thread>> {
+    match state.arg(narg).value() {
+        Value::Thread(co) => Ok(co),
+        _ => Err(state.arg_error(narg, "coroutine expected")),
+    }
+}
+
+/// push the results of a resume, returning how many there are
+fn push_results(state: &mut LuaState, results: &[TValue]) -> usize {
+    state.check_stack(results.len());
+    for v in results {
+        state.push(*v);
+    }
+    results.len()
+}
+
+/// coroutine.create(f)
+fn co_create(state: &mut LuaState) -> Result<usize> {
+    let f = state.arg(1);
+    if !matches!(f.value(), Value::LuaClosure(_)) {
+        return Err(state.arg_error(1, "Lua function expected"));
+    }
+    let co = state.new_thread(f);
+    state.push(TValue::thread(co));
+    Ok(1)
+}
+
+/// coroutine.resume(co, ...)
+fn co_resume(state: &mut LuaState) -> Result<usize> {
+    let co = check_co(state, 1)?;
+    let args = state.stack[state.arg_index(2)..state.top].to_vec();
+    match state.resume(co, &args) {
+        Ok(results) => {
+            state.push(TValue::boolean(true));
+            Ok(1 + push_results(state, &results))
+        }
+        Err(e) if e.is_uncatchable() => Err(e.into()),
+        Err(e) => {
+            state.push(TValue::boolean(false));
+            state.push(e.value());
+            Ok(2)
+        }
+    }
+}
+
+/// function returned by coroutine.wrap; errors are propagated to the caller
+fn aux_wrap(state: &mut LuaState) -> Result<usize> {
+    let co = match state.upvalue(1).value() {
+        Value::Thread(co) => co,
+        _ => unreachable!("wrap without a coroutine"),
+    };
+    let args = state.stack[state.arg_index(1)..state.top].to_vec();
+    match state.resume(co, &args) {
+        Ok(results) => Ok(push_results(state, &results)),
+        Err(e) if e.is_uncatchable() => Err(e.into()),
+        Err(e) => {
+            let mut err = e.value();
+            if let Some(msg) = state.to_str_bytes(&err) {
+                let mut bytes = state.where_(1).into_bytes();
+                bytes.extend_from_slice(&msg);
+                err = state.intern(&bytes);
+            }
+            Err(state.error_value(err))
+        }
+    }
+}
+
+/// coroutine.wrap(f)
+fn co_wrap(state: &mut LuaState) -> Result<usize> {
+    co_create(state)?;
+    let co = state.stack[state.top - 1];
+    let f = state.new_native_closure(aux_wrap, vec![co]);
+    state.stack[state.top - 1] = f;
+    Ok(1)
+}
+
+/// coroutine.yield(...)
+fn co_yield(state: &mut LuaState) -> Result<usize> {
+    let n = state.get_top();
+    state.yield_(n)
+}
+
+/// coroutine.status(co)
+fn co_status(state: &mut LuaState) -> Result<usize> {
+    let co = check_co(state, 1)?;
+    let status = state.intern(state.thread_status(co).name().as_bytes());
+    state.push(status);
+    Ok(1)
+}
+
+/// coroutine.running(), nil in the main thread
+fn co_running(state: &mut LuaState) -> Result<usize> {
+    if state.current == state.main_thread {
+        state.push(TValue::nil());
+    } else {
+        state.push(TValue::thread(state.current));
+    }
+    Ok(1)
+}
+
+pub fn open_base(state: &mut LuaState) {
+    let globals = state.global.globals;
+    state.raw_set_global("_G", TValue::table(globals));
+    // record the globals as the loaded module "_G"
+    state.register_lib("_G", &[]);
+    state.register("assert", lua_assert);
+    state.register("collectgarbage", lua_collectgarbage);
+    state.register("dofile", lua_dofile);
+    state.register("error", lua_error);
+    state.register("gcinfo", lua_gcinfo);
+    state.register("getfenv", lua_getfenv);
+    state.register("getmetatable", lua_getmetatable);
+    state.register("load", lua_load);
+    state.register("loadfile", lua_loadfile);
+    state.register("loadstring", lua_loadstring);
+    state.register("next", lua_next);
+    state.register("pcall", lua_pcall);
+    state.register("print", lua_print);
+    state.register("rawequal", lua_rawequal);
+    state.register("rawget", lua_rawget);
+    state.register("rawset", lua_rawset);
+    state.register("select", lua_select);
+    state.register("setfenv", lua_setfenv);
+    state.register("setmetatable", lua_setmetatable);
+    state.register("tonumber", lua_tonumber);
+    state.register("tostring", lua_tostring);
+    state.register("type", lua_type);
+    state.register("unpack", lua_unpack);
+    state.register("xpcall", lua_xpcall);
+    let version = state.intern(b"Lua 5.1");
+    state.raw_set_global("_VERSION", version);
+    // pairs and ipairs keep their iterator as an upvalue
+    let next = state.get_global("next");
+    let pairs = state.new_native_closure(lua_pairs, vec![next]);
+    state.raw_set_global("pairs", pairs);
+    let aux = state.new_native(ipairs_aux);
+    let ipairs = state.new_native_closure(lua_ipairs, vec![aux]);
+    state.raw_set_global("ipairs", ipairs);
+    // newproxy keeps the metatables it created as keys of a weak table
+    let valid = state.new_table();
+    let mode = state.intern(b"__mode");
+    let kv = state.intern(b"kv");
+    let heap = &mut state.global.heap;
+    heap.get_mut(valid).set(mode, kv).unwrap();
+    heap.get_mut(valid).metatable = Some(valid);
+    let newproxy = state.new_native_closure(lua_newproxy, vec![TValue::table(valid)]);
+    state.raw_set_global("newproxy", newproxy);
+    state.register_lib(
+        "coroutine",
+        &[
+            ("create", co_create),
+            ("resume", co_resume),
+            ("running", co_running),
+            ("status", co_status),
+            ("wrap", co_wrap),
+            ("yield", co_yield),
+        ],
+    );
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// call global `name` with `args`, returning all its results
+    fn call(state: &mut LuaState, name: &str, args: &[TValue]) -> Vec<TValue> {
+        let f = state.get_global(name);
+        call_value(state, f, args)
+    }
+
+    pub(crate) fn call_value(state: &mut LuaState, f: TValue, args: &[TValue]) -> Vec<TValue> {
+        let func = state.top;
+        state.push(f);
+        for arg in args {
+            state.push(*arg);
+        }
+        state.call(func, LUA_MULTRET).unwrap();
+        let res = state.stack[func..state.top].to_vec();
+        state.top = func;
+        res
+    }
+
+    pub(crate) fn show(state: &LuaState, vals: &[TValue]) -> Vec<String> {
+        vals.iter()
+            .map(|v| match state.to_str_bytes(v) {
+                Some(b) => String::from_utf8(b).unwrap(),
+                None => type_name(v).to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tonumber_and_tostring() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        let cases: [(&[u8], Option<f64>, &str); 5] = [
+            (b" 0x10 ", None, "16"),
+            (b"ff", Some(16.0), "255"),
+            (b"z", Some(36.0), "35"),
+            (b"8", Some(8.0), "nil"),
+            (b"1e15", None, "1e+15"),
+        ];
+        for (s, base, expected) in cases {
+            let mut args = vec![state.intern(s)];
+            args.extend(base.map(TValue::number));
+            let n = call(&mut state, "tonumber", &args);
+            let s = call(&mut state, "tostring", &n);
+            assert_eq!(show(&state, &s), vec![expected]);
+        }
+        let s = call(&mut state, "tostring", &[TValue::number(0.1)]);
+        assert_eq!(show(&state, &s), vec!["0.1"]);
+    }
+
+    #[test]
+    fn test_select_and_unpack() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        let (a, b) = (state.intern(b"a"), state.intern(b"b"));
+        let hash = state.intern(b"#");
+        let res = call(&mut state, "select", &[hash, a, b]);
+        assert_eq!(show(&state, &res), vec!["2"]);
+        let res = call(&mut state, "select", &[TValue::number(-1.0), a, b]);
+        assert_eq!(show(&state, &res), vec!["b"]);
+        let t = state.new_table();
+        for (i, v) in [a, b].into_iter().enumerate() {
+            state.global.heap.get_mut(t).set_int(i as i64 + 1, v);
+        }
+        let res = call(&mut state, "unpack", &[TValue::table(t)]);
+        assert_eq!(show(&state, &res), vec!["a", "b"]);
+        let res = call(
+            &mut state,
+            "unpack",
+            &[TValue::table(t), TValue::number(2.0)],
+        );
+        assert_eq!(show(&state, &res), vec!["b"]);
+    }
+
+    #[test]
+    fn test_fenv_and_gcinfo() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        let res: (bool, bool, bool, i64, String) = state
+            .exec(
+                "local function f() return x end
+                 local env = {x = 'env'}
+                 local same = setfenv(f, env) == f and getfenv(f) == env
+                 local level = getfenv() == _G and getfenv(1) == _G and getfenv(0) == _G
+                 local native = getfenv(print) == _G
+                 return same and f() == 'env', level, native, gcinfo(),
+                     select(2, pcall(setfenv, print, env))",
+            )
+            .unwrap();
+        assert!(res.0 && res.1 && res.2);
+        assert!(res.3 > 0);
+        assert!(
+            res.4
+                .ends_with("'setfenv' cannot change environment of given object"),
+            "{}",
+            res.4
+        );
+        // setfenv(1, t) changes the caller's globals
+        let x: i32 = state
+            .exec("setfenv(1, {getfenv = getfenv, x = 7}) return getfenv(1).x")
+            .unwrap();
+        assert_eq!(x, 7);
+    }
+
+    #[test]
+    fn test_loadstring() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        let source = state.intern(b"local a, b = ... return a * b");
+        let f = call(&mut state, "loadstring", &[source]);
+        let res = call_value(
+            &mut state,
+            f[0],
+            &[TValue::number(6.0), TValue::number(7.0)],
+        );
+        assert_eq!(show(&state, &res), vec!["42"]);
+
+        let (bad, name) = (state.intern(b"return +"), state.intern(b"=chunk"));
+        let res = call(&mut state, "loadstring", &[bad, name]);
+        let msg = show(&state, &res).remove(1);
+        assert!(msg.starts_with("chunk:1: "), "{msg}");
+
+        // a precompiled chunk is refused on request and checked otherwise
+        let binary = state.intern(b"\x1bLua\x51\x00\x01\x04\x04\x04\x08\x00");
+        let res = call(&mut state, "loadstring", &[binary, name]);
+        assert_eq!(
+            show(&state, &res),
+            vec!["nil", "chunk: unexpected end in precompiled chunk"]
+        );
+        state.set_binary_chunks(false);
+        let res = call(&mut state, "loadstring", &[binary]);
+        assert_eq!(
+            show(&state, &res),
+            vec!["nil", "attempt to load a binary chunk"]
+        );
+    }
+}
@@ -0,0 +1,2163 @@
+//! Compiler from the full_moon AST to Lua 5.1 bytecode. Code generation
+//! follows lparser.c and lcode.c step by step, so the chunks it produces use
+//! registers, jumps and constants the way `luac` does.
+
+use std::collections::HashMap;
+
+use full_moon::ast::{
+    Ast, BinOp, Block, Call, Expression, Field, FunctionArgs, FunctionBody, Index, LastStmt,
+    Parameter, Prefix, Stmt, Suffix, TableConstructor, UnOp, Var,
+};
+use full_moon::node::Node;
+use full_moon::tokenizer::{StringLiteralQuoteType, Symbol, TokenReference, TokenType};
+
+use crate::debug::chunk_id;
+use crate::eval::{LuaInteger, LuaNumber, MAX_EXACT_INTEGER};
+use crate::func::{VARARG_HASARG, VARARG_ISVARARG, VARARG_NEEDSARG};
+use crate::opcodes::{
+    LFIELDS_PER_FLUSH, MAXARG_BX, MAXARG_C, MAXARG_SBX, MAXINDEXRK, NO_REG, OpCode, create_abc,
+    create_abx, create_asbx, get_a, get_b, get_c, get_op, get_sbx, is_k, is_test, rk_ask, set_a,
+    set_b, set_c, set_op, set_sbx,
+};
+use crate::parser::check_depth;
+use crate::undump::{Chunk, Constant, Local, LuaInt, MetaInfo};
+use crate::vm::{LUA_MULTRET, LUAI_MAXCCALLS, str2number};
+
+/// empty jump list
+const NO_JUMP: i32 = -1;
+/// maximum number of registers of a function
+const MAXSTACK: usize = 250;
+/// maximum number of local variables per function
+const LUAI_MAXVARS: usize = 200;
+/// maximum number of upvalues per function
+const LUAI_MAXUPVALUES: usize = 60;
+
+/// Error message without the position, which `compile` adds.
+type CResult<T> = Result<T, String>;
+
+/// Where the value of an expression is (expkind in lparser.h)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExpKind {
+    /// no value
+    Void,
+    Nil,
+    True,
+    False,
+    /// constant index
+    K(usize),
+    KNum(LuaNumber),
+    /// integral numeral, kept apart for the integer variant
+    KInt(LuaInteger),
+    /// register of a local variable
+    Local(usize),
+    /// upvalue index
+    Upval(usize),
+    /// constant index of the name
+    Global(usize),
+    /// table register and key RK operand
+    Indexed(usize, usize),
+    /// pc of a test instruction's jump
+    Jmp(usize),
+    /// pc of an instruction whose result register is still to be set
+    Relocable(usize),
+    /// fixed register
+    NonReloc(usize),
+    /// pc of the CALL instruction
+    Call(usize),
+    /// pc of the VARARG instruction
+    Vararg(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ExpDesc {
+    k: ExpKind,
+    /// patch list of "exit when true"
+    t: i32,
+    /// patch list of "exit when false"
+    f: i32,
+}
+
+impl ExpDesc {
+    fn new(k: ExpKind) -> Self {
+        Self {
+            k,
+            t: NO_JUMP,
+            f: NO_JUMP,
+        }
+    }
+    fn has_jumps(&self) -> bool {
+        self.t != self.f
+    }
+    fn has_multret(&self) -> bool {
+        matches!(self.k, ExpKind::Call(_) | ExpKind::Vararg(_))
+    }
+    /// a numeric constant without pending jumps (isnumeral)
+    fn numeral(&self) -> Option<LuaNumber> {
+        match self.k {
+            ExpKind::KNum(n) if !self.has_jumps() && self.t == NO_JUMP => Some(n),
+            ExpKind::KInt(n) if !self.has_jumps() && self.t == NO_JUMP => Some(n as LuaNumber),
+            _ => None,
+        }
+    }
+    /// an integral numeric constant without pending jumps
+    fn integer(&self) -> Option<LuaInteger> {
+        match self.k {
+            ExpKind::KInt(n) if !self.has_jumps() && self.t == NO_JUMP => Some(n),
+            _ => None,
+        }
+    }
+    /// register of a discharged expression
+    fn reg(&self) -> usize {
+        match self.k {
+            ExpKind::NonReloc(r) | ExpKind::Local(r) => r,
+            k => unreachable!("expression not in a register: {k:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinOpr {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Concat,
+    Ne,
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnOpr {
+    Minus,
+    Not,
+    Len,
+}
+
+/// key of the constant cache; numbers by bits, with zeros merged as in a Lua table
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum KKey {
+    Nil,
+    Bool(bool),
+    Num(u64),
+    Int(i64),
+    Str(Vec<u8>),
+}
+
+struct BlockCnt {
+    /// list of jumps out of this loop
+    breaklist: i32,
+    /// number of active locals outside the block
+    nactvar: usize,
+    /// some variable in the block is an upvalue
+    upval: bool,
+    /// the block is a loop
+    is_breakable: bool,
+}
+
+/// Where an upvalue of a function comes from in the enclosing one
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UpvalDesc {
+    Local(usize),
+    Upval(usize),
+}
+
+/// State of the function being compiled (FuncState in lparser.h)
+struct FuncState {
+    code: Vec<u32>,
+    lineinfo: Vec<usize>,
+    constants: Vec<Constant>,
+    kcache: HashMap<KKey, usize>,
+    protos: Vec<Chunk>,
+    locvars: Vec<Local>,
+    upvalues: Vec<(String, UpvalDesc)>,
+    /// indices in `locvars` of the declared variables, active ones first
+    actvar: Vec<usize>,
+    nactvar: usize,
+    /// first free register
+    freereg: usize,
+    /// list of pending jumps to the next instruction
+    jpc: i32,
+    /// pc of the last jump target
+    lasttarget: i32,
+    blocks: Vec<BlockCnt>,
+    line_defined: usize,
+    num_params: usize,
+    is_vararg: u8,
+    max_stack: usize,
+    /// line given to emitted instructions
+    line: usize,
+}
+
+impl FuncState {
+    fn new(line_defined: usize) -> Self {
+        Self {
+            code: Vec::new(),
+            lineinfo: Vec::new(),
+            constants: Vec::new(),
+            kcache: HashMap::new(),
+            protos: Vec::new(),
+            locvars: Vec::new(),
+            upvalues: Vec::new(),
+            actvar: Vec::new(),
+            nactvar: 0,
+            freereg: 0,
+            jpc: NO_JUMP,
+            lasttarget: -1,
+            blocks: Vec::new(),
+            line_defined,
+            num_params: 0,
+            is_vararg: 0,
+            max_stack: 2,
+            line: line_defined,
+        }
+    }
+
+    fn pc(&self) -> usize {
+        self.code.len()
+    }
+
+    // ---- instructions ----
+
+    fn code(&mut self, inst: u32) -> CResult<usize> {
+        self.discharge_jpc()?;
+        self.code.push(inst);
+        self.lineinfo.push(self.line);
+        Ok(self.pc() - 1)
+    }
+    fn code_abc(&mut self, op: OpCode, a: usize, b: usize, c: usize) -> CResult<usize> {
+        self.code(create_abc(op, a, b, c))
+    }
+    fn code_abx(&mut self, op: OpCode, a: usize, bx: usize) -> CResult<usize> {
+        self.code(create_abx(op, a, bx))
+    }
+    fn code_asbx(&mut self, op: OpCode, a: usize, sbx: isize) -> CResult<usize> {
+        self.code(create_asbx(op, a, sbx))
+    }
+    /// give the last instruction the line of its construct
+    fn fix_line(&mut self, line: usize) {
+        if let Some(l) = self.lineinfo.last_mut() {
+            *l = line;
+        }
+    }
+    /// set registers `from..from+n` to nil, merging with a previous LOADNIL
+    fn nil(&mut self, from: usize, n: usize) -> CResult<()> {
+        if self.pc() as i32 > self.lasttarget {
+            if self.pc() == 0 {
+                if from >= self.nactvar {
+                    // positions are already clean at function start
+                    return Ok(());
+                }
+            } else {
+                let prev = self.code.last_mut().unwrap();
+                if get_op(*prev) == OpCode::OpLoadNil as u32 {
+                    let (pfrom, pto) = (get_a(*prev), get_b(*prev));
+                    if pfrom <= from && from <= pto + 1 {
+                        if from + n - 1 > pto {
+                            set_b(prev, from + n - 1);
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        self.code_abc(OpCode::OpLoadNil, from, from + n - 1, 0)?;
+        Ok(())
+    }
+    fn ret(&mut self, first: usize, nret: i32) -> CResult<()> {
+        self.code_abc(OpCode::OpReturn, first, (nret + 1) as usize, 0)?;
+        Ok(())
+    }
+
+    // ---- jumps ----
+
+    fn jump(&mut self) -> CResult<i32> {
+        let jpc = self.jpc;
+        self.jpc = NO_JUMP;
+        let mut j = self.code_asbx(OpCode::OpJmp, 0, NO_JUMP as isize)? as i32;
+        self.concat(&mut j, jpc)?;
+        Ok(j)
+    }
+    fn cond_jump(&mut self, op: OpCode, a: usize, b: usize, c: usize) -> CResult<i32> {
+        self.code_abc(op, a, b, c)?;
+        self.jump()
+    }
+    /// mark the current pc as a jump target
+    fn get_label(&mut self) -> i32 {
+        self.lasttarget = self.pc() as i32;
+        self.lasttarget
+    }
+    fn get_jump(&self, pc: i32) -> i32 {
+        let offset = get_sbx(self.code[pc as usize]);
+        if offset == NO_JUMP as isize {
+            NO_JUMP
+        } else {
+            pc + 1 + offset as i32
+        }
+    }
+    fn fix_jump(&mut self, pc: i32, dest: i32) -> CResult<()> {
+        let offset = dest - (pc + 1);
+        if offset.unsigned_abs() as usize > MAXARG_SBX {
+            return Err("control structure too long".to_string());
+        }
+        set_sbx(&mut self.code[pc as usize], offset as isize);
+        Ok(())
+    }
+    /// the instruction controlling the jump at `pc`: its test, if any
+    fn jump_control(&self, pc: i32) -> usize {
+        let pc = pc as usize;
+        if pc >= 1 && is_test(get_op(self.code[pc - 1])) {
+            pc - 1
+        } else {
+            pc
+        }
+    }
+    /// does some jump in the list need a value (not produced by a TESTSET)
+    fn need_value(&self, mut list: i32) -> bool {
+        while list != NO_JUMP {
+            if get_op(self.code[self.jump_control(list)]) != OpCode::OpTestSet as u32 {
+                return true;
+            }
+            list = self.get_jump(list);
+        }
+        false
+    }
+    fn patch_test_reg(&mut self, node: i32, reg: usize) -> bool {
+        let pc = self.jump_control(node);
+        let inst = self.code[pc];
+        if get_op(inst) != OpCode::OpTestSet as u32 {
+            return false;
+        }
+        if reg != NO_REG && reg != get_b(inst) {
+            set_a(&mut self.code[pc], reg);
+        } else {
+            // no register to put the value or it is already there
+            self.code[pc] = create_abc(OpCode::OpTest, get_b(inst), 0, get_c(inst));
+        }
+        true
+    }
+    fn remove_values(&mut self, mut list: i32) {
+        while list != NO_JUMP {
+            self.patch_test_reg(list, NO_REG);
+            list = self.get_jump(list);
+        }
+    }
+    fn patch_list_aux(
+        &mut self,
+        mut list: i32,
+        vtarget: i32,
+        reg: usize,
+        dtarget: i32,
+    ) -> CResult<()> {
+        while list != NO_JUMP {
+            let next = self.get_jump(list);
+            if self.patch_test_reg(list, reg) {
+                self.fix_jump(list, vtarget)?;
+            } else {
+                self.fix_jump(list, dtarget)?;
+            }
+            list = next;
+        }
+        Ok(())
+    }
+    fn discharge_jpc(&mut self) -> CResult<()> {
+        let pc = self.pc() as i32;
+        let jpc = self.jpc;
+        self.jpc = NO_JUMP;
+        self.patch_list_aux(jpc, pc, NO_REG, pc)
+    }
+    fn patch_list(&mut self, list: i32, target: i32) -> CResult<()> {
+        if target == self.pc() as i32 {
+            self.patch_to_here(list)
+        } else {
+            self.patch_list_aux(list, target, NO_REG, target)
+        }
+    }
+    fn patch_to_here(&mut self, list: i32) -> CResult<()> {
+        self.get_label();
+        let mut jpc = self.jpc;
+        self.concat(&mut jpc, list)?;
+        self.jpc = jpc;
+        Ok(())
+    }
+    fn concat(&mut self, l1: &mut i32, l2: i32) -> CResult<()> {
+        if l2 == NO_JUMP {
+            return Ok(());
+        }
+        if *l1 == NO_JUMP {
+            *l1 = l2;
+            return Ok(());
+        }
+        let mut list = *l1;
+        loop {
+            let next = self.get_jump(list);
+            if next == NO_JUMP {
+                break;
+            }
+            list = next;
+        }
+        self.fix_jump(list, l2)
+    }
+
+    // ---- registers and constants ----
+
+    fn check_stack(&mut self, n: usize) -> CResult<()> {
+        let newstack = self.freereg + n;
+        if newstack > self.max_stack {
+            if newstack >= MAXSTACK {
+                return Err("function or expression too complex".to_string());
+            }
+            self.max_stack = newstack;
+        }
+        Ok(())
+    }
+    fn reserve_regs(&mut self, n: usize) -> CResult<()> {
+        self.check_stack(n)?;
+        self.freereg += n;
+        Ok(())
+    }
+    fn free_reg(&mut self, reg: usize) {
+        if !is_k(reg) && reg >= self.nactvar {
+            self.freereg -= 1;
+            debug_assert_eq!(reg, self.freereg);
+        }
+    }
+    fn free_exp(&mut self, e: &ExpDesc) {
+        if let ExpKind::NonReloc(r) = e.k {
+            self.free_reg(r);
+        }
+    }
+    fn add_k(&mut self, key: KKey, val: Constant) -> CResult<usize> {
+        if let Some(&i) = self.kcache.get(&key) {
+            return Ok(i);
+        }
+        let i = self.constants.len();
+        if i > MAXARG_BX {
+            return Err("constant table overflow".to_string());
+        }
+        self.constants.push(val);
+        self.kcache.insert(key, i);
+        Ok(i)
+    }
+    fn string_k(&mut self, s: &[u8]) -> CResult<usize> {
+        self.add_k(KKey::Str(s.to_vec()), Constant::String(s.to_vec()))
+    }
+    fn number_k(&mut self, n: LuaNumber) -> CResult<usize> {
+        let bits = if n == 0.0 { 0 } else { n.to_bits() };
+        self.add_k(KKey::Num(bits), Constant::Number(n))
+    }
+    fn integer_k(&mut self, n: LuaInteger) -> CResult<usize> {
+        self.add_k(KKey::Int(n), Constant::Integer(n))
+    }
+
+    // ---- expressions ----
+
+    fn set_returns(&mut self, e: &mut ExpDesc, nresults: i32) -> CResult<()> {
+        match e.k {
+            ExpKind::Call(pc) => set_c(&mut self.code[pc], (nresults + 1) as usize),
+            ExpKind::Vararg(pc) => {
+                set_b(&mut self.code[pc], (nresults + 1) as usize);
+                set_a(&mut self.code[pc], self.freereg);
+                self.reserve_regs(1)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    fn set_one_ret(&mut self, e: &mut ExpDesc) {
+        match e.k {
+            // calls are created returning one result
+            ExpKind::Call(pc) => e.k = ExpKind::NonReloc(get_a(self.code[pc])),
+            ExpKind::Vararg(pc) => {
+                set_b(&mut self.code[pc], 2);
+                e.k = ExpKind::Relocable(pc);
+            }
+            _ => {}
+        }
+    }
+    fn discharge_vars(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        match e.k {
+            ExpKind::Local(r) => e.k = ExpKind::NonReloc(r),
+            ExpKind::Upval(i) => {
+                e.k = ExpKind::Relocable(self.code_abc(OpCode::OpGetUpval, 0, i, 0)?);
+            }
+            ExpKind::Global(k) => {
+                e.k = ExpKind::Relocable(self.code_abx(OpCode::OpGetGlobal, 0, k)?);
+            }
+            ExpKind::Indexed(t, key) => {
+                self.free_reg(key);
+                self.free_reg(t);
+                e.k = ExpKind::Relocable(self.code_abc(OpCode::OpGetTable, 0, t, key)?);
+            }
+            ExpKind::Call(_) | ExpKind::Vararg(_) => self.set_one_ret(e),
+            _ => {}
+        }
+        Ok(())
+    }
+    fn code_label(&mut self, a: usize, b: usize, jump: usize) -> CResult<i32> {
+        self.get_label();
+        Ok(self.code_abc(OpCode::OpLoadBool, a, b, jump)? as i32)
+    }
+    fn discharge2reg(&mut self, e: &mut ExpDesc, reg: usize) -> CResult<()> {
+        self.discharge_vars(e)?;
+        match e.k {
+            ExpKind::Nil => self.nil(reg, 1)?,
+            ExpKind::False | ExpKind::True => {
+                let b = (e.k == ExpKind::True) as usize;
+                self.code_abc(OpCode::OpLoadBool, reg, b, 0)?;
+            }
+            ExpKind::K(i) => {
+                self.code_abx(OpCode::OpLoadK, reg, i)?;
+            }
+            ExpKind::KNum(n) => {
+                let i = self.number_k(n)?;
+                self.code_abx(OpCode::OpLoadK, reg, i)?;
+            }
+            ExpKind::KInt(n) => {
+                let i = self.integer_k(n)?;
+                self.code_abx(OpCode::OpLoadK, reg, i)?;
+            }
+            ExpKind::Relocable(pc) => set_a(&mut self.code[pc], reg),
+            ExpKind::NonReloc(r) => {
+                if reg != r {
+                    self.code_abc(OpCode::OpMove, reg, r, 0)?;
+                }
+            }
+            // nothing to do for Void and Jmp
+            _ => return Ok(()),
+        }
+        e.k = ExpKind::NonReloc(reg);
+        Ok(())
+    }
+    fn discharge2anyreg(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        if !matches!(e.k, ExpKind::NonReloc(_)) {
+            self.reserve_regs(1)?;
+            self.discharge2reg(e, self.freereg - 1)?;
+        }
+        Ok(())
+    }
+    fn exp2reg(&mut self, e: &mut ExpDesc, reg: usize) -> CResult<()> {
+        self.discharge2reg(e, reg)?;
+        if let ExpKind::Jmp(pc) = e.k {
+            let mut t = e.t;
+            self.concat(&mut t, pc as i32)?;
+            e.t = t;
+        }
+        if e.has_jumps() {
+            let mut p_f = NO_JUMP;
+            let mut p_t = NO_JUMP;
+            if self.need_value(e.t) || self.need_value(e.f) {
+                let fj = if matches!(e.k, ExpKind::Jmp(_)) {
+                    NO_JUMP
+                } else {
+                    self.jump()?
+                };
+                p_f = self.code_label(reg, 0, 1)?;
+                p_t = self.code_label(reg, 1, 0)?;
+                self.patch_to_here(fj)?;
+            }
+            let end = self.get_label();
+            self.patch_list_aux(e.f, end, reg, p_f)?;
+            self.patch_list_aux(e.t, end, reg, p_t)?;
+        }
+        e.t = NO_JUMP;
+        e.f = NO_JUMP;
+        e.k = ExpKind::NonReloc(reg);
+        Ok(())
+    }
+    fn exp2nextreg(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        self.discharge_vars(e)?;
+        self.free_exp(e);
+        self.reserve_regs(1)?;
+        self.exp2reg(e, self.freereg - 1)
+    }
+    fn exp2anyreg(&mut self, e: &mut ExpDesc) -> CResult<usize> {
+        self.discharge_vars(e)?;
+        if let ExpKind::NonReloc(r) = e.k {
+            if !e.has_jumps() {
+                return Ok(r);
+            }
+            if r >= self.nactvar {
+                // the register is not a local: put the final result in it
+                self.exp2reg(e, r)?;
+                return Ok(r);
+            }
+        }
+        self.exp2nextreg(e)?;
+        Ok(e.reg())
+    }
+    fn exp2val(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        if e.has_jumps() {
+            self.exp2anyreg(e)?;
+            Ok(())
+        } else {
+            self.discharge_vars(e)
+        }
+    }
+    fn exp2rk(&mut self, e: &mut ExpDesc) -> CResult<usize> {
+        self.exp2val(e)?;
+        match e.k {
+            ExpKind::KNum(_) | ExpKind::KInt(_) | ExpKind::True | ExpKind::False | ExpKind::Nil
+                if self.constants.len() <= MAXINDEXRK =>
+            {
+                let i = match e.k {
+                    ExpKind::Nil => self.add_k(KKey::Nil, Constant::Nil)?,
+                    ExpKind::KNum(n) => self.number_k(n)?,
+                    ExpKind::KInt(n) => self.integer_k(n)?,
+                    k => {
+                        let b = k == ExpKind::True;
+                        self.add_k(KKey::Bool(b), Constant::Bool(b))?
+                    }
+                };
+                e.k = ExpKind::K(i);
+                return Ok(rk_ask(i));
+            }
+            ExpKind::K(i) if i <= MAXINDEXRK => return Ok(rk_ask(i)),
+            _ => {}
+        }
+        // not a constant in the right range: put it in a register
+        self.exp2anyreg(e)
+    }
+    fn store_var(&mut self, var: &ExpDesc, ex: &mut ExpDesc) -> CResult<()> {
+        match var.k {
+            ExpKind::Local(r) => {
+                self.free_exp(ex);
+                return self.exp2reg(ex, r);
+            }
+            ExpKind::Upval(i) => {
+                let e = self.exp2anyreg(ex)?;
+                self.code_abc(OpCode::OpSetUpval, e, i, 0)?;
+            }
+            ExpKind::Global(k) => {
+                let e = self.exp2anyreg(ex)?;
+                self.code_abx(OpCode::OpSetGlobal, e, k)?;
+            }
+            ExpKind::Indexed(t, key) => {
+                let e = self.exp2rk(ex)?;
+                self.code_abc(OpCode::OpSetTable, t, key, e)?;
+            }
+            k => unreachable!("invalid assignment target {k:?}"),
+        }
+        self.free_exp(ex);
+        Ok(())
+    }
+    fn self_(&mut self, e: &mut ExpDesc, key: &mut ExpDesc) -> CResult<()> {
+        self.exp2anyreg(e)?;
+        self.free_exp(e);
+        let func = self.freereg;
+        self.reserve_regs(2)?;
+        let k = self.exp2rk(key)?;
+        self.code_abc(OpCode::OpSelf, func, e.reg(), k)?;
+        self.free_exp(key);
+        e.k = ExpKind::NonReloc(func);
+        Ok(())
+    }
+    fn invert_jump(&mut self, pc: usize) {
+        let pc = self.jump_control(pc as i32);
+        let a = get_a(self.code[pc]);
+        set_a(&mut self.code[pc], (a == 0) as usize);
+    }
+    fn jump_on_cond(&mut self, e: &mut ExpDesc, cond: bool) -> CResult<i32> {
+        if let ExpKind::Relocable(pc) = e.k {
+            let inst = self.code[pc];
+            if get_op(inst) == OpCode::OpNot as u32 {
+                // remove the NOT and test its operand instead
+                self.code.pop();
+                self.lineinfo.pop();
+                return self.cond_jump(OpCode::OpTest, get_b(inst), 0, !cond as usize);
+            }
+        }
+        self.discharge2anyreg(e)?;
+        self.free_exp(e);
+        self.cond_jump(OpCode::OpTestSet, NO_REG, e.reg(), cond as usize)
+    }
+    fn go_if_true(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        self.discharge_vars(e)?;
+        let pc = match e.k {
+            // always true: do nothing
+            ExpKind::K(_) | ExpKind::KNum(_) | ExpKind::KInt(_) | ExpKind::True => NO_JUMP,
+            ExpKind::False => self.jump()?,
+            ExpKind::Jmp(pc) => {
+                self.invert_jump(pc);
+                pc as i32
+            }
+            _ => self.jump_on_cond(e, false)?,
+        };
+        let mut f = e.f;
+        self.concat(&mut f, pc)?;
+        e.f = f;
+        self.patch_to_here(e.t)?;
+        e.t = NO_JUMP;
+        Ok(())
+    }
+    fn go_if_false(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        self.discharge_vars(e)?;
+        let pc = match e.k {
+            // always false: do nothing
+            ExpKind::Nil | ExpKind::False => NO_JUMP,
+            ExpKind::True => self.jump()?,
+            ExpKind::Jmp(pc) => pc as i32,
+            _ => self.jump_on_cond(e, true)?,
+        };
+        let mut t = e.t;
+        self.concat(&mut t, pc)?;
+        e.t = t;
+        self.patch_to_here(e.f)?;
+        e.f = NO_JUMP;
+        Ok(())
+    }
+    fn code_not(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        self.discharge_vars(e)?;
+        match e.k {
+            ExpKind::Nil | ExpKind::False => e.k = ExpKind::True,
+            ExpKind::K(_) | ExpKind::KNum(_) | ExpKind::KInt(_) | ExpKind::True => {
+                e.k = ExpKind::False
+            }
+            ExpKind::Jmp(pc) => self.invert_jump(pc),
+            ExpKind::Relocable(_) | ExpKind::NonReloc(_) => {
+                self.discharge2anyreg(e)?;
+                self.free_exp(e);
+                e.k = ExpKind::Relocable(self.code_abc(OpCode::OpNot, 0, e.reg(), 0)?);
+            }
+            k => unreachable!("cannot negate {k:?}"),
+        }
+        // interchange true and false lists
+        std::mem::swap(&mut e.f, &mut e.t);
+        self.remove_values(e.f);
+        self.remove_values(e.t);
+        Ok(())
+    }
+    fn indexed(&mut self, t: &mut ExpDesc, k: &mut ExpDesc) -> CResult<()> {
+        let key = self.exp2rk(k)?;
+        t.k = ExpKind::Indexed(t.reg(), key);
+        Ok(())
+    }
+    fn code_arith(&mut self, op: OpCode, e1: &mut ExpDesc, e2: &mut ExpDesc) -> CResult<()> {
+        if const_folding(op, e1, e2) {
+            return Ok(());
+        }
+        let o2 = if op != OpCode::OpUnm && op != OpCode::OpLen {
+            self.exp2rk(e2)?
+        } else {
+            0
+        };
+        let o1 = self.exp2rk(e1)?;
+        if o1 > o2 {
+            self.free_exp(e1);
+            self.free_exp(e2);
+        } else {
+            self.free_exp(e2);
+            self.free_exp(e1);
+        }
+        e1.k = ExpKind::Relocable(self.code_abc(op, 0, o1, o2)?);
+        Ok(())
+    }
+    fn code_comp(
+        &mut self,
+        op: OpCode,
+        cond: usize,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+    ) -> CResult<()> {
+        let mut o1 = self.exp2rk(e1)?;
+        let mut o2 = self.exp2rk(e2)?;
+        self.free_exp(e2);
+        self.free_exp(e1);
+        let mut cond = cond;
+        if cond == 0 && op != OpCode::OpEq {
+            // exchange args to replace by `<' or `<='
+            std::mem::swap(&mut o1, &mut o2);
+            cond = 1;
+        }
+        e1.k = ExpKind::Jmp(self.cond_jump(op, cond, o1, o2)? as usize);
+        Ok(())
+    }
+    fn prefix(&mut self, op: UnOpr, e: &mut ExpDesc) -> CResult<()> {
+        let mut e2 = ExpDesc::new(ExpKind::KNum(0.0));
+        match op {
+            UnOpr::Minus => {
+                if e.numeral().is_none() {
+                    self.exp2anyreg(e)?;
+                }
+                self.code_arith(OpCode::OpUnm, e, &mut e2)
+            }
+            UnOpr::Not => self.code_not(e),
+            UnOpr::Len => {
+                self.exp2anyreg(e)?;
+                self.code_arith(OpCode::OpLen, e, &mut e2)
+            }
+        }
+    }
+    fn infix(&mut self, op: BinOpr, v: &mut ExpDesc) -> CResult<()> {
+        match op {
+            BinOpr::And => self.go_if_true(v),
+            BinOpr::Or => self.go_if_false(v),
+            // operand must be on the `stack'
+            BinOpr::Concat => self.exp2nextreg(v),
+            BinOpr::Add | BinOpr::Sub | BinOpr::Mul | BinOpr::Div | BinOpr::Mod | BinOpr::Pow => {
+                if v.numeral().is_none() {
+                    self.exp2rk(v)?;
+                }
+                Ok(())
+            }
+            _ => {
+                self.exp2rk(v)?;
+                Ok(())
+            }
+        }
+    }
+    fn posfix(&mut self, op: BinOpr, e1: &mut ExpDesc, e2: &mut ExpDesc) -> CResult<()> {
+        match op {
+            BinOpr::And => {
+                debug_assert_eq!(e1.t, NO_JUMP);
+                self.discharge_vars(e2)?;
+                let mut f = e2.f;
+                self.concat(&mut f, e1.f)?;
+                e2.f = f;
+                *e1 = *e2;
+            }
+            BinOpr::Or => {
+                debug_assert_eq!(e1.f, NO_JUMP);
+                self.discharge_vars(e2)?;
+                let mut t = e2.t;
+                self.concat(&mut t, e1.t)?;
+                e2.t = t;
+                *e1 = *e2;
+            }
+            BinOpr::Concat => {
+                self.exp2val(e2)?;
+                match e2.k {
+                    ExpKind::Relocable(pc) if get_op(self.code[pc]) == OpCode::OpConcat as u32 => {
+                        // merge into the following CONCAT
+                        debug_assert_eq!(e1.reg(), get_b(self.code[pc]) - 1);
+                        self.free_exp(e1);
+                        set_b(&mut self.code[pc], e1.reg());
+                        e1.k = ExpKind::Relocable(pc);
+                    }
+                    _ => {
+                        self.exp2nextreg(e2)?;
+                        self.code_arith(OpCode::OpConcat, e1, e2)?;
+                    }
+                }
+            }
+            BinOpr::Add => self.code_arith(OpCode::OpAdd, e1, e2)?,
+            BinOpr::Sub => self.code_arith(OpCode::OpSub, e1, e2)?,
+            BinOpr::Mul => self.code_arith(OpCode::OpMul, e1, e2)?,
+            BinOpr::Div => self.code_arith(OpCode::OpDiv, e1, e2)?,
+            BinOpr::Mod => self.code_arith(OpCode::OpMod, e1, e2)?,
+            BinOpr::Pow => self.code_arith(OpCode::OpPow, e1, e2)?,
+            BinOpr::Eq => self.code_comp(OpCode::OpEq, 1, e1, e2)?,
+            BinOpr::Ne => self.code_comp(OpCode::OpEq, 0, e1, e2)?,
+            BinOpr::Lt => self.code_comp(OpCode::OpLt, 1, e1, e2)?,
+            BinOpr::Le => self.code_comp(OpCode::OpLe, 1, e1, e2)?,
+            BinOpr::Gt => self.code_comp(OpCode::OpLt, 0, e1, e2)?,
+            BinOpr::Ge => self.code_comp(OpCode::OpLe, 0, e1, e2)?,
+        }
+        Ok(())
+    }
+    fn set_list(&mut self, base: usize, nelems: usize, tostore: i32) -> CResult<()> {
+        let c = (nelems - 1) / LFIELDS_PER_FLUSH + 1;
+        let b = if tostore == LUA_MULTRET {
+            0
+        } else {
+            tostore as usize
+        };
+        if c <= MAXARG_C {
+            self.code_abc(OpCode::OpSetList, base, b, c)?;
+        } else {
+            self.code_abc(OpCode::OpSetList, base, b, 0)?;
+            self.code(c as u32)?;
+        }
+        // free registers with list values
+        self.freereg = base + 1;
+        Ok(())
+    }
+
+    // ---- variables and blocks ----
+
+    fn new_localvar(&mut self, name: &str, n: usize) -> CResult<()> {
+        if self.nactvar + n + 1 > LUAI_MAXVARS {
+            return Err(self.limit_error(LUAI_MAXVARS, "local variables"));
+        }
+        self.locvars
+            .push(Local::new(name.to_string(), LuaInt::U32(0), LuaInt::U32(0)));
+        self.actvar.truncate(self.nactvar + n);
+        self.actvar.push(self.locvars.len() - 1);
+        Ok(())
+    }
+    fn adjust_localvars(&mut self, nvars: usize) {
+        self.nactvar += nvars;
+        let pc = LuaInt::U32(self.pc() as u32);
+        for i in self.nactvar - nvars..self.nactvar {
+            self.locvars[self.actvar[i]].start_line = pc;
+        }
+    }
+    fn remove_vars(&mut self, tolevel: usize) {
+        let pc = LuaInt::U32(self.pc() as u32);
+        while self.nactvar > tolevel {
+            self.nactvar -= 1;
+            self.locvars[self.actvar[self.nactvar]].end_line = pc;
+        }
+        self.actvar.truncate(tolevel);
+    }
+    fn search_var(&self, name: &str) -> Option<usize> {
+        (0..self.nactvar)
+            .rev()
+            .find(|&i| self.locvars[self.actvar[i]].name == name)
+    }
+    /// mark the block where local `level` was defined as having an upvalue
+    fn mark_upval(&mut self, level: usize) {
+        if let Some(bl) = self.blocks.iter_mut().rev().find(|bl| bl.nactvar <= level) {
+            bl.upval = true;
+        }
+    }
+    fn index_upvalue(&mut self, name: &str, desc: UpvalDesc) -> CResult<usize> {
+        if let Some(i) = self.upvalues.iter().position(|(_, d)| *d == desc) {
+            return Ok(i);
+        }
+        if self.upvalues.len() + 1 > LUAI_MAXUPVALUES {
+            return Err(self.limit_error(LUAI_MAXUPVALUES, "upvalues"));
+        }
+        self.upvalues.push((name.to_string(), desc));
+        Ok(self.upvalues.len() - 1)
+    }
+    fn limit_error(&self, limit: usize, what: &str) -> String {
+        if self.line_defined == 0 {
+            format!("main function has more than {limit} {what}")
+        } else {
+            format!(
+                "function at line {} has more than {limit} {what}",
+                self.line_defined
+            )
+        }
+    }
+    fn enter_block(&mut self, is_breakable: bool) {
+        self.blocks.push(BlockCnt {
+            breaklist: NO_JUMP,
+            nactvar: self.nactvar,
+            upval: false,
+            is_breakable,
+        });
+        debug_assert_eq!(self.freereg, self.nactvar);
+    }
+    fn leave_block(&mut self) -> CResult<()> {
+        let bl = self.blocks.pop().unwrap();
+        self.remove_vars(bl.nactvar);
+        if bl.upval {
+            self.code_abc(OpCode::OpClose, bl.nactvar, 0, 0)?;
+        }
+        // a block either controls scope or breaks (never both)
+        debug_assert!(!bl.is_breakable || !bl.upval);
+        self.freereg = self.nactvar;
+        self.patch_to_here(bl.breaklist)
+    }
+
+    /// the finished prototype (close_func); `last_line` is where the function ends
+    fn close(mut self, source: &str, last_line: usize) -> CResult<Chunk> {
+        self.remove_vars(0);
+        self.line = last_line;
+        // the main function has no defining lines
+        let last_line = if self.line_defined == 0 { 0 } else { last_line };
+        self.ret(0, 0)?;
+        let line = |n: usize| LuaInt::U32(n as u32);
+        Ok(Chunk {
+            name: source.to_string(),
+            meta_info: MetaInfo {
+                first_line: line(self.line_defined),
+                last_line: line(last_line),
+                num_upvals: self.upvalues.len() as u8,
+                num_params: self.num_params as u8,
+                is_varg: self.is_vararg,
+                max_stack: self.max_stack as u8,
+            },
+            instructions: self.code,
+            constant_table: self.constants,
+            protos: self.protos,
+            lines: self.lineinfo.into_iter().map(line).collect(),
+            locals: self.locvars,
+            upvalues: self.upvalues.into_iter().map(|(name, _)| name).collect(),
+        })
+    }
+}
+
+/// `e1 op e2` computed at compile time when both are numerals (constfolding)
+fn const_folding(op: OpCode, e1: &mut ExpDesc, e2: &ExpDesc) -> bool {
+    let (Some(v1), Some(v2)) = (e1.numeral(), e2.numeral()) else {
+        return false;
+    };
+    // integers fold like `arith_op` computes them
+    if let Some(i1) = e1.integer() {
+        let r = match (op, e2.integer()) {
+            // a zero with a negative operand is -0, which only a float holds
+            (OpCode::OpUnm, _) if i1 != 0 => i1.checked_neg(),
+            (OpCode::OpAdd, Some(i2)) => i1.checked_add(i2),
+            (OpCode::OpSub, Some(i2)) => i1.checked_sub(i2),
+            (OpCode::OpMul, Some(i2)) => i1
+                .checked_mul(i2)
+                .filter(|&r| r != 0 || (i1 >= 0 && i2 >= 0)),
+            (OpCode::OpMod, Some(i2)) if i2 != 0 => {
+                let r = i1 % i2;
+                Some(if r != 0 && (r ^ i2) < 0 { r + i2 } else { r })
+            }
+            _ => None,
+        };
+        if let Some(r) = r.filter(|r| r.abs() <= MAX_EXACT_INTEGER) {
+            e1.k = ExpKind::KInt(r);
+            return true;
+        }
+    }
+    let r = match op {
+        OpCode::OpAdd => v1 + v2,
+        OpCode::OpSub => v1 - v2,
+        OpCode::OpMul => v1 * v2,
+        // do not attempt to divide by 0
+        OpCode::OpDiv if v2 == 0.0 => return false,
+        OpCode::OpDiv => v1 / v2,
+        OpCode::OpMod if v2 == 0.0 => return false,
+        OpCode::OpMod => v1 - (v1 / v2).floor() * v2,
+        OpCode::OpPow => v1.powf(v2),
+        OpCode::OpUnm => -v1,
+        _ => return false,
+    };
+    if r.is_nan() {
+        // do not fold a NaN, it cannot be a constant key
+        return false;
+    }
+    e1.k = ExpKind::KNum(r);
+    true
+}
+
+/// whether the numeral `text` of value `n` gets the integer variant: no
+/// decimal point or exponent, and exact as a double
+fn is_integer_literal(text: &str, n: LuaNumber) -> bool {
+    let hex = text.starts_with("0x") || text.starts_with("0X");
+    (hex || !text.contains(['.', 'e', 'E'])) && n.abs() <= MAX_EXACT_INTEGER as LuaNumber
+}
+
+/// table size hint as a "floating point byte" (luaO_int2fb)
+fn int2fb(x: usize) -> usize {
+    let mut x = x;
+    let mut e = 0;
+    while x >= 16 {
+        x = (x + 1) >> 1;
+        e += 1;
+    }
+    if x < 8 { x } else { ((e + 1) << 3) | (x - 8) }
+}
+
+fn binopr(op: &BinOp) -> BinOpr {
+    match op {
+        BinOp::Plus(_) => BinOpr::Add,
+        BinOp::Minus(_) => BinOpr::Sub,
+        BinOp::Star(_) => BinOpr::Mul,
+        BinOp::Slash(_) => BinOpr::Div,
+        BinOp::Percent(_) => BinOpr::Mod,
+        BinOp::Caret(_) => BinOpr::Pow,
+        BinOp::TwoDots(_) => BinOpr::Concat,
+        BinOp::TildeEqual(_) => BinOpr::Ne,
+        BinOp::TwoEqual(_) => BinOpr::Eq,
+        BinOp::LessThan(_) => BinOpr::Lt,
+        BinOp::LessThanEqual(_) => BinOpr::Le,
+        BinOp::GreaterThan(_) => BinOpr::Gt,
+        BinOp::GreaterThanEqual(_) => BinOpr::Ge,
+        BinOp::And(_) => BinOpr::And,
+        _ => BinOpr::Or,
+    }
+}
+
+/// line of the first token of `node`, read from the lazy token walk:
+/// `start_position` also computes the end, going down whole operator chains
+fn line_of(node: &impl Node) -> Option<usize> {
+    node.tokens()
+        .next()
+        .map(|t| t.token().start_position().line())
+}
+
+fn name_of(token: &TokenReference) -> String {
+    match token.token_type() {
+        TokenType::Identifier { identifier } => identifier.to_string(),
+        _ => token.token().to_string(),
+    }
+}
+
+/// contents of a string literal with its escapes resolved (read_string in llex.c)
+fn string_value(token: &TokenReference) -> CResult<Vec<u8>> {
+    let TokenType::StringLiteral {
+        literal,
+        quote_type,
+        ..
+    } = token.token_type()
+    else {
+        unreachable!("not a string literal")
+    };
+    let bytes = literal.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    if *quote_type == StringLiteralQuoteType::Brackets {
+        // long string: skip a first line break and turn the others into '\n'
+        let mut i = 0;
+        while i < bytes.len() {
+            let c = bytes[i];
+            i += 1;
+            if c != b'\n' && c != b'\r' {
+                out.push(c);
+                continue;
+            }
+            let first = i == 1;
+            // `\n\r' and `\r\n' are one line break
+            if bytes
+                .get(i)
+                .is_some_and(|&n| matches!(n, b'\n' | b'\r') && n != c)
+            {
+                i += 1;
+            }
+            if !first {
+                out.push(b'\n');
+            }
+        }
+        return Ok(out);
+    }
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        i += 1;
+        if c != b'\\' {
+            out.push(c);
+            continue;
+        }
+        let Some(&e) = bytes.get(i) else {
+            break;
+        };
+        i += 1;
+        match e {
+            b'a' => out.push(0x07),
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            b'v' => out.push(0x0b),
+            b'\n' | b'\r' => {
+                out.push(b'\n');
+                if bytes
+                    .get(i)
+                    .is_some_and(|&n| matches!(n, b'\n' | b'\r') && n != e)
+                {
+                    i += 1;
+                }
+            }
+            b'0'..=b'9' => {
+                let mut n = (e - b'0') as u32;
+                for _ in 0..2 {
+                    match bytes.get(i) {
+                        Some(d) if d.is_ascii_digit() => {
+                            n = n * 10 + (d - b'0') as u32;
+                            i += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                if n > u8::MAX as u32 {
+                    return Err("escape sequence too large".to_string());
+                }
+                out.push(n as u8);
+            }
+            // \\, \", \' and any other character stand for themselves
+            _ => out.push(e),
+        }
+    }
+    Ok(out)
+}
+
+/// Compiler of one chunk; `fs` holds the functions being compiled, innermost last.
+struct Compiler {
+    source: String,
+    fs: Vec<FuncState>,
+    /// nesting of blocks and expressions, limited like C Lua's recursion
+    level: usize,
+}
+
+impl Compiler {
+    fn fs(&mut self) -> &mut FuncState {
+        self.fs.last_mut().unwrap()
+    }
+    fn set_line(&mut self, node: &impl Node) {
+        if let Some(line) = line_of(node) {
+            self.fs().line = line;
+        }
+    }
+    fn line(&mut self) -> usize {
+        self.fs().line
+    }
+    fn enter_level(&mut self) -> CResult<()> {
+        self.level += 1;
+        if self.level > LUAI_MAXCCALLS {
+            return Err("chunk has too many syntax levels".to_string());
+        }
+        Ok(())
+    }
+
+    // ---- variables ----
+
+    /// the variable `name` as seen from function `level` (singlevaraux)
+    fn single_var_aux(&mut self, level: usize, name: &str, base: bool) -> CResult<Option<ExpKind>> {
+        let fs = &mut self.fs[level];
+        if let Some(v) = fs.search_var(name) {
+            if !base {
+                // a local used as upvalue
+                fs.mark_upval(v);
+            }
+            return Ok(Some(ExpKind::Local(v)));
+        }
+        if level == 0 {
+            return Ok(None);
+        }
+        let desc = match self.single_var_aux(level - 1, name, false)? {
+            None => return Ok(None),
+            Some(ExpKind::Local(r)) => UpvalDesc::Local(r),
+            Some(ExpKind::Upval(i)) => UpvalDesc::Upval(i),
+            Some(k) => unreachable!("unexpected variable kind {k:?}"),
+        };
+        let i = self.fs[level].index_upvalue(name, desc)?;
+        Ok(Some(ExpKind::Upval(i)))
+    }
+    fn single_var(&mut self, token: &TokenReference) -> CResult<ExpDesc> {
+        let name = name_of(token);
+        let level = self.fs.len() - 1;
+        let k = match self.single_var_aux(level, &name, true)? {
+            Some(k) => k,
+            None => ExpKind::Global(self.fs().string_k(name.as_bytes())?),
+        };
+        Ok(ExpDesc::new(k))
+    }
+    fn string_exp(&mut self, s: &[u8]) -> CResult<ExpDesc> {
+        Ok(ExpDesc::new(ExpKind::K(self.fs().string_k(s)?)))
+    }
+
+    // ---- expressions ----
+
+    fn expr(&mut self, e: &Expression) -> CResult<ExpDesc> {
+        self.enter_level()?;
+        let v = self.expr_inner(e)?;
+        self.level -= 1;
+        Ok(v)
+    }
+    fn expr_inner(&mut self, e: &Expression) -> CResult<ExpDesc> {
+        if let Expression::BinaryOperator { .. } = e {
+            return self.binary_chain(e);
+        }
+        self.set_line(e);
+        match e {
+            Expression::UnaryOperator { unop, expression } => {
+                let op = match unop {
+                    UnOp::Minus(_) => UnOpr::Minus,
+                    UnOp::Not(_) => UnOpr::Not,
+                    _ => UnOpr::Len,
+                };
+                let mut v = self.expr(expression)?;
+                self.set_line(unop.token());
+                self.fs().prefix(op, &mut v)?;
+                Ok(v)
+            }
+            Expression::Parentheses { expression, .. } => {
+                let mut v = self.expr(expression)?;
+                self.fs().discharge_vars(&mut v)?;
+                Ok(v)
+            }
+            Expression::Function(f) => {
+                let line = line_of(f.function_token()).unwrap_or(0);
+                self.body(f.body(), false, line)
+            }
+            Expression::FunctionCall(call) => self.primary_exp(call.prefix(), call.suffixes()),
+            Expression::Var(Var::Name(name)) => self.single_var(name),
+            Expression::Var(Var::Expression(var)) => self.primary_exp(var.prefix(), var.suffixes()),
+            Expression::TableConstructor(t) => self.constructor(t),
+            Expression::Number(token) => {
+                let text = token.token().to_string();
+                match str2number(text.as_bytes()) {
+                    Some(n) if is_integer_literal(&text, n) => {
+                        Ok(ExpDesc::new(ExpKind::KInt(n as LuaInteger)))
+                    }
+                    Some(n) => Ok(ExpDesc::new(ExpKind::KNum(n))),
+                    None => Err(format!("malformed number near '{text}'")),
+                }
+            }
+            Expression::String(token) => {
+                let s = string_value(token)?;
+                self.string_exp(&s)
+            }
+            Expression::Symbol(token) => match token.token_type() {
+                TokenType::Symbol {
+                    symbol: Symbol::Nil,
+                } => Ok(ExpDesc::new(ExpKind::Nil)),
+                TokenType::Symbol {
+                    symbol: Symbol::True,
+                } => Ok(ExpDesc::new(ExpKind::True)),
+                TokenType::Symbol {
+                    symbol: Symbol::False,
+                } => Ok(ExpDesc::new(ExpKind::False)),
+                _ => {
+                    let fs = self.fs();
+                    if fs.is_vararg & VARARG_ISVARARG == 0 {
+                        return Err(
+                            "cannot use '...' outside a vararg function near '...'".to_string()
+                        );
+                    }
+                    // a function using '...' does not need the 'arg' table
+                    fs.is_vararg &= !VARARG_NEEDSARG;
+                    let pc = fs.code_abc(OpCode::OpVarArg, 0, 1, 0)?;
+                    Ok(ExpDesc::new(ExpKind::Vararg(pc)))
+                }
+            },
+            _ => Err(format!("unexpected symbol near '{}'", e.to_string().trim())),
+        }
+    }
+    /// Compile `a op b op c ...`. The left operands of a chain nest in the
+    /// tree, so they are walked in a loop and take a single syntax level,
+    /// like the loop of subexpr.
+    fn binary_chain(&mut self, e: &Expression) -> CResult<ExpDesc> {
+        let mut ops = Vec::new();
+        let mut first = e;
+        while let Expression::BinaryOperator { lhs, binop, rhs } = first {
+            ops.push((binopr(binop), &**rhs));
+            first = lhs;
+        }
+        // the operand sets the line, a chain node would walk down to it
+        let mut v = self.expr(first)?;
+        for (op, rhs) in ops.into_iter().rev() {
+            self.fs().infix(op, &mut v)?;
+            let mut v2 = self.expr(rhs)?;
+            self.fs().posfix(op, &mut v, &mut v2)?;
+        }
+        Ok(v)
+    }
+    /// compile `e1, e2, ...`: all but the last go to consecutive registers (explist1)
+    fn exp_list(&mut self, exprs: &[&Expression]) -> CResult<(usize, ExpDesc)> {
+        let mut v = self.expr(exprs[0])?;
+        for e in &exprs[1..] {
+            self.fs().exp2nextreg(&mut v)?;
+            v = self.expr(e)?;
+        }
+        Ok((exprs.len(), v))
+    }
+    fn primary_exp<'a>(
+        &mut self,
+        prefix: &Prefix,
+        suffixes: impl Iterator<Item = &'a Suffix>,
+    ) -> CResult<ExpDesc> {
+        let mut v = match prefix {
+            Prefix::Name(name) => {
+                self.set_line(name);
+                self.single_var(name)?
+            }
+            Prefix::Expression(e) => {
+                let mut v = self.expr(e)?;
+                self.fs().discharge_vars(&mut v)?;
+                v
+            }
+            _ => return Err("unexpected symbol".to_string()),
+        };
+        for suffix in suffixes {
+            match suffix {
+                Suffix::Index(Index::Dot { name, .. }) => {
+                    self.set_line(name);
+                    self.fs().exp2anyreg(&mut v)?;
+                    let mut key = self.string_exp(name_of(name).as_bytes())?;
+                    self.fs().indexed(&mut v, &mut key)?;
+                }
+                Suffix::Index(Index::Brackets { expression, .. }) => {
+                    self.fs().exp2anyreg(&mut v)?;
+                    let mut key = self.expr(expression)?;
+                    self.fs().exp2val(&mut key)?;
+                    self.fs().indexed(&mut v, &mut key)?;
+                }
+                Suffix::Call(Call::MethodCall(m)) => {
+                    self.set_line(m.name());
+                    let mut key = self.string_exp(name_of(m.name()).as_bytes())?;
+                    self.fs().self_(&mut v, &mut key)?;
+                    self.func_args(&mut v, m.args())?;
+                }
+                Suffix::Call(Call::AnonymousCall(args)) => {
+                    self.fs().exp2nextreg(&mut v)?;
+                    self.func_args(&mut v, args)?;
+                }
+                _ => return Err("unexpected symbol".to_string()),
+            }
+        }
+        Ok(v)
+    }
+    fn func_args(&mut self, f: &mut ExpDesc, args: &FunctionArgs) -> CResult<()> {
+        let line = line_of(args).unwrap_or_else(|| self.line());
+        let mut a = match args {
+            FunctionArgs::Parentheses { arguments, .. } => {
+                let exprs: Vec<&Expression> = arguments.iter().collect();
+                if exprs.is_empty() {
+                    ExpDesc::new(ExpKind::Void)
+                } else {
+                    let (_, mut a) = self.exp_list(&exprs)?;
+                    self.fs().set_returns(&mut a, LUA_MULTRET)?;
+                    a
+                }
+            }
+            FunctionArgs::TableConstructor(t) => self.constructor(t)?,
+            FunctionArgs::String(s) => {
+                let s = string_value(s)?;
+                self.string_exp(&s)?
+            }
+            _ => return Err("function arguments expected".to_string()),
+        };
+        let fs = self.fs();
+        let base = f.reg();
+        let nparams = if a.has_multret() {
+            // open call
+            LUA_MULTRET
+        } else {
+            if a.k != ExpKind::Void {
+                // close last argument
+                fs.exp2nextreg(&mut a)?;
+            }
+            (fs.freereg - (base + 1)) as i32
+        };
+        fs.line = line;
+        let pc = fs.code_abc(OpCode::OpCall, base, (nparams + 1) as usize, 2)?;
+        f.k = ExpKind::Call(pc);
+        // call removes function and arguments and leaves one result
+        fs.freereg = base + 1;
+        Ok(())
+    }
+    fn constructor(&mut self, t: &TableConstructor) -> CResult<ExpDesc> {
+        self.set_line(t);
+        let fs = self.fs();
+        let pc = fs.code_abc(OpCode::OpNewTable, 0, 0, 0)?;
+        let mut table = ExpDesc::new(ExpKind::Relocable(pc));
+        // fix it at stack top (for gc)
+        fs.exp2nextreg(&mut table)?;
+        let treg = table.reg();
+        let mut pending = ExpDesc::new(ExpKind::Void);
+        let (mut na, mut nh, mut tostore) = (0, 0, 0);
+        for field in t.fields() {
+            // close the previous list item (closelistfield)
+            if pending.k != ExpKind::Void {
+                let fs = self.fs();
+                fs.exp2nextreg(&mut pending)?;
+                pending.k = ExpKind::Void;
+                if tostore == LFIELDS_PER_FLUSH {
+                    fs.set_list(treg, na, tostore as i32)?;
+                    tostore = 0;
+                }
+            }
+            match field {
+                Field::NameKey { key, value, .. } => {
+                    let mut k = self.string_exp(name_of(key).as_bytes())?;
+                    self.rec_field(treg, &mut k, value)?;
+                    nh += 1;
+                }
+                Field::ExpressionKey { key, value, .. } => {
+                    let reg = self.fs().freereg;
+                    let mut k = self.expr(key)?;
+                    self.fs().exp2val(&mut k)?;
+                    self.rec_field(treg, &mut k, value)?;
+                    self.fs().freereg = reg;
+                    nh += 1;
+                }
+                Field::NoKey(value) => {
+                    pending = self.expr(value)?;
+                    na += 1;
+                    tostore += 1;
+                }
+                _ => return Err("unexpected symbol in table constructor".to_string()),
+            }
+        }
+        let fs = self.fs();
+        if tostore > 0 {
+            if pending.has_multret() {
+                fs.set_returns(&mut pending, LUA_MULTRET)?;
+                fs.set_list(treg, na, LUA_MULTRET)?;
+                // do not count last expression (unknown number of elements)
+                na -= 1;
+            } else {
+                if pending.k != ExpKind::Void {
+                    fs.exp2nextreg(&mut pending)?;
+                }
+                fs.set_list(treg, na, tostore as i32)?;
+            }
+        }
+        set_b(&mut fs.code[pc], int2fb(na));
+        set_c(&mut fs.code[pc], int2fb(nh));
+        Ok(table)
+    }
+    fn rec_field(&mut self, treg: usize, key: &mut ExpDesc, value: &Expression) -> CResult<()> {
+        let reg = self.fs().freereg;
+        let rkkey = self.fs().exp2rk(key)?;
+        let mut val = self.expr(value)?;
+        let fs = self.fs();
+        let rkval = fs.exp2rk(&mut val)?;
+        fs.code_abc(OpCode::OpSetTable, treg, rkkey, rkval)?;
+        fs.freereg = reg;
+        Ok(())
+    }
+    /// compile a function body into a closure in the current function
+    fn body(&mut self, body: &FunctionBody, needself: bool, line: usize) -> CResult<ExpDesc> {
+        self.fs.push(FuncState::new(line));
+        if needself {
+            self.fs().new_localvar("self", 0)?;
+            self.fs().adjust_localvars(1);
+        }
+        // parameters (parlist)
+        let fs = self.fs();
+        let mut nparams = 0;
+        for param in body.parameters() {
+            match param {
+                Parameter::Name(name) => fs.new_localvar(&name_of(name), nparams)?,
+                _ => {
+                    // use `arg' as default name
+                    fs.new_localvar("arg", nparams)?;
+                    fs.is_vararg = VARARG_HASARG | VARARG_NEEDSARG;
+                    fs.is_vararg |= VARARG_ISVARARG;
+                }
+            }
+            nparams += 1;
+        }
+        fs.adjust_localvars(nparams);
+        fs.num_params = fs.nactvar - (fs.is_vararg & VARARG_HASARG) as usize;
+        fs.reserve_regs(fs.nactvar)?;
+        self.chunk(body.block())?;
+        let last_line = line_of(body.end_token()).unwrap_or(line);
+        let child = self.fs.pop().unwrap();
+        let upvalues: Vec<UpvalDesc> = child.upvalues.iter().map(|(_, d)| *d).collect();
+        let chunk = child.close("", last_line)?;
+        let fs = self.fs();
+        fs.protos.push(chunk);
+        fs.line = last_line;
+        let pc = fs.code_abx(OpCode::OpClosure, 0, fs.protos.len() - 1)?;
+        for desc in upvalues {
+            match desc {
+                UpvalDesc::Local(r) => fs.code_abc(OpCode::OpMove, 0, r, 0)?,
+                UpvalDesc::Upval(i) => fs.code_abc(OpCode::OpGetUpval, 0, i, 0)?,
+            };
+        }
+        Ok(ExpDesc::new(ExpKind::Relocable(pc)))
+    }
+
+    // ---- statements ----
+
+    fn chunk(&mut self, block: &Block) -> CResult<()> {
+        self.enter_level()?;
+        for stmt in block.stmts() {
+            self.statement(stmt)?;
+            let fs = self.fs();
+            debug_assert!(fs.max_stack >= fs.freereg && fs.freereg >= fs.nactvar);
+            // free registers
+            fs.freereg = fs.nactvar;
+        }
+        if let Some(last) = block.last_stmt() {
+            self.set_line(last);
+            match last {
+                LastStmt::Return(ret) => {
+                    let exprs: Vec<&Expression> = ret.returns().iter().collect();
+                    self.ret_stat(&exprs)?;
+                }
+                LastStmt::Break(_) => self.break_stat()?,
+                _ => return Err("unexpected symbol".to_string()),
+            }
+            let fs = self.fs();
+            fs.freereg = fs.nactvar;
+        }
+        self.level -= 1;
+        Ok(())
+    }
+    fn block(&mut self, block: &Block) -> CResult<()> {
+        self.fs().enter_block(false);
+        self.chunk(block)?;
+        self.fs().leave_block()
+    }
+    /// condition of a loop or if: jump list taken when it is false
+    fn cond(&mut self, e: &Expression) -> CResult<i32> {
+        let mut v = self.expr(e)?;
+        if v.k == ExpKind::Nil {
+            // `falses' are all equal here
+            v.k = ExpKind::False;
+        }
+        self.fs().go_if_true(&mut v)?;
+        Ok(v.f)
+    }
+    fn statement(&mut self, stmt: &Stmt) -> CResult<()> {
+        self.set_line(stmt);
+        match stmt {
+            Stmt::If(s) => {
+                let mut flist = self.cond(s.condition())?;
+                self.block(s.block())?;
+                let mut escapelist = NO_JUMP;
+                for elseif in s.else_if().into_iter().flatten() {
+                    self.set_line(elseif);
+                    let fs = self.fs();
+                    let j = fs.jump()?;
+                    fs.concat(&mut escapelist, j)?;
+                    fs.patch_to_here(flist)?;
+                    flist = self.cond(elseif.condition())?;
+                    self.block(elseif.block())?;
+                }
+                if let Some(else_block) = s.else_block() {
+                    if let Some(token) = s.else_token() {
+                        self.set_line(token);
+                    }
+                    let fs = self.fs();
+                    let j = fs.jump()?;
+                    fs.concat(&mut escapelist, j)?;
+                    fs.patch_to_here(flist)?;
+                    self.block(else_block)?;
+                } else {
+                    self.fs().concat(&mut escapelist, flist)?;
+                }
+                self.fs().patch_to_here(escapelist)
+            }
+            Stmt::While(s) => {
+                let whileinit = self.fs().get_label();
+                let condexit = self.cond(s.condition())?;
+                self.fs().enter_block(true);
+                self.block(s.block())?;
+                self.set_line(s.end_token());
+                let fs = self.fs();
+                let j = fs.jump()?;
+                fs.patch_list(j, whileinit)?;
+                fs.leave_block()?;
+                // false conditions finish the loop
+                fs.patch_to_here(condexit)
+            }
+            Stmt::Do(s) => self.block(s.block()),
+            Stmt::NumericFor(s) => {
+                let line = self.line();
+                // scope for loop and control variables
+                self.fs().enter_block(true);
+                let fs = self.fs();
+                let base = fs.freereg;
+                fs.new_localvar("(for index)", 0)?;
+                fs.new_localvar("(for limit)", 1)?;
+                fs.new_localvar("(for step)", 2)?;
+                fs.new_localvar(&name_of(s.index_variable()), 3)?;
+                self.exp1(s.start())?;
+                self.exp1(s.end())?;
+                match s.step() {
+                    Some(step) => self.exp1(step)?,
+                    None => {
+                        // default step = 1
+                        let fs = self.fs();
+                        let k = fs.integer_k(1)?;
+                        fs.code_abx(OpCode::OpLoadK, fs.freereg, k)?;
+                        fs.reserve_regs(1)?;
+                    }
+                }
+                self.for_body(base, line, 1, true, s.block(), s.end_token())?;
+                self.fs().leave_block()
+            }
+            Stmt::GenericFor(s) => {
+                let line = self.line();
+                self.fs().enter_block(true);
+                let fs = self.fs();
+                let base = fs.freereg;
+                // create control variables
+                fs.new_localvar("(for generator)", 0)?;
+                fs.new_localvar("(for state)", 1)?;
+                fs.new_localvar("(for control)", 2)?;
+                let mut nvars = 3;
+                for name in s.names() {
+                    fs.new_localvar(&name_of(name), nvars)?;
+                    nvars += 1;
+                }
+                let exprs: Vec<&Expression> = s.expressions().iter().collect();
+                let (nexps, mut e) = self.exp_list(&exprs)?;
+                self.fs.last_mut().unwrap().line = line;
+                self.adjust_assign(3, nexps, &mut e)?;
+                // extra space to call generator
+                self.fs().check_stack(3)?;
+                self.for_body(base, line, nvars - 3, false, s.block(), s.end_token())?;
+                self.fs().leave_block()
+            }
+            Stmt::Repeat(s) => {
+                let fs = self.fs();
+                let repeat_init = fs.get_label();
+                // loop block and scope block
+                fs.enter_block(true);
+                fs.enter_block(false);
+                self.chunk(s.block())?;
+                self.set_line(s.until_token());
+                let condexit = self.cond(s.until())?;
+                let fs = self.fs();
+                if !fs.blocks.last().unwrap().upval {
+                    // no upvalues: finish scope and repeat if the condition is false
+                    fs.leave_block()?;
+                    fs.patch_list(condexit, repeat_init)?;
+                } else {
+                    // complete semantics when there are upvalues
+                    self.break_stat()?;
+                    let fs = self.fs();
+                    fs.patch_to_here(condexit)?;
+                    fs.leave_block()?;
+                    let j = fs.jump()?;
+                    fs.patch_list(j, repeat_init)?;
+                }
+                self.fs().leave_block()
+            }
+            Stmt::FunctionDeclaration(s) => {
+                let line = line_of(s.function_token()).unwrap_or(0);
+                let name = s.name();
+                let mut names = name.names().iter();
+                let mut v = self.single_var(names.next().unwrap())?;
+                let mut keys: Vec<&TokenReference> = names.collect();
+                let needself = name.method_name().is_some();
+                keys.extend(name.method_name());
+                for key in keys {
+                    self.fs().exp2anyreg(&mut v)?;
+                    let mut k = self.string_exp(name_of(key).as_bytes())?;
+                    self.fs().indexed(&mut v, &mut k)?;
+                }
+                let mut b = self.body(s.body(), needself, line)?;
+                let fs = self.fs();
+                fs.store_var(&v, &mut b)?;
+                // definition `happens' in the first line
+                fs.fix_line(line);
+                Ok(())
+            }
+            Stmt::LocalFunction(s) => {
+                let line = line_of(s.function_token()).unwrap_or(0);
+                let fs = self.fs();
+                fs.new_localvar(&name_of(s.name()), 0)?;
+                let v = ExpDesc::new(ExpKind::Local(fs.freereg));
+                fs.reserve_regs(1)?;
+                fs.adjust_localvars(1);
+                let mut b = self.body(s.body(), false, line)?;
+                let fs = self.fs();
+                fs.store_var(&v, &mut b)?;
+                // debug information will only see the variable after this point
+                let pc = LuaInt::U32(fs.pc() as u32);
+                let var = fs.actvar[fs.nactvar - 1];
+                fs.locvars[var].start_line = pc;
+                Ok(())
+            }
+            Stmt::LocalAssignment(s) => {
+                let mut nvars = 0;
+                for name in s.names() {
+                    self.fs().new_localvar(&name_of(name), nvars)?;
+                    nvars += 1;
+                }
+                let exprs: Vec<&Expression> = s.expressions().iter().collect();
+                let (nexps, mut e) = if exprs.is_empty() {
+                    (0, ExpDesc::new(ExpKind::Void))
+                } else {
+                    self.exp_list(&exprs)?
+                };
+                self.adjust_assign(nvars, nexps, &mut e)?;
+                self.fs().adjust_localvars(nvars);
+                Ok(())
+            }
+            Stmt::FunctionCall(call) => {
+                let v = self.primary_exp(call.prefix(), call.suffixes())?;
+                let ExpKind::Call(pc) = v.k else {
+                    return Err("syntax error".to_string());
+                };
+                // call statement uses no results
+                set_c(&mut self.fs().code[pc], 1);
+                Ok(())
+            }
+            Stmt::Assignment(s) => {
+                let vars: Vec<&Var> = s.variables().iter().collect();
+                let exprs: Vec<&Expression> = s.expressions().iter().collect();
+                self.assignment(&vars, &exprs)
+            }
+            _ => Err("unexpected symbol".to_string()),
+        }
+    }
+    /// expression into the next register, for loop bounds
+    fn exp1(&mut self, e: &Expression) -> CResult<()> {
+        let mut v = self.expr(e)?;
+        self.fs().exp2nextreg(&mut v)
+    }
+    fn for_body(
+        &mut self,
+        base: usize,
+        line: usize,
+        nvars: usize,
+        isnum: bool,
+        block: &Block,
+        end: &TokenReference,
+    ) -> CResult<()> {
+        let fs = self.fs();
+        // control variables
+        fs.adjust_localvars(3);
+        let prep = if isnum {
+            fs.code_asbx(OpCode::OpForPrep, base, NO_JUMP as isize)? as i32
+        } else {
+            fs.jump()?
+        };
+        // scope for declared variables
+        fs.enter_block(false);
+        fs.adjust_localvars(nvars);
+        fs.reserve_regs(nvars)?;
+        self.block(block)?;
+        self.set_line(end);
+        let fs = self.fs();
+        // end of scope for declared variables
+        fs.leave_block()?;
+        fs.patch_to_here(prep)?;
+        let endfor = if isnum {
+            fs.code_asbx(OpCode::OpForLoop, base, NO_JUMP as isize)? as i32
+        } else {
+            fs.code_abc(OpCode::OpTForLoop, base, 0, nvars)? as i32
+        };
+        // pretend that `OP_FOR' starts the loop
+        fs.fix_line(line);
+        let j = if isnum { endfor } else { fs.jump()? };
+        fs.patch_list(j, prep + 1)
+    }
+    fn adjust_assign(&mut self, nvars: usize, nexps: usize, e: &mut ExpDesc) -> CResult<()> {
+        let fs = self.fs();
+        let mut extra = nvars as i32 - nexps as i32;
+        if e.has_multret() {
+            // includes call itself
+            extra += 1;
+            if extra < 0 {
+                extra = 0;
+            }
+            // last exp. provides the difference
+            fs.set_returns(e, extra)?;
+            if extra > 1 {
+                fs.reserve_regs(extra as usize - 1)?;
+            }
+        } else {
+            if e.k != ExpKind::Void {
+                // close last expression
+                fs.exp2nextreg(e)?;
+            }
+            if extra > 0 {
+                let reg = fs.freereg;
+                fs.reserve_regs(extra as usize)?;
+                fs.nil(reg, extra as usize)?;
+            }
+        }
+        Ok(())
+    }
+    /// `v1, v2, ... = e1, e2, ...` (restassign)
+    fn assignment(&mut self, vars: &[&Var], exprs: &[&Expression]) -> CResult<()> {
+        let mut lhs: Vec<ExpDesc> = Vec::with_capacity(vars.len());
+        for var in vars {
+            let v = match var {
+                Var::Name(name) => self.single_var(name)?,
+                Var::Expression(var) => self.primary_exp(var.prefix(), var.suffixes())?,
+                _ => return Err("syntax error".to_string()),
+            };
+            if !matches!(
+                v.k,
+                ExpKind::Local(_) | ExpKind::Upval(_) | ExpKind::Global(_) | ExpKind::Indexed(..)
+            ) {
+                return Err("syntax error".to_string());
+            }
+            if let ExpKind::Local(r) = v.k {
+                self.check_conflict(&mut lhs, r)?;
+            }
+            lhs.push(v);
+        }
+        if vars.len() > LUAI_MAXCCALLS {
+            return Err(self
+                .fs()
+                .limit_error(LUAI_MAXCCALLS, "variables in assignment"));
+        }
+        let nvars = lhs.len();
+        let (nexps, mut e) = self.exp_list(exprs)?;
+        let fs = self.fs();
+        let last = lhs.pop().unwrap();
+        if nexps != nvars {
+            self.adjust_assign(nvars, nexps, &mut e)?;
+            let fs = self.fs();
+            if nexps > nvars {
+                // remove extra values
+                fs.freereg -= nexps - nvars;
+            }
+            let mut e = ExpDesc::new(ExpKind::NonReloc(fs.freereg - 1));
+            fs.store_var(&last, &mut e)?;
+        } else {
+            // close last expression
+            fs.set_one_ret(&mut e);
+            fs.store_var(&last, &mut e)?;
+        }
+        // the other variables take the values left in registers, last first
+        let fs = self.fs();
+        for v in lhs.iter().rev() {
+            let mut e = ExpDesc::new(ExpKind::NonReloc(fs.freereg - 1));
+            fs.store_var(v, &mut e)?;
+        }
+        Ok(())
+    }
+    /// a local assigned after being used as table or key of an earlier target
+    /// must be read from a copy (check_conflict)
+    fn check_conflict(&mut self, lhs: &mut [ExpDesc], reg: usize) -> CResult<()> {
+        let fs = self.fs();
+        let extra = fs.freereg;
+        let mut conflict = false;
+        for v in lhs.iter_mut() {
+            if let ExpKind::Indexed(t, k) = v.k {
+                let t = if t == reg {
+                    conflict = true;
+                    extra
+                } else {
+                    t
+                };
+                let k = if k == reg {
+                    conflict = true;
+                    extra
+                } else {
+                    k
+                };
+                v.k = ExpKind::Indexed(t, k);
+            }
+        }
+        if conflict {
+            fs.code_abc(OpCode::OpMove, fs.freereg, reg, 0)?;
+            fs.reserve_regs(1)?;
+        }
+        Ok(())
+    }
+    fn ret_stat(&mut self, exprs: &[&Expression]) -> CResult<()> {
+        let (first, nret) = if exprs.is_empty() {
+            // return no values
+            (0, 0)
+        } else {
+            let (n, mut e) = self.exp_list(exprs)?;
+            let fs = self.fs();
+            if e.has_multret() {
+                fs.set_returns(&mut e, LUA_MULTRET)?;
+                if let ExpKind::Call(pc) = e.k
+                    && n == 1
+                {
+                    set_op(&mut fs.code[pc], OpCode::OpTailCall);
+                }
+                (fs.nactvar, LUA_MULTRET)
+            } else if n == 1 {
+                // can use original slot
+                (fs.exp2anyreg(&mut e)?, 1)
+            } else {
+                // values must go to the `stack'
+                fs.exp2nextreg(&mut e)?;
+                (fs.nactvar, n as i32)
+            }
+        };
+        self.fs().ret(first, nret)
+    }
+    fn break_stat(&mut self) -> CResult<()> {
+        let fs = self.fs();
+        let mut upval = false;
+        let mut found = None;
+        for (i, bl) in fs.blocks.iter().enumerate().rev() {
+            if bl.is_breakable {
+                found = Some(i);
+                break;
+            }
+            upval |= bl.upval;
+        }
+        let Some(i) = found else {
+            return Err("no loop to break near 'break'".to_string());
+        };
+        if upval {
+            fs.code_abc(OpCode::OpClose, fs.blocks[i].nactvar, 0, 0)?;
+        }
+        let j = fs.jump()?;
+        let mut breaklist = fs.blocks[i].breaklist;
+        fs.concat(&mut breaklist, j)?;
+        fs.blocks[i].breaklist = breaklist;
+        Ok(())
+    }
+}
+
+/// Stack used to parse and compile a level of nesting, and an operator of a
+/// chain, measured with some margin. full_moon takes most of it, several
+/// times more in unoptimized builds.
+const LEVEL_STACK: usize = if cfg!(debug_assertions) {
+    128 << 10
+} else {
+    24 << 10
+};
+const CHAIN_STACK: usize = if cfg!(debug_assertions) { 128 } else { 64 };
+/// Stack a chunk may use on the caller's thread, half of what Rust gives a
+/// spawned thread. Deeper chunks are compiled on a thread of their own.
+const INLINE_STACK: usize = 1 << 20;
+
+/// Compile Lua source into the prototype of its main function.
+/// Errors are messages like "chunkname:line: message".
+pub fn compile(source: &[u8], chunkname: &str) -> Result<Chunk, String> {
+    let depth = check_depth(source).map_err(|line| {
+        format!(
+            "{}:{line}: chunk has too many syntax levels",
+            chunk_id(chunkname)
+        )
+    })?;
+    let text = String::from_utf8_lossy(source);
+    let stack = depth.levels * LEVEL_STACK + depth.chain * CHAIN_STACK;
+    if stack <= INLINE_STACK {
+        return compile_text(&text, chunkname);
+    }
+    // full_moon recurses on every level, and the tree is dropped recursively
+    std::thread::scope(|s| {
+        std::thread::Builder::new()
+            .stack_size(2 * stack + INLINE_STACK)
+            .spawn_scoped(s, || compile_text(&text, chunkname))
+            .map_err(|e| e.to_string())?
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+    })
+}
+
+fn compile_text(text: &str, chunkname: &str) -> Result<Chunk, String> {
+    let ast: Ast = match crate::parser::parse(text) {
+        Ok(ast) => ast,
+        Err(errors) => {
+            let err = &errors[0];
+            let line = err.range().0.line();
+            let msg = match err {
+                full_moon::Error::AstError(e) => {
+                    let near = match e.token().token_type() {
+                        TokenType::Eof => "<eof>".to_string(),
+                        _ => format!("'{}'", e.token()),
+                    };
+                    format!("{} near {near}", e.error_message())
+                }
+                full_moon::Error::TokenizerError(e) => e.error().to_string(),
+            };
+            return Err(format!("{}:{line}: {msg}", chunk_id(chunkname)));
+        }
+    };
+    let mut compiler = Compiler {
+        source: chunkname.to_string(),
+        fs: vec![FuncState::new(0)],
+        level: 0,
+    };
+    // main function is always vararg
+    compiler.fs().is_vararg = VARARG_ISVARARG;
+    let eof_line = ast.eof().token().start_position().line();
+    let res = compiler.chunk(ast.nodes()).and_then(|()| {
+        let main = compiler.fs.pop().unwrap();
+        main.close(&compiler.source, eof_line)
+    });
+    res.map_err(|msg| {
+        let line = compiler.fs.last().map_or(eof_line, |fs| fs.line);
+        format!("{}:{line}: {msg}", chunk_id(chunkname))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::baselib::open_base;
+    use crate::baselib::tests::{call_value, show};
+    use crate::debug::check_code;
+    use crate::func::Proto;
+    use crate::vm::LuaState;
+    use pretty_assertions::assert_eq;
+
+    /// results of running `source` as a chunk
+    fn run(source: &str) -> Vec<String> {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        let chunk = compile(source.as_bytes(), "=test").unwrap();
+        let proto = Proto::from_chunk(&chunk, "=?", &mut state.global.heap);
+        assert!(check_code(&proto), "{source}");
+        let f = state.load(&chunk);
+        let res = call_value(&mut state, f, &[]);
+        show(&state, &res)
+    }
+
+    #[test]
+    fn test_expressions() {
+        assert_eq!(
+            run("return 1 + 2 * 3 ^ 2, 7 % -3, -2 ^ 2"),
+            vec!["19", "-2", "-4"]
+        );
+        assert_eq!(
+            run("local a = 'x' return a .. 1 .. 'y', #'abc'"),
+            vec!["x1y", "3"]
+        );
+        assert_eq!(
+            run("local a, b = nil, 2 return a or b, a and b, not a, b > 1 and 'big' or 'small'"),
+            vec!["2", "nil", "boolean", "big"]
+        );
+        assert_eq!(
+            run("return '\\65\\t\\'', [[\nab]], [==[]]]==]"),
+            vec!["A\t'", "ab", "]]"]
+        );
+        assert_eq!(
+            run("return 0/0 ~= 0/0, 1/0 > 0"),
+            vec!["boolean", "boolean"]
+        );
+    }
+
+    #[test]
+    fn test_statements() {
+        let source = "
+            local t, s = {10, 20, 30, n = 3}, 0
+            for i = #t, 1, -1 do s = s + t[i] end
+            for k, v in next, t do if k == 'n' then s = s + v end end
+            local i = 0
+            while true do i = i + 1 if i == 5 then break end end
+            repeat local j = i; i = i + 1 until j >= 6
+            local a, b = 1, 2
+            a, b = b, a
+            return s, i, a, b
+        ";
+        assert_eq!(run(source), vec!["63", "7", "2", "1"]);
+    }
+
+    #[test]
+    fn test_functions() {
+        let source = "
+            local function counter()
+                local n = 0
+                return function() n = n + 1 return n end
+            end
+            local c = counter()
+            c() c()
+            local obj = {v = 4}
+            function obj:twice() return self.v * 2 end
+            local function pack(...) return select('#', ...), ... end
+            local function compat(...) return arg.n, arg[2] end
+            return c(), obj:twice(), pack(nil, nil), compat('a', 'b')
+        ";
+        assert_eq!(run(source), vec!["3", "8", "2", "2", "b"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = compile(b"x = = 1", "=test").unwrap_err();
+        assert!(err.starts_with("test:1: "), "{err}");
+        let err = compile(b"local x\nbreak", "=test").unwrap_err();
+        assert_eq!(err, "test:2: no loop to break near 'break'");
+        let err = compile(b"function f() return ... end", "=test").unwrap_err();
+        assert_eq!(
+            err,
+            "test:1: cannot use '...' outside a vararg function near '...'"
+        );
+    }
+
+    #[test]
+    fn test_syntax_levels() {
+        // operator chains take one level, like in subexpr
+        let chain = |n| format!("return 1{}", " + 1".repeat(n));
+        assert_eq!(run(&chain(199)), vec!["200"]);
+        assert_eq!(run(&chain(100_000)), vec!["100001"]);
+        // deep nesting is refused before full_moon overflows the stack
+        let nested = format!("local t = {}{}", "{".repeat(300), "}".repeat(300));
+        let err = compile(nested.as_bytes(), "=test").unwrap_err();
+        assert_eq!(err, "test:1: chunk has too many syntax levels");
+        let blocks = format!("{}{}", "do ".repeat(150), "end ".repeat(150));
+        assert_eq!(run(&blocks), Vec::<String>::new());
+        let functions = format!(
+            "local f = {}1{} return f",
+            "function() return ".repeat(95),
+            " end".repeat(95)
+        );
+        assert_eq!(compile(functions.as_bytes(), "=test").err(), None);
+    }
+}
@@ -0,0 +1,355 @@
+//! Conversions between Rust values and Lua values for the host API.
+//! Failed conversions are errors reading like `luaL_typerror`, e.g.
+//! "number expected, got nil". Converting a list of values tells which one
+//! failed with a [`BadArgument`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+use anyhow::{Result, anyhow};
+
+use crate::eval::{LuaNumber, TValue, Value};
+use crate::table::{Table, TableKey};
+use crate::vm::{LuaState, is_false, type_name};
+
+/// A Rust value which can be turned into a Lua value.
+pub trait ToLua {
+    fn to_lua(self, state: &mut LuaState) -> TValue;
+}
+
+/// A Rust value which can be read from a Lua value.
+pub trait FromLua: Sized {
+    fn from_lua(val: TValue, state: &mut LuaState) -> Result<Self>;
+}
+
+/// Any number of Lua values: arguments of a call.
+pub trait ToLuaMulti {
+    fn to_lua_multi(self, state: &mut LuaState) -> Vec<TValue>;
+}
+
+/// Rust values read from a list of Lua values, missing values being nil:
+/// results of a call.
+pub trait FromLuaMulti: Sized {
+    fn from_lua_multi(values: Vec<TValue>, state: &mut LuaState) -> Result<Self>;
+}
+
+/// Conversion error of the value at `position` (from 1) of a list, turned
+/// into "bad argument #n to 'f' (message)" by functions made with
+/// `create_function`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BadArgument {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for BadArgument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for BadArgument {}
+
+/// `e` as a `BadArgument`, for a list whose first `skipped` values come
+/// before the one (or the sublist) that failed
+pub(crate) fn bad_argument(e: anyhow::Error, skipped: usize) -> anyhow::Error {
+    let (position, message) = match e.downcast::<BadArgument>() {
+        Ok(bad) => (bad.position, bad.message),
+        Err(e) => (1, e.to_string()),
+    };
+    anyhow::Error::new(BadArgument {
+        position: skipped + position,
+        message,
+    })
+}
+
+fn type_mismatch(expected: &str, val: &TValue) -> anyhow::Error {
+    anyhow!("{expected} expected, got {}", type_name(val))
+}
+
+impl ToLua for TValue {
+    fn to_lua(self, _: &mut LuaState) -> TValue {
+        self
+    }
+}
+
+impl FromLua for TValue {
+    fn from_lua(val: TValue, _: &mut LuaState) -> Result<Self> {
+        Ok(val)
+    }
+}
+
+impl ToLua for bool {
+    fn to_lua(self, _: &mut LuaState) -> TValue {
+        TValue::boolean(self)
+    }
+}
+
+/// Lua truth: only nil and false are false
+impl FromLua for bool {
+    fn from_lua(val: TValue, _: &mut LuaState) -> Result<Self> {
+        Ok(!is_false(&val))
+    }
+}
+
+/// numeric value, converting strings like arithmetic does
+fn to_number(state: &LuaState, val: &TValue) -> Result<LuaNumber> {
+    match state.to_number(val) {
+        Some(Value::Number(n)) => Ok(n),
+        Some(Value::Integer(n)) => Ok(n as LuaNumber),
+        _ => Err(type_mismatch("number", val)),
+    }
+}
+
+macro_rules! float_conversions {
+    ($($t:ty),*) => {$(
+        impl ToLua for $t {
+            fn to_lua(self, _: &mut LuaState) -> TValue {
+                TValue::number(self as LuaNumber)
+            }
+        }
+        impl FromLua for $t {
+            fn from_lua(val: TValue, state: &mut LuaState) -> Result<Self> {
+                Ok(to_number(state, &val)? as $t)
+            }
+        }
+    )*};
+}
+
+float_conversions!(f32, f64);
+
+// Integers are read truncated toward zero, like luaL_checkinteger
+macro_rules! integer_conversions {
+    ($($t:ty),*) => {$(
+        impl ToLua for $t {
+            fn to_lua(self, _: &mut LuaState) -> TValue {
+                TValue::int_or_float(self as i128)
+            }
+        }
+        impl FromLua for $t {
+            fn from_lua(val: TValue, state: &mut LuaState) -> Result<Self> {
+                let n = to_number(state, &val)?.trunc();
+                if n >= <$t>::MIN as LuaNumber && n <= <$t>::MAX as LuaNumber {
+                    Ok(n as $t)
+                } else {
+                    Err(anyhow!("number out of range"))
+                }
+            }
+        }
+    )*};
+}
+
+integer_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl ToLua for &str {
+    fn to_lua(self, state: &mut LuaState) -> TValue {
+        state.intern(self.as_bytes())
+    }
+}
+
+impl ToLua for String {
+    fn to_lua(self, state: &mut LuaState) -> TValue {
+        state.intern(self.as_bytes())
+    }
+}
+
+/// strings and numbers, which convert like `tostring`
+impl FromLua for String {
+    fn from_lua(val: TValue, state: &mut LuaState) -> Result<Self> {
+        let bytes = state
+            .to_str_bytes(&val)
+            .ok_or_else(|| type_mismatch("string", &val))?;
+        String::from_utf8(bytes).map_err(|_| anyhow!("string is not valid UTF-8"))
+    }
+}
+
+impl<T: ToLua> ToLua for Option<T> {
+    fn to_lua(self, state: &mut LuaState) -> TValue {
+        match self {
+            Some(v) => v.to_lua(state),
+            None => TValue::nil(),
+        }
+    }
+}
+
+/// nil is `None`, anything else must convert to `T`
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(val: TValue, state: &mut LuaState) -> Result<Self> {
+        match val.value() {
+            Value::Nil => Ok(None),
+            _ => T::from_lua(val, state).map(Some),
+        }
+    }
+}
+
+/// array part of a new table
+impl<T: ToLua> ToLua for Vec<T> {
+    fn to_lua(self, state: &mut LuaState) -> TValue {
+        let vals: Vec<TValue> = self.into_iter().map(|v| v.to_lua(state)).collect();
+        let t = state.global.heap.alloc(Table::with_capacity(vals.len(), 0));
+        let table = state.global.heap.get_mut(t);
+        for (i, v) in vals.into_iter().enumerate() {
+            table.set_int(i as i64 + 1, v);
+        }
+        TValue::table(t)
+    }
+}
+
+/// `t[1]` to `t[#t]`, without metamethods
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(val: TValue, state: &mut LuaState) -> Result<Self> {
+        let Value::Table(t) = val.value() else {
+            return Err(type_mismatch("table", &val));
+        };
+        let table = state.global.heap.get(t);
+        let vals: Vec<TValue> = (1..=table.length()).map(|i| table.get_int(i)).collect();
+        vals.into_iter().map(|v| T::from_lua(v, state)).collect()
+    }
+}
+
+/// a new table; entries whose key is nil or NaN in Lua are left out
+impl<K: ToLua, V: ToLua> ToLua for HashMap<K, V> {
+    fn to_lua(self, state: &mut LuaState) -> TValue {
+        let entries: Vec<(TValue, TValue)> = self
+            .into_iter()
+            .map(|(k, v)| (k.to_lua(state), v.to_lua(state)))
+            .collect();
+        let t = state
+            .global
+            .heap
+            .alloc(Table::with_capacity(0, entries.len()));
+        let table = state.global.heap.get_mut(t);
+        for (k, v) in entries {
+            if let Ok(Some(_)) = TableKey::from_value(&k) {
+                table.set(k, v).expect("valid table key");
+            }
+        }
+        TValue::table(t)
+    }
+}
+
+/// all the entries of a table, without metamethods
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    fn from_lua(val: TValue, state: &mut LuaState) -> Result<Self> {
+        let Value::Table(t) = val.value() else {
+            return Err(type_mismatch("table", &val));
+        };
+        let entries: Vec<(TValue, TValue)> = state.global.heap.get(t).entries().collect();
+        entries
+            .into_iter()
+            .map(|(k, v)| Ok((K::from_lua(k, state)?, V::from_lua(v, state)?)))
+            .collect()
+    }
+}
+
+/// Any number of values of the same type: the trailing arguments of a
+/// function (`...`) or a variable number of results.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+
+impl<T: ToLua> ToLuaMulti for Variadic<T> {
+    fn to_lua_multi(self, state: &mut LuaState) -> Vec<TValue> {
+        self.0.into_iter().map(|v| v.to_lua(state)).collect()
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+    fn from_lua_multi(values: Vec<TValue>, state: &mut LuaState) -> Result<Self> {
+        let mut vals = Vec::with_capacity(values.len());
+        for (i, v) in values.into_iter().enumerate() {
+            vals.push(T::from_lua(v, state).map_err(|e| bad_argument(e, i))?);
+        }
+        Ok(Variadic(vals))
+    }
+}
+
+impl ToLuaMulti for () {
+    fn to_lua_multi(self, _: &mut LuaState) -> Vec<TValue> {
+        Vec::new()
+    }
+}
+
+impl FromLuaMulti for () {
+    fn from_lua_multi(_: Vec<TValue>, _: &mut LuaState) -> Result<Self> {
+        Ok(())
+    }
+}
+
+impl<T: ToLua> ToLuaMulti for T {
+    fn to_lua_multi(self, state: &mut LuaState) -> Vec<TValue> {
+        vec![self.to_lua(state)]
+    }
+}
+
+/// the first value; extra values are dropped
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(values: Vec<TValue>, state: &mut LuaState) -> Result<Self> {
+        let first = values.into_iter().next().unwrap_or_else(TValue::nil);
+        T::from_lua(first, state).map_err(|e| bad_argument(e, 0))
+    }
+}
+
+// The last element of a tuple takes all the remaining values, so that it
+// can be a `Variadic`.
+macro_rules! tuple_conversions {
+    ($($name:ident)* ; $last:ident) => {
+        impl<$($name: ToLua,)* $last: ToLuaMulti> ToLuaMulti for ($($name,)* $last,) {
+            #[allow(non_snake_case)]
+            fn to_lua_multi(self, state: &mut LuaState) -> Vec<TValue> {
+                let ($($name,)* $last,) = self;
+                let mut vals = vec![$($name.to_lua(state)),*];
+                vals.extend($last.to_lua_multi(state));
+                vals
+            }
+        }
+        impl<$($name: FromLua,)* $last: FromLuaMulti> FromLuaMulti for ($($name,)* $last,) {
+            #[allow(non_snake_case, unused_mut)]
+            fn from_lua_multi(values: Vec<TValue>, state: &mut LuaState) -> Result<Self> {
+                let mut values = values.into_iter();
+                let mut skipped = 0;
+                $(
+                    let v = values.next().unwrap_or_else(TValue::nil);
+                    let $name = $name::from_lua(v, state).map_err(|e| bad_argument(e, skipped))?;
+                    skipped += 1;
+                )*
+                let $last = $last::from_lua_multi(values.collect(), state)
+                    .map_err(|e| bad_argument(e, skipped))?;
+                Ok(($($name,)* $last,))
+            }
+        }
+    };
+}
+
+tuple_conversions!(; A);
+tuple_conversions!(A; B);
+tuple_conversions!(A B; C);
+tuple_conversions!(A B C; D);
+tuple_conversions!(A B C D; E);
+tuple_conversions!(A B C D E; F);
+tuple_conversions!(A B C D E F; G);
+tuple_conversions!(A B C D E F G; H);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_round_trips() {
+        let mut state = LuaState::new();
+        let vals = (1u8, -2.5f64, "three", Some(true), None::<i32>).to_lua_multi(&mut state);
+        assert_eq!(vals.len(), 5);
+        let back: (i64, f32, String, Option<bool>, Option<i32>, Option<String>) =
+            FromLuaMulti::from_lua_multi(vals, &mut state).unwrap();
+        assert_eq!(back, (1, -2.5, "three".to_string(), Some(true), None, None));
+        // numeric strings and truncation, like luaL_checkinteger
+        let s = state.intern(b" 0x10 ");
+        assert_eq!(i32::from_lua(s, &mut state).unwrap(), 16);
+        assert_eq!(i32::from_lua(TValue::number(-3.7), &mut state).unwrap(), -3);
+        let err = u8::from_lua(TValue::number(300.0), &mut state).unwrap_err();
+        assert_eq!(err.to_string(), "number out of range");
+        let err = String::from_lua(TValue::nil(), &mut state).unwrap_err();
+        assert_eq!(err.to_string(), "string expected, got nil");
+    }
+}
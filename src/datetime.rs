@@ -0,0 +1,353 @@
+//! Calendar computations behind `os.date` and `os.time`: conversions between
+//! POSIX times and broken-down dates, C-locale `strftime`, and `mktime`-style
+//! normalisation. Only the local timezone rules come from the C library.
+
+use std::ffi::CStr;
+
+const SECS_PER_DAY: i64 = 86400;
+
+/// years representable in C's `struct tm`, whose year is an int from 1900
+const YEARS: std::ops::RangeInclusive<i64> = (i32::MIN as i64 + 1900)..=(i32::MAX as i64 + 1900);
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Broken-down time, like C's `struct tm` but with a full year and a 1-based month.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tm {
+    pub year: i64,
+    /// 1 to 12
+    pub month: i64,
+    /// 1 to 31
+    pub day: i64,
+    pub hour: i64,
+    pub min: i64,
+    pub sec: i64,
+    /// days since Sunday, 0 to 6
+    pub wday: i64,
+    /// days since January 1st, 0 to 365
+    pub yday: i64,
+    pub isdst: bool,
+    /// seconds east of UTC
+    pub utc_offset: i64,
+    /// abbreviated timezone name, as printed by `%Z`
+    pub zone: String,
+}
+
+/// days since 1970-01-01 of a proleptic Gregorian date; the month must be 1 to 12
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// year, month and day of a number of days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+impl Tm {
+    /// the date at `t` seconds since the epoch, in a zone `utc_offset` seconds
+    /// east of UTC; None if the year does not fit a C `struct tm`
+    fn at_offset(t: i64, utc_offset: i64, isdst: bool, zone: String) -> Option<Tm> {
+        let local = t.checked_add(utc_offset)?;
+        let days = local.div_euclid(SECS_PER_DAY);
+        let secs = local.rem_euclid(SECS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        if !YEARS.contains(&year) {
+            return None;
+        }
+        Some(Tm {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            min: secs / 60 % 60,
+            sec: secs % 60,
+            wday: (days + 4).rem_euclid(7),
+            yday: days - days_from_civil(year, 1, 1),
+            isdst,
+            utc_offset,
+            zone,
+        })
+    }
+    /// UTC date at `t` (gmtime)
+    pub fn utc(t: i64) -> Option<Tm> {
+        Tm::at_offset(t, 0, false, "GMT".to_string())
+    }
+    /// local date at `t` (localtime); None if the C library cannot place `t`
+    pub fn local(t: i64) -> Option<Tm> {
+        let (offset, isdst, zone) = local_zone(t)?;
+        Tm::at_offset(t, offset, isdst, zone)
+    }
+}
+
+/// UTC offset, daylight saving flag and zone name in effect at `t`
+fn local_zone(t: i64) -> Option<(i64, bool, String)> {
+    let t = libc::time_t::try_from(t).ok()?;
+    // SAFETY: tm is plain data and all zeros is a valid value
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&t, &mut tm) }.is_null() {
+        return None;
+    }
+    let zone = if tm.tm_zone.is_null() {
+        String::new()
+    } else {
+        // SAFETY: tm_zone points to a static NUL-terminated name
+        unsafe { CStr::from_ptr(tm.tm_zone) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    Some((tm.tm_gmtoff as i64, tm.tm_isdst > 0, zone))
+}
+
+/// Seconds since the epoch of a UTC date (timegm). Fields out of their
+/// range carry over, e.g. month 13 is January of the next year.
+pub fn timegm(year: i64, month: i64, day: i64, hour: i64, min: i64, sec: i64) -> Option<i64> {
+    let months = year.checked_mul(12)?.checked_add(month.checked_sub(1)?)?;
+    let year = months.div_euclid(12);
+    if !YEARS.contains(&year) {
+        return None;
+    }
+    let days =
+        days_from_civil(year, months.rem_euclid(12) + 1, 1).checked_add(day.checked_sub(1)?)?;
+    days.checked_mul(SECS_PER_DAY)?
+        .checked_add(hour.checked_mul(3600)?)?
+        .checked_add(min.checked_mul(60)?)?
+        .checked_add(sec)
+}
+
+/// Seconds since the epoch of a local date, normalised like `timegm` (mktime).
+/// `isdst` picks between the two readings of a time repeated when clocks go back.
+pub fn mktime(
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    min: i64,
+    sec: i64,
+    isdst: Option<bool>,
+) -> Option<i64> {
+    let naive = timegm(year, month, day, hour, min, sec)?;
+    // the offsets in effect around the date; at most one transition in between
+    let before = local_zone(naive.checked_sub(SECS_PER_DAY)?)?;
+    let after = local_zone(naive.checked_add(SECS_PER_DAY)?)?;
+    let mut candidates = Vec::new();
+    for (offset, dst, _) in [before.clone(), after] {
+        let t = naive - offset;
+        if local_zone(t).is_some_and(|(o, d, _)| o == offset && d == dst) {
+            candidates.push((t, dst));
+        }
+    }
+    let chosen = candidates
+        .iter()
+        .find(|(_, dst)| isdst.is_none_or(|want| want == *dst))
+        .or(candidates.first());
+    // a time skipped when clocks go forward is read with the earlier offset
+    Some(chosen.map_or(naive - before.0, |(t, _)| *t))
+}
+
+/// ISO 8601 week-based year and week number
+fn iso_week(tm: &Tm) -> (i64, i64) {
+    let weeks_in = |y: i64| {
+        let p =
+            |y: i64| (y + y.div_euclid(4) - y.div_euclid(100) + y.div_euclid(400)).rem_euclid(7);
+        if p(y) == 4 || p(y - 1) == 3 { 53 } else { 52 }
+    };
+    let monday_based = (tm.wday + 6) % 7;
+    let week = (tm.yday - monday_based + 10) / 7;
+    if week < 1 {
+        (tm.year - 1, weeks_in(tm.year - 1))
+    } else if week > weeks_in(tm.year) {
+        (tm.year + 1, 1)
+    } else {
+        (tm.year, week)
+    }
+}
+
+/// Format `tm` like C's `strftime` in the "C" locale. The `E` and `O`
+/// modifiers are accepted and ignored; unknown conversions are copied.
+pub fn strftime(format: &[u8], tm: &Tm) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' || i + 1 == format.len() {
+            out.push(format[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        if matches!(format[i], b'E' | b'O') && i + 1 < format.len() {
+            i += 1;
+        }
+        let conv = format[i];
+        i += 1;
+        if !convert(&mut out, conv, tm) {
+            out.extend_from_slice(&format[start..i]);
+        }
+    }
+    out
+}
+
+/// append conversion `%conv`; false if it is unknown
+fn convert(out: &mut Vec<u8>, conv: u8, tm: &Tm) -> bool {
+    let s = match conv {
+        b'a' => WEEKDAYS[tm.wday as usize][..3].to_string(),
+        b'A' => WEEKDAYS[tm.wday as usize].to_string(),
+        b'b' | b'h' => MONTHS[tm.month as usize - 1][..3].to_string(),
+        b'B' => MONTHS[tm.month as usize - 1].to_string(),
+        b'c' => return append(out, b"%a %b %e %H:%M:%S %Y", tm),
+        b'C' => format!("{:02}", tm.year.div_euclid(100)),
+        b'd' => format!("{:02}", tm.day),
+        b'D' | b'x' => return append(out, b"%m/%d/%y", tm),
+        b'e' => format!("{:2}", tm.day),
+        b'F' => return append(out, b"%Y-%m-%d", tm),
+        b'g' => format!("{:02}", iso_week(tm).0.rem_euclid(100)),
+        b'G' => iso_week(tm).0.to_string(),
+        b'H' => format!("{:02}", tm.hour),
+        b'I' => format!("{:02}", (tm.hour + 11) % 12 + 1),
+        b'j' => format!("{:03}", tm.yday + 1),
+        b'm' => format!("{:02}", tm.month),
+        b'M' => format!("{:02}", tm.min),
+        b'n' => "\n".to_string(),
+        b'p' => if tm.hour < 12 { "AM" } else { "PM" }.to_string(),
+        b'r' => return append(out, b"%I:%M:%S %p", tm),
+        b'R' => return append(out, b"%H:%M", tm),
+        b'S' => format!("{:02}", tm.sec),
+        b't' => "\t".to_string(),
+        b'T' | b'X' => return append(out, b"%H:%M:%S", tm),
+        b'u' => ((tm.wday + 6) % 7 + 1).to_string(),
+        b'U' => format!("{:02}", (tm.yday + 7 - tm.wday) / 7),
+        b'V' => format!("{:02}", iso_week(tm).1),
+        b'w' => tm.wday.to_string(),
+        b'W' => format!("{:02}", (tm.yday + 7 - (tm.wday + 6) % 7) / 7),
+        b'y' => format!("{:02}", tm.year.rem_euclid(100)),
+        b'Y' => tm.year.to_string(),
+        b'z' => {
+            let sign = if tm.utc_offset < 0 { '-' } else { '+' };
+            let off = tm.utc_offset.abs() / 60;
+            format!("{sign}{:02}{:02}", off / 60, off % 60)
+        }
+        b'Z' => tm.zone.clone(),
+        b'%' => "%".to_string(),
+        _ => return false,
+    };
+    out.extend_from_slice(s.as_bytes());
+    true
+}
+
+fn append(out: &mut Vec<u8>, format: &[u8], tm: &Tm) -> bool {
+    out.extend(strftime(format, tm));
+    true
+}
+
+/// number of days of a month, for checks in tests
+#[cfg(test)]
+fn month_days(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_civil_round_trip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        let mut days = days_from_civil(1899, 1, 1);
+        for year in 1899..2101 {
+            for month in 1..=12 {
+                for day in 1..=month_days(year, month) {
+                    assert_eq!(civil_from_days(days), (year, month, day));
+                    days += 1;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_strftime() {
+        // Friday 2021-01-01 13:05:09 UTC
+        let tm = Tm::utc(1609506309).unwrap();
+        let cases: [(&str, &str); 12] = [
+            ("%Y-%m-%dT%H:%M:%S", "2021-01-01T13:05:09"),
+            ("%c", "Fri Jan  1 13:05:09 2021"),
+            ("%a %A %b %B %h", "Fri Friday Jan January Jan"),
+            ("%C %y %D %F", "20 21 01/01/21 2021-01-01"),
+            ("%e|%j|%u|%w", " 1|001|5|5"),
+            ("%I %p %r %R %T", "01 PM 01:05:09 PM 13:05 13:05:09"),
+            ("%g %G %V", "20 2020 53"),
+            ("%U %W", "00 00"),
+            ("%x %X", "01/01/21 13:05:09"),
+            ("%z %Z %%", "+0000 GMT %"),
+            ("%Ey %Od", "21 01"),
+            ("%Q %", "%Q %"),
+        ];
+        for (format, expected) in cases {
+            let res = strftime(format.as_bytes(), &tm);
+            assert_eq!(String::from_utf8(res).unwrap(), expected, "{format}");
+        }
+        // Monday 2024-12-30 is in week 1 of 2025
+        let tm = Tm::utc(timegm(2024, 12, 30, 0, 0, 0).unwrap()).unwrap();
+        assert_eq!(strftime(b"%G-W%V-%u %U %W", &tm), b"2025-W01-1 52 53");
+        let tm = Tm::utc(-1).unwrap();
+        assert_eq!(strftime(b"%F %T %j", &tm), b"1969-12-31 23:59:59 365");
+    }
+
+    #[test]
+    fn test_timegm_normalises() {
+        assert_eq!(timegm(1970, 1, 1, 0, 0, 0), Some(0));
+        assert_eq!(timegm(2020, 13, 1, 0, 0, 0), timegm(2021, 1, 1, 0, 0, 0));
+        assert_eq!(timegm(2021, 0, 1, 0, 0, 0), timegm(2020, 12, 1, 0, 0, 0));
+        assert_eq!(timegm(2021, 3, 0, 0, 0, 0), timegm(2021, 2, 28, 0, 0, 0));
+        assert_eq!(timegm(2021, 1, 1, 25, 61, -1), timegm(2021, 1, 2, 2, 0, 59));
+        let tm = Tm::utc(timegm(2000, 2, 30, 0, 0, 0).unwrap()).unwrap();
+        assert_eq!((tm.month, tm.day, tm.wday, tm.yday), (3, 1, 3, 60));
+        assert_eq!(timegm(i64::MAX / 12, 1, 1, 0, 0, 0), None);
+        assert!(Tm::utc(i64::MAX).is_none());
+    }
+}
@@ -0,0 +1,464 @@
+use anyhow::Result;
+
+use crate::debug::{HookEvent, HookFn, MASK_CALL, MASK_COUNT, MASK_LINE, MASK_RET};
+use crate::eval::{LuaNumber, TValue, Value};
+use crate::heap::Gc;
+use crate::table::Table;
+use crate::thread::Thread;
+use crate::vm::LuaState;
+
+/// optional thread as first argument, and the offset of the other arguments
+fn get_thread(state: &LuaState) -> (Gc<Thread>, usize) {
+    match state.arg(1).value() {
+        Value::Thread(th) => (th, 1),
+        _ => (state.current, 0),
+    }
+}
+
+fn set_field(state: &mut LuaState, t: Gc<Table>, name: &str, val: TValue) {
+    let key = state.global.heap.intern(name.as_bytes());
+    state.global.heap.get_mut(t).set_str(key, val);
+}
+
+fn is_function(val: &TValue) -> bool {
+    matches!(val.value(), Value::LuaClosure(_) | Value::NativeClosure(_))
+}
+
+/// debug.getregistry()
+fn db_getregistry(state: &mut LuaState) -> Result<usize> {
+    state.push(TValue::table(state.global.registry));
+    Ok(1)
+}
+
+/// debug.getmetatable(object), ignoring `__metatable`
+fn db_getmetatable(state: &mut LuaState) -> Result<usize> {
+    let obj = state.check_any(1)?;
+    let mt = state
+        .get_metatable(&obj)
+        .map_or(TValue::nil(), TValue::table);
+    state.push(mt);
+    Ok(1)
+}
+
+/// debug.setmetatable(object, table)
+fn db_setmetatable(state: &mut LuaState) -> Result<usize> {
+    let obj = state.arg(1);
+    let mt = match state.arg(2).value() {
+        Value::Nil => None,
+        Value::Table(mt) => Some(mt),
+        _ => return Err(state.type_error_arg(2, "nil or table")),
+    };
+    state.set_metatable(&obj, mt);
+    state.push(TValue::boolean(true));
+    Ok(1)
+}
+
+/// debug.getinfo([thread,] function | level [, what])
+fn db_getinfo(state: &mut LuaState) -> Result<usize> {
+    let (th, arg) = get_thread(state);
+    let options = match state.opt_string(arg + 2)? {
+        Some(s) => String::from_utf8_lossy(state.global.heap.get(s).as_bytes()).into_owned(),
+        None => "flnSu".to_string(),
+    };
+    let target = state.arg(arg + 1);
+    let ar = if state.to_number(&target).is_some() {
+        let level = state.check_integer(arg + 1)?;
+        let ar = state.with_thread(th, |s| {
+            let ci = s.get_stack(usize::try_from(level).ok()?)?;
+            Some(s.get_info(&options, ci))
+        });
+        match ar {
+            Some(ar) => ar,
+            None => {
+                // level out of range
+                state.push(TValue::nil());
+                return Ok(1);
+            }
+        }
+    } else if is_function(&target) {
+        state.get_func_info(&options, target)
+    } else {
+        return Err(state.arg_error(arg + 1, "function or level expected"));
+    };
+    let Some(ar) = ar else {
+        return Err(state.arg_error(arg + 2, "invalid option"));
+    };
+    let t = state.new_table();
+    let num = |n: i64| TValue::number(n as LuaNumber);
+    if options.contains('S') {
+        let source = state.intern(ar.source.as_bytes());
+        set_field(state, t, "source", source);
+        let short_src = state.intern(ar.short_src.as_bytes());
+        set_field(state, t, "short_src", short_src);
+        set_field(state, t, "linedefined", num(ar.line_defined));
+        set_field(state, t, "lastlinedefined", num(ar.last_line_defined));
+        let what = state.intern(ar.what.as_bytes());
+        set_field(state, t, "what", what);
+    }
+    if options.contains('l') {
+        set_field(state, t, "currentline", num(ar.current_line));
+    }
+    if options.contains('u') {
+        set_field(state, t, "nups", num(ar.nups as i64));
+    }
+    if options.contains('n') {
+        let name = match &ar.name {
+            Some(name) => state.intern(name.as_bytes()),
+            None => TValue::nil(),
+        };
+        set_field(state, t, "name", name);
+        let namewhat = state.intern(ar.namewhat.as_bytes());
+        set_field(state, t, "namewhat", namewhat);
+    }
+    if options.contains('f') {
+        set_field(state, t, "func", ar.func.unwrap_or(TValue::nil()));
+    }
+    state.push(TValue::table(t));
+    Ok(1)
+}
+
+/// stack frame of the level given as argument `narg`, in thread `th`
+fn check_level(state: &mut LuaState, th: Gc<Thread>, narg: usize) -> Result<usize> {
+    let level = state.check_integer(narg)?;
+    let ci = usize::try_from(level)
+        .ok()
+        .and_then(|level| state.with_thread(th, |s| s.get_stack(level)));
+    ci.ok_or_else(|| state.arg_error(narg, "level out of range"))
+}
+
+/// debug.getlocal([thread,] level, local): name and value of a local variable
+fn db_getlocal(state: &mut LuaState) -> Result<usize> {
+    let (th, arg) = get_thread(state);
+    let ci = check_level(state, th, arg + 1)?;
+    let n = state.check_integer(arg + 2)?;
+    let local = match usize::try_from(n) {
+        Ok(n) => state.with_thread(th, |s| s.get_local(ci, n)),
+        Err(_) => None,
+    };
+    match local {
+        Some((name, val)) => {
+            let name = state.intern(name.as_bytes());
+            state.push(name);
+            state.push(val);
+            Ok(2)
+        }
+        None => {
+            state.push(TValue::nil());
+            Ok(1)
+        }
+    }
+}
+
+/// debug.setlocal([thread,] level, local, value): the name of the local, or nil
+fn db_setlocal(state: &mut LuaState) -> Result<usize> {
+    let (th, arg) = get_thread(state);
+    let ci = check_level(state, th, arg + 1)?;
+    let n = state.check_integer(arg + 2)?;
+    let val = state.check_any(arg + 3)?;
+    let name = match usize::try_from(n) {
+        Ok(n) => state.with_thread(th, |s| s.set_local(ci, n, val)),
+        Err(_) => None,
+    };
+    let name = match name {
+        Some(name) => state.intern(name.as_bytes()),
+        None => TValue::nil(),
+    };
+    state.push(name);
+    Ok(1)
+}
+
+/// function argument 1 and upvalue number argument 2; None for native
+/// functions, whose upvalues are not visible from Lua
+fn check_upvalue(state: &mut LuaState) -> Result<Option<(TValue, usize)>> {
+    let n = state.check_integer(2)?;
+    let f = state.arg(1);
+    match f.value() {
+        Value::LuaClosure(_) => Ok(usize::try_from(n).ok().map(|n| (f, n))),
+        Value::NativeClosure(_) => Ok(None),
+        _ => Err(state.type_error_arg(1, "function")),
+    }
+}
+
+/// debug.getupvalue(f, up): name and value of an upvalue
+fn db_getupvalue(state: &mut LuaState) -> Result<usize> {
+    let Some((f, n)) = check_upvalue(state)? else {
+        return Ok(0);
+    };
+    let Some((name, val)) = state.get_upvalue(&f, n) else {
+        return Ok(0);
+    };
+    let name = state.intern(name.as_bytes());
+    state.push(name);
+    state.push(val);
+    Ok(2)
+}
+
+/// debug.setupvalue(f, up, value): the name of the upvalue
+fn db_setupvalue(state: &mut LuaState) -> Result<usize> {
+    let val = state.check_any(3)?;
+    let Some((f, n)) = check_upvalue(state)? else {
+        return Ok(0);
+    };
+    let Some(name) = state.set_upvalue_of(&f, n, val) else {
+        return Ok(0);
+    };
+    let name = state.intern(name.as_bytes());
+    state.push(name);
+    Ok(1)
+}
+
+/// hook functions set from Lua, by thread (weak keys)
+fn hook_table(state: &mut LuaState) -> Gc<Table> {
+    let key = state.global.heap.intern(b"_HOOKS");
+    let registry = state.global.registry;
+    if let Value::Table(t) = state.global.heap.get(registry).get_str(key).value() {
+        return t;
+    }
+    let t = state.new_table();
+    let mode = state.intern(b"__mode");
+    let k = state.intern(b"k");
+    let heap = &mut state.global.heap;
+    heap.get_mut(t).set(mode, k).unwrap();
+    heap.get_mut(t).metatable = Some(t);
+    heap.get_mut(registry).set_str(key, TValue::table(t));
+    t
+}
+
+/// hook installed by `debug.sethook`: calls the Lua hook of the running
+/// thread with the event name and the line number
+fn hookf(state: &mut LuaState, event: HookEvent) -> Result<()> {
+    let hooks = hook_table(state);
+    let f = state
+        .global
+        .heap
+        .get(hooks)
+        .get(&TValue::thread(state.current));
+    if !is_function(&f) {
+        return Ok(());
+    }
+    let func = state.top;
+    state.push(f);
+    let name = state.intern(event.name().as_bytes());
+    state.push(name);
+    let line = match event {
+        HookEvent::Line(line) => TValue::number(line as LuaNumber),
+        _ => TValue::nil(),
+    };
+    state.push(line);
+    state.call(func, 0)
+}
+
+fn make_mask(smask: &[u8], count: usize) -> u8 {
+    let mut mask = 0;
+    if smask.contains(&b'c') {
+        mask |= MASK_CALL;
+    }
+    if smask.contains(&b'r') {
+        mask |= MASK_RET;
+    }
+    if smask.contains(&b'l') {
+        mask |= MASK_LINE;
+    }
+    if count > 0 {
+        mask |= MASK_COUNT;
+    }
+    mask
+}
+
+fn unmake_mask(mask: u8) -> String {
+    let mut smask = String::new();
+    if mask & MASK_CALL != 0 {
+        smask.push('c');
+    }
+    if mask & MASK_RET != 0 {
+        smask.push('r');
+    }
+    if mask & MASK_LINE != 0 {
+        smask.push('l');
+    }
+    smask
+}
+
+/// debug.sethook([thread,] hook, mask [, count]); no hook turns hooks off
+fn db_sethook(state: &mut LuaState) -> Result<usize> {
+    let (th, arg) = get_thread(state);
+    let hook = state.arg(arg + 1);
+    let (func, mask, count): (Option<HookFn>, u8, usize) = if let Value::Nil = hook.value() {
+        (None, 0, 0)
+    } else {
+        let smask = state.check_string(arg + 2)?;
+        if !is_function(&hook) {
+            return Err(state.type_error_arg(arg + 1, "function"));
+        }
+        let count = state.opt_integer(arg + 3, 0)?.max(0) as usize;
+        let mask = make_mask(state.global.heap.get(smask).as_bytes(), count);
+        (Some(hookf), mask, count)
+    };
+    let hooks = hook_table(state);
+    state
+        .global
+        .heap
+        .get_mut(hooks)
+        .set(TValue::thread(th), hook)
+        .unwrap();
+    state.with_thread(th, |s| s.set_hook(func, mask, count));
+    Ok(0)
+}
+
+/// debug.gethook([thread]): hook function, mask and count
+fn db_gethook(state: &mut LuaState) -> Result<usize> {
+    let (th, _) = get_thread(state);
+    let (func, mask, count) = state.with_thread(th, |s| s.get_hook());
+    let hook = match func {
+        Some(f) if !std::ptr::fn_addr_eq(f, hookf as HookFn) => state.intern(b"external hook"),
+        _ => {
+            let hooks = hook_table(state);
+            state.global.heap.get(hooks).get(&TValue::thread(th))
+        }
+    };
+    state.push(hook);
+    let smask = state.intern(unmake_mask(mask).as_bytes());
+    state.push(smask);
+    state.push(TValue::number(count as LuaNumber));
+    Ok(3)
+}
+
+/// debug.traceback([thread,] [message [, level]])
+fn db_traceback(state: &mut LuaState) -> Result<usize> {
+    let (th, arg) = get_thread(state);
+    let level = match state.arg(arg + 2) {
+        l if state.to_number(&l).is_some() => state.check_integer(arg + 2)?.max(0) as usize,
+        _ if th == state.current => 1,
+        _ => 0,
+    };
+    let msg = state.arg(arg + 1);
+    let mut out = Vec::new();
+    if state.get_top() > arg {
+        let Some(msg) = state.to_str_bytes(&msg) else {
+            // not a string message: returned untouched
+            state.push(msg);
+            return Ok(1);
+        };
+        out.extend(msg);
+        out.push(b'\n');
+    }
+    out.extend(state.with_thread(th, |s| s.traceback(level)).into_bytes());
+    let tb = state.intern(&out);
+    state.push(tb);
+    Ok(1)
+}
+
+pub fn open_debug(state: &mut LuaState) {
+    state.register_lib(
+        "debug",
+        &[
+            ("gethook", db_gethook),
+            ("getinfo", db_getinfo),
+            ("getlocal", db_getlocal),
+            ("getregistry", db_getregistry),
+            ("getmetatable", db_getmetatable),
+            ("getupvalue", db_getupvalue),
+            ("setlocal", db_setlocal),
+            ("setmetatable", db_setmetatable),
+            ("setupvalue", db_setupvalue),
+            ("sethook", db_sethook),
+            ("traceback", db_traceback),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::baselib::open_base;
+    use crate::baselib::tests::{call_value, show};
+    use crate::tablib::open_table;
+    use pretty_assertions::assert_eq;
+
+    fn run(state: &mut LuaState, source: &str) -> Vec<String> {
+        let f = state.load_buffer(source.as_bytes(), "=test").unwrap();
+        let res = call_value(state, f, &[]);
+        show(state, &res)
+    }
+
+    fn new_state() -> LuaState {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        open_debug(&mut state);
+        open_table(&mut state);
+        state
+    }
+
+    #[test]
+    fn test_getinfo_and_locals() {
+        let mut state = new_state();
+        let source = "
+            local up = 1
+            local function f(a)
+                local info = debug.getinfo(1, 'nSlu')
+                local name, val = debug.getlocal(1, 1)
+                debug.setlocal(1, 1, val * 10)
+                return info.name, info.namewhat, info.what, info.currentline,
+                    info.linedefined, info.nups, name, a, up
+            end
+            return select(1, f(4))
+        ";
+        assert_eq!(
+            run(&mut state, source),
+            vec!["f", "local", "Lua", "4", "3", "1", "a", "40", "1"]
+        );
+        let source = "
+            local a, b = 1, 2
+            local function g() return a + b end
+            local n1 = debug.getupvalue(g, 2)
+            local n2 = debug.setupvalue(g, 1, 10)
+            return n1, n2, g(), debug.getupvalue(print, 1), debug.getinfo(print).what
+        ";
+        assert_eq!(run(&mut state, source), vec!["b", "a", "12", "nil", "C"]);
+    }
+
+    #[test]
+    fn test_traceback() {
+        let mut state = new_state();
+        let source = "
+            local function f() return debug.traceback('oops') end
+            local function g() return (f()) end
+            return g()
+        ";
+        assert_eq!(
+            run(&mut state, source),
+            vec![
+                "oops\nstack traceback:\n\ttest:2: in function 'f'\n\t\
+                 test:3: in function <test:3>\n\t(tail call): ?"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut state = new_state();
+        let source = "
+            local events = {}
+            local function f() return 1 end
+            debug.sethook(function(ev, line)
+                events[#events + 1] = line and ev .. line or ev
+            end, 'crl')
+            f()
+            debug.sethook()
+            return table.concat(events, ' '), debug.gethook()
+        ";
+        assert_eq!(
+            run(&mut state, source),
+            vec!["return line7 call line3 return line8 call", "nil", "", "0"]
+        );
+        let source = "
+            local n = 0
+            debug.sethook(function() n = n + 1 end, '', 1)
+            local x = 1
+            x = x + 1
+            debug.sethook()
+            return n
+        ";
+        assert_eq!(run(&mut state, source), vec!["5"]);
+    }
+}
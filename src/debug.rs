@@ -0,0 +1,827 @@
+use anyhow::Result;
+
+use crate::eval::{TValue, Value};
+use crate::func::{Proto, VARARG_HASARG, VARARG_ISVARARG, VARARG_NEEDSARG};
+use crate::opcodes::{
+    Instruction, OpCode, get_a, get_b, get_bx, get_c, get_op, get_sbx, index_k, is_k,
+};
+use crate::vm::{LUA_MINSTACK, LuaState};
+
+/// maximum registers of a function, as the compiler enforces it
+const MAXSTACK: usize = 250;
+
+/// size of a chunk id, including the terminating NUL of C Lua
+const LUA_IDSIZE: usize = 60;
+
+/// levels shown at the top and at the bottom of a long traceback
+const LEVELS1: usize = 12;
+const LEVELS2: usize = 10;
+
+/// printable form of a chunk source name (luaO_chunkid)
+pub fn chunk_id(source: &str) -> String {
+    if let Some(name) = source.strip_prefix('=') {
+        name.chars().take(LUA_IDSIZE - 1).collect()
+    } else if let Some(file) = source.strip_prefix('@') {
+        // keep the tail of long file names
+        let bufflen = LUA_IDSIZE - " '...' ".len() - 1;
+        let len = file.chars().count();
+        if len > bufflen {
+            format!(
+                "...{}",
+                file.chars().skip(len - bufflen).collect::<String>()
+            )
+        } else {
+            file.to_string()
+        }
+    } else {
+        let bufflen = LUA_IDSIZE - " [string \"...\"] ".len() - 1;
+        let first_line: String = source
+            .chars()
+            .take_while(|c| *c != '\n' && *c != '\r')
+            .collect();
+        let len = first_line.chars().count();
+        if len > bufflen || len < source.chars().count() {
+            let head: String = first_line.chars().take(bufflen).collect();
+            format!("[string \"{head}...\"]")
+        } else {
+            format!("[string \"{source}\"]")
+        }
+    }
+}
+
+/// does `op` write register A (testAMode in lopcodes.c)
+fn sets_a(op: u32) -> bool {
+    !matches!(op, 7 | 8 | 9 | 22 | 23 | 24 | 25 | 26 | 30 | 33 | 34 | 35)
+}
+
+/// Symbolic execution up to `lastpc`: the last instruction that changed `reg` (symbexec).
+fn symbexec(p: &Proto, lastpc: usize, reg: usize) -> u32 {
+    let mut last = p.code.len() - 1;
+    let mut pc = 0;
+    while pc < lastpc {
+        let inst = p.code[pc];
+        let op = get_op(inst);
+        let a = get_a(inst);
+        match Instruction::from(inst) {
+            Instruction::LoadNil(_) => {
+                if a <= reg && reg <= get_b(inst) {
+                    last = pc;
+                }
+            }
+            Instruction::TForLoop(_) => {
+                if reg >= a + 2 {
+                    last = pc;
+                }
+            }
+            Instruction::Call(_) | Instruction::TailCall(_) => {
+                if reg >= a {
+                    last = pc;
+                }
+            }
+            Instruction::Jmp(_) => {
+                // skip forward jumps that do not go past `lastpc`
+                let dest = pc as isize + 1 + get_sbx(inst);
+                if (pc as isize) < dest && dest <= lastpc as isize {
+                    pc = (dest - 1) as usize;
+                }
+            }
+            Instruction::Closure(_) => {
+                if a == reg {
+                    last = pc;
+                }
+                pc += p.protos[get_bx(inst)].num_upvals;
+            }
+            Instruction::OpSelf(_) => {
+                if reg == a || reg == a + 1 {
+                    last = pc;
+                }
+            }
+            _ => {
+                if sets_a(op) && a == reg {
+                    last = pc;
+                }
+            }
+        }
+        pc += 1;
+    }
+    p.code[last]
+}
+
+/// How an instruction uses an operand (OpArgMask in lopcodes.h)
+#[derive(Clone, Copy, PartialEq)]
+enum ArgMode {
+    /// not used
+    N,
+    /// used for something else
+    U,
+    /// a register or a jump offset
+    R,
+    /// a constant or register
+    K,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Abc,
+    Abx,
+    AsBx,
+}
+
+/// test mode, sets A, B and C modes and format of each opcode (luaP_opmodes)
+#[rustfmt::skip]
+const OPMODES: [(bool, bool, ArgMode, ArgMode, Format); 38] = {
+    use ArgMode::*;
+    use Format::*;
+    [
+        (false, true,  R, N, Abc),  // MOVE
+        (false, true,  K, N, Abx),  // LOADK
+        (false, true,  U, U, Abc),  // LOADBOOL
+        (false, true,  R, N, Abc),  // LOADNIL
+        (false, true,  U, N, Abc),  // GETUPVAL
+        (false, true,  K, N, Abx),  // GETGLOBAL
+        (false, true,  R, K, Abc),  // GETTABLE
+        (false, false, K, N, Abx),  // SETGLOBAL
+        (false, false, U, N, Abc),  // SETUPVAL
+        (false, false, K, K, Abc),  // SETTABLE
+        (false, true,  U, U, Abc),  // NEWTABLE
+        (false, true,  R, K, Abc),  // SELF
+        (false, true,  K, K, Abc),  // ADD
+        (false, true,  K, K, Abc),  // SUB
+        (false, true,  K, K, Abc),  // MUL
+        (false, true,  K, K, Abc),  // DIV
+        (false, true,  K, K, Abc),  // MOD
+        (false, true,  K, K, Abc),  // POW
+        (false, true,  R, N, Abc),  // UNM
+        (false, true,  R, N, Abc),  // NOT
+        (false, true,  R, N, Abc),  // LEN
+        (false, true,  R, R, Abc),  // CONCAT
+        (false, false, R, N, AsBx), // JMP
+        (true,  false, K, K, Abc),  // EQ
+        (true,  false, K, K, Abc),  // LT
+        (true,  false, K, K, Abc),  // LE
+        (true,  true,  R, U, Abc),  // TEST
+        (true,  true,  R, U, Abc),  // TESTSET
+        (false, true,  U, U, Abc),  // CALL
+        (false, true,  U, U, Abc),  // TAILCALL
+        (false, false, U, N, Abc),  // RETURN
+        (false, true,  R, N, AsBx), // FORLOOP
+        (false, true,  R, N, AsBx), // FORPREP
+        (true,  false, N, U, Abc),  // TFORLOOP
+        (false, false, U, U, Abc),  // SETLIST
+        (false, false, N, N, Abc),  // CLOSE
+        (false, true,  U, N, Abx),  // CLOSURE
+        (false, true,  U, N, Abc),  // VARARG
+    ]
+};
+
+/// header fields of a prototype that the code relies on (precheck)
+fn precheck(p: &Proto) -> bool {
+    p.max_stack <= MAXSTACK
+        && p.num_params + (p.is_vararg & VARARG_HASARG) as usize <= p.max_stack
+        && (p.is_vararg & VARARG_NEEDSARG == 0 || p.is_vararg & VARARG_HASARG != 0)
+        && p.upvalue_names.len() <= p.num_upvals
+        && (p.lineinfo.len() == p.code.len() || p.lineinfo.is_empty())
+        && p.code
+            .last()
+            .is_some_and(|&i| get_op(i) == OpCode::OpReturn as u32)
+}
+
+/// an instruction that can follow an open call (luaG_checkopenop)
+fn check_open_op(p: &Proto, pc: usize) -> bool {
+    let i = p.code[pc + 1];
+    let op = get_op(i);
+    (op == OpCode::OpCall as u32
+        || op == OpCode::OpTailCall as u32
+        || op == OpCode::OpReturn as u32
+        || op == OpCode::OpSetList as u32)
+        && get_b(i) == 0
+}
+
+fn check_arg_mode(p: &Proto, r: usize, mode: ArgMode) -> bool {
+    match mode {
+        ArgMode::N => r == 0,
+        ArgMode::U => true,
+        ArgMode::R => r < p.max_stack,
+        ArgMode::K if is_k(r) => index_k(r) < p.constants.len(),
+        ArgMode::K => r < p.max_stack,
+    }
+}
+
+/// Check that the code of `p` and of its nested functions cannot make the VM
+/// read out of its registers, constants or code (luaG_checkcode).
+pub fn check_code(p: &Proto) -> bool {
+    check_proto(p) && p.protos.iter().all(|child| check_code(child))
+}
+
+/// symbexec in checking mode, over the whole code
+fn check_proto(p: &Proto) -> bool {
+    if !precheck(p) {
+        return false;
+    }
+    let size = p.code.len() as isize;
+    let reg_ok = |r: isize| r < p.max_stack as isize;
+    let mut pc = 0;
+    while pc < p.code.len() {
+        let i = p.code[pc];
+        let op = get_op(i) as usize;
+        let Some(&(test, _, bmode, cmode, format)) = OPMODES.get(op) else {
+            return false;
+        };
+        let a = get_a(i) as isize;
+        if !reg_ok(a) {
+            return false;
+        }
+        let (b, c) = match format {
+            Format::Abc => {
+                let (b, c) = (get_b(i), get_c(i));
+                if !check_arg_mode(p, b, bmode) || !check_arg_mode(p, c, cmode) {
+                    return false;
+                }
+                (b as isize, c as isize)
+            }
+            Format::Abx => {
+                let b = get_bx(i);
+                if bmode == ArgMode::K && b >= p.constants.len() {
+                    return false;
+                }
+                (b as isize, 0)
+            }
+            Format::AsBx => {
+                let b = get_sbx(i);
+                let dest = pc as isize + 1 + b;
+                if !(0 <= dest && dest < size) {
+                    return false;
+                }
+                // a jump must not land on the count word of a SETLIST, which a
+                // chain of them can only tell apart by parity
+                let dest = dest as usize;
+                let j = (0..dest)
+                    .take_while(|&j| {
+                        let d = p.code[dest - 1 - j];
+                        get_op(d) == OpCode::OpSetList as u32 && get_c(d) == 0
+                    })
+                    .count();
+                if j % 2 != 0 {
+                    return false;
+                }
+                (b, 0)
+            }
+        };
+        if test && !(pc as isize + 2 < size && get_op(p.code[pc + 1]) == OpCode::OpJmp as u32) {
+            return false;
+        }
+        let ok = match Instruction::from(i) {
+            Instruction::LoadBool(_) if c == 1 => {
+                pc as isize + 2 < size
+                    && !(get_op(p.code[pc + 1]) == OpCode::OpSetList as u32
+                        && get_c(p.code[pc + 1]) == 0)
+            }
+            Instruction::GetUpval(_) | Instruction::SetUpval(_) => (b as usize) < p.num_upvals,
+            Instruction::GetGlobal(_) | Instruction::SetGlobal(_) => {
+                matches!(p.constants[b as usize].value(), Value::String(_))
+            }
+            Instruction::OpSelf(_) => reg_ok(a + 1),
+            // at least two operands
+            Instruction::Concat(_) => b < c,
+            // at least one result (control variable) and space for results
+            Instruction::TForLoop(_) => c >= 1 && reg_ok(a + 2 + c),
+            Instruction::ForLoop(_) | Instruction::ForPrep(_) => reg_ok(a + 3),
+            Instruction::Call(_) | Instruction::TailCall(_) => {
+                let nresults = c - 1;
+                (b == 0 || reg_ok(a + b - 1))
+                    && match nresults {
+                        -1 => check_open_op(p, pc),
+                        0 => true,
+                        n => reg_ok(a + n - 1),
+                    }
+            }
+            Instruction::Return(_) => b - 1 <= 0 || reg_ok(a + b - 2),
+            Instruction::SetList(_) => {
+                let ok = b == 0 || reg_ok(a + b);
+                if c == 0 {
+                    // the next word is the block number
+                    pc += 1;
+                    ok && (pc as isize) < size - 1
+                } else {
+                    ok
+                }
+            }
+            Instruction::Closure(_) => match p.protos.get(b as usize) {
+                Some(child) => {
+                    let nup = child.num_upvals;
+                    pc + nup < p.code.len()
+                        && p.code[pc + 1..=pc + nup].iter().all(|&u| {
+                            let op = get_op(u);
+                            op == OpCode::OpGetUpval as u32 || op == OpCode::OpMove as u32
+                        })
+                }
+                None => false,
+            },
+            Instruction::VarArg(_) => {
+                p.is_vararg & VARARG_ISVARARG != 0
+                    && p.is_vararg & VARARG_NEEDSARG == 0
+                    && (b - 1 != -1 || check_open_op(p, pc))
+                    && reg_ok(a + b - 2)
+            }
+            _ => true,
+        };
+        if !ok {
+            return false;
+        }
+        pc += 1;
+    }
+    true
+}
+
+/// name of the constant used as key by an RK operand
+fn kname(p: &Proto, c: usize, state: &LuaState) -> String {
+    if is_k(c)
+        && let Value::String(s) = p.constants[index_k(c)].value()
+    {
+        return String::from_utf8_lossy(state.str_bytes(s)).into_owned();
+    }
+    "?".to_string()
+}
+
+/// what register `reg` holds at `pc`, e.g. `("global", "x")` (getobjname)
+pub fn get_obj_name(
+    state: &LuaState,
+    p: &Proto,
+    pc: usize,
+    reg: usize,
+) -> Option<(&'static str, String)> {
+    if let Some(name) = p.local_name(reg + 1, pc) {
+        return Some(("local", name.to_string()));
+    }
+    let inst = symbexec(p, pc, reg);
+    match Instruction::from(inst) {
+        Instruction::GetGlobal(_) => match p.constants[get_bx(inst)].value() {
+            Value::String(s) => Some((
+                "global",
+                String::from_utf8_lossy(state.str_bytes(s)).into_owned(),
+            )),
+            _ => None,
+        },
+        Instruction::Move(_) => {
+            let (a, b) = (get_a(inst), get_b(inst));
+            if b < a {
+                get_obj_name(state, p, pc, b)
+            } else {
+                None
+            }
+        }
+        Instruction::GetTable(_) => Some(("field", kname(p, get_c(inst), state))),
+        Instruction::GetUpval(_) => Some((
+            "upvalue",
+            p.upvalue_names
+                .get(get_b(inst))
+                .cloned()
+                .unwrap_or_else(|| "?".to_string()),
+        )),
+        Instruction::OpSelf(_) => Some(("method", kname(p, get_c(inst), state))),
+        _ => None,
+    }
+}
+
+impl LuaState {
+    /// prototype of the Lua function running in `base_ci[ci]`
+    pub(crate) fn ci_proto(&self, ci: usize) -> Option<std::rc::Rc<Proto>> {
+        match self.stack[self.base_ci[ci].func].value() {
+            Value::LuaClosure(cl) if ci > 0 => Some(self.global.heap.get(cl).proto.clone()),
+            _ => None,
+        }
+    }
+    /// pc of the instruction being executed by `base_ci[ci]`
+    pub(crate) fn current_pc(&self, ci: usize) -> usize {
+        self.base_ci[ci].saved_pc.saturating_sub(1)
+    }
+    /// "chunkname:currentline:" of a Lua frame
+    pub(crate) fn where_ci(&self, ci: usize) -> Option<String> {
+        let p = self.ci_proto(ci)?;
+        let line = p.lineinfo.get(self.current_pc(ci))?;
+        Some(format!("{}:{}:", chunk_id(&p.source), line))
+    }
+    /// `CallInfo` index of stack level `level`, level 0 being the running
+    /// function (lua_getstack). Levels lost to tail calls give index 0.
+    pub(crate) fn get_stack(&self, level: usize) -> Option<usize> {
+        let mut level = level as i64;
+        let mut ci = self.base_ci.len() - 1;
+        while level > 0 && ci > 0 {
+            level -= 1;
+            if self.ci_proto(ci).is_some() {
+                level -= self.base_ci[ci].tailcalls as i64;
+            }
+            ci -= 1;
+        }
+        if level == 0 && ci > 0 {
+            Some(ci)
+        } else if level < 0 {
+            Some(0)
+        } else {
+            None
+        }
+    }
+    /// position prefix for errors raised at stack level `level` (luaL_where)
+    pub(crate) fn where_(&self, level: usize) -> String {
+        self.get_stack(level)
+            .and_then(|ci| self.where_ci(ci))
+            .map(|w| format!("{w} "))
+            .unwrap_or_default()
+    }
+    /// kind and name of the variable held in stack slot `index` of the current frame
+    pub(crate) fn var_info(&self, index: usize) -> Option<(&'static str, String)> {
+        let ci = self.base_ci.len() - 1;
+        let p = self.ci_proto(ci)?;
+        let base = self.base_ci[ci].base;
+        if index < base || index >= self.base_ci[ci].top {
+            return None;
+        }
+        get_obj_name(self, &p, self.current_pc(ci), index - base)
+    }
+    /// how the function running in `base_ci[ci]` was called, e.g. `("method", "insert")` (getfuncname)
+    pub(crate) fn func_name(&self, ci: usize) -> Option<(&'static str, String)> {
+        if self.base_ci[ci].tailcalls > 0 {
+            return None;
+        }
+        let caller = ci.checked_sub(1)?;
+        let p = self.ci_proto(caller)?;
+        let pc = self.current_pc(caller);
+        let inst = p.code[pc];
+        match get_op(inst) {
+            op if op == OpCode::OpCall as u32 || op == OpCode::OpTailCall as u32 => {
+                get_obj_name(self, &p, pc, get_a(inst))
+            }
+            op if op == OpCode::OpTForLoop as u32 => {
+                Some(("for iterator", "for iterator".to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Hook mask bits, selecting the events that call the hook
+pub const MASK_CALL: u8 = 1;
+pub const MASK_RET: u8 = 2;
+pub const MASK_LINE: u8 = 4;
+pub const MASK_COUNT: u8 = 8;
+
+/// Event reported to a hook. The hooked function is the running one (stack
+/// level 0), except for `TailReturn` which stands for a frame lost to a tail call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    Call,
+    Return,
+    /// a new line is about to run
+    Line(usize),
+    /// the instruction count ran out
+    Count,
+    TailReturn,
+}
+
+impl HookEvent {
+    /// name given to hooks set by `debug.sethook`
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Call => "call",
+            HookEvent::Return => "return",
+            HookEvent::Line(_) => "line",
+            HookEvent::Count => "count",
+            HookEvent::TailReturn => "tail return",
+        }
+    }
+}
+
+pub type HookFn = fn(&mut LuaState, HookEvent) -> Result<()>;
+
+/// Hook of a thread (the `hook` fields of a C `lua_State`)
+#[derive(Clone, Copy)]
+pub(crate) struct HookState {
+    pub(crate) func: Option<HookFn>,
+    pub(crate) mask: u8,
+    pub(crate) base_count: usize,
+    /// instructions left before the next count event
+    pub(crate) count: usize,
+    /// false while a hook runs, so hooks do not call themselves
+    pub(crate) allow: bool,
+}
+
+impl Default for HookState {
+    fn default() -> Self {
+        Self {
+            func: None,
+            mask: 0,
+            base_count: 0,
+            count: 0,
+            allow: true,
+        }
+    }
+}
+
+/// Information about a function or an active frame (lua_Debug)
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    pub source: String,
+    pub short_src: String,
+    /// "Lua", "C", "main" or "tail"
+    pub what: &'static str,
+    pub line_defined: i64,
+    pub last_line_defined: i64,
+    /// -1 when not available
+    pub current_line: i64,
+    pub nups: usize,
+    pub name: Option<String>,
+    /// "global", "local", "method", "field", "upvalue" or ""
+    pub namewhat: &'static str,
+    /// the function, absent for tail calls
+    pub func: Option<TValue>,
+}
+
+impl LuaState {
+    /// line of the instruction being executed by `base_ci[ci]`, -1 for native frames
+    fn current_line(&self, ci: usize) -> i64 {
+        self.ci_proto(ci)
+            .and_then(|p| p.lineinfo.get(self.current_pc(ci)).copied())
+            .map_or(-1, |l| l as i64)
+    }
+    /// Fields selected by `options` ('n', 'S', 'l', 'u', 'f') for the frame
+    /// `ci` returned by `get_stack` (lua_getinfo). None on an invalid option.
+    pub(crate) fn get_info(&self, options: &str, ci: usize) -> Option<DebugInfo> {
+        let func = (ci > 0).then(|| self.stack[self.base_ci[ci].func]);
+        self.aux_get_info(options, func, Some(ci))
+    }
+    /// Fields selected by `options` for the function `f`, which is not running
+    pub(crate) fn get_func_info(&self, options: &str, f: TValue) -> Option<DebugInfo> {
+        self.aux_get_info(options, Some(f), None)
+    }
+    fn aux_get_info(
+        &self,
+        options: &str,
+        f: Option<TValue>,
+        ci: Option<usize>,
+    ) -> Option<DebugInfo> {
+        let mut ar = DebugInfo {
+            current_line: -1,
+            ..DebugInfo::default()
+        };
+        let ci = ci.filter(|ci| *ci > 0);
+        for opt in options.chars() {
+            match opt {
+                'S' => match f.map(|f| f.value()) {
+                    Some(Value::LuaClosure(cl)) => {
+                        let p = &self.global.heap.get(cl).proto;
+                        ar.source = p.source.clone();
+                        ar.line_defined = p.line_defined as i64;
+                        ar.last_line_defined = p.last_line_defined as i64;
+                        ar.what = if p.line_defined == 0 { "main" } else { "Lua" };
+                    }
+                    Some(_) => {
+                        ar.source = "=[C]".to_string();
+                        ar.line_defined = -1;
+                        ar.last_line_defined = -1;
+                        ar.what = "C";
+                    }
+                    None => {
+                        ar.source = "=(tail call)".to_string();
+                        ar.line_defined = -1;
+                        ar.last_line_defined = -1;
+                        ar.what = "tail";
+                    }
+                },
+                'l' => ar.current_line = ci.map_or(-1, |ci| self.current_line(ci)),
+                'u' => {
+                    ar.nups = match f.map(|f| f.value()) {
+                        Some(Value::LuaClosure(cl)) => self.global.heap.get(cl).upvals.len(),
+                        Some(Value::NativeClosure(cl)) => self.global.heap.get(cl).upvalues.len(),
+                        _ => 0,
+                    }
+                }
+                'n' => {
+                    if let Some((namewhat, name)) = ci.and_then(|ci| self.func_name(ci)) {
+                        ar.namewhat = namewhat;
+                        ar.name = Some(name);
+                    }
+                }
+                'f' => ar.func = f,
+                _ => return None,
+            }
+        }
+        ar.short_src = chunk_id(&ar.source);
+        Some(ar)
+    }
+
+    /// C-Lua style stack traceback starting at stack level `level`
+    pub(crate) fn traceback(&self, level: usize) -> String {
+        let mut out = String::from("stack traceback:");
+        let mut level = level;
+        let mut first_part = true;
+        while let Some(ci) = self.get_stack(level) {
+            level += 1;
+            if level > LEVELS1 && first_part {
+                // show only the last LEVELS2 levels after the first LEVELS1
+                if self.get_stack(level + LEVELS2).is_none() {
+                    level -= 1;
+                } else {
+                    out.push_str("\n\t...");
+                    while self.get_stack(level + LEVELS2).is_some() {
+                        level += 1;
+                    }
+                }
+                first_part = false;
+                continue;
+            }
+            let ar = self.get_info("Snl", ci).unwrap();
+            out.push_str(&format!("\n\t{}:", ar.short_src));
+            if ar.current_line > 0 {
+                out.push_str(&format!("{}:", ar.current_line));
+            }
+            match (ar.name, ar.what) {
+                (Some(name), _) => out.push_str(&format!(" in function '{name}'")),
+                (None, "main") => out.push_str(" in main chunk"),
+                (None, "C" | "tail") => out.push_str(" ?"),
+                (None, _) => out.push_str(&format!(
+                    " in function <{}:{}>",
+                    ar.short_src, ar.line_defined
+                )),
+            }
+        }
+        out
+    }
+
+    /// name of local `n` of frame `ci`; "(*temporary)" for other live slots
+    fn find_local(&self, ci: usize, n: usize) -> Option<String> {
+        if n == 0 {
+            return None;
+        }
+        if let Some(p) = self.ci_proto(ci)
+            && let Some(name) = p.local_name(n, self.current_pc(ci))
+        {
+            return Some(name.to_string());
+        }
+        let limit = if ci == self.base_ci.len() - 1 {
+            self.top
+        } else {
+            self.base_ci[ci + 1].func
+        };
+        if limit.saturating_sub(self.base_ci[ci].base) >= n {
+            Some("(*temporary)".to_string())
+        } else {
+            None
+        }
+    }
+    /// name and value of local `n` (from 1) of the frame `ci` (lua_getlocal)
+    pub(crate) fn get_local(&self, ci: usize, n: usize) -> Option<(String, TValue)> {
+        let name = self.find_local(ci, n)?;
+        Some((name, self.stack[self.base_ci[ci].base + n - 1]))
+    }
+    /// assign local `n` of the frame `ci`, returning its name (lua_setlocal)
+    pub(crate) fn set_local(&mut self, ci: usize, n: usize, val: TValue) -> Option<String> {
+        let name = self.find_local(ci, n)?;
+        let base = self.base_ci[ci].base;
+        self.stack[base + n - 1] = val;
+        Some(name)
+    }
+    /// name and value of upvalue `n` (from 1) of a function (lua_getupvalue).
+    /// Upvalues of native functions have an empty name.
+    pub(crate) fn get_upvalue(&self, f: &TValue, n: usize) -> Option<(String, TValue)> {
+        match f.value() {
+            Value::LuaClosure(cl) => {
+                let cl = self.global.heap.get(cl);
+                let name = cl.proto.upvalue_names.get(n.checked_sub(1)?)?;
+                Some((name.clone(), self.get_upval(cl.upvals[n - 1])))
+            }
+            Value::NativeClosure(cl) => {
+                let v = *self.global.heap.get(cl).upvalues.get(n.checked_sub(1)?)?;
+                Some((String::new(), v))
+            }
+            _ => None,
+        }
+    }
+    /// assign upvalue `n` of a function, returning its name (lua_setupvalue)
+    pub(crate) fn set_upvalue_of(&mut self, f: &TValue, n: usize, val: TValue) -> Option<String> {
+        let (name, _) = self.get_upvalue(f, n)?;
+        match f.value() {
+            Value::LuaClosure(cl) => {
+                let uv = self.global.heap.get(cl).upvals[n - 1];
+                self.set_upval(uv, val);
+            }
+            Value::NativeClosure(cl) => self.global.heap.get_mut(cl).upvalues[n - 1] = val,
+            _ => unreachable!(),
+        }
+        Some(name)
+    }
+
+    /// Install `func` as the hook of the running thread for the events in
+    /// `mask`; count events happen every `count` instructions (lua_sethook).
+    pub(crate) fn set_hook(&mut self, func: Option<HookFn>, mask: u8, count: usize) {
+        let (func, mask) = match func {
+            Some(f) if mask != 0 => (Some(f), mask),
+            _ => (None, 0),
+        };
+        self.hook.func = func;
+        self.hook.mask = mask;
+        self.hook.base_count = count;
+        self.hook.count = count;
+    }
+    /// hook, mask and count of the running thread
+    pub(crate) fn get_hook(&self) -> (Option<HookFn>, u8, usize) {
+        (self.hook.func, self.hook.mask, self.hook.base_count)
+    }
+    /// call the hook for `event` on the running frame (luaD_callhook)
+    pub(crate) fn call_hook(&mut self, event: HookEvent) -> Result<()> {
+        let Some(hook) = self.hook.func.filter(|_| self.hook.allow) else {
+            return Ok(());
+        };
+        let top = self.top;
+        let ci_top = self.base_ci.last().unwrap().top;
+        self.check_stack(LUA_MINSTACK);
+        self.base_ci.last_mut().unwrap().top = self.top + LUA_MINSTACK;
+        self.hook.allow = false;
+        let res = hook(self, event);
+        self.hook.allow = true;
+        self.base_ci.last_mut().unwrap().top = ci_top;
+        self.top = top;
+        res
+    }
+    /// return hooks of the running frame and of the tail calls it replaced
+    pub(crate) fn ret_hooks(&mut self) -> Result<()> {
+        self.call_hook(HookEvent::Return)?;
+        for _ in 0..self.base_ci.last().unwrap().tailcalls {
+            self.call_hook(HookEvent::TailReturn)?;
+        }
+        Ok(())
+    }
+    /// Line and count hooks before the instruction at `pc - 1` of `p`;
+    /// `oldpc` is the `pc` of the previous call (traceexec)
+    pub(crate) fn trace_exec(&mut self, p: &Proto, oldpc: usize, pc: usize) -> Result<()> {
+        let mask = self.hook.mask;
+        self.hook.count = self.hook.count.saturating_sub(1);
+        if mask & MASK_COUNT != 0 && self.hook.count == 0 {
+            self.hook.count = self.hook.base_count;
+            self.call_hook(HookEvent::Count)?;
+        }
+        if mask & MASK_LINE != 0 {
+            let npc = pc - 1;
+            let newline = p.lineinfo.get(npc).copied().unwrap_or(0);
+            // a new function, a jump back (loop) or a new line
+            if npc == 0
+                || pc <= oldpc
+                || p.lineinfo.get(oldpc.wrapping_sub(1)).copied() != Some(newline)
+            {
+                self.call_hook(HookEvent::Line(newline))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_code() {
+        use crate::opcodes::{create_abc, create_asbx};
+        let ret = create_abc(OpCode::OpReturn, 0, 1, 0);
+        let proto = |code: Vec<u32>| Proto {
+            max_stack: 2,
+            code,
+            ..Proto::default()
+        };
+        assert!(check_code(&proto(vec![
+            create_abc(OpCode::OpMove, 1, 0, 0),
+            ret
+        ])));
+        // register out of the frame
+        assert!(!check_code(&proto(vec![
+            create_abc(OpCode::OpMove, 5, 0, 0),
+            ret
+        ])));
+        // jump out of the code
+        assert!(!check_code(&proto(vec![
+            create_asbx(OpCode::OpJmp, 0, 3),
+            ret
+        ])));
+        // missing final return
+        assert!(!check_code(&proto(vec![create_abc(
+            OpCode::OpMove,
+            1,
+            0,
+            0
+        )])));
+        // constant that does not exist
+        assert!(!check_code(&proto(vec![
+            create_abc(OpCode::OpLoadK, 0, 0, 0),
+            ret
+        ])));
+    }
+
+    #[test]
+    fn test_chunk_id() {
+        assert_eq!(chunk_id("=stdin"), "stdin");
+        assert_eq!(chunk_id("@add.lua"), "add.lua");
+        assert_eq!(chunk_id("x = 1"), "[string \"x = 1\"]");
+        assert_eq!(chunk_id("x = 1\ny = 2"), "[string \"x = 1...\"]");
+        let long = format!("@{}", "a".repeat(100));
+        assert_eq!(chunk_id(&long), format!("...{}", "a".repeat(52)));
+    }
+}
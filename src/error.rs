@@ -0,0 +1,55 @@
+use crate::eval::TValue;
+
+/// Error raised by Lua code or the VM.
+/// It carries the Lua error object, which can be any value (`error({code = 1})`).
+#[derive(Debug, Clone)]
+pub struct LuaError {
+    value: TValue,
+    message: String,
+    traceback: Option<String>,
+    /// raised by an exceeded limit, not stopped by `pcall`
+    uncatchable: bool,
+}
+
+impl LuaError {
+    pub fn new(value: TValue, message: String) -> Self {
+        Self {
+            value,
+            message,
+            traceback: None,
+            uncatchable: false,
+        }
+    }
+    pub(crate) fn uncatchable(self) -> Self {
+        Self {
+            uncatchable: true,
+            ..self
+        }
+    }
+    pub(crate) fn with_traceback(self, traceback: String) -> Self {
+        Self {
+            traceback: Some(traceback),
+            ..self
+        }
+    }
+    /// the error object as seen by `pcall`
+    pub fn value(&self) -> TValue {
+        self.value
+    }
+    /// stack traceback at the point of the error, for errors caught by the host API
+    pub fn traceback(&self) -> Option<&str> {
+        self.traceback.as_deref()
+    }
+    /// whether protected calls in Lua let the error through to the host
+    pub fn is_uncatchable(&self) -> bool {
+        self.uncatchable
+    }
+}
+
+impl std::fmt::Display for LuaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for LuaError {}
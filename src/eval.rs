@@ -1,28 +1,96 @@
-use anyhow::Result;
 use core::panic;
-use std::collections::HashMap;
 
-use full_moon::ast::{BinOp, Block, Expression, LastStmt, Stmt};
-use full_moon::tokenizer::TokenType;
+use crate::func::{LuaClosure, NativeClosure};
+use crate::heap::{Gc, LuaString};
+use crate::table::Table;
+use crate::thread::Thread;
+use crate::userdata::Userdata;
+use crate::vm::number2str;
 
-#[derive(Debug, Clone)]
+/// A value together with its type tag. The tag is always derived from the
+/// payload, so the two can't disagree.
+#[derive(Debug, Clone, Copy)]
 pub struct TValue {
     val: Value,
     ttag: TypeTag,
 }
 
 impl TValue {
-    pub fn new(val: Value, lua_type: LuaType) -> TValue {
-        TValue {
-            val,
-            ttag: TypeTag::from_basictype(lua_type),
+    pub fn nil() -> TValue {
+        TValue::from(Value::Nil)
+    }
+    pub fn boolean(b: bool) -> TValue {
+        TValue::from(Value::Boolean(b))
+    }
+    pub fn integer(n: LuaInteger) -> TValue {
+        TValue::from(Value::Integer(n))
+    }
+    /// `n` with the integer variant if its magnitude is at most
+    /// `MAX_EXACT_INTEGER`, as a float otherwise
+    pub fn int_or_float(n: i128) -> TValue {
+        if n.unsigned_abs() <= MAX_EXACT_INTEGER as u128 {
+            TValue::integer(n as LuaInteger)
+        } else {
+            TValue::number(n as LuaNumber)
         }
     }
-    pub fn lua_type(&self) -> Option<LuaType> {
+    pub fn number(n: LuaNumber) -> TValue {
+        TValue::from(Value::Number(n))
+    }
+    pub fn string(s: Gc<LuaString>) -> TValue {
+        TValue::from(Value::String(s))
+    }
+    pub fn table(t: Gc<Table>) -> TValue {
+        TValue::from(Value::Table(t))
+    }
+    pub fn lua_closure(c: Gc<LuaClosure>) -> TValue {
+        TValue::from(Value::LuaClosure(c))
+    }
+    pub fn native_closure(c: Gc<NativeClosure>) -> TValue {
+        TValue::from(Value::NativeClosure(c))
+    }
+    pub fn thread(t: Gc<Thread>) -> TValue {
+        TValue::from(Value::Thread(t))
+    }
+    pub fn userdata(u: Gc<Userdata>) -> TValue {
+        TValue::from(Value::UserData(u))
+    }
+    pub fn lua_type(&self) -> LuaType {
         self.ttag.lua_type()
     }
     pub fn value(&self) -> Value {
-        self.val.clone()
+        self.val
+    }
+    /// variant bits of the tag (`VARIANT_*`)
+    pub fn variant(&self) -> u8 {
+        self.ttag.variant()
+    }
+    pub fn is_integer(&self) -> bool {
+        self.lua_type() == LuaType::Number && self.variant() == VARIANT_INTEGER
+    }
+    pub fn is_float(&self) -> bool {
+        self.lua_type() == LuaType::Number && self.variant() == VARIANT_FLOAT
+    }
+    /// value of a number of either variant, without string conversion
+    pub fn as_number(&self) -> Option<LuaNumber> {
+        match self.val {
+            Value::Integer(n) => Some(n as LuaNumber),
+            Value::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+    /// true for values living on the heap
+    pub fn is_collectable(&self) -> bool {
+        self.ttag.is_collectable()
+    }
+}
+
+impl From<Value> for TValue {
+    fn from(val: Value) -> TValue {
+        TValue {
+            val,
+            ttag: TypeTag::of(&val),
+        }
     }
 }
 
@@ -35,11 +103,24 @@ impl std::fmt::Display for TValue {
 pub type LuaNumber = f64;
 pub type LuaInteger = i64;
 
-#[derive(Debug, Clone)]
+/// Bound of the integer variant. Lua 5.1 numbers are doubles, so integers
+/// are kept where a double is exact and behave like one: integer literals,
+/// lengths and integer arithmetic give integers, which `math.type` tells
+/// apart, and a result beyond this bound becomes a float.
+pub const MAX_EXACT_INTEGER: LuaInteger = 1 << 53;
+
+#[derive(Debug, Clone, Copy)]
 pub enum Value {
     Nil,
+    Boolean(bool),
     Integer(LuaInteger),
     Number(LuaNumber),
+    String(Gc<LuaString>),
+    Table(Gc<Table>),
+    LuaClosure(Gc<LuaClosure>),
+    NativeClosure(Gc<NativeClosure>),
+    Thread(Gc<Thread>),
+    UserData(Gc<Userdata>),
 }
 
 impl std::ops::Add for Value {
@@ -57,8 +138,15 @@ impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Value::Nil => write!(f, "Nil"),
-            Value::Integer(n) => write!(f, "{}", n),
-            Value::Number(n) => write!(f, "{}", n),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Integer(n) => write!(f, "{}", number2str(*n as LuaNumber)),
+            Value::Number(n) => write!(f, "{}", number2str(*n)),
+            Value::String(s) => write!(f, "string: 0x{:08x}", s.index()),
+            Value::Table(t) => write!(f, "table: 0x{:08x}", t.index()),
+            Value::LuaClosure(c) => write!(f, "function: 0x{:08x}", c.index()),
+            Value::NativeClosure(c) => write!(f, "function: builtin: 0x{:08x}", c.index()),
+            Value::Thread(t) => write!(f, "thread: 0x{:08x}", t.index()),
+            Value::UserData(u) => write!(f, "userdata: 0x{:08x}", u.index()),
         }
     }
 }
@@ -68,7 +156,14 @@ const VARIANT_MASK: u8 = 0x30; // b0011_0000
 const VARIANT_SHIFT: u8 = 4;
 const COLLECTABILITY_FLAG: u8 = 0x40; // b0100_0000
 
-#[derive(Debug, Clone)]
+/// number variants (LUA_TNUMFLT / LUA_TNUMINT)
+pub const VARIANT_FLOAT: u8 = 0;
+pub const VARIANT_INTEGER: u8 = 1;
+/// function variants (LUA_TLCL / LUA_TCCL)
+pub const VARIANT_LUA_CLOSURE: u8 = 0;
+pub const VARIANT_NATIVE_CLOSURE: u8 = 2;
+
+#[derive(Debug, Clone, Copy)]
 struct TypeTag {
     /// bits 0-3: BasicType
     /// bits 4-5: Variant bits
@@ -78,41 +173,61 @@ struct TypeTag {
 }
 
 impl TypeTag {
-    pub fn from_basictype(lua_type: LuaType) -> TypeTag {
-        TypeTag {
-            bits: lua_type as u8,
+    fn of(val: &Value) -> TypeTag {
+        let (lua_type, variant, collectable) = match val {
+            Value::Nil => (LuaType::Nil, 0, false),
+            Value::Boolean(_) => (LuaType::Boolean, 0, false),
+            Value::Integer(_) => (LuaType::Number, VARIANT_INTEGER, false),
+            Value::Number(_) => (LuaType::Number, VARIANT_FLOAT, false),
+            Value::String(_) => (LuaType::String, 0, true),
+            Value::Table(_) => (LuaType::Table, 0, true),
+            Value::LuaClosure(_) => (LuaType::Function, VARIANT_LUA_CLOSURE, true),
+            Value::NativeClosure(_) => (LuaType::Function, VARIANT_NATIVE_CLOSURE, true),
+            Value::Thread(_) => (LuaType::Thread, 0, true),
+            Value::UserData(_) => (LuaType::UserData, 0, true),
+        };
+        let mut bits = lua_type as u8 | (variant << VARIANT_SHIFT);
+        if collectable {
+            bits |= COLLECTABILITY_FLAG;
         }
+        TypeTag { bits }
     }
     fn tag(&self) -> u8 {
         self.bits & TAG_MASK
     }
-    pub fn lua_type(&self) -> Option<LuaType> {
+    fn variant(&self) -> u8 {
+        (self.bits & VARIANT_MASK) >> VARIANT_SHIFT
+    }
+    fn is_collectable(&self) -> bool {
+        self.bits & COLLECTABILITY_FLAG != 0
+    }
+    fn lua_type(&self) -> LuaType {
         match self.tag() {
-            0 => Some(LuaType::Nil),
-            1 => Some(LuaType::Boolean),
-            2 => Some(LuaType::LightUserData),
-            3 => Some(LuaType::Number),
-            4 => Some(LuaType::String),
-            5 => Some(LuaType::Table),
-            6 => Some(LuaType::Function),
-            7 => Some(LuaType::UserData),
-            8 => Some(LuaType::Thread),
-            9 => Some(LuaType::Proto),
-            10 => Some(LuaType::Upval),
-            11 => Some(LuaType::Deadkey),
-            _ => None,
+            0 => LuaType::Nil,
+            1 => LuaType::Boolean,
+            2 => LuaType::LightUserData,
+            3 => LuaType::Number,
+            4 => LuaType::String,
+            5 => LuaType::Table,
+            6 => LuaType::Function,
+            7 => LuaType::UserData,
+            8 => LuaType::Thread,
+            9 => LuaType::Proto,
+            10 => LuaType::Upval,
+            11 => LuaType::Deadkey,
+            _ => unreachable!("invalid type tag"),
         }
     }
 }
 
 impl std::fmt::Display for TypeTag {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.lua_type().unwrap())
+        write!(f, "{}", self.lua_type())
     }
 }
 
 /// called `Basic type`(LUA_T*) in lua type tag system
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum LuaType {
     Nil = 0,
@@ -128,6 +243,25 @@ pub enum LuaType {
     Upval = 10,
     Deadkey = 11,
 }
+impl LuaType {
+    /// name as returned by `type()`
+    pub fn name(&self) -> &'static str {
+        match self {
+            LuaType::Nil => "nil",
+            LuaType::Boolean => "boolean",
+            LuaType::LightUserData | LuaType::UserData => "userdata",
+            LuaType::Number => "number",
+            LuaType::String => "string",
+            LuaType::Table => "table",
+            LuaType::Function => "function",
+            LuaType::Thread => "thread",
+            LuaType::Proto => "proto",
+            LuaType::Upval => "upval",
+            LuaType::Deadkey => "deadkey",
+        }
+    }
+}
+
 impl std::fmt::Display for LuaType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let s = match self {
@@ -148,86 +282,27 @@ impl std::fmt::Display for LuaType {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Env {
-    envs: HashMap<String, TValue>,
-}
-
-impl Env {
-    pub fn new() -> Self {
-        Self {
-            envs: HashMap::new(),
-        }
-    }
-    fn insert(&mut self, key: String, val: TValue) {
-        self.envs.insert(key, val);
-    }
-}
-
-pub fn eval_block(block: &Block, env: &mut Env) -> Result<()> {
-    for stmt in block.stmts() {
-        eval_stmt(stmt, env);
-    }
-    // eval_last_stmt(block.last_stmt(), env);
-    Ok(())
-}
-
-pub fn eval_stmt(stmt: &Stmt, env: &mut Env) -> Result<()> {
-    match stmt {
-        Stmt::Assignment(assign) => {
-            for (name, e) in assign.variables().iter().zip(assign.expressions()) {
-                let val = eval_expr(e, env)?;
-                env.insert(name.to_string(), val);
-            }
-            Ok(())
-        }
-        _ => unimplemented!(),
-    }
-}
-
-// pub fn eval_last_stmt(last_stmt: &LastStmt, env: &mut Env) -> Result<()> {
-//     match last_stmt {
-//         Stmt::Assignment(assign) => {
-//             for (name, e) in assign.variables().iter().zip(assign.expressions()) {
-//                 let val = eval_expr(e, env)?;
-//                 env.insert(name.to_string(), val);
-//             }
-//             Ok(())
-//         }
-//         _ => unimplemented!(),
-//     }
-// }
-
-pub fn eval_expr(expr: &Expression, env: &Env) -> Result<TValue> {
-    match expr {
-        Expression::Number(tkn) => match tkn.token_type() {
-            TokenType::Number { text } => Ok(TValue::new(
-                Value::Number(text.as_str().parse::<f64>()?),
-                LuaType::Number,
-            )),
-            _ => panic!(),
-        },
-        Expression::BinaryOperator { lhs, binop, rhs } => {
-            let lhs_val = eval_expr(lhs, env)?;
-            let rhs_val = eval_expr(rhs, env)?;
-            match binop {
-                BinOp::Plus(_) => match (lhs_val.val, rhs_val.val) {
-                    (Value::Number(lf), Value::Number(rf)) => {
-                        Ok(TValue::new(Value::Number(lf + rf), LuaType::Number))
-                    }
-                    (Value::Integer(li), Value::Integer(ri)) => {
-                        Ok(TValue::new(Value::Integer(li + ri), LuaType::Number))
-                    }
-                    (_, _) => unimplemented!(),
-                },
-                _ => unimplemented!(),
-            }
-        }
-        _ => unimplemented!(),
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::heap::Heap;
+    use crate::table::Table;
+
+    #[test]
+    fn test_tag_follows_payload() {
+        let int = TValue::integer(1);
+        let float = TValue::number(1.0);
+        assert_eq!(int.lua_type(), LuaType::Number);
+        assert_eq!(float.lua_type(), LuaType::Number);
+        assert!(int.is_integer() && !int.is_float());
+        assert!(float.is_float() && !float.is_integer());
+        assert!(!TValue::boolean(false).is_collectable());
+        assert_eq!(TValue::nil().lua_type(), LuaType::Nil);
+
+        let mut heap = Heap::new();
+        let t = TValue::table(heap.alloc(Table::new()));
+        assert_eq!(t.lua_type(), LuaType::Table);
+        assert!(t.is_collectable());
+        assert_eq!(TValue::from(t.value()).lua_type(), LuaType::Table);
+    }
 }
@@ -0,0 +1,130 @@
+use std::rc::Rc;
+
+use anyhow::Result;
+
+use crate::eval::TValue;
+use crate::heap::{Gc, Heap};
+use crate::table::Table;
+use crate::thread::Thread;
+use crate::undump::{Chunk, Constant};
+use crate::vm::LuaState;
+
+/// `is_vararg` flags of functions declared with `...`: they have the
+/// compatibility `arg` parameter, are vararg, and build `arg` at call time
+/// unless the body uses `...` itself
+pub const VARARG_HASARG: u8 = 1;
+pub const VARARG_ISVARARG: u8 = 2;
+pub const VARARG_NEEDSARG: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocVar {
+    pub name: String,
+    /// first pc where the variable is active
+    pub startpc: usize,
+    /// first pc where the variable is dead
+    pub endpc: usize,
+}
+
+/// Function prototype, the runtime form of an undumped `Chunk`
+#[derive(Debug, Default)]
+pub struct Proto {
+    pub source: String,
+    pub line_defined: usize,
+    pub last_line_defined: usize,
+    pub num_upvals: usize,
+    pub num_params: usize,
+    pub is_vararg: u8,
+    pub max_stack: usize,
+    pub code: Vec<u32>,
+    pub constants: Vec<TValue>,
+    pub protos: Vec<Rc<Proto>>,
+    pub lineinfo: Vec<usize>,
+    pub locvars: Vec<LocVar>,
+    pub upvalue_names: Vec<String>,
+}
+
+impl Proto {
+    pub fn from_chunk(chunk: &Chunk, parent_source: &str, heap: &mut Heap) -> Proto {
+        let source = if chunk.name.is_empty() {
+            parent_source.to_string()
+        } else {
+            chunk.name.clone()
+        };
+        let constants = chunk
+            .constant_table
+            .iter()
+            .map(|c| match c {
+                Constant::Nil => TValue::nil(),
+                Constant::Bool(b) => TValue::boolean(*b),
+                Constant::Number(n) => TValue::number(*n),
+                Constant::Integer(n) => TValue::integer(*n),
+                Constant::String(s) => TValue::string(heap.intern(s)),
+            })
+            .collect();
+        let protos = chunk
+            .protos
+            .iter()
+            .map(|p| Rc::new(Proto::from_chunk(p, &source, heap)))
+            .collect();
+        Proto {
+            line_defined: usize::from(chunk.meta_info.first_line),
+            last_line_defined: usize::from(chunk.meta_info.last_line),
+            num_upvals: chunk.meta_info.num_upvals as usize,
+            num_params: chunk.meta_info.num_params as usize,
+            is_vararg: chunk.meta_info.is_varg,
+            max_stack: chunk.meta_info.max_stack as usize,
+            code: chunk.instructions.clone(),
+            constants,
+            protos,
+            lineinfo: chunk.lines.iter().map(|l| usize::from(*l)).collect(),
+            locvars: chunk
+                .locals
+                .iter()
+                .map(|l| LocVar {
+                    name: l.name.clone(),
+                    startpc: usize::from(l.start_line),
+                    endpc: usize::from(l.end_line),
+                })
+                .collect(),
+            upvalue_names: chunk.upvalues.clone(),
+            source,
+        }
+    }
+    /// name of the `local_number`-th local variable active at `pc` (luaF_getlocalname)
+    pub fn local_name(&self, local_number: usize, pc: usize) -> Option<&str> {
+        let mut n = local_number;
+        for lv in self.locvars.iter().take_while(|lv| lv.startpc <= pc) {
+            if pc < lv.endpc {
+                n -= 1;
+                if n == 0 {
+                    return Some(&lv.name);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum UpVal {
+    /// still living in a stack slot of the thread running the enclosing function
+    Open(Gc<Thread>, usize),
+    Closed(TValue),
+}
+
+pub struct LuaClosure {
+    pub proto: Rc<Proto>,
+    pub upvals: Vec<Gc<UpVal>>,
+    pub env: Gc<Table>,
+}
+
+/// Rust function callable from Lua.
+/// Arguments are the stack values of the current frame; the function pushes its
+/// results and returns how many there are, like `lua_CFunction`.
+pub type NativeFn = fn(&mut LuaState) -> Result<usize>;
+
+pub struct NativeClosure {
+    pub func: NativeFn,
+    pub env: Gc<Table>,
+    pub upvalues: Vec<TValue>,
+}
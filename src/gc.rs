@@ -0,0 +1,653 @@
+//! Incremental tri-colour mark and sweep collector, after lgc.c.
+//!
+//! A cycle marks everything reachable from the roots (globals, registry,
+//! metatables of basic types, the stacks of running threads and their open
+//! upvalues), then sweeps the heap freeing what stayed white, and finally runs
+//! the `__gc` metamethods of the userdata found dead. All phases are split into
+//! small steps interleaved with the program; `Heap::get_mut` acts as the write
+//! barrier keeping the marking consistent while the program runs.
+//!
+//! Steps only run at safe points of the VM, where every live value is reachable
+//! from the roots. Native functions must therefore keep the values they still
+//! need on the stack while calling back into Lua.
+
+use anyhow::Result;
+
+use crate::eval::{TValue, Value};
+use crate::func::{Proto, UpVal};
+use crate::heap::{BLACK, GRAY, Gc, GcObject, Heap, LuaString, WHITE1};
+use crate::table::Table;
+use crate::vm::{CallInfo, LuaState, TagMethod};
+
+/// bytes of allocation paid for by one collection step
+const GCSTEPSIZE: usize = 1024;
+/// objects looked at by one sweep step
+const GCSWEEPMAX: usize = 40;
+/// work accounted for each object swept
+const GCSWEEPCOST: usize = 10;
+/// work accounted for running one finalizer
+const GCFINALIZECOST: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcState {
+    /// between two cycles
+    Pause,
+    /// marking reachable objects
+    Propagate,
+    /// freeing the objects left white
+    Sweep,
+    /// calling the `__gc` metamethods of dead userdata
+    Finalize,
+}
+
+/// heap slot of the object referenced by `val`, if it is collectable
+fn gc_index(val: &TValue) -> Option<usize> {
+    if !val.is_collectable() {
+        return None;
+    }
+    match val.value() {
+        Value::String(s) => Some(s.index()),
+        Value::Table(t) => Some(t.index()),
+        Value::LuaClosure(c) => Some(c.index()),
+        Value::NativeClosure(c) => Some(c.index()),
+        Value::Thread(t) => Some(t.index()),
+        Value::UserData(u) => Some(u.index()),
+        _ => None,
+    }
+}
+
+/// constants of `p` and its nested prototypes
+fn proto_refs(p: &Proto, refs: &mut Vec<usize>) {
+    refs.extend(p.constants.iter().filter_map(gc_index));
+    for child in &p.protos {
+        proto_refs(child, refs);
+    }
+}
+
+/// end of the part of a stack in use by some frame
+fn stack_limit(stack: &[TValue], top: usize, base_ci: &[CallInfo]) -> usize {
+    base_ci
+        .iter()
+        .map(|ci| ci.top)
+        .fold(top, usize::max)
+        .min(stack.len())
+}
+
+impl Heap {
+    fn mark(&mut self, index: usize) {
+        if self.colors[index] <= WHITE1 {
+            if let Some(GcObject::String(_)) = self.objects[index] {
+                // strings reference nothing
+                self.colors[index] = BLACK;
+            } else {
+                self.colors[index] = GRAY;
+                self.gray.push(index as u32);
+            }
+        }
+    }
+    fn mark_value(&mut self, val: &TValue) {
+        if let Some(index) = gc_index(val) {
+            self.mark(index);
+        }
+    }
+    /// weakness of keys and values of tables with metatable `mt`, from its `__mode` field
+    fn weak_mode(&self, mt: Gc<Table>, mode: Gc<LuaString>) -> (bool, bool) {
+        match self.get(mt).get_str(mode).value() {
+            Value::String(s) => {
+                let s = self.get(s).as_bytes();
+                (s.contains(&b'k'), s.contains(&b'v'))
+            }
+            _ => (false, false),
+        }
+    }
+    /// Blacken a gray object by marking everything it references; returns the work done.
+    /// `mode` is the interned "__mode".
+    fn traverse(&mut self, index: usize, mode: Gc<LuaString>) -> usize {
+        self.colors[index] = BLACK;
+        let mut refs = Vec::new();
+        let mut weak = false;
+        match &self.objects[index] {
+            Some(GcObject::Table(t)) => {
+                let (weak_keys, weak_values) = match t.metatable {
+                    Some(mt) => self.weak_mode(mt, mode),
+                    None => (false, false),
+                };
+                weak = weak_keys || weak_values;
+                refs.extend(t.metatable.map(|mt| mt.index()));
+                // strings are values, never weak
+                let strong =
+                    |v: &TValue, weak: bool| !weak || matches!(v.value(), Value::String(_));
+                for (k, v) in t.entries() {
+                    if strong(&k, weak_keys) {
+                        refs.extend(gc_index(&k));
+                    }
+                    if strong(&v, weak_values) {
+                        refs.extend(gc_index(&v));
+                    }
+                }
+            }
+            Some(GcObject::LuaClosure(c)) => {
+                refs.push(c.env.index());
+                refs.extend(c.upvals.iter().map(|uv| uv.index()));
+                proto_refs(&c.proto, &mut refs);
+            }
+            Some(GcObject::NativeClosure(c)) => {
+                refs.push(c.env.index());
+                refs.extend(c.upvalues.iter().filter_map(gc_index));
+            }
+            Some(GcObject::UpVal(UpVal::Open(th, _))) => refs.push(th.index()),
+            Some(GcObject::UpVal(UpVal::Closed(v))) => refs.extend(gc_index(v)),
+            Some(GcObject::Thread(th)) => {
+                let lim = stack_limit(&th.stack, th.top, &th.base_ci);
+                refs.extend(th.stack[..lim].iter().filter_map(gc_index));
+                refs.extend(th.open_upvals.iter().map(|uv| uv.index()));
+                refs.extend(th.resumer.map(|r| r.index()));
+            }
+            Some(GcObject::Userdata(u)) => {
+                refs.push(u.env.index());
+                refs.extend(u.metatable.map(|mt| mt.index()));
+            }
+            Some(GcObject::String(_)) | None => {}
+        }
+        if weak {
+            self.weak.push(index as u32);
+        }
+        for r in refs {
+            self.mark(r);
+        }
+        self.size_of(index)
+    }
+    /// empty the gray lists, including objects caught by the barrier
+    fn propagate_all(&mut self, mode: Gc<LuaString>) {
+        loop {
+            while let Some(index) = self.gray.pop() {
+                self.traverse(index as usize, mode);
+            }
+            if self.gray_again.is_empty() {
+                break;
+            }
+            let again = std::mem::take(&mut self.gray_again);
+            self.gray.extend(again);
+        }
+    }
+    /// does a weak entry holding `val` go away (iscleared)
+    fn is_cleared(&self, val: &TValue, is_key: bool) -> bool {
+        match gc_index(val) {
+            None => false,
+            Some(index) => match &self.objects[index] {
+                Some(GcObject::String(_)) => false,
+                // values are removed as soon as the userdata is finalized
+                Some(GcObject::Userdata(u)) if !is_key && u.finalized => true,
+                _ => self.colors[index] <= WHITE1,
+            },
+        }
+    }
+    /// remove the collected entries of the weak tables
+    fn clear_weak_tables(&mut self, mode: Gc<LuaString>) {
+        for index in std::mem::take(&mut self.weak) {
+            let Some(GcObject::Table(t)) = &self.objects[index as usize] else {
+                continue;
+            };
+            let (weak_keys, weak_values) = match t.metatable {
+                Some(mt) => self.weak_mode(mt, mode),
+                None => (false, false),
+            };
+            let dead: Vec<TValue> = t
+                .entries()
+                .filter(|(k, v)| {
+                    (weak_keys && self.is_cleared(k, true))
+                        || (weak_values && self.is_cleared(v, false))
+                })
+                .map(|(k, _)| k)
+                .collect();
+            if let Some(GcObject::Table(t)) = &mut self.objects[index as usize] {
+                for k in dead {
+                    t.set(k, TValue::nil()).expect("weak table keys are valid");
+                }
+            }
+        }
+    }
+    /// free the next objects left white by the last marking; true when the sweep is over
+    fn sweep_step(&mut self) -> bool {
+        let dead = self.other_white();
+        for _ in 0..GCSWEEPMAX {
+            if self.sweep_pos >= self.objects.len() {
+                return true;
+            }
+            let index = self.sweep_pos;
+            self.sweep_pos += 1;
+            if self.objects[index].is_none() {
+                continue;
+            }
+            if self.colors[index] == dead {
+                self.free_object(index);
+            } else {
+                self.colors[index] = self.current_white;
+                self.update_size(index);
+            }
+        }
+        false
+    }
+    fn set_threshold(&mut self) {
+        self.threshold = (self.estimate / 100).saturating_mul(self.gc_pause);
+    }
+}
+
+impl LuaState {
+    fn mark_roots(&mut self) {
+        self.expire_registry_values();
+        let g = &mut self.global;
+        g.heap.mark(g.globals.index());
+        g.heap.mark(g.registry.index());
+        for s in &g.tm_names {
+            g.heap.mark(s.index());
+        }
+        for mt in g.mt.iter().flatten() {
+            g.heap.mark(mt.index());
+        }
+        for mt in g.userdata_mt.values() {
+            g.heap.mark(mt.index());
+        }
+        for i in 0..g.heap.tmudata.len() {
+            let u = g.heap.tmudata[i];
+            g.heap.mark(u.index());
+        }
+        g.heap.mark(self.main_thread.index());
+        self.mark_running_thread();
+    }
+    /// the stack of the running thread lives outside the heap
+    fn mark_running_thread(&mut self) {
+        let heap = &mut self.global.heap;
+        let mut lim = stack_limit(&self.stack, self.top, &self.base_ci);
+        let ci = self.base_ci.last().unwrap();
+        if self.base_ci.len() == 1 || !matches!(self.stack[ci.func].value(), Value::LuaClosure(_)) {
+            // above the top of a native frame or of the host nothing is live:
+            // clear it rather than keep garbage alive (traversestack)
+            self.stack[self.top..lim].fill(TValue::nil());
+            lim = self.top;
+        }
+        for v in &self.stack[..lim] {
+            heap.mark_value(v);
+        }
+        for uv in &self.open_upvals {
+            heap.mark(uv.index());
+        }
+        heap.mark(self.current.index());
+    }
+    /// Queue the userdata with a `__gc` metamethod for finalization; only the
+    /// unmarked ones unless `all` is set. The queued userdata are marked.
+    fn separate_udata(&mut self, all: bool) {
+        let gc_name = self.tm_name(TagMethod::Gc);
+        let heap = &mut self.global.heap;
+        let mut found = Vec::new();
+        for u in heap.udata.values() {
+            let ud = heap.get(*u);
+            if ud.finalized || (!all && heap.colors[u.index()] > WHITE1) {
+                continue;
+            }
+            let has_tm = ud
+                .metatable
+                .is_some_and(|mt| !matches!(heap.get(mt).get_str(gc_name).value(), Value::Nil));
+            found.push((*u, has_tm));
+        }
+        // oldest first, so that the newest runs first
+        for (u, has_tm) in found {
+            if let Some(GcObject::Userdata(ud)) = &mut heap.objects[u.index()] {
+                ud.finalized = true;
+            }
+            if has_tm {
+                heap.tmudata.push(u);
+                heap.mark(u.index());
+            }
+        }
+    }
+    /// finish marking in one go, then start the sweep
+    fn atomic(&mut self) {
+        let mode = self.tm_name(TagMethod::Mode);
+        // roots which are not heap objects may have changed without a barrier
+        self.mark_roots();
+        self.global.heap.propagate_all(mode);
+        // dead userdata to finalize, and what they reference, survive this cycle
+        self.separate_udata(false);
+        let heap = &mut self.global.heap;
+        heap.propagate_all(mode);
+        heap.clear_weak_tables(mode);
+        heap.current_white = heap.other_white();
+        heap.sweep_pos = 0;
+        heap.gc_state = GcState::Sweep;
+        heap.estimate = heap.total_bytes;
+    }
+    /// push the `__gc` metamethod of the next userdata to finalize and the userdata
+    /// itself; returns the stack index of the metamethod
+    fn push_gc_tm(&mut self) -> Option<usize> {
+        let u = self.global.heap.tmudata.pop()?;
+        let udata = TValue::userdata(u);
+        let tm = self.get_tm_by_obj(&udata, TagMethod::Gc);
+        if matches!(tm.value(), Value::Nil) {
+            return None;
+        }
+        let func = self.top;
+        self.push(tm);
+        self.push(udata);
+        Some(func)
+    }
+    /// run one pending finalizer (GCTM); its errors propagate
+    fn gc_tm(&mut self) -> Result<()> {
+        if let Some(func) = self.push_gc_tm() {
+            // avoid collection steps inside the finalizer
+            let old_threshold = self.global.heap.threshold;
+            self.global.heap.threshold = 2 * self.global.heap.total_bytes;
+            let res = self.call(func, 0);
+            self.global.heap.threshold = old_threshold;
+            // don't let the stale slots keep the userdata alive
+            for v in &mut self.stack[func..func + 2] {
+                *v = TValue::nil();
+            }
+            res?;
+        }
+        Ok(())
+    }
+    /// one unit of collection work; returns its cost
+    fn single_step(&mut self) -> Result<usize> {
+        match self.global.heap.gc_state {
+            GcState::Pause => {
+                self.mark_roots();
+                self.global.heap.gc_state = GcState::Propagate;
+                Ok(0)
+            }
+            GcState::Propagate => match self.global.heap.gray.pop() {
+                Some(index) => {
+                    let mode = self.tm_name(TagMethod::Mode);
+                    Ok(self.global.heap.traverse(index as usize, mode))
+                }
+                None => {
+                    self.atomic();
+                    Ok(0)
+                }
+            },
+            GcState::Sweep => {
+                let heap = &mut self.global.heap;
+                if heap.sweep_step() {
+                    heap.gc_state = GcState::Finalize;
+                    heap.estimate = heap.total_bytes;
+                }
+                Ok(GCSWEEPMAX * GCSWEEPCOST)
+            }
+            GcState::Finalize => {
+                if self.global.heap.tmudata.is_empty() {
+                    self.global.heap.gc_state = GcState::Pause;
+                    return Ok(0);
+                }
+                self.gc_tm()?;
+                Ok(GCFINALIZECOST)
+            }
+        }
+    }
+    /// advance the collector in proportion to the memory allocated (luaC_step)
+    fn incremental_step(&mut self) -> Result<()> {
+        let heap = &self.global.heap;
+        let mut lim = match heap.gc_stepmul {
+            0 => isize::MAX,
+            mul => (GCSTEPSIZE / 100 * mul) as isize,
+        };
+        loop {
+            lim -= self.single_step()? as isize;
+            if lim <= 0 || self.global.heap.gc_state == GcState::Pause {
+                break;
+            }
+        }
+        let heap = &mut self.global.heap;
+        if heap.gc_state == GcState::Pause {
+            heap.set_threshold();
+        } else {
+            heap.threshold = heap.total_bytes + GCSTEPSIZE;
+        }
+        Ok(())
+    }
+    /// collection safe point of the VM (luaC_checkGC)
+    pub(crate) fn check_gc(&mut self) -> Result<()> {
+        self.global.heap.update_written_sizes();
+        if self.global.heap.total_bytes >= self.global.heap.threshold {
+            self.incremental_step()?;
+        }
+        self.check_memory()
+    }
+
+    /// run a full collection cycle, finalizers included
+    pub fn gc_collect(&mut self) -> Result<()> {
+        let heap = &mut self.global.heap;
+        if heap.gc_state == GcState::Propagate {
+            // drop the marks of the current cycle: sweeping turns them white again
+            heap.gray.clear();
+            heap.gray_again.clear();
+            heap.weak.clear();
+            heap.sweep_pos = 0;
+            heap.gc_state = GcState::Sweep;
+        }
+        while self.global.heap.gc_state != GcState::Pause {
+            self.single_step()?;
+        }
+        loop {
+            self.single_step()?;
+            if self.global.heap.gc_state == GcState::Pause {
+                break;
+            }
+        }
+        self.global.heap.set_threshold();
+        Ok(())
+    }
+    /// bytes in use
+    pub fn gc_count(&self) -> usize {
+        self.global.heap.total_bytes
+    }
+    pub fn gc_stop(&mut self) {
+        self.global.heap.threshold = usize::MAX;
+    }
+    pub fn gc_restart(&mut self) {
+        self.global.heap.threshold = self.global.heap.total_bytes;
+    }
+    /// collection work worth `kb` kilobytes of allocation; true if a cycle finished
+    pub fn gc_step(&mut self, kb: usize) -> Result<bool> {
+        let heap = &mut self.global.heap;
+        heap.threshold = heap.total_bytes.saturating_sub(kb << 10);
+        while self.global.heap.threshold <= self.global.heap.total_bytes {
+            self.incremental_step()?;
+            if self.global.heap.gc_state == GcState::Pause {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+    /// set the pause between cycles, in percent of the memory in use; returns the old value
+    pub fn gc_set_pause(&mut self, pause: usize) -> usize {
+        std::mem::replace(&mut self.global.heap.gc_pause, pause)
+    }
+    /// set the speed of the collector relative to allocation; returns the old value
+    pub fn gc_set_stepmul(&mut self, stepmul: usize) -> usize {
+        std::mem::replace(&mut self.global.heap.gc_stepmul, stepmul)
+    }
+}
+
+impl Drop for LuaState {
+    /// run the finalizers of all userdata, ignoring their errors (lua_close)
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        self.separate_udata(true);
+        while !self.global.heap.tmudata.is_empty() {
+            if let Some(func) = self.push_gc_tm() {
+                let _ = self.pcall(func, 0, None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::heap::Gc;
+    use crate::opcodes::{OpCode, create_abc, create_abx, create_asbx, rk_ask};
+    use crate::table::Table;
+    use crate::vm::tests::{proto, run};
+
+    #[test]
+    fn test_full_collection_frees_garbage() {
+        let mut state = LuaState::new();
+        state.gc_collect().unwrap();
+        let before = state.gc_count();
+        let kept = state.new_table();
+        state.set_global("kept", TValue::table(kept)).unwrap();
+        for i in 0..100 {
+            let t = state.new_table();
+            let s = state.intern(format!("garbage {i}").as_bytes());
+            state.global.heap.get_mut(t).set_int(1, s);
+        }
+        assert!(state.gc_count() > before + 100 * std::mem::size_of::<GcObject>());
+        state.gc_collect().unwrap();
+        // only `kept`, its name and the grown globals table remain
+        assert!(state.gc_count() < before + 1024);
+        assert!(matches!(state.get_global("kept").value(), Value::Table(t) if t == kept));
+    }
+
+    #[test]
+    fn test_table_churn_stays_bounded() {
+        let mut state = LuaState::new();
+        let num = |n: f64| TValue::number(n);
+        // for i = 1, 20000 do local t = {}; t[1] = i end
+        let p = proto(
+            vec![
+                create_abx(OpCode::OpLoadK, 0, 0),
+                create_abx(OpCode::OpLoadK, 1, 1),
+                create_abx(OpCode::OpLoadK, 2, 0),
+                create_asbx(OpCode::OpForPrep, 0, 2),
+                create_abc(OpCode::OpNewTable, 4, 0, 0),
+                create_abc(OpCode::OpSetTable, 4, rk_ask(0), 3),
+                create_asbx(OpCode::OpForLoop, 0, -3),
+                create_abc(OpCode::OpReturn, 0, 1, 0),
+            ],
+            vec![num(1.0), num(20000.0)],
+        );
+        run(&mut state, p).unwrap();
+        assert!(state.gc_count() < 256 * 1024);
+    }
+
+    #[test]
+    fn test_barrier_keeps_objects_stored_in_black_table() {
+        let mut state = LuaState::new();
+        state.gc_collect().unwrap();
+        let globals = state.global.globals;
+        // mark until the globals table is black
+        state.single_step().unwrap();
+        while state.global.heap.colors[globals.index()] != BLACK {
+            state.single_step().unwrap();
+        }
+        assert_eq!(state.global.heap.gc_state, GcState::Propagate);
+        let t: Gc<Table> = state.new_table();
+        state.set_global("late", TValue::table(t)).unwrap();
+        while state.global.heap.gc_state != GcState::Pause {
+            state.single_step().unwrap();
+        }
+        // still alive and the same object
+        assert!(state.global.heap.get(t).metatable.is_none());
+        assert!(matches!(state.get_global("late").value(), Value::Table(x) if x == t));
+    }
+
+    fn table(t: Gc<Table>) -> TValue {
+        TValue::table(t)
+    }
+
+    /// new global table `weak` with the given `__mode`
+    fn weak_table(state: &mut LuaState, mode: &[u8]) -> Gc<Table> {
+        let t = state.new_table();
+        let mt = state.new_table();
+        let key = state.intern(b"__mode");
+        let mode = state.intern(mode);
+        state.global.heap.get_mut(mt).set(key, mode).unwrap();
+        state.global.heap.get_mut(t).metatable = Some(mt);
+        state.set_global("weak", table(t)).unwrap();
+        t
+    }
+
+    #[test]
+    fn test_weak_values_are_cleared() {
+        let mut state = LuaState::new();
+        let t = weak_table(&mut state, b"v");
+        let kept = state.new_table();
+        state.set_global("kept", table(kept)).unwrap();
+        let garbage = state.new_table();
+        let s = state.intern(b"strings are never weak");
+        let heap = &mut state.global.heap;
+        heap.get_mut(t).set_int(1, table(garbage));
+        heap.get_mut(t).set_int(2, table(kept));
+        heap.get_mut(t).set_int(3, s);
+        state.gc_collect().unwrap();
+        let t = state.global.heap.get(t);
+        assert!(matches!(t.get_int(1).value(), Value::Nil));
+        assert!(matches!(t.get_int(2).value(), Value::Table(x) if x == kept));
+        assert!(matches!(t.get_int(3).value(), Value::String(_)));
+    }
+
+    #[test]
+    fn test_weak_keys_are_cleared() {
+        let mut state = LuaState::new();
+        let t = weak_table(&mut state, b"k");
+        let kept = state.new_table();
+        state.set_global("kept", table(kept)).unwrap();
+        let garbage = state.new_table();
+        let value = state.new_table();
+        let heap = &mut state.global.heap;
+        heap.get_mut(t).set(table(garbage), table(kept)).unwrap();
+        heap.get_mut(t).set(table(kept), table(value)).unwrap();
+        state.gc_collect().unwrap();
+        let t = state.global.heap.get(t);
+        let nil = TValue::nil();
+        let (k, v) = t.next(&nil).unwrap().unwrap();
+        assert!(matches!(k.value(), Value::Table(x) if x == kept));
+        // values of a weak-keyed table stay strong
+        assert!(matches!(v.value(), Value::Table(x) if x == value));
+        assert!(t.next(&k).unwrap().is_none());
+    }
+
+    /// `__gc` appending the number held by the userdata to the global `log`
+    fn record(state: &mut LuaState) -> Result<usize> {
+        let n = match state.arg(1).value() {
+            Value::UserData(u) => *state.global.heap.get(u).data.downcast_ref::<i64>().unwrap(),
+            _ => unreachable!(),
+        };
+        let log = match state.get_global("log").value() {
+            Value::Table(t) => t,
+            _ => unreachable!(),
+        };
+        let len = state.global.heap.get(log).length();
+        let n = TValue::number(n as f64);
+        state.global.heap.get_mut(log).set_int(len + 1, n);
+        Ok(0)
+    }
+
+    #[test]
+    fn test_finalizers_run_newest_first() {
+        let mut state = LuaState::new();
+        let log = state.new_table();
+        state.set_global("log", table(log)).unwrap();
+        let mt = state.new_table();
+        let gc = state.intern(b"__gc");
+        let f = state.new_native(record);
+        state.global.heap.get_mut(mt).set(gc, f).unwrap();
+        for i in 1..=3i64 {
+            let u = state.new_userdata(Box::new(i));
+            state.global.heap.get_mut(u).metatable = Some(mt);
+        }
+        state.gc_collect().unwrap();
+        let log = state.global.heap.get(log);
+        let order: Vec<f64> = (1..=3)
+            .map(|i| log.get_int(i).as_number().unwrap_or(0.0))
+            .collect();
+        assert_eq!(order, vec![3.0, 2.0, 1.0]);
+        // finalized userdata are freed by the next cycle
+        assert_eq!(state.global.heap.udata.len(), 3);
+        state.gc_collect().unwrap();
+        assert_eq!(state.global.heap.udata.len(), 0);
+    }
+}
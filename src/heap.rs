@@ -0,0 +1,348 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use crate::eval::TValue;
+use crate::func::{LuaClosure, NativeClosure, UpVal};
+use crate::gc::GcState;
+use crate::table::Table;
+use crate::thread::Thread;
+use crate::userdata::Userdata;
+
+/// Handle to an object living in the `Heap`.
+/// Handles are plain indices, so values holding them are `Copy`. The
+/// generation of the slot tells a handle to a collected object from one to
+/// the object now using its slot.
+pub struct Gc<T> {
+    index: u32,
+    generation: u32,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Gc<T> {
+    fn new(index: u32, generation: u32) -> Self {
+        Self {
+            index,
+            generation,
+            _marker: PhantomData,
+        }
+    }
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+}
+
+impl<T> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Gc<T> {}
+
+impl<T> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation
+    }
+}
+
+impl<T> Eq for Gc<T> {}
+
+impl<T> Hash for Gc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+
+impl<T> std::fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Gc(0x{:08x})", self.index)
+    }
+}
+
+/// Immutable byte string. Every string is interned, so two strings are
+/// equal iff their handles are equal.
+#[derive(Debug)]
+pub struct LuaString {
+    bytes: Rc<[u8]>,
+}
+
+impl LuaString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+pub enum GcObject {
+    String(LuaString),
+    Table(Table),
+    LuaClosure(LuaClosure),
+    NativeClosure(NativeClosure),
+    UpVal(UpVal),
+    Thread(Thread),
+    Userdata(Userdata),
+}
+
+pub trait Collectable: Sized {
+    fn into_object(self) -> GcObject;
+    fn from_object(obj: &GcObject) -> Option<&Self>;
+    fn from_object_mut(obj: &mut GcObject) -> Option<&mut Self>;
+}
+
+macro_rules! impl_collectable {
+    ($ty:ty, $variant:ident) => {
+        impl Collectable for $ty {
+            fn into_object(self) -> GcObject {
+                GcObject::$variant(self)
+            }
+            fn from_object(obj: &GcObject) -> Option<&Self> {
+                match obj {
+                    GcObject::$variant(o) => Some(o),
+                    _ => None,
+                }
+            }
+            fn from_object_mut(obj: &mut GcObject) -> Option<&mut Self> {
+                match obj {
+                    GcObject::$variant(o) => Some(o),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+impl_collectable!(LuaString, String);
+impl_collectable!(Table, Table);
+impl_collectable!(LuaClosure, LuaClosure);
+impl_collectable!(NativeClosure, NativeClosure);
+impl_collectable!(UpVal, UpVal);
+impl_collectable!(Thread, Thread);
+impl_collectable!(Userdata, Userdata);
+
+impl GcObject {
+    /// approximate memory used by the object, for the collector's accounting
+    pub(crate) fn mem_size(&self) -> usize {
+        std::mem::size_of::<GcObject>()
+            + match self {
+                GcObject::String(s) => s.len(),
+                GcObject::Table(t) => t.mem_size(),
+                GcObject::LuaClosure(c) => c.upvals.len() * std::mem::size_of::<Gc<UpVal>>(),
+                GcObject::NativeClosure(c) => c.upvalues.len() * std::mem::size_of::<TValue>(),
+                GcObject::UpVal(_) => 0,
+                GcObject::Thread(th) => th.mem_size(),
+                GcObject::Userdata(u) => std::mem::size_of_val(&*u.data),
+            }
+    }
+}
+
+/// object colours of the tri-colour collector; there are two whites so that
+/// objects created during a sweep are not mistaken for dead ones
+pub(crate) const WHITE0: u8 = 0;
+pub(crate) const WHITE1: u8 = 1;
+pub(crate) const GRAY: u8 = 2;
+pub(crate) const BLACK: u8 = 3;
+
+/// Arena owning every collectable object of a `LuaState`, along with the
+/// bookkeeping of the collector (see `gc.rs`).
+pub struct Heap {
+    pub(crate) objects: Vec<Option<GcObject>>,
+    free: Vec<u32>,
+    strings: HashMap<Rc<[u8]>, Gc<LuaString>>,
+    pub(crate) colors: Vec<u8>,
+    /// size of each object when it was allocated or last swept
+    sizes: Vec<usize>,
+    /// generation of each slot, bumped when its object is freed
+    generations: Vec<u32>,
+    /// objects borrowed mutably since their size was last accounted, with
+    /// a flag per slot to list each once
+    written: Vec<u32>,
+    written_flags: Vec<bool>,
+    pub(crate) current_white: u8,
+    pub(crate) gc_state: GcState,
+    pub(crate) gray: Vec<u32>,
+    /// black objects modified during propagation, traversed again by the atomic step
+    pub(crate) gray_again: Vec<u32>,
+    pub(crate) sweep_pos: usize,
+    pub(crate) total_bytes: usize,
+    /// a collection step runs when `total_bytes` reaches this
+    pub(crate) threshold: usize,
+    /// `total_bytes` in use by live objects at the end of the last cycle
+    pub(crate) estimate: usize,
+    pub(crate) gc_pause: usize,
+    pub(crate) gc_stepmul: usize,
+    /// weak tables found while marking, cleared by the atomic step
+    pub(crate) weak: Vec<u32>,
+    /// every userdata by creation order
+    pub(crate) udata: BTreeMap<u64, Gc<Userdata>>,
+    next_serial: u64,
+    /// collected userdata waiting for their `__gc`, the last one runs first
+    pub(crate) tmudata: Vec<Gc<Userdata>>,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            free: Vec::new(),
+            strings: HashMap::new(),
+            colors: Vec::new(),
+            sizes: Vec::new(),
+            generations: Vec::new(),
+            written: Vec::new(),
+            written_flags: Vec::new(),
+            current_white: WHITE0,
+            gc_state: GcState::Pause,
+            gray: Vec::new(),
+            gray_again: Vec::new(),
+            sweep_pos: 0,
+            total_bytes: 0,
+            threshold: 0,
+            estimate: 0,
+            gc_pause: 200,
+            gc_stepmul: 200,
+            weak: Vec::new(),
+            udata: BTreeMap::new(),
+            next_serial: 0,
+            tmudata: Vec::new(),
+        }
+    }
+    pub fn alloc<T: Collectable>(&mut self, obj: T) -> Gc<T> {
+        let obj = obj.into_object();
+        let size = obj.mem_size();
+        self.total_bytes += size;
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index as usize] = Some(obj);
+                self.colors[index as usize] = self.current_white;
+                self.sizes[index as usize] = size;
+                Gc::new(index, self.generations[index as usize])
+            }
+            None => {
+                self.objects.push(Some(obj));
+                self.colors.push(self.current_white);
+                self.sizes.push(size);
+                self.generations.push(0);
+                self.written_flags.push(false);
+                Gc::new((self.objects.len() - 1) as u32, 0)
+            }
+        }
+    }
+    pub(crate) fn alloc_userdata(&mut self, data: Box<dyn Any>, env: Gc<Table>) -> Gc<Userdata> {
+        let serial = self.next_serial;
+        self.next_serial += 1;
+        let u = self.alloc(Userdata {
+            metatable: None,
+            env,
+            data,
+            serial,
+            finalized: false,
+        });
+        self.udata.insert(serial, u);
+        u
+    }
+    /// release the object in slot `index`
+    pub(crate) fn free_object(&mut self, index: usize) {
+        match &self.objects[index] {
+            Some(GcObject::String(s)) => {
+                self.strings.remove(s.as_bytes());
+            }
+            Some(GcObject::Userdata(u)) => {
+                self.udata.remove(&u.serial);
+            }
+            _ => {}
+        }
+        self.objects[index] = None;
+        self.generations[index] = self.generations[index].wrapping_add(1);
+        self.total_bytes -= self.sizes[index];
+        self.free.push(index as u32);
+    }
+    /// recompute the accounted size of a surviving object
+    pub(crate) fn update_size(&mut self, index: usize) {
+        if let Some(obj) = &self.objects[index] {
+            let size = obj.mem_size();
+            self.total_bytes = self.total_bytes - self.sizes[index] + size;
+            self.sizes[index] = size;
+        }
+    }
+    /// Account for the growth of the objects written since the last call,
+    /// whatever wrote them: the VM, `rawset`, the libraries or the host.
+    pub(crate) fn update_written_sizes(&mut self) {
+        while let Some(index) = self.written.pop() {
+            self.written_flags[index as usize] = false;
+            self.update_size(index as usize);
+        }
+    }
+    pub(crate) fn size_of(&self, index: usize) -> usize {
+        self.sizes[index]
+    }
+    /// white of the previous cycle: unmarked objects of that colour are garbage
+    pub(crate) fn other_white(&self) -> u8 {
+        self.current_white ^ 1
+    }
+    /// whether `r` still refers to a live object
+    pub fn is_live<T: Collectable>(&self, r: Gc<T>) -> bool {
+        self.generations.get(r.index()) == Some(&r.generation)
+            && self.objects[r.index()]
+                .as_ref()
+                .and_then(T::from_object)
+                .is_some()
+    }
+    pub fn get<T: Collectable>(&self, r: Gc<T>) -> &T {
+        self.objects[r.index()]
+            .as_ref()
+            .filter(|_| self.generations[r.index()] == r.generation)
+            .and_then(T::from_object)
+            .expect("dangling gc reference")
+    }
+    /// Mutable access to an object. This is the write barrier of the collector:
+    /// a black object modified while marking is turned gray again. The object
+    /// is also listed for `update_written_sizes`.
+    pub fn get_mut<T: Collectable>(&mut self, r: Gc<T>) -> &mut T {
+        assert!(
+            self.generations[r.index()] == r.generation,
+            "dangling gc reference"
+        );
+        if self.colors[r.index()] == BLACK && self.gc_state == GcState::Propagate {
+            self.colors[r.index()] = GRAY;
+            self.gray_again.push(r.index() as u32);
+        }
+        if !self.written_flags[r.index()] {
+            self.written_flags[r.index()] = true;
+            self.written.push(r.index() as u32);
+        }
+        self.objects[r.index()]
+            .as_mut()
+            .and_then(T::from_object_mut)
+            .expect("dangling gc reference")
+    }
+    pub fn intern(&mut self, bytes: &[u8]) -> Gc<LuaString> {
+        if let Some(s) = self.strings.get(bytes) {
+            let s = *s;
+            // resurrect a dead string not swept yet
+            if self.gc_state == GcState::Sweep && self.colors[s.index()] == self.other_white() {
+                self.colors[s.index()] = self.current_white;
+            }
+            return s;
+        }
+        let bytes: Rc<[u8]> = Rc::from(bytes);
+        let s = self.alloc(LuaString {
+            bytes: bytes.clone(),
+        });
+        self.strings.insert(bytes, s);
+        s
+    }
+}
@@ -0,0 +1,304 @@
+use std::rc::Rc;
+
+use anyhow::Result;
+
+use crate::conversion::{BadArgument, FromLua, FromLuaMulti, ToLua, ToLuaMulti};
+use crate::error::LuaError;
+use crate::eval::{TValue, Value};
+use crate::iolib::SystemAccess;
+use crate::vm::{LUA_MULTRET, LuaState};
+use crate::{baselib, dblib, iolib, jsonlib, loadlib, mathlib, oslib, strlib, tablib};
+
+/// A host function with its arguments and results converted, kept in a
+/// userdata upvalue of `call_host`.
+pub(crate) type HostFn = Rc<dyn Fn(&mut LuaState, Vec<TValue>) -> Result<Vec<TValue>>>;
+
+/// Error of a host function raised in Lua: conversion errors become
+/// "bad argument" errors, any other error but a Lua one is raised with the
+/// position of the caller like `luaL_error`.
+pub(crate) fn host_error(state: &mut LuaState, e: anyhow::Error) -> anyhow::Error {
+    match e.downcast::<BadArgument>() {
+        Ok(bad) => state.arg_error(bad.position, &bad.message),
+        Err(e) if e.is::<LuaError>() => e,
+        Err(e) => state.error(e.to_string()),
+    }
+}
+
+/// `f` with its arguments and results converted
+pub(crate) fn host_function<A, R, F>(f: F) -> HostFn
+where
+    A: FromLuaMulti,
+    R: ToLuaMulti,
+    F: Fn(&mut LuaState, A) -> Result<R> + 'static,
+{
+    Rc::new(move |state, args| {
+        let args = A::from_lua_multi(args, state)?;
+        Ok(f(state, args)?.to_lua_multi(state))
+    })
+}
+
+/// Native function running the `HostFn` in its first upvalue.
+fn call_host(state: &mut LuaState) -> Result<usize> {
+    let f = match state.upvalue(1).value() {
+        Value::UserData(u) => state
+            .global
+            .heap
+            .get(u)
+            .data
+            .downcast_ref::<HostFn>()
+            .cloned(),
+        _ => None,
+    };
+    let f = f.expect("host function upvalue");
+    let args = state.stack[state.arg_index(1)..state.top].to_vec();
+    let results = f(state, args).map_err(|e| host_error(state, e))?;
+    state.check_stack(results.len());
+    let n = results.len();
+    for v in results {
+        state.push(v);
+    }
+    Ok(n)
+}
+
+/// Embedding API: running code and exchanging values without touching the stack.
+/// Errors are returned as `LuaError`, never raised.
+impl LuaState {
+    /// Open the standard libraries (luaL_openlibs). `access` decides what
+    /// `io`, `os` and `require` may do on the host system.
+    pub fn open_libs(&mut self, access: SystemAccess) {
+        self.global.read_files = access.read_files;
+        baselib::open_base(self);
+        loadlib::open_package(self, access);
+        tablib::open_table(self);
+        iolib::open_io(self, access);
+        oslib::open_os(self, access);
+        strlib::open_string(self);
+        mathlib::open_math(self);
+        dblib::open_debug(self);
+        jsonlib::open_json(self);
+    }
+
+    /// Compile `source`, or undump it if it is a precompiled chunk, into a
+    /// function without running it. `chunkname` appears in error messages.
+    pub fn load_chunk(
+        &mut self,
+        source: impl AsRef<[u8]>,
+        chunkname: &str,
+    ) -> Result<TValue, LuaError> {
+        self.load_buffer(source.as_ref(), chunkname)
+            .map_err(|e| self.error_object(e))
+    }
+
+    /// Run `source` and convert its results (luaL_dostring). The source is
+    /// its own chunk name, so errors read `[string "..."]:line: message`.
+    pub fn exec<R: FromLuaMulti>(&mut self, source: &str) -> Result<R, LuaError> {
+        let f = self.load_chunk(source, source)?;
+        self.call_function(&f, ())
+    }
+
+    /// Call `f` with `args` in protected mode and convert its results.
+    /// The error of a failed call carries a stack traceback.
+    pub fn call_function<A: ToLuaMulti, R: FromLuaMulti>(
+        &mut self,
+        f: &TValue,
+        args: A,
+    ) -> Result<R, LuaError> {
+        let args = args.to_lua_multi(self);
+        self.check_live(&[*f])?;
+        self.check_live(&args)?;
+        self.start_deadline();
+        let func = self.top;
+        self.push(*f);
+        self.check_stack(args.len());
+        for arg in args {
+            self.push(arg);
+        }
+        self.pcall_traceback(func, LUA_MULTRET)?;
+        let results = self.stack[func..self.top].to_vec();
+        self.top = func;
+        R::from_lua_multi(results, self).map_err(|e| self.error_object(e))
+    }
+
+    /// Lua function calling `f` with its arguments converted to `A`, returning
+    /// the values of `R`. A failed conversion raises
+    /// "bad argument #n to 'name' (message)" like `luaL_argerror`; an error
+    /// returned by `f` is raised with the position of the caller prepended.
+    pub fn create_function<A, R, F>(&mut self, f: F) -> TValue
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+        F: Fn(&mut LuaState, A) -> Result<R> + 'static,
+    {
+        let f = host_function(f);
+        self.new_host_function(f)
+    }
+
+    pub(crate) fn new_host_function(&mut self, f: HostFn) -> TValue {
+        let ud = self.new_userdata(Box::new(f));
+        self.new_native_closure(call_host, vec![TValue::userdata(ud)])
+    }
+
+    /// set the global `name` to the function made by `create_function`
+    pub fn register_function<A, R, F>(&mut self, name: &str, f: F)
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+        F: Fn(&mut LuaState, A) -> Result<R> + 'static,
+    {
+        let func = self.create_function(f);
+        self.raw_set_global(name, func);
+    }
+
+    /// global variable `name` converted to `T`
+    pub fn global<T: FromLua>(&mut self, name: &str) -> Result<T, LuaError> {
+        let val = self.get_global(name);
+        T::from_lua(val, self).map_err(|e| self.error_object(e))
+    }
+
+    /// Set the global variable `name` to `val`.
+    pub fn set_global(&mut self, name: &str, val: impl ToLua) -> Result<(), LuaError> {
+        let val = val.to_lua(self);
+        self.check_live(&[val])?;
+        self.raw_set_global(name, val);
+        Ok(())
+    }
+
+    /// whether the object `val` refers to, if any, was not collected
+    pub fn is_live(&self, val: &TValue) -> bool {
+        let heap = &self.global.heap;
+        match val.value() {
+            Value::String(s) => heap.is_live(s),
+            Value::Table(t) => heap.is_live(t),
+            Value::LuaClosure(c) => heap.is_live(c),
+            Value::NativeClosure(c) => heap.is_live(c),
+            Value::Thread(th) => heap.is_live(th),
+            Value::UserData(u) => heap.is_live(u),
+            Value::Nil | Value::Boolean(_) | Value::Integer(_) | Value::Number(_) => true,
+        }
+    }
+
+    /// Values kept by the host are not rooted: one whose object was
+    /// collected is refused rather than mistaken for the object now in its
+    /// slot.
+    pub(crate) fn check_live(&mut self, vals: &[TValue]) -> Result<(), LuaError> {
+        if vals.iter().all(|v| self.is_live(v)) {
+            return Ok(());
+        }
+        let msg = "attempt to use a collected value";
+        Err(LuaError::new(self.intern(msg.as_bytes()), msg.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_host_api() {
+        let mut lua = LuaState::new();
+        lua.open_libs(SystemAccess::SAFE);
+        lua.set_global("base", 40).unwrap();
+        lua.exec::<()>("function add(a, b) return base + a + b, 'sum' end")
+            .unwrap();
+        let add = lua.get_global("add");
+        let (sum, what): (i64, String) = lua.call_function(&add, (1, 1)).unwrap();
+        assert_eq!((sum, what.as_str()), (42, "sum"));
+        assert_eq!(lua.global::<Option<String>>("missing").unwrap(), None);
+
+        let err = lua.call_function::<_, ()>(&add, ()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"function add(a, b) return base + a + b, 'su...\"]:1: \
+             attempt to perform arithmetic on local 'a' (a nil value)"
+        );
+        assert_eq!(
+            err.traceback(),
+            Some(
+                "stack traceback:\n\t[string \"function add(a, b) return base + a + b, \
+                 'su...\"]:1: in function <[string \"function add(a, b) return base + a \
+                 + b, 'su...\"]:1>"
+            )
+        );
+        let err = lua.exec::<()>("x = = 1").unwrap_err();
+        assert!(err.to_string().starts_with("[string \"x = = 1\"]:1: "));
+        assert_eq!(err.traceback(), None);
+        let err = lua.exec::<i32>("return {}").unwrap_err();
+        assert_eq!(err.to_string(), "number expected, got table");
+        // the stack is left as it was
+        assert_eq!(lua.get_top(), 0);
+    }
+
+    #[test]
+    fn test_create_function() {
+        use crate::conversion::Variadic;
+        use std::collections::HashMap;
+
+        let mut lua = LuaState::new();
+        lua.open_libs(SystemAccess::SAFE);
+        lua.register_function("foo", |_, (s, n): (String, f64)| {
+            Ok((s.repeat(n as usize), n * 2.0))
+        });
+        lua.register_function("sum", |_, Variadic(xs): Variadic<i64>| {
+            Ok(xs.iter().sum::<i64>())
+        });
+        lua.register_function("keys", |_, t: HashMap<String, bool>| {
+            let mut keys: Vec<String> = t.into_keys().collect();
+            keys.sort();
+            Ok(keys)
+        });
+        lua.register_function("fail", |_, ()| -> Result<()> { anyhow::bail!("no luck") });
+        let r: (String, f64, i64) = lua
+            .exec("local s, n = foo('ab', 2) return s, n, sum(1, 2, 3)")
+            .unwrap();
+        assert_eq!(r, ("abab".to_string(), 4.0, 6));
+        let keys: String = lua
+            .exec("return table.concat(keys{x = true, y = 1}, ',')")
+            .unwrap();
+        assert_eq!(keys, "x,y");
+
+        let err = lua.exec::<()>("foo('x')").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"foo('x')\"]:1: bad argument #2 to 'foo' (number expected, got nil)"
+        );
+        let err = lua.exec::<()>("sum(1, 2, {})").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"sum(1, 2, {})\"]:1: bad argument #3 to 'sum' (number expected, got table)"
+        );
+        let err = lua.exec::<()>("keys(1)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"keys(1)\"]:1: bad argument #1 to 'keys' (table expected, got number)"
+        );
+        let (ok, msg): (bool, String) = lua.exec("return pcall(fail)").unwrap();
+        assert_eq!((ok, msg.as_str()), (false, "no luck"));
+        let err = lua.exec::<()>("fail()").unwrap_err();
+        assert_eq!(err.to_string(), "[string \"fail()\"]:1: no luck");
+    }
+
+    #[test]
+    fn test_collected_values() {
+        let mut lua = LuaState::new();
+        lua.open_libs(SystemAccess::SAFE);
+        let f: TValue = lua.exec("return function() return 1 end").unwrap();
+        let t: TValue = lua.exec("return {}").unwrap();
+        assert!(lua.is_live(&f));
+        lua.gc_collect().unwrap();
+        assert!(!lua.is_live(&f));
+        // new objects reuse the freed slots
+        lua.exec::<()>("g = function() end; u = {}").unwrap();
+        let err = lua.call_function::<_, ()>(&f, ()).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to use a collected value");
+        let err = lua.set_global("t", t).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to use a collected value");
+        assert!(lua.create_registry_value(t).is_err());
+        // a rooted value stays usable
+        let g = lua.get_global("g");
+        let key = lua.create_registry_value(g).unwrap();
+        lua.gc_collect().unwrap();
+        let g: TValue = lua.registry_value(&key).unwrap();
+        lua.call_function::<_, ()>(&g, ()).unwrap();
+    }
+}
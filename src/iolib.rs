@@ -0,0 +1,791 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+
+use anyhow::Result;
+
+use crate::eval::{LuaNumber, TValue, Value};
+use crate::func::NativeFn;
+use crate::heap::Gc;
+use crate::table::Table;
+use crate::userdata::Userdata;
+use crate::vm::{LUA_MINSTACK, LuaState};
+
+/// What scripts may do with the host system through the `io` and `os`
+/// libraries. The standard streams are always available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemAccess {
+    /// open files for reading (`io.open(f, "r")`, `io.lines(f)`, `io.input(f)`)
+    pub read_files: bool,
+    /// create, modify, remove or rename files
+    pub write_files: bool,
+    /// read environment variables with `os.getenv`
+    pub env: bool,
+    /// terminate the process with `os.exit`
+    pub exit: bool,
+}
+
+impl SystemAccess {
+    /// everything the standard libraries can do
+    pub const FULL: SystemAccess = SystemAccess {
+        read_files: true,
+        write_files: true,
+        env: true,
+        exit: true,
+    };
+    /// only the standard streams, clocks and dates
+    pub const SAFE: SystemAccess = SystemAccess {
+        read_files: false,
+        write_files: false,
+        env: false,
+        exit: false,
+    };
+}
+
+/// EACCES, reported when `SystemAccess` forbids an operation
+pub(crate) const EACCES: i32 = 13;
+/// EBADF, for reading an output stream or writing an input one
+const EBADF: i32 = 9;
+
+/// registry keys of the file metatable and of the default files
+const FILE_HANDLE: &[u8] = b"FILE*";
+const IO_INPUT: &[u8] = b"_IO_input";
+const IO_OUTPUT: &[u8] = b"_IO_output";
+
+enum Stream {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(BufReader<File>),
+}
+
+/// Payload of file userdata (`FILE*` in liolib.c); `None` once closed.
+pub struct LuaFile(Option<Stream>);
+
+impl LuaFile {
+    fn stream(&mut self) -> io::Result<&mut Stream> {
+        self.0
+            .as_mut()
+            .ok_or_else(|| io::Error::from_raw_os_error(EBADF))
+    }
+    fn with_reader<T>(
+        &mut self,
+        f: impl FnOnce(&mut dyn BufRead) -> io::Result<T>,
+    ) -> io::Result<T> {
+        match self.stream()? {
+            Stream::Stdin => f(&mut io::stdin().lock()),
+            Stream::File(r) => f(r),
+            _ => Err(io::Error::from_raw_os_error(EBADF)),
+        }
+    }
+    fn with_writer<T>(&mut self, f: impl FnOnce(&mut dyn Write) -> io::Result<T>) -> io::Result<T> {
+        match self.stream()? {
+            Stream::Stdout => f(&mut io::stdout()),
+            Stream::Stderr => f(&mut io::stderr()),
+            Stream::File(r) => {
+                // drop read-ahead so that the write lands at the logical position
+                if !r.buffer().is_empty() {
+                    let pos = r.stream_position()?;
+                    r.seek(SeekFrom::Start(pos))?;
+                }
+                f(r.get_mut())
+            }
+            Stream::Stdin => Err(io::Error::from_raw_os_error(EBADF)),
+        }
+    }
+    fn is_std(&self) -> bool {
+        !matches!(self.0, Some(Stream::File(_)) | None)
+    }
+}
+
+/// a line without its newline, `None` at end of file
+fn read_line(r: &mut dyn BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = Vec::new();
+    if r.read_until(b'\n', &mut buf)? == 0 {
+        return Ok(None);
+    }
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    }
+    Ok(Some(buf))
+}
+
+/// up to `n` bytes, `None` at end of file
+fn read_chars(r: &mut dyn BufRead, n: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = Vec::new();
+    r.take(n as u64).read_to_end(&mut buf)?;
+    Ok(if buf.is_empty() { None } else { Some(buf) })
+}
+
+/// a number in the syntax of `fscanf("%lf")`, `None` if there is none
+fn read_number(r: &mut dyn BufRead) -> io::Result<Option<LuaNumber>> {
+    let mut buf = Vec::new();
+    loop {
+        let chunk = r.fill_buf()?;
+        let Some(&c) = chunk.first() else {
+            break;
+        };
+        if buf.is_empty() && c.is_ascii_whitespace() {
+            r.consume(1);
+            continue;
+        }
+        if !(c.is_ascii_hexdigit() || b"+-.xX".contains(&c)) {
+            break;
+        }
+        buf.push(c);
+        r.consume(1);
+    }
+    Ok(crate::vm::str2number(&buf))
+}
+
+/// message of an I/O error like C's `strerror`
+fn error_message(e: &io::Error) -> String {
+    let msg = e.to_string();
+    match msg.rfind(" (os error") {
+        Some(i) => msg[..i].to_string(),
+        None => msg,
+    }
+}
+
+/// `true`, or nil, message and error number (pushresult)
+fn push_result(state: &mut LuaState, res: io::Result<()>, filename: Option<&[u8]>) -> usize {
+    match res {
+        Ok(()) => {
+            state.push(TValue::boolean(true));
+            1
+        }
+        Err(e) => push_error(state, &e, filename),
+    }
+}
+
+fn push_error(state: &mut LuaState, e: &io::Error, filename: Option<&[u8]>) -> usize {
+    let mut msg = Vec::new();
+    if let Some(name) = filename {
+        msg.extend_from_slice(name);
+        msg.extend_from_slice(b": ");
+    }
+    msg.extend_from_slice(error_message(e).as_bytes());
+    state.push(TValue::nil());
+    let msg = state.intern(&msg);
+    state.push(msg);
+    state.push(TValue::number(e.raw_os_error().unwrap_or(0) as LuaNumber));
+    3
+}
+
+fn registry_get(state: &mut LuaState, key: &[u8]) -> TValue {
+    let key = state.global.heap.intern(key);
+    state.global.heap.get(state.global.registry).get_str(key)
+}
+
+fn registry_set(state: &mut LuaState, key: &[u8], val: TValue) {
+    let key = state.global.heap.intern(key);
+    let registry = state.global.registry;
+    state.global.heap.get_mut(registry).set_str(key, val);
+}
+
+fn file_metatable(state: &mut LuaState) -> Option<Gc<Table>> {
+    match registry_get(state, FILE_HANDLE).value() {
+        Value::Table(t) => Some(t),
+        _ => None,
+    }
+}
+
+fn new_file(state: &mut LuaState, stream: Stream) -> TValue {
+    let u = state.new_userdata(Box::new(LuaFile(Some(stream))));
+    state.global.heap.get_mut(u).metatable = file_metatable(state);
+    TValue::userdata(u)
+}
+
+/// the file userdata in `val`, if it is one
+fn to_file(state: &mut LuaState, val: &TValue) -> Option<Gc<Userdata>> {
+    let mt = file_metatable(state);
+    match val.value() {
+        Value::UserData(u) if mt.is_some() && state.global.heap.get(u).metatable == mt => Some(u),
+        _ => None,
+    }
+}
+
+fn file_mut(state: &mut LuaState, u: Gc<Userdata>) -> &mut LuaFile {
+    state
+        .global
+        .heap
+        .get_mut(u)
+        .data
+        .downcast_mut::<LuaFile>()
+        .unwrap()
+}
+
+/// file argument `narg`, open or closed
+fn check_file(state: &mut LuaState, narg: usize) -> Result<Gc<Userdata>> {
+    let val = state.arg(narg);
+    match to_file(state, &val) {
+        Some(u) => Ok(u),
+        None => Err(state.type_error_arg(narg, "FILE*")),
+    }
+}
+
+/// open file argument `narg` (tofile)
+fn check_open_file(state: &mut LuaState, narg: usize) -> Result<Gc<Userdata>> {
+    let u = check_file(state, narg)?;
+    if file_mut(state, u).0.is_none() {
+        return Err(state.error("attempt to use a closed file".to_string()));
+    }
+    Ok(u)
+}
+
+/// the access rights stored as upvalue of the io functions
+fn access(state: &LuaState) -> SystemAccess {
+    match state.upvalue(1).value() {
+        Value::UserData(u) => *state
+            .global
+            .heap
+            .get(u)
+            .data
+            .downcast_ref::<SystemAccess>()
+            .unwrap(),
+        _ => SystemAccess::SAFE,
+    }
+}
+
+/// open `name` with a C `fopen` mode, checking the access rights
+fn open_file(access: SystemAccess, name: &[u8], mode: &[u8]) -> io::Result<File> {
+    let path = std::str::from_utf8(name).map_err(|_| io::Error::from_raw_os_error(2))?;
+    let (base, rest) = match mode.split_first() {
+        Some((b, rest)) if b"rwa".contains(b) => (*b, rest),
+        _ => return Err(io::Error::from_raw_os_error(22)),
+    };
+    let rest = rest.strip_suffix(b"b").unwrap_or(rest);
+    let update = match rest {
+        b"" => false,
+        b"+" | b"+b" => true,
+        _ => return Err(io::Error::from_raw_os_error(22)),
+    };
+    let writes = base != b'r' || update;
+    if !access.read_files || (writes && !access.write_files) {
+        return Err(io::Error::from_raw_os_error(EACCES));
+    }
+    let mut opts = OpenOptions::new();
+    match base {
+        b'r' => opts.read(true).write(update),
+        b'w' => opts.write(true).create(true).truncate(true).read(update),
+        _ => opts.append(true).create(true).read(update),
+    };
+    opts.open(path)
+}
+
+/// io.open(filename [, mode])
+fn io_open(state: &mut LuaState) -> Result<usize> {
+    let name = state.check_string(1)?;
+    let name = state.str_bytes(name).to_vec();
+    let mode = match state.opt_string(2)? {
+        Some(m) => state.str_bytes(m).to_vec(),
+        None => b"r".to_vec(),
+    };
+    match open_file(access(state), &name, &mode) {
+        Ok(f) => {
+            let file = new_file(state, Stream::File(BufReader::new(f)));
+            state.push(file);
+            Ok(1)
+        }
+        Err(e) => Ok(push_error(state, &e, Some(&name))),
+    }
+}
+
+/// io.type(obj)
+fn io_type(state: &mut LuaState) -> Result<usize> {
+    let val = state.check_any(1)?;
+    let res = match to_file(state, &val) {
+        Some(u) if file_mut(state, u).0.is_none() => state.intern(b"closed file"),
+        Some(_) => state.intern(b"file"),
+        None => TValue::nil(),
+    };
+    state.push(res);
+    Ok(1)
+}
+
+fn close_file(state: &mut LuaState, u: Gc<Userdata>) -> usize {
+    let file = file_mut(state, u);
+    if file.is_std() {
+        state.push(TValue::nil());
+        let msg = state.intern(b"cannot close standard file");
+        state.push(msg);
+        return 2;
+    }
+    let res = match file.0.take() {
+        Some(Stream::File(mut r)) => r.get_mut().flush(),
+        _ => Ok(()),
+    };
+    push_result(state, res, None)
+}
+
+/// file:close()
+fn f_close(state: &mut LuaState) -> Result<usize> {
+    let u = check_open_file(state, 1)?;
+    Ok(close_file(state, u))
+}
+
+/// io.close([file])
+fn io_close(state: &mut LuaState) -> Result<usize> {
+    if state.get_top() == 0 {
+        let out = registry_get(state, IO_OUTPUT);
+        state.push(out);
+    }
+    f_close(state)
+}
+
+/// __gc of files: close them, except the standard streams
+fn io_gc(state: &mut LuaState) -> Result<usize> {
+    let u = check_file(state, 1)?;
+    let file = file_mut(state, u);
+    if !file.is_std() {
+        file.0 = None;
+    }
+    Ok(0)
+}
+
+/// __tostring of files
+fn io_tostring(state: &mut LuaState) -> Result<usize> {
+    let u = check_file(state, 1)?;
+    let s = if file_mut(state, u).0.is_none() {
+        "file (closed)".to_string()
+    } else {
+        format!("file (0x{:08x})", u.index())
+    };
+    let s = state.intern(s.as_bytes());
+    state.push(s);
+    Ok(1)
+}
+
+/// A format of `read`
+enum Format {
+    Number,
+    Line,
+    All,
+    Chars(usize),
+}
+
+/// read one format; `None` when nothing could be read
+fn read_format(file: &mut LuaFile, format: Format) -> io::Result<Option<ReadValue>> {
+    file.with_reader(|r| match format {
+        Format::Number => Ok(read_number(r)?.map(ReadValue::Number)),
+        Format::Line => Ok(read_line(r)?.map(ReadValue::Bytes)),
+        Format::All => {
+            let mut buf = Vec::new();
+            r.read_to_end(&mut buf)?;
+            Ok(Some(ReadValue::Bytes(buf)))
+        }
+        // test for end of file
+        Format::Chars(0) => Ok((!r.fill_buf()?.is_empty()).then(|| ReadValue::Bytes(Vec::new()))),
+        Format::Chars(n) => Ok(read_chars(r, n)?.map(ReadValue::Bytes)),
+    })
+}
+
+/// a value read, before it is put on the Lua stack
+enum ReadValue {
+    Number(LuaNumber),
+    Bytes(Vec<u8>),
+}
+
+/// read with the formats from argument `first` on (g_read)
+fn g_read(state: &mut LuaState, u: Gc<Userdata>, first: usize) -> Result<usize> {
+    let nargs = (state.get_top() + 1).saturating_sub(first);
+    state.check_stack(nargs + LUA_MINSTACK);
+    let mut formats = Vec::new();
+    for n in first..first + nargs {
+        let format = match state.arg(n).value() {
+            Value::Number(_) | Value::Integer(_) => {
+                Format::Chars(state.check_integer(n)?.max(0) as usize)
+            }
+            Value::String(s) => match state.str_bytes(s) {
+                [b'*', b'n', ..] => Format::Number,
+                [b'*', b'l', ..] => Format::Line,
+                [b'*', b'a', ..] => Format::All,
+                [b'*', ..] => return Err(state.arg_error(n, "invalid format")),
+                _ => return Err(state.arg_error(n, "invalid option")),
+            },
+            _ => return Err(state.arg_error(n, "invalid option")),
+        };
+        formats.push(format);
+    }
+    if formats.is_empty() {
+        formats.push(Format::Line);
+    }
+    let mut results = Vec::new();
+    for format in formats {
+        match read_format(file_mut(state, u), format) {
+            Ok(Some(v)) => results.push(v),
+            Ok(None) => break,
+            Err(e) => return Ok(push_error(state, &e, None)),
+        }
+    }
+    // a failed read is reported as nil in its place
+    let failed = results.len() < nargs.max(1);
+    let n = results.len() + failed as usize;
+    for v in results {
+        let v = match v {
+            ReadValue::Number(x) => TValue::number(x),
+            ReadValue::Bytes(b) => state.intern(&b),
+        };
+        state.push(v);
+    }
+    if failed {
+        state.push(TValue::nil());
+    }
+    Ok(n)
+}
+
+/// file:read(...)
+fn f_read(state: &mut LuaState) -> Result<usize> {
+    let u = check_open_file(state, 1)?;
+    g_read(state, u, 2)
+}
+
+/// the default input or output file
+fn io_file(state: &mut LuaState, key: &[u8]) -> Result<Gc<Userdata>> {
+    let f = registry_get(state, key);
+    match to_file(state, &f) {
+        Some(u) if file_mut(state, u).0.is_some() => Ok(u),
+        _ => {
+            let which = if key == IO_INPUT { "input" } else { "output" };
+            Err(state.error(format!("standard {which} file is closed")))
+        }
+    }
+}
+
+/// io.read(...)
+fn io_read(state: &mut LuaState) -> Result<usize> {
+    let u = io_file(state, IO_INPUT)?;
+    g_read(state, u, 1)
+}
+
+/// write the arguments from `first` on (g_write)
+fn g_write(state: &mut LuaState, u: Gc<Userdata>, first: usize) -> Result<usize> {
+    let mut res = Ok(());
+    for n in first..=state.get_top() {
+        let bytes = match state.arg(n).value() {
+            Value::Number(_) | Value::Integer(_) => state.to_str_bytes(&state.arg(n)).unwrap(),
+            _ => {
+                let s = state.check_string(n)?;
+                state.str_bytes(s).to_vec()
+            }
+        };
+        if res.is_ok() {
+            res = file_mut(state, u).with_writer(|w| w.write_all(&bytes));
+        }
+    }
+    Ok(push_result(state, res, None))
+}
+
+/// file:write(...)
+fn f_write(state: &mut LuaState) -> Result<usize> {
+    let u = check_open_file(state, 1)?;
+    g_write(state, u, 2)
+}
+
+/// io.write(...)
+fn io_write(state: &mut LuaState) -> Result<usize> {
+    let u = io_file(state, IO_OUTPUT)?;
+    g_write(state, u, 1)
+}
+
+/// file:flush()
+fn f_flush(state: &mut LuaState) -> Result<usize> {
+    let u = check_open_file(state, 1)?;
+    let res = file_mut(state, u).with_writer(|w| w.flush());
+    Ok(push_result(state, res, None))
+}
+
+/// io.flush()
+fn io_flush(state: &mut LuaState) -> Result<usize> {
+    let u = io_file(state, IO_OUTPUT)?;
+    let res = file_mut(state, u).with_writer(|w| w.flush());
+    Ok(push_result(state, res, None))
+}
+
+/// file:seek([whence [, offset]])
+fn f_seek(state: &mut LuaState) -> Result<usize> {
+    const MODES: [&str; 3] = ["set", "cur", "end"];
+    let u = check_open_file(state, 1)?;
+    let whence = state.check_option(2, Some("cur"), &MODES)?;
+    let offset = state.opt_integer(3, 0)?;
+    let pos = match whence {
+        0 => SeekFrom::Start(offset.max(0) as u64),
+        1 => SeekFrom::Current(offset),
+        _ => SeekFrom::End(offset),
+    };
+    let res = match file_mut(state, u).stream() {
+        Ok(Stream::File(r)) => r.seek(pos),
+        Ok(_) => Err(io::Error::from_raw_os_error(29)),
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(pos) => {
+            state.push(TValue::number(pos as LuaNumber));
+            Ok(1)
+        }
+        Err(e) => Ok(push_error(state, &e, None)),
+    }
+}
+
+/// file:setvbuf(mode [, size]); writes are not buffered, so only the mode is checked
+fn f_setvbuf(state: &mut LuaState) -> Result<usize> {
+    const MODES: [&str; 3] = ["no", "full", "line"];
+    check_open_file(state, 1)?;
+    state.check_option(2, None, &MODES)?;
+    state.opt_integer(3, 0)?;
+    Ok(push_result(state, Ok(()), None))
+}
+
+/// iterator of lines; upvalues are the file and whether to close it at the end
+fn io_readline(state: &mut LuaState) -> Result<usize> {
+    let file = state.upvalue(1);
+    let Some(u) = to_file(state, &file) else {
+        unreachable!("lines without its file")
+    };
+    if file_mut(state, u).0.is_none() {
+        return Err(state.error("file is already closed".to_string()));
+    }
+    match file_mut(state, u).with_reader(read_line) {
+        Ok(Some(line)) => {
+            let line = state.intern(&line);
+            state.push(line);
+            Ok(1)
+        }
+        Ok(None) => {
+            if !crate::vm::is_false(&state.upvalue(2)) {
+                file_mut(state, u).0 = None;
+            }
+            Ok(0)
+        }
+        Err(e) => Err(state.error(error_message(&e))),
+    }
+}
+
+fn aux_lines(state: &mut LuaState, file: TValue, to_close: bool) -> usize {
+    let f = state.new_native_closure(io_readline, vec![file, TValue::boolean(to_close)]);
+    state.push(f);
+    1
+}
+
+/// file:lines()
+fn f_lines(state: &mut LuaState) -> Result<usize> {
+    check_open_file(state, 1)?;
+    let file = state.arg(1);
+    Ok(aux_lines(state, file, false))
+}
+
+/// io.lines([filename])
+fn io_lines(state: &mut LuaState) -> Result<usize> {
+    if matches!(state.arg(1).value(), Value::Nil) {
+        let u = io_file(state, IO_INPUT)?;
+        return Ok(aux_lines(state, TValue::userdata(u), false));
+    }
+    let name = state.check_string(1)?;
+    let name = state.str_bytes(name).to_vec();
+    match open_file(access(state), &name, b"r") {
+        Ok(f) => {
+            let file = new_file(state, Stream::File(BufReader::new(f)));
+            Ok(aux_lines(state, file, true))
+        }
+        Err(e) => {
+            let msg = format!("{}: {}", String::from_utf8_lossy(&name), error_message(&e));
+            Err(state.arg_error(1, &msg))
+        }
+    }
+}
+
+/// io.input([file]) and io.output([file]) (g_iofile)
+fn g_iofile(state: &mut LuaState, key: &[u8], mode: &[u8]) -> Result<usize> {
+    let arg = state.arg(1);
+    if !matches!(arg.value(), Value::Nil) {
+        let file = match arg.value() {
+            Value::String(s) => {
+                let name = state.str_bytes(s).to_vec();
+                match open_file(access(state), &name, mode) {
+                    Ok(f) => new_file(state, Stream::File(BufReader::new(f))),
+                    Err(e) => {
+                        let msg =
+                            format!("{}: {}", String::from_utf8_lossy(&name), error_message(&e));
+                        return Err(state.arg_error(1, &msg));
+                    }
+                }
+            }
+            _ => {
+                check_open_file(state, 1)?;
+                arg
+            }
+        };
+        registry_set(state, key, file);
+    }
+    let f = registry_get(state, key);
+    state.push(f);
+    Ok(1)
+}
+
+/// io.input([file])
+fn io_input(state: &mut LuaState) -> Result<usize> {
+    g_iofile(state, IO_INPUT, b"r")
+}
+
+/// io.output([file])
+fn io_output(state: &mut LuaState) -> Result<usize> {
+    g_iofile(state, IO_OUTPUT, b"w")
+}
+
+/// Open the `io` library; `access` limits which files scripts can open.
+pub fn open_io(state: &mut LuaState, access: SystemAccess) {
+    // metatable of file handles, which is also their method table
+    let mt = state.new_table();
+    registry_set(state, FILE_HANDLE, TValue::table(mt));
+    let methods: [(&[u8], NativeFn); 9] = [
+        (b"close", f_close),
+        (b"flush", f_flush),
+        (b"lines", f_lines),
+        (b"read", f_read),
+        (b"seek", f_seek),
+        (b"setvbuf", f_setvbuf),
+        (b"write", f_write),
+        (b"__gc", io_gc),
+        (b"__tostring", io_tostring),
+    ];
+    for (name, func) in methods {
+        let key = state.global.heap.intern(name);
+        let f = state.new_native(func);
+        state.global.heap.get_mut(mt).set_str(key, f);
+    }
+    let key = state.global.heap.intern(b"__index");
+    state
+        .global
+        .heap
+        .get_mut(mt)
+        .set_str(key, TValue::table(mt));
+    // the io functions share the access rights as an upvalue
+    let access = TValue::userdata(state.new_userdata(Box::new(access)));
+    let lib = state.register_lib("io", &[]);
+    let funcs: [(&[u8], NativeFn); 9] = [
+        (b"close", io_close),
+        (b"flush", io_flush),
+        (b"input", io_input),
+        (b"lines", io_lines),
+        (b"open", io_open),
+        (b"output", io_output),
+        (b"read", io_read),
+        (b"type", io_type),
+        (b"write", io_write),
+    ];
+    for (name, func) in funcs {
+        let key = state.global.heap.intern(name);
+        let f = state.new_native_closure(func, vec![access]);
+        state.global.heap.get_mut(lib).set_str(key, f);
+    }
+    let streams = [
+        (b"stdin" as &[u8], Stream::Stdin, Some(IO_INPUT)),
+        (b"stdout", Stream::Stdout, Some(IO_OUTPUT)),
+        (b"stderr", Stream::Stderr, None),
+    ];
+    for (name, stream, default) in streams {
+        let file = new_file(state, stream);
+        let key = state.global.heap.intern(name);
+        state.global.heap.get_mut(lib).set_str(key, file);
+        if let Some(default) = default {
+            registry_set(state, default, file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::baselib::open_base;
+    use crate::baselib::tests::{call_value, show};
+    use pretty_assertions::assert_eq;
+
+    fn field(state: &mut LuaState, t: TValue, name: &str) -> TValue {
+        let key = state.intern(name.as_bytes());
+        state.get_table(t, key, None).unwrap()
+    }
+
+    fn call_method(state: &mut LuaState, file: TValue, name: &str, args: &[&str]) -> Vec<String> {
+        let method = field(state, file, name);
+        let mut argv = vec![file];
+        for a in args {
+            let v = match a.parse::<f64>() {
+                Ok(n) => TValue::number(n),
+                Err(_) => state.intern(a.as_bytes()),
+            };
+            argv.push(v);
+        }
+        let res = call_value(state, method, &argv);
+        show(state, &res)
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        open_io(&mut state, SystemAccess::FULL);
+        let path = std::env::temp_dir().join(format!("mini_lua_io_{}", std::process::id()));
+        let io = state.get_global("io");
+        let open = field(&mut state, io, "open");
+        let name = state.intern(path.to_str().unwrap().as_bytes());
+        let mode = state.intern(b"w+");
+        let file = call_value(&mut state, open, &[name, mode])[0];
+
+        let res = call_method(
+            &mut state,
+            file,
+            "write",
+            &["first line\n", "42", " 0x10 rest"],
+        );
+        assert_eq!(res, vec!["boolean"]);
+        assert_eq!(call_method(&mut state, file, "seek", &["set"]), vec!["0"]);
+        let res = call_method(
+            &mut state,
+            file,
+            "read",
+            &["*l", "*n", "*n", "3", "*a", "*l"],
+        );
+        assert_eq!(res, vec!["first line", "42", "16", " re", "st", "nil"]);
+        assert_eq!(call_method(&mut state, file, "seek", &["end"]), vec!["23"]);
+        assert_eq!(call_method(&mut state, file, "close", &[]), vec!["boolean"]);
+
+        let io_type = field(&mut state, io, "type");
+        let res = call_value(&mut state, io_type, &[file]);
+        assert_eq!(show(&state, &res), vec!["closed file"]);
+        let read = field(&mut state, file, "read");
+        let func = state.top;
+        state.push(read);
+        state.push(file);
+        let err = state.pcall(func, 0, None).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to use a closed file");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_safe_access_denies_files() {
+        let mut state = LuaState::new();
+        state.open_libs(SystemAccess::SAFE);
+        let io = state.get_global("io");
+        let open = field(&mut state, io, "open");
+        let name = state.intern(b"/etc/hostname");
+        let res = call_value(&mut state, open, &[name]);
+        assert_eq!(
+            show(&state, &res),
+            vec!["nil", "/etc/hostname: Permission denied", "13"]
+        );
+        let stdout = field(&mut state, io, "stdout");
+        let res = call_method(&mut state, stdout, "close", &[]);
+        assert_eq!(res, vec!["nil", "cannot close standard file"]);
+        // nor can chunks be loaded from files
+        let loadfile = state.get_global("loadfile");
+        let res = call_value(&mut state, loadfile, &[name]);
+        assert_eq!(
+            show(&state, &res),
+            vec!["nil", "cannot open /etc/hostname: Permission denied"]
+        );
+        let err = state.exec::<()>("dofile('/etc/hostname')").unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("cannot open /etc/hostname: Permission denied")
+        );
+    }
+}
@@ -0,0 +1,624 @@
+//! The `json` module: `json.encode(value [, opts])` and `json.decode(s)`.
+//!
+//! Tables whose keys are all positive integers are arrays, with holes
+//! encoded as `null`; other tables are objects, written with their keys in
+//! sorted order so that the output is deterministic. `json.null` stands for
+//! JSON `null` in both directions. An empty table is an empty object.
+
+use anyhow::Result;
+
+use crate::eval::{LuaInteger, LuaNumber, MAX_EXACT_INTEGER, TValue, Value};
+use crate::heap::Gc;
+use crate::table::Table;
+use crate::userdata::{UserData, UserDataMethods};
+use crate::vm::{LuaState, number2str, type_name};
+
+/// nesting limit of encoded and decoded values
+const MAX_DEPTH: usize = 1000;
+/// An array may have holes unless its largest index is above both this and
+/// twice its number of elements (cjson's defaults).
+const SPARSE_SAFE: i64 = 10;
+const SPARSE_RATIO: i64 = 2;
+
+/// the `json.null` sentinel
+struct Null;
+
+impl UserData for Null {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_meta_method("__tostring", |_, _, ()| Ok("null"));
+    }
+}
+
+fn is_null(val: &TValue, null: &TValue) -> bool {
+    matches!((val.value(), null.value()), (Value::UserData(a), Value::UserData(b)) if a == b)
+}
+
+struct Encoder<'a> {
+    state: &'a LuaState,
+    null: TValue,
+    /// pretty-printing indentation of one level
+    indent: Option<Vec<u8>>,
+    /// encode excessively sparse arrays as objects instead of failing
+    sparse_as_object: bool,
+    /// tables being encoded, to detect recursion
+    path: Vec<Gc<Table>>,
+    out: Vec<u8>,
+}
+
+impl Encoder<'_> {
+    fn newline(&mut self) {
+        if let Some(indent) = &self.indent {
+            self.out.push(b'\n');
+            for _ in 0..self.path.len() {
+                self.out.extend_from_slice(indent);
+            }
+        }
+    }
+    fn string(&mut self, bytes: &[u8]) {
+        self.out.push(b'"');
+        for &c in bytes {
+            match c {
+                b'"' => self.out.extend_from_slice(b"\\\""),
+                b'\\' => self.out.extend_from_slice(b"\\\\"),
+                b'\n' => self.out.extend_from_slice(b"\\n"),
+                b'\r' => self.out.extend_from_slice(b"\\r"),
+                b'\t' => self.out.extend_from_slice(b"\\t"),
+                0x08 => self.out.extend_from_slice(b"\\b"),
+                0x0c => self.out.extend_from_slice(b"\\f"),
+                0..0x20 | 0x7f => self.out.extend_from_slice(format!("\\u{c:04x}").as_bytes()),
+                _ => self.out.push(c),
+            }
+        }
+        self.out.push(b'"');
+    }
+    fn value(&mut self, val: TValue) -> Result<(), String> {
+        match val.value() {
+            Value::Nil => self.out.extend_from_slice(b"null"),
+            Value::Boolean(b) => self
+                .out
+                .extend_from_slice(if b { b"true" } else { b"false" }),
+            Value::Integer(n) => self.out.extend_from_slice(n.to_string().as_bytes()),
+            Value::Number(n) => {
+                if !n.is_finite() {
+                    return Err(format!("cannot encode number {}", number2str(n)));
+                }
+                // integers exactly, other numbers in the shortest form
+                // reading back as the same double
+                let text = if n.fract() == 0.0 && n.abs() <= MAX_EXACT_INTEGER as LuaNumber {
+                    (n as i64).to_string()
+                } else if (1e-5..1e17).contains(&n.abs()) {
+                    format!("{n}")
+                } else {
+                    format!("{n:e}")
+                };
+                self.out.extend_from_slice(text.as_bytes());
+            }
+            Value::String(s) => self.string(self.state.str_bytes(s)),
+            Value::Table(t) => self.table(t)?,
+            _ if is_null(&val, &self.null) => self.out.extend_from_slice(b"null"),
+            _ => return Err(format!("cannot encode a {} value", type_name(&val))),
+        }
+        Ok(())
+    }
+    /// `Some(n)` if `entries` are those of an array of `n` elements
+    fn array_len(&self, entries: &[(TValue, TValue)]) -> Result<Option<i64>, String> {
+        let mut max = 0;
+        for (k, _) in entries {
+            match k.as_number() {
+                Some(n) if n.fract() == 0.0 && n >= 1.0 => max = max.max(n as i64),
+                _ => return Ok(None),
+            }
+        }
+        let count = entries.len() as i64;
+        if max > SPARSE_SAFE && max > count * SPARSE_RATIO {
+            if self.sparse_as_object {
+                return Ok(None);
+            }
+            return Err("cannot encode excessively sparse array".to_string());
+        }
+        Ok(if count == 0 { None } else { Some(max) })
+    }
+    fn table(&mut self, t: Gc<Table>) -> Result<(), String> {
+        if self.path.contains(&t) {
+            return Err("cannot encode recursive table".to_string());
+        }
+        if self.path.len() >= MAX_DEPTH {
+            return Err("cannot encode table: too deeply nested".to_string());
+        }
+        let table = self.state.global.heap.get(t);
+        let entries: Vec<(TValue, TValue)> = table.entries().collect();
+        let array_len = self.array_len(&entries)?;
+        self.path.push(t);
+        if let Some(n) = array_len {
+            self.out.push(b'[');
+            for i in 1..=n {
+                if i > 1 {
+                    self.out.push(b',');
+                }
+                self.newline();
+                let v = self.state.global.heap.get(t).get_int(i);
+                self.value(v)?;
+            }
+            self.path.pop();
+            self.newline();
+            self.out.push(b']');
+            return Ok(());
+        }
+        let mut fields = Vec::with_capacity(entries.len());
+        for (k, v) in entries {
+            let key = match k.value() {
+                Value::String(s) => self.state.str_bytes(s).to_vec(),
+                Value::Number(_) | Value::Integer(_) => self.state.to_str_bytes(&k).unwrap(),
+                _ => {
+                    return Err(format!("cannot encode table with a {} key", type_name(&k)));
+                }
+            };
+            fields.push((key, v));
+        }
+        fields.sort_by(|a, b| a.0.cmp(&b.0));
+        self.out.push(b'{');
+        for (i, (k, v)) in fields.iter().enumerate() {
+            if i > 0 {
+                self.out.push(b',');
+            }
+            self.newline();
+            self.string(k);
+            self.out.push(b':');
+            if self.indent.is_some() {
+                self.out.push(b' ');
+            }
+            self.value(*v)?;
+        }
+        self.path.pop();
+        if !fields.is_empty() {
+            self.newline();
+        }
+        self.out.push(b'}');
+        Ok(())
+    }
+}
+
+/// option `name` of the table `opts`, raw
+fn option(state: &mut LuaState, opts: Option<Gc<Table>>, name: &str) -> TValue {
+    match opts {
+        Some(opts) => {
+            let key = state.global.heap.intern(name.as_bytes());
+            state.global.heap.get(opts).get_str(key)
+        }
+        None => TValue::nil(),
+    }
+}
+
+/// json.encode(value [, opts]): `opts.indent` (a number of spaces or a
+/// string) pretty-prints, `opts.sparse = "object"` writes excessively sparse
+/// arrays as objects instead of failing.
+fn json_encode(state: &mut LuaState) -> Result<usize> {
+    let val = state.check_any(1)?;
+    let opts = match state.arg(2).value() {
+        Value::Nil => None,
+        _ => Some(state.check_table(2)?),
+    };
+    let indent = option(state, opts, "indent");
+    let indent = match indent.value() {
+        Value::Nil => None,
+        Value::Integer(_) | Value::Number(_) => {
+            let n = indent.as_number().unwrap_or(0.0);
+            Some(vec![b' '; n.max(0.0) as usize])
+        }
+        Value::String(s) => Some(state.str_bytes(s).to_vec()),
+        _ => return Err(state.arg_error(2, "'indent' must be a number or a string")),
+    };
+    let sparse = option(state, opts, "sparse");
+    let sparse_as_object = match state.to_str_bytes(&sparse).as_deref() {
+        None if matches!(sparse.value(), Value::Nil) => false,
+        Some(b"error") => false,
+        Some(b"object") => true,
+        _ => return Err(state.arg_error(2, "'sparse' must be \"error\" or \"object\"")),
+    };
+    let mut encoder = Encoder {
+        state,
+        null: state.upvalue(1),
+        indent,
+        sparse_as_object,
+        path: Vec::new(),
+        out: Vec::new(),
+    };
+    match encoder.value(val) {
+        Ok(()) => {
+            let s = state.intern(&encoder.out);
+            state.push(s);
+            Ok(1)
+        }
+        Err(msg) => Err(state.error(msg)),
+    }
+}
+
+struct Decoder<'a> {
+    state: &'a mut LuaState,
+    null: TValue,
+    input: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Decoder<'_> {
+    /// "invalid JSON at line l, column c: msg" for the current position
+    fn error(&self, msg: &str) -> String {
+        let before = &self.input[..self.pos.min(self.input.len())];
+        let line = before.iter().filter(|&&c| c == b'\n').count() + 1;
+        let column = before.len()
+            - before
+                .iter()
+                .rposition(|&c| c == b'\n')
+                .map_or(0, |i| i + 1)
+            + 1;
+        format!("invalid JSON at line {line}, column {column}: {msg}")
+    }
+    fn unexpected(&self) -> String {
+        match self.input.get(self.pos) {
+            Some(&c) if c.is_ascii_graphic() => {
+                self.error(&format!("unexpected character '{}'", c as char))
+            }
+            Some(&c) => self.error(&format!("unexpected byte 0x{c:02x}")),
+            None => self.error("unexpected end of input"),
+        }
+    }
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+    fn skip_space(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        self.skip_space();
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("expected '{}'", c as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+    fn literal(&mut self, word: &[u8], val: TValue) -> Result<TValue, String> {
+        if self.input[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            Ok(val)
+        } else {
+            Err(self.unexpected())
+        }
+    }
+    fn value(&mut self) -> Result<TValue, String> {
+        self.skip_space();
+        match self.peek() {
+            Some(b'{') => self.nested(Self::object),
+            Some(b'[') => self.nested(Self::array),
+            Some(b'"') => {
+                let s = self.string()?;
+                Ok(self.state.intern(&s))
+            }
+            Some(b't') => self.literal(b"true", TValue::boolean(true)),
+            Some(b'f') => self.literal(b"false", TValue::boolean(false)),
+            Some(b'n') => self.literal(b"null", self.null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(self.unexpected()),
+        }
+    }
+    fn nested(&mut self, f: fn(&mut Self) -> Result<TValue, String>) -> Result<TValue, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("too deeply nested"));
+        }
+        self.depth += 1;
+        let val = f(self);
+        self.depth -= 1;
+        val
+    }
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+    fn number(&mut self) -> Result<TValue, String> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        let int_start = self.pos;
+        match self.digits() {
+            0 => return Err(self.unexpected()),
+            n if n > 1 && self.input[int_start] == b'0' => {
+                self.pos = int_start + 1;
+                return Err(self.error("leading zero in number"));
+            }
+            _ => {}
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if self.digits() == 0 {
+                return Err(self.unexpected());
+            }
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if self.digits() == 0 {
+                return Err(self.unexpected());
+            }
+        }
+        let text = std::str::from_utf8(&self.input[start..self.pos]).unwrap();
+        // integers without fraction or exponent keep the integer variant
+        if let Ok(n) = text.parse::<LuaInteger>() {
+            return Ok(TValue::integer(n));
+        }
+        match text.parse() {
+            Ok(n) => Ok(TValue::number(n)),
+            Err(_) => {
+                self.pos = start;
+                Err(self.error("invalid number"))
+            }
+        }
+    }
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.input.get(self.pos..self.pos + 4);
+        match digits.and_then(|d| u32::from_str_radix(std::str::from_utf8(d).ok()?, 16).ok()) {
+            Some(n) => {
+                self.pos += 4;
+                Ok(n)
+            }
+            None => Err(self.error("invalid unicode escape")),
+        }
+    }
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        self.pos += 1;
+        let mut s = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                Some(0..0x20) => return Err(self.error("control character in string")),
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = match self.peek() {
+                        Some(b'"') => b'"',
+                        Some(b'\\') => b'\\',
+                        Some(b'/') => b'/',
+                        Some(b'b') => 0x08,
+                        Some(b'f') => 0x0c,
+                        Some(b'n') => b'\n',
+                        Some(b'r') => b'\r',
+                        Some(b't') => b'\t',
+                        Some(b'u') => {
+                            self.pos += 1;
+                            let c = self.unicode_escape()?;
+                            s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                            continue;
+                        }
+                        _ => return Err(self.error("invalid escape in string")),
+                    };
+                    self.pos += 1;
+                    s.push(c);
+                }
+                Some(c) => {
+                    self.pos += 1;
+                    s.push(c);
+                }
+            }
+        }
+    }
+    /// the character of `\uXXXX`, or of a surrogate pair `\uXXXX\uXXXX`
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let hi = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&hi) {
+            if !self.input[self.pos..].starts_with(b"\\u") {
+                return Err(self.error("missing low surrogate"));
+            }
+            self.pos += 2;
+            let lo = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&lo) {
+                return Err(self.error("invalid low surrogate"));
+            }
+            0x10000 + ((hi - 0xd800) << 10) + (lo - 0xdc00)
+        } else {
+            hi
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+    fn array(&mut self) -> Result<TValue, String> {
+        self.pos += 1;
+        let t = self.state.new_table();
+        self.skip_space();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(TValue::table(t));
+        }
+        let mut n = 0;
+        loop {
+            let v = self.value()?;
+            n += 1;
+            self.state.global.heap.get_mut(t).set_int(n, v);
+            self.skip_space();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(TValue::table(t));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+    fn object(&mut self) -> Result<TValue, String> {
+        self.pos += 1;
+        let t = self.state.new_table();
+        self.skip_space();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(TValue::table(t));
+        }
+        loop {
+            self.skip_space();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected string key"));
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            let v = self.value()?;
+            let key = self.state.global.heap.intern(&key);
+            self.state.global.heap.get_mut(t).set_str(key, v);
+            self.skip_space();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(TValue::table(t));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+}
+
+/// json.decode(s)
+fn json_decode(state: &mut LuaState) -> Result<usize> {
+    let s = state.check_string(1)?;
+    let input = state.str_bytes(s).to_vec();
+    let null = state.upvalue(1);
+    let mut decoder = Decoder {
+        state,
+        null,
+        input: &input,
+        pos: 0,
+        depth: 0,
+    };
+    let result = decoder.value().and_then(|v| {
+        decoder.skip_space();
+        match decoder.peek() {
+            None => Ok(v),
+            Some(_) => Err(decoder.error("expected end of input")),
+        }
+    });
+    match result {
+        Ok(v) => {
+            state.push(v);
+            Ok(1)
+        }
+        Err(msg) => Err(state.error(msg)),
+    }
+}
+
+pub fn open_json(state: &mut LuaState) {
+    let null = state.create_userdata(Null);
+    let encode = state.new_native_closure(json_encode, vec![null]);
+    let decode = state.new_native_closure(json_decode, vec![null]);
+    let lib = state.register_lib("json", &[]);
+    for (name, v) in [("encode", encode), ("decode", decode), ("null", null)] {
+        let key = state.global.heap.intern(name.as_bytes());
+        state.global.heap.get_mut(lib).set_str(key, v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::iolib::SystemAccess;
+    use crate::vm::LuaState;
+    use pretty_assertions::assert_eq;
+
+    fn lua() -> LuaState {
+        let mut lua = LuaState::new();
+        lua.open_libs(SystemAccess::SAFE);
+        lua
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut lua = lua();
+        let s: String = lua
+            .exec(
+                r#"return json.encode({b = {1, 2.5, nil, "x\n"}, a = true, c = json.null, d = {}})"#,
+            )
+            .unwrap();
+        assert_eq!(s, r#"{"a":true,"b":[1,2.5,null,"x\n"],"c":null,"d":{}}"#);
+        let s: String = lua
+            .exec("return json.encode({list = {1, {}}, n = 0}, {indent = 2})")
+            .unwrap();
+        assert_eq!(s, "{\n  \"list\": [\n    1,\n    {}\n  ],\n  \"n\": 0\n}");
+        let s: String = lua
+            .exec("return json.encode({[1] = 1, [20] = 2}, {sparse = 'object'})")
+            .unwrap();
+        assert_eq!(s, r#"{"1":1,"20":2}"#);
+        // numbers survive a round trip
+        let (s, same): (String, bool) = lua
+            .exec(
+                "local t = {123456789012345, 0.1 + 0.2, 2^53 + 2, 1e300, -1.5e-7}
+                 local back = json.decode(json.encode(t))
+                 for i, v in ipairs(t) do if back[i] ~= v then return json.encode(t), false end end
+                 return json.encode(t), true",
+            )
+            .unwrap();
+        assert!(same, "{s}");
+        let types: String = lua
+            .exec(
+                "local t = json.decode('[1, 1.0, 2e0, 9007199254740993]')
+                   return math.type(t[1]) .. math.type(t[2]) .. math.type(t[3]) .. math.type(t[4])",
+            )
+            .unwrap();
+        assert_eq!(types, "integerfloatfloatinteger");
+        assert_eq!(
+            s,
+            "[123456789012345,0.30000000000000004,9007199254740994,1e300,-1.5e-7]"
+        );
+        for (source, msg) in [
+            (
+                "json.encode({[1] = 1, [20] = 2})",
+                "cannot encode excessively sparse array",
+            ),
+            (
+                "local t = {} t[1] = t json.encode(t)",
+                "cannot encode recursive table",
+            ),
+            ("json.encode({print})", "cannot encode a function value"),
+            ("json.encode(0/0)", "cannot encode number nan"),
+        ] {
+            let err = lua.exec::<()>(source).unwrap_err();
+            assert!(err.to_string().ends_with(msg), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut lua = lua();
+        let s: String = lua
+            .exec(
+                r#"
+                local v = json.decode(' {"a": [1, -2.5e1, null, "é😀"], "b": {"c": false}} ')
+                assert(v.a[3] == json.null and tostring(json.null) == "null")
+                return table.concat({v.a[1], v.a[2], v.a[4], tostring(v.b.c), #v.a}, " ")
+                "#,
+            )
+            .unwrap();
+        assert_eq!(s, "1 -25 é😀 false 4");
+        let s: String = lua
+            .exec(r#"return json.encode(json.decode('{"k":[{"x":null}]}'))"#)
+            .unwrap();
+        assert_eq!(s, r#"{"k":[{"x":null}]}"#);
+        for (input, msg) in [
+            ("{\"a\": 1,\n  \"b\" 2}", "line 2, column 7: expected ':'"),
+            ("[1, 2", "line 1, column 6: expected ',' or ']'"),
+            ("[01]", "line 1, column 3: leading zero in number"),
+            ("\"abc", "line 1, column 5: unterminated string"),
+            ("{} x", "line 1, column 4: expected end of input"),
+            ("[tru]", "line 1, column 2: unexpected character 't'"),
+        ] {
+            lua.set_global("input", input).unwrap();
+            let err = lua.exec::<()>("json.decode(input)").unwrap_err();
+            let expected = format!("invalid JSON at {msg}");
+            assert!(err.to_string().ends_with(&expected), "{err}");
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! A Lua 5.1 virtual machine, compiler and standard library.
+//!
+//! Everything hangs off [`LuaState`]. The host API loads and runs chunks,
+//! reads and writes globals and calls Lua functions, converting arguments and
+//! results with [`ToLua`] and [`FromLua`]:
+//!
+//! ```
+//! use mini_lua::{LuaState, SystemAccess};
+//!
+//! let mut lua = LuaState::new();
+//! lua.open_libs(SystemAccess::SAFE);
+//! lua.exec::<()>("function greet(name) return 'hello ' .. name, #name end")
+//!     .unwrap();
+//! let greet = lua.get_global("greet");
+//! let (msg, len): (String, i64) = lua.call_function(&greet, "lua").unwrap();
+//! assert_eq!((msg.as_str(), len), ("hello lua", 3));
+//!
+//! lua.set_global("limit", 10).unwrap();
+//! let n: f64 = lua.exec("return limit / 4").unwrap();
+//! assert_eq!(n, 2.5);
+//! let err = lua.exec::<()>("error('boom')").unwrap_err();
+//! assert_eq!(err.to_string(), "[string \"error('boom')\"]:1: boom");
+//! ```
+//!
+//! Values handed to the host are not rooted: they stay valid while they are
+//! reachable from Lua (globals, the registry) or until Lua code runs again.
+//! [`RegistryKey`]s made by `create_registry_value` keep them alive. Handing
+//! back a value which was collected fails with a [`LuaError`].
+//!
+//! Untrusted code can be bounded with [`Limits`] on instructions, memory,
+//! call depth, string length and time, stopped from another thread with an
+//! [`InterruptHandle`], and kept apart from other scripts by running it in a
+//! [`Sandbox`].
+//!
+//! Async hosts can hand futures to Lua with `create_async_function` and run
+//! scripts with `call_async`, which suspends them while a future is pending.
+//!
+//! The standard library is built on lower level, stack based helpers
+//! modelled on the C API. They are internal: only the host API is exported.
+
+mod api;
+mod asyncfn;
+mod baselib;
+mod compiler;
+mod conversion;
+mod datetime;
+mod dblib;
+mod debug;
+mod error;
+mod eval;
+mod func;
+mod gc;
+mod heap;
+mod host;
+mod iolib;
+mod jsonlib;
+mod limits;
+mod loadlib;
+mod mathlib;
+mod opcodes;
+mod oslib;
+mod parser;
+mod printf;
+mod registry;
+mod sandbox;
+mod serialize;
+mod strlib;
+mod table;
+mod tablib;
+mod thread;
+mod undump;
+mod userdata;
+mod vm;
+
+pub use conversion::{BadArgument, FromLua, FromLuaMulti, ToLua, ToLuaMulti, Variadic};
+pub use error::LuaError;
+pub use eval::{LuaType, TValue, Value};
+pub use iolib::SystemAccess;
+pub use limits::{InterruptHandle, Limits};
+pub use registry::RegistryKey;
+pub use sandbox::Sandbox;
+pub use serialize::DeserializeOptions;
+pub use userdata::{UserData, UserDataMethods};
+pub use vm::LuaState;
@@ -0,0 +1,366 @@
+//! Resource limits for running untrusted code.
+//!
+//! The VM counts down the instructions left before its next check, so that
+//! the dispatch loop only pays for a decrement and a comparison. The check
+//! counts the instructions and looks at the heap size, which is also
+//! checked at the collection safe points, the clock and the interrupt flag.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::vm::{LUAI_MAXCALLS, LuaState};
+
+/// instructions between two checks of the memory limit, clock or interrupt flag
+const CHECK_INTERVAL: u64 = 1000;
+
+/// Resource limits of a state, `None` meaning unlimited. An exceeded limit
+/// raises an error which `pcall` and `coroutine.resume` catch only if
+/// `catchable` is set; otherwise it reaches the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    /// VM instructions executed since the limits were set
+    pub instructions: Option<u64>,
+    /// bytes in use by the Lua heap
+    pub memory: Option<usize>,
+    /// nested calls of a thread, Lua and native ones
+    pub call_depth: Option<usize>,
+    /// bytes of a string built by concatenation or the string functions
+    pub string_length: Option<usize>,
+    /// wall-clock time of each call made by the host (`exec`,
+    /// `call_function`, `call_async`)
+    pub timeout: Option<Duration>,
+    pub catchable: bool,
+}
+
+/// Handle stopping the scripts of a state from another thread, made by
+/// `interrupt_handle`.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    /// Make the running script, or the next one, fail with "interrupted" at
+    /// its next check of the limits.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Instruction accounting: `countdown` is decremented by the VM for every
+/// instruction and the limits are checked when it reaches zero.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Counter {
+    pub(crate) countdown: u64,
+    /// length of the current period between two checks
+    period: u64,
+    /// instructions of the previous periods
+    counted: u64,
+    /// end of the time limit of the running call
+    deadline: Option<Instant>,
+    /// a full collection freeing memory for the limit is running
+    collecting: bool,
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Counter {
+            countdown: u64::MAX,
+            period: u64::MAX,
+            counted: 0,
+            deadline: None,
+            collecting: false,
+        }
+    }
+}
+
+impl LuaState {
+    /// Set the resource limits and restart counting instructions.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.global.limits = limits;
+        self.global.counter = Counter {
+            deadline: limits.timeout.map(|t| Instant::now() + t),
+            ..Counter::default()
+        };
+        self.start_period();
+    }
+
+    /// Start the time limit for a call made by the host. Calls made from
+    /// inside a running script share its limit.
+    pub(crate) fn start_deadline(&mut self) {
+        if self.n_ccalls == 0 {
+            self.global.counter.deadline = self.global.limits.timeout.map(|t| Instant::now() + t);
+        }
+    }
+
+    /// Handle for other threads to interrupt the scripts of this state.
+    /// The VM checks for an interrupt every few instructions from then on.
+    pub fn interrupt_handle(&mut self) -> InterruptHandle {
+        let flag = match &self.global.interrupt {
+            Some(flag) => flag.clone(),
+            None => {
+                let flag = Arc::new(AtomicBool::new(false));
+                self.global.interrupt = Some(flag.clone());
+                // end the current period now to shorten the following ones
+                let c = &mut self.global.counter;
+                c.counted += c.period - c.countdown;
+                self.start_period();
+                flag
+            }
+        };
+        InterruptHandle { flag }
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.global.limits
+    }
+
+    /// VM instructions executed since the limits were last set
+    pub fn instruction_count(&self) -> u64 {
+        let c = &self.global.counter;
+        c.counted + (c.period - c.countdown)
+    }
+
+    fn start_period(&mut self) {
+        let g = &mut self.global;
+        let mut period = match g.limits.instructions {
+            // the check runs before the instruction which would exceed the limit
+            Some(max) => (max + 1).saturating_sub(g.counter.counted).max(1),
+            None => u64::MAX,
+        };
+        if g.limits.memory.is_some() || g.limits.timeout.is_some() || g.interrupt.is_some() {
+            period = period.min(CHECK_INTERVAL);
+        }
+        g.counter.period = period;
+        g.counter.countdown = period;
+    }
+
+    /// run by the VM when the countdown reaches zero
+    pub(crate) fn check_limits(&mut self) -> Result<()> {
+        let c = &mut self.global.counter;
+        c.counted = c.counted.saturating_add(c.period);
+        if let Some(max) = self.global.limits.instructions
+            && self.global.counter.counted > max
+        {
+            // the current instruction does not run
+            self.global.counter.counted -= 1;
+            self.start_period();
+            return Err(self.limit_error("instruction limit exceeded"));
+        }
+        self.start_period();
+        if let Some(flag) = &self.global.interrupt
+            && flag.swap(false, Ordering::Relaxed)
+        {
+            return Err(self.limit_error("interrupted"));
+        }
+        if let Some(deadline) = self.global.counter.deadline
+            && Instant::now() >= deadline
+        {
+            return Err(self.limit_error("time limit exceeded"));
+        }
+        self.check_memory()
+    }
+
+    /// Fail if the heap is above the memory limit even after a full collection.
+    pub(crate) fn check_memory(&mut self) -> Result<()> {
+        let Some(max) = self.global.limits.memory else {
+            return Ok(());
+        };
+        self.global.heap.update_written_sizes();
+        if self.global.heap.total_bytes <= max || self.global.counter.collecting {
+            return Ok(());
+        }
+        self.global.counter.collecting = true;
+        let res = self.gc_collect();
+        self.global.counter.collecting = false;
+        res?;
+        if self.global.heap.total_bytes > max {
+            return Err(self.limit_error("not enough memory"));
+        }
+        Ok(())
+    }
+
+    /// fail if a new frame would exceed the call depth (luaD_precall)
+    pub(crate) fn check_call_depth(&mut self) -> Result<()> {
+        let depth = self.base_ci.len();
+        match self.global.limits.call_depth {
+            Some(max) if depth > max.min(LUAI_MAXCALLS) => Err(self.limit_error("stack overflow")),
+            _ if depth >= LUAI_MAXCALLS => Err(self.runtime_error("stack overflow".to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// fail if a string of `len` bytes exceeds the string length limit
+    pub(crate) fn check_string_length(&mut self, len: usize) -> Result<()> {
+        match self.global.limits.string_length {
+            Some(max) if len > max => Err(self.limit_error("string length overflow")),
+            _ => Ok(()),
+        }
+    }
+
+    /// error of an exceeded limit, uncatchable unless the limits say otherwise
+    fn limit_error(&mut self, msg: &str) -> anyhow::Error {
+        let e = self.runtime_error(msg.to_string());
+        if self.global.limits.catchable {
+            return e;
+        }
+        let e = self.error_object(e).uncatchable();
+        anyhow::Error::new(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iolib::SystemAccess;
+    use pretty_assertions::assert_eq;
+
+    fn limited(limits: Limits) -> LuaState {
+        let mut lua = LuaState::new();
+        lua.open_libs(SystemAccess::SAFE);
+        lua.set_limits(limits);
+        lua
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        let mut lua = limited(Limits {
+            instructions: Some(10_000),
+            ..Limits::default()
+        });
+        let err = lua.exec::<()>("while true do end").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"while true do end\"]:1: instruction limit exceeded"
+        );
+        assert!(err.is_uncatchable());
+        assert_eq!(lua.instruction_count(), 10_000);
+        // pcall does not stop it, and the budget stays spent
+        lua.set_limits(Limits {
+            instructions: Some(10_000),
+            ..Limits::default()
+        });
+        let err = lua
+            .exec::<()>("pcall(function() while true do end end) x = 1")
+            .unwrap_err();
+        assert!(err.to_string().ends_with("instruction limit exceeded"));
+        assert!(lua.exec::<()>("return").is_err());
+
+        // pcall stops the error but the code after it has no budget left
+        let mut lua = limited(Limits {
+            instructions: Some(10_000),
+            catchable: true,
+            ..Limits::default()
+        });
+        let err = lua
+            .exec::<()>("caught = not pcall(function() while true do end end)")
+            .unwrap_err();
+        assert!(!err.is_uncatchable());
+        assert_eq!(lua.instruction_count(), 10_000);
+    }
+
+    #[test]
+    fn test_memory_and_string_limits() {
+        let mut lua = limited(Limits {
+            memory: Some(1 << 20),
+            string_length: Some(1000),
+            ..Limits::default()
+        });
+        // garbage is collected before the limit is reached
+        lua.exec::<()>("for i = 1, 100000 do local t = {i, tostring(i)} end")
+            .unwrap();
+        let err = lua
+            .exec::<()>("local t = {} for i = 1, 1e7 do t[i] = {} end")
+            .unwrap_err();
+        assert!(err.to_string().ends_with("not enough memory"), "{err}");
+        let err = lua
+            .exec::<()>("local t = {} for i = 1, 1e7 do t[i] = i end")
+            .unwrap_err();
+        assert!(err.to_string().ends_with("not enough memory"), "{err}");
+        // growth is counted whatever writes the table
+        for source in [
+            "local t = {} for i = 1, 1e7 do rawset(t, i, i) end",
+            "local t = {} for i = 1, 1e7 do table.insert(t, i) end",
+            "local t = {} for i = 1, 1e6 do t[i] = {1, 2, 3, 4, 5, 6, 7, 8} end",
+        ] {
+            let err = lua.exec::<()>(source).unwrap_err();
+            assert!(err.to_string().ends_with("not enough memory"), "{err}");
+        }
+        for source in [
+            "local s = string.rep('x', 600) return s .. s",
+            "return string.rep('x', 1001)",
+            "return table.concat({string.rep('x', 600), string.rep('x', 600)})",
+        ] {
+            let err = lua.exec::<()>(source).unwrap_err();
+            assert!(err.to_string().ends_with("string length overflow"), "{err}");
+        }
+        assert_eq!(
+            lua.exec::<usize>("return #string.rep('x', 1000)").unwrap(),
+            1000
+        );
+
+        lua.set_limits(Limits {
+            string_length: Some(1000),
+            catchable: true,
+            ..Limits::default()
+        });
+        let (ok, msg): (bool, String) = lua.exec("return pcall(string.rep, 'x', 1001)").unwrap();
+        assert!(!ok);
+        assert!(msg.ends_with("string length overflow"));
+    }
+
+    #[test]
+    fn test_interrupt_and_timeout() {
+        let mut lua = limited(Limits {
+            timeout: Some(Duration::from_millis(50)),
+            ..Limits::default()
+        });
+        let start = Instant::now();
+        let err = lua.exec::<()>("while true do end").unwrap_err();
+        assert!(err.to_string().ends_with("time limit exceeded"), "{err}");
+        assert!(err.is_uncatchable());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // every call gets the whole time
+        for _ in 0..3 {
+            lua.exec::<()>("local t = os.clock() while os.clock() - t < 0.03 do end")
+                .unwrap();
+        }
+
+        lua.set_limits(Limits::default());
+        let handle = lua.interrupt_handle();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            handle.interrupt();
+        });
+        let err = lua
+            .exec::<()>("while true do pcall(function() end) end")
+            .unwrap_err();
+        assert!(err.to_string().ends_with("interrupted"), "{err}");
+        thread.join().unwrap();
+        // the interrupt is spent
+        assert_eq!(lua.exec::<i32>("return 1").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_call_depth_limit() {
+        let mut lua = limited(Limits {
+            call_depth: Some(50),
+            catchable: true,
+            ..Limits::default()
+        });
+        let (ok, msg, depth): (bool, String, i32) = lua
+            .exec(
+                "local depth = 0
+                 local function f() depth = depth + 1 f() end
+                 local ok, msg = pcall(f)
+                 return ok, msg, depth",
+            )
+            .unwrap();
+        assert!(!ok);
+        assert!(msg.ends_with("stack overflow"));
+        assert!((45..=50).contains(&depth), "{depth}");
+    }
+}
@@ -0,0 +1,464 @@
+use anyhow::Result;
+
+use crate::eval::{TValue, Value};
+use crate::func::NativeFn;
+use crate::heap::Gc;
+use crate::iolib::SystemAccess;
+use crate::table::Table;
+use crate::vm::{LuaState, is_false};
+
+const LUA_PATH_DEFAULT: &str = "./?.lua;/usr/local/share/lua/5.1/?.lua;\
+/usr/local/share/lua/5.1/?/init.lua;/usr/local/lib/lua/5.1/?.lua;\
+/usr/local/lib/lua/5.1/?/init.lua";
+const LUA_CPATH_DEFAULT: &str =
+    "./?.so;/usr/local/lib/lua/5.1/?.so;/usr/local/lib/lua/5.1/loadall.so";
+/// directory separator, path separator, substitution mark, executable
+/// directory mark and mark to ignore the rest of a C module name
+const LUA_CONFIG: &str = "/\n;\n?\n!\n-";
+const DLL_NOT_ENABLED: &str = "dynamic libraries not enabled; check your Lua installation";
+
+fn get_field(state: &mut LuaState, t: Gc<Table>, key: &str) -> TValue {
+    let key = state.global.heap.intern(key.as_bytes());
+    state.global.heap.get(t).get_str(key)
+}
+
+fn set_field(state: &mut LuaState, t: Gc<Table>, key: &str, val: TValue) {
+    let key = state.global.heap.intern(key.as_bytes());
+    state.global.heap.get_mut(t).set_str(key, val);
+}
+
+/// the package table, upvalue 1 of the functions of this library
+fn package(state: &LuaState) -> Gc<Table> {
+    match state.upvalue(1).value() {
+        Value::Table(t) => t,
+        _ => unreachable!("package function without its table"),
+    }
+}
+
+/// string field `pname` of the package table, e.g. `package.path`
+fn package_path(state: &mut LuaState, pname: &str) -> Result<Vec<u8>> {
+    let package = package(state);
+    match get_field(state, package, pname).value() {
+        Value::String(s) => Ok(state.str_bytes(s).to_vec()),
+        _ => Err(state.error(format!("'package.{pname}' must be a string"))),
+    }
+}
+
+/// First file of `path` that can be read, with `?` replaced by `name` and
+/// dots of `name` turned into directory separators (findfile). Otherwise
+/// the list of files tried, as a message.
+fn find_file(name: &[u8], path: &[u8]) -> std::result::Result<Vec<u8>, Vec<u8>> {
+    let name: Vec<u8> = name
+        .iter()
+        .map(|&c| if c == b'.' { b'/' } else { c })
+        .collect();
+    let mut msg = Vec::new();
+    for template in path.split(|&c| c == b';').filter(|t| !t.is_empty()) {
+        let mut filename = Vec::new();
+        for (i, part) in template.split(|&c| c == b'?').enumerate() {
+            if i > 0 {
+                filename.extend_from_slice(&name);
+            }
+            filename.extend_from_slice(part);
+        }
+        let readable = std::str::from_utf8(&filename).is_ok_and(|f| {
+            std::fs::File::open(f).is_ok_and(|f| f.metadata().is_ok_and(|m| !m.is_dir()))
+        });
+        if readable {
+            return Ok(filename);
+        }
+        msg.extend_from_slice(b"\n\tno file '");
+        msg.extend_from_slice(&filename);
+        msg.push(b'\'');
+    }
+    Err(msg)
+}
+
+fn load_error(state: &mut LuaState, filename: &[u8], why: &str) -> anyhow::Error {
+    let name = state.check_string(1).map(|s| state.str_bytes(s).to_vec());
+    let name = String::from_utf8_lossy(&name.unwrap_or_default()).into_owned();
+    let filename = String::from_utf8_lossy(filename);
+    state.error(format!(
+        "error loading module '{name}' from file '{filename}':\n\t{why}"
+    ))
+}
+
+/// searcher of `package.preload`
+fn loader_preload(state: &mut LuaState) -> Result<usize> {
+    let name = state.check_string(1)?;
+    let package = package(state);
+    let Value::Table(preload) = get_field(state, package, "preload").value() else {
+        return Err(state.error("'package.preload' must be a table".to_string()));
+    };
+    let f = state.global.heap.get(preload).get_str(name);
+    if matches!(f.value(), Value::Nil) {
+        let mut msg = b"\n\tno field package.preload['".to_vec();
+        msg.extend_from_slice(state.str_bytes(name));
+        msg.extend_from_slice(b"']");
+        let msg = state.intern(&msg);
+        state.push(msg);
+    } else {
+        state.push(f);
+    }
+    Ok(1)
+}
+
+/// searcher of Lua files along `package.path`
+fn loader_lua(state: &mut LuaState) -> Result<usize> {
+    let name = state.check_string(1)?;
+    let name = state.str_bytes(name).to_vec();
+    let path = package_path(state, "path")?;
+    let filename = match find_file(&name, &path) {
+        Ok(filename) => filename,
+        Err(msg) => {
+            // library not found in this path
+            let msg = state.intern(&msg);
+            state.push(msg);
+            return Ok(1);
+        }
+    };
+    let file = String::from_utf8_lossy(&filename).into_owned();
+    match state.load_file(Some(&file)) {
+        Ok(f) => {
+            state.push(f);
+            Ok(1)
+        }
+        Err(e) => {
+            let e = state.error_object(e);
+            Err(load_error(state, &filename, &e.to_string()))
+        }
+    }
+}
+
+/// searcher of C libraries along `package.cpath`, which can only fail here
+fn loader_c(state: &mut LuaState) -> Result<usize> {
+    let name = state.check_string(1)?;
+    let name = state.str_bytes(name).to_vec();
+    search_c(state, &name)
+}
+
+/// searcher of the C library of the root of a dotted name (loader_Croot)
+fn loader_croot(state: &mut LuaState) -> Result<usize> {
+    let name = state.check_string(1)?;
+    let name = state.str_bytes(name).to_vec();
+    match name.iter().position(|&c| c == b'.') {
+        Some(dot) => search_c(state, &name[..dot]),
+        // is root
+        None => Ok(0),
+    }
+}
+
+fn search_c(state: &mut LuaState, name: &[u8]) -> Result<usize> {
+    let path = package_path(state, "cpath")?;
+    match find_file(name, &path) {
+        Ok(filename) => Err(load_error(state, &filename, DLL_NOT_ENABLED)),
+        Err(msg) => {
+            let msg = state.intern(&msg);
+            state.push(msg);
+            Ok(1)
+        }
+    }
+}
+
+/// package.loadlib(libname, funcname)
+fn ll_loadlib(state: &mut LuaState) -> Result<usize> {
+    state.check_string(1)?;
+    state.check_string(2)?;
+    state.push(TValue::nil());
+    let msg = state.intern(DLL_NOT_ENABLED.as_bytes());
+    state.push(msg);
+    let at = state.intern(b"absent");
+    state.push(at);
+    Ok(3)
+}
+
+/// require(name)
+fn ll_require(state: &mut LuaState) -> Result<usize> {
+    let name = state.check_string(1)?;
+    state.set_top(1);
+    let loaded = state.loaded_table();
+    let sentinel = state.upvalue(2);
+    let module = state.global.heap.get(loaded).get_str(name);
+    if !is_false(&module) {
+        if state.raw_equal(&module, &sentinel) {
+            let name = String::from_utf8_lossy(state.str_bytes(name)).into_owned();
+            return Err(state.error(format!("loop or previous error loading module '{name}'")));
+        }
+        // package is already loaded
+        state.push(module);
+        return Ok(1);
+    }
+    // else must load it; iterate over available loaders
+    let package = package(state);
+    let Value::Table(loaders) = get_field(state, package, "loaders").value() else {
+        return Err(state.error("'package.loaders' must be a table".to_string()));
+    };
+    // error message accumulator, kept on the stack at index 2
+    let msg = state.intern(b"");
+    state.push(msg);
+    let msg_slot = state.arg_index(2);
+    let mut i = 1;
+    let loader = loop {
+        let loader = state.global.heap.get(loaders).get_int(i);
+        if matches!(loader.value(), Value::Nil) {
+            let mut msg = b"module '".to_vec();
+            msg.extend_from_slice(state.str_bytes(name));
+            msg.extend_from_slice(b"' not found:");
+            msg.extend(state.to_str_bytes(&state.arg(2)).unwrap_or_default());
+            return Err(state.error(String::from_utf8_lossy(&msg).into_owned()));
+        }
+        let func = state.top;
+        state.push(loader);
+        state.push(TValue::string(name));
+        state.call(func, 1)?;
+        let res = state.stack[func];
+        match res.value() {
+            // loader found
+            Value::LuaClosure(_) | Value::NativeClosure(_) => break res,
+            Value::String(s) => {
+                let mut msg = state.to_str_bytes(&state.arg(2)).unwrap_or_default();
+                msg.extend_from_slice(state.str_bytes(s));
+                state.stack[msg_slot] = state.intern(&msg);
+            }
+            _ => {}
+        }
+        state.top = func;
+        i += 1;
+    };
+    // mark the module as being loaded, for circular requires
+    state.global.heap.get_mut(loaded).set_str(name, sentinel);
+    let func = state.top - 1;
+    state.stack[func] = loader;
+    state.push(TValue::string(name));
+    state.call(func, 1)?;
+    let res = state.stack[func];
+    if !matches!(res.value(), Value::Nil) {
+        state.global.heap.get_mut(loaded).set_str(name, res);
+    }
+    let module = state.global.heap.get(loaded).get_str(name);
+    if state.raw_equal(&module, &sentinel) {
+        // module did not set a value: use true
+        state
+            .global
+            .heap
+            .get_mut(loaded)
+            .set_str(name, TValue::boolean(true));
+    }
+    let res = state.global.heap.get(loaded).get_str(name);
+    state.push(res);
+    Ok(1)
+}
+
+/// set the usual fields of a new module table (modinit)
+fn mod_init(state: &mut LuaState, module: Gc<Table>, modname: &[u8]) {
+    set_field(state, module, "_M", TValue::table(module));
+    let name = state.intern(modname);
+    set_field(state, module, "_NAME", name);
+    // the package name is the module name up to its last dot, included
+    let pkg = match modname.iter().rposition(|&c| c == b'.') {
+        Some(dot) => &modname[..=dot],
+        None => b"",
+    };
+    let pkg = state.intern(pkg);
+    set_field(state, module, "_PACKAGE", pkg);
+}
+
+/// module(name [, ...])
+fn ll_module(state: &mut LuaState) -> Result<usize> {
+    let modname = state.check_string(1)?;
+    let modname = state.str_bytes(modname).to_vec();
+    let loaded = state.loaded_table();
+    let key = state.global.heap.intern(&modname);
+    let module = match state.global.heap.get(loaded).get_str(key).value() {
+        Value::Table(t) => t,
+        _ => {
+            // try global variable (and create one if it does not exist)
+            let globals = state.global.globals;
+            let name = String::from_utf8_lossy(&modname).into_owned();
+            let Ok(t) = state.find_table(globals, &name) else {
+                return Err(state.error(format!("name conflict for module '{name}'")));
+            };
+            state
+                .global
+                .heap
+                .get_mut(loaded)
+                .set_str(key, TValue::table(t));
+            t
+        }
+    };
+    // check whether table already has a _NAME field
+    if matches!(get_field(state, module, "_NAME").value(), Value::Nil) {
+        mod_init(state, module, &modname);
+    }
+    state.push(TValue::table(module));
+    // the calling function runs in the module from now on
+    let caller = state
+        .base_ci
+        .len()
+        .checked_sub(2)
+        .map(|ci| state.base_ci[ci].func);
+    let caller = caller.map(|f| state.stack[f]);
+    match caller {
+        Some(f) if matches!(f.value(), Value::LuaClosure(_)) => {
+            state.set_fenv(&f, module);
+        }
+        _ => return Err(state.error("'module' not called from a Lua function".to_string())),
+    }
+    // apply the options
+    let n = state.get_top() - 1;
+    for i in 2..=n {
+        let func = state.top;
+        state.push(state.arg(i));
+        state.push(TValue::table(module));
+        state.call(func, 0)?;
+    }
+    Ok(0)
+}
+
+/// package.seeall(module): give the module access to the globals
+fn ll_seeall(state: &mut LuaState) -> Result<usize> {
+    let module = state.check_table(1)?;
+    let obj = TValue::table(module);
+    let mt = match state.get_metatable(&obj) {
+        Some(mt) => mt,
+        None => {
+            let mt = state.new_table();
+            state.set_metatable(&obj, Some(mt));
+            mt
+        }
+    };
+    let globals = state.global.globals;
+    set_field(state, mt, "__index", TValue::table(globals));
+    Ok(0)
+}
+
+/// `package.path` or `package.cpath` from the environment variable
+/// `envname`, with ";;" standing for the default path (setpath)
+fn set_path(
+    state: &mut LuaState,
+    package: Gc<Table>,
+    fieldname: &str,
+    envname: &str,
+    default: &str,
+    access: SystemAccess,
+) {
+    let env = if access.env {
+        std::env::var(envname).ok()
+    } else {
+        None
+    };
+    let path = match env {
+        Some(path) => path.replace(";;", &format!(";{default};")),
+        None => default.to_string(),
+    };
+    let path = state.intern(path.as_bytes());
+    set_field(state, package, fieldname, path);
+}
+
+/// Open the `package` library with `require` and `module`. Without file
+/// access only `package.preload` is searched.
+pub fn open_package(state: &mut LuaState, access: SystemAccess) {
+    let package = state.register_lib("package", &[]);
+    let sentinel = TValue::userdata(state.new_userdata(Box::new(())));
+    let up = vec![TValue::table(package)];
+    let mut searchers: Vec<NativeFn> = vec![loader_preload];
+    if access.read_files {
+        searchers.extend([loader_lua as NativeFn, loader_c, loader_croot]);
+    }
+    let loaders = state.new_table();
+    for (i, searcher) in searchers.into_iter().enumerate() {
+        let f = state.new_native_closure(searcher, up.clone());
+        state.global.heap.get_mut(loaders).set_int(i as i64 + 1, f);
+    }
+    set_field(state, package, "loaders", TValue::table(loaders));
+    set_path(state, package, "path", "LUA_PATH", LUA_PATH_DEFAULT, access);
+    set_path(
+        state,
+        package,
+        "cpath",
+        "LUA_CPATH",
+        LUA_CPATH_DEFAULT,
+        access,
+    );
+    let config = state.intern(LUA_CONFIG.as_bytes());
+    set_field(state, package, "config", config);
+    let loaded = state.loaded_table();
+    set_field(state, package, "loaded", TValue::table(loaded));
+    let preload = state.new_table();
+    set_field(state, package, "preload", TValue::table(preload));
+    let loadlib = state.new_native(ll_loadlib);
+    set_field(state, package, "loadlib", loadlib);
+    let seeall = state.new_native(ll_seeall);
+    set_field(state, package, "seeall", seeall);
+    let module = state.new_native_closure(ll_module, up);
+    state.raw_set_global("module", module);
+    let require = state.new_native_closure(ll_require, vec![TValue::table(package), sentinel]);
+    state.raw_set_global("require", require);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::baselib::open_base;
+    use crate::baselib::tests::{call_value, show};
+    use pretty_assertions::assert_eq;
+
+    fn run(state: &mut LuaState, source: &str) -> Vec<String> {
+        let f = state.load_buffer(source.as_bytes(), "=test").unwrap();
+        let res = call_value(state, f, &[]);
+        show(state, &res)
+    }
+
+    #[test]
+    fn test_require() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        open_package(&mut state, SystemAccess::SAFE);
+        state.preload("counter", |state, _name: String| {
+            let t = state.new_table();
+            let opened: Option<f64> = state.global("opened")?;
+            state.set_global("opened", opened.map_or(1.0, |_| 2.0))?;
+            Ok(TValue::table(t))
+        });
+        let source = "
+            local a, b = require 'counter', require 'counter'
+            return a == b, opened, package.loaded.counter == a
+        ";
+        assert_eq!(run(&mut state, source), vec!["boolean", "1", "boolean"]);
+        let res = run(&mut state, "return pcall(require, 'missing')");
+        assert_eq!(
+            res[1],
+            "module 'missing' not found:\n\tno field package.preload['missing']"
+        );
+    }
+
+    #[test]
+    fn test_module() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        open_package(&mut state, SystemAccess::SAFE);
+        let source = "
+            package.preload['lib.util'] = function(...)
+                module(..., package.seeall)
+                function name() return _NAME, _PACKAGE, type(print) end
+            end
+            require 'lib.util'
+            return lib.util.name()
+        ";
+        assert_eq!(
+            run(&mut state, source),
+            vec!["lib.util", "lib.", "function"]
+        );
+        // a module whose loader fails cannot be required again
+        let source = "
+            package.preload.broken = function() error('oops', 0) end
+            local _, e1 = pcall(require, 'broken')
+            local _, e2 = pcall(require, 'broken')
+            return e1, e2
+        ";
+        assert_eq!(
+            run(&mut state, source),
+            vec!["oops", "loop or previous error loading module 'broken'"]
+        );
+    }
+}
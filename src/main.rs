@@ -1,43 +1,22 @@
-mod eval;
-mod opcodes;
-mod parser;
-mod undump;
-mod vm;
+use mini_lua::{LuaState, SystemAccess};
 
-use std::path::PathBuf;
-
-use anyhow::Result;
-
-use crate::undump::Constant;
-use crate::{opcodes::Instruction, undump::Undump};
-use crate::vm::{LuaState, vm_execute};
-
-fn main() -> Result<()> {
-    // let program = unindent(
-    //     "
-    // a = 1 + 2
-    // ",
-    // );
-    // let ast = parser::parse(program.as_str()).unwrap();
-    // let mut env = eval::Env::new();
-    // eval::eval_block(ast.nodes(), &mut env)?;
-    // println!("{:#?}", env);
-    // let args: Vec<String> = std::env::args().collect();
-    // let mut p = PathBuf::new();
-    // p.push(args.get(1).unwrap());
-    // let data = std::fs::read(p)?;
-    // let mut ud = Undump::new(data);
-    // ud.print();
-    let mut state = LuaState::with_consts(vec![
-        Constant::Number(100.0),
-        Constant::Number(123.0),
-    ]);
-    let insts = vec![
-        Instruction::LoadK(0x00000001),
-        Instruction::Add(0x0040404c),
-        Instruction::Return(0x0000001d),
-    ];
-    vm_execute(&mut state, insts);
-    state.print_register();
-    Ok(())
+fn main() {
+    // source or precompiled chunk, from the file given or from stdin
+    let args: Vec<String> = std::env::args().collect();
+    let mut state = LuaState::new();
+    state.open_libs(SystemAccess::FULL);
+    let main = match state.load_file(args.get(1).map(String::as_str)) {
+        Ok(main) => main,
+        Err(e) => {
+            eprintln!("mini_lua: {e}");
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = state.call_function::<_, ()>(&main, ()) {
+        eprintln!("mini_lua: {e}");
+        if let Some(traceback) = e.traceback() {
+            eprintln!("{traceback}");
+        }
+        std::process::exit(1);
+    }
 }
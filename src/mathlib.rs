@@ -0,0 +1,382 @@
+use anyhow::Result;
+
+use crate::eval::{LuaNumber, TValue, Value};
+use crate::vm::LuaState;
+
+/// C's RAND_MAX with glibc
+const RAND_MAX: u32 = 2147483647;
+
+/// The generator behind glibc's `rand()` (additive feedback, TYPE_3), so that
+/// seeded sequences are the ones reference Lua produces on Linux.
+pub struct Rand {
+    state: [u32; 31],
+    f: usize,
+    r: usize,
+}
+
+impl Rand {
+    pub fn new(seed: u32) -> Self {
+        let mut rand = Rand {
+            state: [0; 31],
+            f: 3,
+            r: 0,
+        };
+        rand.seed(seed);
+        rand
+    }
+    /// srand
+    pub fn seed(&mut self, seed: u32) {
+        let mut word = if seed == 0 { 1 } else { seed as i32 };
+        self.state[0] = word as u32;
+        for i in 1..31 {
+            let hi = word / 127773;
+            let lo = word % 127773;
+            word = 16807 * lo - 2836 * hi;
+            if word < 0 {
+                word += 2147483647;
+            }
+            self.state[i] = word as u32;
+        }
+        self.f = 3;
+        self.r = 0;
+        for _ in 0..310 {
+            self.next_u32();
+        }
+    }
+    /// rand
+    pub fn next_u32(&mut self) -> u32 {
+        self.state[self.f] = self.state[self.f].wrapping_add(self.state[self.r]);
+        let result = self.state[self.f] >> 1;
+        self.f = (self.f + 1) % 31;
+        self.r = (self.r + 1) % 31;
+        result
+    }
+}
+
+fn unary(state: &mut LuaState, f: fn(LuaNumber) -> LuaNumber) -> Result<usize> {
+    let x = state.check_number(1)?;
+    state.push(TValue::number(f(x)));
+    Ok(1)
+}
+
+fn binary(state: &mut LuaState, f: fn(LuaNumber, LuaNumber) -> LuaNumber) -> Result<usize> {
+    let x = state.check_number(1)?;
+    let y = state.check_number(2)?;
+    state.push(TValue::number(f(x, y)));
+    Ok(1)
+}
+
+fn math_abs(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::abs)
+}
+fn math_ceil(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::ceil)
+}
+fn math_floor(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::floor)
+}
+fn math_sqrt(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::sqrt)
+}
+fn math_exp(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::exp)
+}
+fn math_log(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::ln)
+}
+fn math_log10(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::log10)
+}
+fn math_sin(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::sin)
+}
+fn math_cos(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::cos)
+}
+fn math_tan(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::tan)
+}
+fn math_asin(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::asin)
+}
+fn math_acos(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::acos)
+}
+fn math_atan(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::atan)
+}
+fn math_sinh(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::sinh)
+}
+fn math_cosh(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::cosh)
+}
+fn math_tanh(state: &mut LuaState) -> Result<usize> {
+    unary(state, LuaNumber::tanh)
+}
+fn math_deg(state: &mut LuaState) -> Result<usize> {
+    unary(state, |x| x * (180.0 / std::f64::consts::PI))
+}
+fn math_rad(state: &mut LuaState) -> Result<usize> {
+    unary(state, |x| x * (std::f64::consts::PI / 180.0))
+}
+fn math_atan2(state: &mut LuaState) -> Result<usize> {
+    binary(state, LuaNumber::atan2)
+}
+fn math_fmod(state: &mut LuaState) -> Result<usize> {
+    binary(state, |x, y| x % y)
+}
+fn math_pow(state: &mut LuaState) -> Result<usize> {
+    binary(state, LuaNumber::powf)
+}
+
+/// math.modf(x): integral and fractional parts
+fn math_modf(state: &mut LuaState) -> Result<usize> {
+    let x = state.check_number(1)?;
+    let ip = x.trunc();
+    let fp = if x.is_infinite() { 0.0 } else { x - ip };
+    state.push(TValue::number(ip));
+    state.push(TValue::number(fp));
+    Ok(2)
+}
+
+/// `x` as `m * 2^e` with `0.5 <= |m| < 1`
+fn frexp(x: LuaNumber) -> (LuaNumber, i32) {
+    if x == 0.0 || !x.is_finite() {
+        return (x, 0);
+    }
+    let bits = x.to_bits();
+    let exp = ((bits >> 52) & 0x7ff) as i32;
+    if exp == 0 {
+        // subnormal: scale into the normal range first
+        let (m, e) = frexp(x * 2f64.powi(54));
+        return (m, e - 54);
+    }
+    let m = LuaNumber::from_bits((bits & !(0x7ff << 52)) | (1022 << 52));
+    (m, exp - 1022)
+}
+
+/// `m * 2^e`, without overflowing the intermediate power of two
+fn ldexp(m: LuaNumber, e: i32) -> LuaNumber {
+    let (mut m, mut e) = (m, e);
+    while e > 1000 {
+        m *= 2f64.powi(1000);
+        e -= 1000;
+    }
+    while e < -1000 {
+        m *= 2f64.powi(-1000);
+        e += 1000;
+    }
+    m * 2f64.powi(e)
+}
+
+/// math.frexp(x)
+fn math_frexp(state: &mut LuaState) -> Result<usize> {
+    let (m, e) = frexp(state.check_number(1)?);
+    state.push(TValue::number(m));
+    state.push(TValue::number(e as LuaNumber));
+    Ok(2)
+}
+
+/// math.ldexp(m, e)
+fn math_ldexp(state: &mut LuaState) -> Result<usize> {
+    let m = state.check_number(1)?;
+    let e = state.check_integer(2)?;
+    state.push(TValue::number(ldexp(m, e as i32)));
+    Ok(1)
+}
+
+/// math.min(x, ...)
+fn math_min(state: &mut LuaState) -> Result<usize> {
+    let mut min = state.check_number(1)?;
+    for i in 2..=state.get_top() {
+        let x = state.check_number(i)?;
+        if x < min {
+            min = x;
+        }
+    }
+    state.push(TValue::number(min));
+    Ok(1)
+}
+
+/// math.max(x, ...)
+fn math_max(state: &mut LuaState) -> Result<usize> {
+    let mut max = state.check_number(1)?;
+    for i in 2..=state.get_top() {
+        let x = state.check_number(i)?;
+        if x > max {
+            max = x;
+        }
+    }
+    state.push(TValue::number(max));
+    Ok(1)
+}
+
+/// the generator kept as upvalue of random and randomseed
+fn with_rand<T>(state: &mut LuaState, f: impl FnOnce(&mut Rand) -> T) -> T {
+    let u = match state.upvalue(1).value() {
+        Value::UserData(u) => u,
+        _ => unreachable!("math.random without its generator"),
+    };
+    let rand = state.global.heap.get_mut(u).data.downcast_mut::<Rand>();
+    f(rand.unwrap())
+}
+
+/// math.random([m [, n]])
+fn math_random(state: &mut LuaState) -> Result<usize> {
+    let r = with_rand(state, |rand| rand.next_u32());
+    let r = (r % RAND_MAX) as LuaNumber / RAND_MAX as LuaNumber;
+    let res = match state.get_top() {
+        0 => r,
+        1 => {
+            let u = state.check_integer(1)?;
+            if u < 1 {
+                return Err(state.arg_error(1, "interval is empty"));
+            }
+            (r * u as LuaNumber).floor() + 1.0
+        }
+        2 => {
+            let l = state.check_integer(1)?;
+            let u = state.check_integer(2)?;
+            if l > u {
+                return Err(state.arg_error(2, "interval is empty"));
+            }
+            (r * (u - l + 1) as LuaNumber).floor() + l as LuaNumber
+        }
+        _ => return Err(state.error("wrong number of arguments".to_string())),
+    };
+    state.push(TValue::number(res));
+    Ok(1)
+}
+
+/// math.randomseed(x)
+fn math_randomseed(state: &mut LuaState) -> Result<usize> {
+    let seed = state.check_integer(1)? as i32 as u32;
+    with_rand(state, |rand| rand.seed(seed));
+    Ok(0)
+}
+
+/// math.type(x): "integer", "float", or nil if `x` is not a number. Integer
+/// literals and exact integer arithmetic give integers (see `MAX_EXACT_INTEGER`).
+fn math_type(state: &mut LuaState) -> Result<usize> {
+    let v = state.check_any(1)?;
+    let res = if v.is_integer() {
+        state.intern(b"integer")
+    } else if v.is_float() {
+        state.intern(b"float")
+    } else {
+        TValue::nil()
+    };
+    state.push(res);
+    Ok(1)
+}
+
+pub fn open_math(state: &mut LuaState) {
+    let lib = state.register_lib(
+        "math",
+        &[
+            ("abs", math_abs),
+            ("acos", math_acos),
+            ("asin", math_asin),
+            ("atan", math_atan),
+            ("atan2", math_atan2),
+            ("ceil", math_ceil),
+            ("cos", math_cos),
+            ("cosh", math_cosh),
+            ("deg", math_deg),
+            ("exp", math_exp),
+            ("floor", math_floor),
+            ("fmod", math_fmod),
+            ("frexp", math_frexp),
+            ("ldexp", math_ldexp),
+            ("log", math_log),
+            ("log10", math_log10),
+            ("max", math_max),
+            ("min", math_min),
+            ("modf", math_modf),
+            ("pow", math_pow),
+            ("rad", math_rad),
+            ("sin", math_sin),
+            ("sinh", math_sinh),
+            ("sqrt", math_sqrt),
+            ("tan", math_tan),
+            ("tanh", math_tanh),
+            ("type", math_type),
+        ],
+    );
+    // random and randomseed share a generator, seeded like an unseeded rand()
+    let rand = state.new_userdata(Box::new(Rand::new(1)));
+    let fields: [(&[u8], TValue); 4] = [
+        (b"pi", TValue::number(std::f64::consts::PI)),
+        (b"huge", TValue::number(LuaNumber::INFINITY)),
+        (
+            b"random",
+            state.new_native_closure(math_random, vec![TValue::userdata(rand)]),
+        ),
+        (
+            b"randomseed",
+            state.new_native_closure(math_randomseed, vec![TValue::userdata(rand)]),
+        ),
+    ];
+    for (name, v) in fields {
+        let key = state.global.heap.intern(name);
+        state.global.heap.get_mut(lib).set_str(key, v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_rand_matches_glibc() {
+        // first values of rand() after srand(1) and srand(42) with glibc
+        let mut rand = Rand::new(1);
+        let first: Vec<u32> = (0..3).map(|_| rand.next_u32()).collect();
+        assert_eq!(first, vec![1804289383, 846930886, 1681692777]);
+        rand.seed(42);
+        assert_eq!(rand.next_u32(), 71876166);
+    }
+
+    #[test]
+    fn test_frexp_ldexp() {
+        for x in [1.0, -3.5, 1e-310, 6.02e23, 0.0] {
+            let (m, e) = frexp(x);
+            assert!(m == 0.0 || (0.5..1.0).contains(&m.abs()), "{x}");
+            assert_eq!(ldexp(m, e), x);
+        }
+        assert_eq!(frexp(8.0), (0.5, 4));
+    }
+
+    #[test]
+    fn test_math_type() {
+        let mut lua = LuaState::new();
+        lua.open_libs(crate::iolib::SystemAccess::SAFE);
+        let types: Vec<String> = lua
+            .exec(
+                "local t = {}
+                 for _, v in ipairs({1, 1.0, 1 + 1, 7 % 3, -2, 0x10, 3 / 1, 2^53,
+                                     9007199254740992 * 2, #'abc', 1e3}) do
+                     t[#t + 1] = math.type(v)
+                 end
+                 return t",
+            )
+            .unwrap();
+        assert_eq!(
+            types,
+            [
+                "integer", "float", "integer", "integer", "integer", "integer", "float", "float",
+                "float", "integer", "float"
+            ]
+        );
+        // integers print and compare like the doubles they stand for
+        let (s, eq): (String, bool) = lua.exec("return tostring(2 * 3), 1 == 1.0").unwrap();
+        assert_eq!((s.as_str(), eq), ("6", true));
+        // folded or not, a zero product or negation keeps the sign of -0
+        let inf: Vec<f64> = lua
+            .exec("local z, m = 0, -1 return {1 / -0, 1 / (0 * -1), 1 / -z, 1 / (z * m)}")
+            .unwrap();
+        assert_eq!(inf, [f64::NEG_INFINITY; 4]);
+    }
+}
@@ -1,5 +1,6 @@
 /// lua5.1
 #[rustfmt::skip]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum OpCode {
     OpMove      = 0u8,
@@ -42,11 +43,6 @@ pub enum OpCode {
     OpVarArg    = 37u8,
 }
 
-pub type OperandA = u8; // 8bit
-pub type OperandB = u16; // 9bit
-pub type OperandC = u16; // 9bit
-pub type OperandBx = u32; // 18bit
-
 pub const SIZE_OP: usize = 6;
 pub const SIZE_A: usize = 8;
 pub const SIZE_C: usize = 9;
@@ -60,6 +56,11 @@ pub const POS_B: usize = POS_C + SIZE_C;
 pub const POS_BX: usize = POS_C;
 
 pub const BITRK: usize = 1 << (SIZE_B - 1);
+pub const MAXARG_BX: usize = (1 << SIZE_BX) - 1;
+pub const MAXARG_SBX: usize = MAXARG_BX >> 1;
+
+/// number of list items to accumulate before a SETLIST instruction
+pub const LFIELDS_PER_FLUSH: usize = 50;
 
 #[rustfmt::skip]
 #[repr(u32)]
@@ -71,48 +72,174 @@ pub enum Mask {
     BX = 0b1111_1111_1111_1111_1100_0000_0000_0000,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum Instruction {
     Move(u32),
     LoadK(u32),
+    LoadBool(u32),
+    LoadNil(u32),
+    GetUpval(u32),
     GetGlobal(u32),
+    GetTable(u32),
     SetGlobal(u32),
+    SetUpval(u32),
+    SetTable(u32),
+    NewTable(u32),
+    OpSelf(u32),
     Add(u32),
+    Sub(u32),
+    Mul(u32),
+    Div(u32),
+    Mod(u32),
+    Pow(u32),
+    Unm(u32),
+    Not(u32),
+    Len(u32),
+    Concat(u32),
+    Jmp(u32),
+    Eq(u32),
+    Lt(u32),
+    Le(u32),
+    Test(u32),
+    TestSet(u32),
+    Call(u32),
+    TailCall(u32),
     Return(u32),
+    ForLoop(u32),
+    ForPrep(u32),
+    TForLoop(u32),
+    SetList(u32),
+    Close(u32),
+    Closure(u32),
+    VarArg(u32),
+    Unknown(u32),
 }
 
-pub fn is_k(x: u32) -> bool {
-    (x & (BITRK as u32)) != 0
+impl From<u32> for Instruction {
+    fn from(inst: u32) -> Self {
+        match get_op(inst) {
+            0 => Instruction::Move(inst),
+            1 => Instruction::LoadK(inst),
+            2 => Instruction::LoadBool(inst),
+            3 => Instruction::LoadNil(inst),
+            4 => Instruction::GetUpval(inst),
+            5 => Instruction::GetGlobal(inst),
+            6 => Instruction::GetTable(inst),
+            7 => Instruction::SetGlobal(inst),
+            8 => Instruction::SetUpval(inst),
+            9 => Instruction::SetTable(inst),
+            10 => Instruction::NewTable(inst),
+            11 => Instruction::OpSelf(inst),
+            12 => Instruction::Add(inst),
+            13 => Instruction::Sub(inst),
+            14 => Instruction::Mul(inst),
+            15 => Instruction::Div(inst),
+            16 => Instruction::Mod(inst),
+            17 => Instruction::Pow(inst),
+            18 => Instruction::Unm(inst),
+            19 => Instruction::Not(inst),
+            20 => Instruction::Len(inst),
+            21 => Instruction::Concat(inst),
+            22 => Instruction::Jmp(inst),
+            23 => Instruction::Eq(inst),
+            24 => Instruction::Lt(inst),
+            25 => Instruction::Le(inst),
+            26 => Instruction::Test(inst),
+            27 => Instruction::TestSet(inst),
+            28 => Instruction::Call(inst),
+            29 => Instruction::TailCall(inst),
+            30 => Instruction::Return(inst),
+            31 => Instruction::ForLoop(inst),
+            32 => Instruction::ForPrep(inst),
+            33 => Instruction::TForLoop(inst),
+            34 => Instruction::SetList(inst),
+            35 => Instruction::Close(inst),
+            36 => Instruction::Closure(inst),
+            37 => Instruction::VarArg(inst),
+            _ => Instruction::Unknown(inst),
+        }
+    }
 }
 
-pub fn index_k(x: u32) -> u32 {
-    x & !(BITRK as u32)
+pub fn get_op(inst: u32) -> u32 {
+    (inst & (Mask::OP as u32)) >> POS_OP
 }
 
-pub fn as_ra(inst: u32, base: usize) -> usize {
-    let ra = (inst & (Mask::A as u32)) >> POS_A;
-    base + (ra as usize)
+pub fn get_a(inst: u32) -> usize {
+    ((inst & (Mask::A as u32)) >> POS_A) as usize
 }
 
-pub fn as_rkb(inst: u32, k: usize, base: usize) -> usize {
-    let rb = (inst & (Mask::B as u32)) >> POS_B;
-    if is_k(rb) {
-        k + (rb as usize)
-    } else {
-        base + (rb as usize)
-    }
+pub fn get_b(inst: u32) -> usize {
+    ((inst & (Mask::B as u32)) >> POS_B) as usize
 }
 
-pub fn as_kbx(inst: u32, k: usize) -> usize {
-    let rbx = (inst & (Mask::BX as u32)) >> POS_BX;
-    k + (rbx as usize)
+pub fn get_c(inst: u32) -> usize {
+    ((inst & (Mask::C as u32)) >> POS_C) as usize
 }
 
-pub fn as_rkc(inst: u32, k: usize, base: usize) -> usize {
-    let rc = (inst & (Mask::C as u32)) >> POS_C;
-    if is_k(rc) {
-        k + (rc as usize)
-    } else {
-        base + (rc as usize)
-    }
+pub fn get_bx(inst: u32) -> usize {
+    ((inst & (Mask::BX as u32)) >> POS_BX) as usize
+}
+
+pub fn get_sbx(inst: u32) -> isize {
+    get_bx(inst) as isize - MAXARG_SBX as isize
+}
+
+pub fn create_abc(op: OpCode, a: usize, b: usize, c: usize) -> u32 {
+    ((op as u32) << POS_OP) | ((a as u32) << POS_A) | ((b as u32) << POS_B) | ((c as u32) << POS_C)
+}
+
+pub fn create_abx(op: OpCode, a: usize, bx: usize) -> u32 {
+    ((op as u32) << POS_OP) | ((a as u32) << POS_A) | ((bx as u32) << POS_BX)
+}
+
+pub fn create_asbx(op: OpCode, a: usize, sbx: isize) -> u32 {
+    create_abx(op, a, (sbx + MAXARG_SBX as isize) as usize)
+}
+
+/// encode constant index `k` as an RK operand
+pub fn rk_ask(k: usize) -> usize {
+    k | BITRK
+}
+
+pub fn is_k(x: usize) -> bool {
+    (x & BITRK) != 0
+}
+
+pub fn index_k(x: usize) -> usize {
+    x & !BITRK
+}
+
+pub const MAXARG_A: usize = (1 << SIZE_A) - 1;
+pub const MAXARG_C: usize = (1 << SIZE_C) - 1;
+/// largest constant index usable as an RK operand
+pub const MAXINDEXRK: usize = BITRK - 1;
+/// invalid register that fits in 8 bits
+pub const NO_REG: usize = MAXARG_A;
+
+pub fn set_op(inst: &mut u32, op: OpCode) {
+    *inst = (*inst & !(Mask::OP as u32)) | ((op as u32) << POS_OP);
+}
+
+pub fn set_a(inst: &mut u32, a: usize) {
+    *inst = (*inst & !(Mask::A as u32)) | ((a as u32) << POS_A);
+}
+
+pub fn set_b(inst: &mut u32, b: usize) {
+    *inst = (*inst & !(Mask::B as u32)) | ((b as u32) << POS_B);
+}
+
+pub fn set_c(inst: &mut u32, c: usize) {
+    *inst = (*inst & !(Mask::C as u32)) | ((c as u32) << POS_C);
+}
+
+pub fn set_sbx(inst: &mut u32, sbx: isize) {
+    let bx = (sbx + MAXARG_SBX as isize) as u32;
+    *inst = (*inst & !(Mask::BX as u32)) | (bx << POS_BX);
+}
+
+/// does the instruction skip the next one when its test fails (testTMode)
+pub fn is_test(op: u32) -> bool {
+    matches!(op, 23..=27 | 33)
 }
@@ -0,0 +1,294 @@
+use std::ffi::CString;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::datetime::{Tm, mktime, strftime};
+use crate::eval::{LuaNumber, TValue, Value};
+use crate::func::NativeFn;
+use crate::iolib::SystemAccess;
+use crate::vm::LuaState;
+
+/// `true`, or nil, message and error number (os_pushresult)
+fn push_result(state: &mut LuaState, res: io::Result<()>, filename: &[u8]) -> usize {
+    match res {
+        Ok(()) => {
+            state.push(TValue::boolean(true));
+            1
+        }
+        Err(e) => {
+            let msg = e.to_string();
+            let msg = msg.rfind(" (os error").map_or(msg.as_str(), |i| &msg[..i]);
+            let mut b = filename.to_vec();
+            b.extend_from_slice(b": ");
+            b.extend_from_slice(msg.as_bytes());
+            state.push(TValue::nil());
+            let msg = state.intern(&b);
+            state.push(msg);
+            state.push(TValue::number(e.raw_os_error().unwrap_or(0) as LuaNumber));
+            3
+        }
+    }
+}
+
+fn check_path(state: &mut LuaState, narg: usize) -> Result<Vec<u8>> {
+    let s = state.check_string(narg)?;
+    Ok(state.str_bytes(s).to_vec())
+}
+
+fn to_path(bytes: &[u8]) -> &std::path::Path {
+    std::path::Path::new(std::ffi::OsStr::from_bytes(bytes))
+}
+
+/// os.remove(filename): files or empty directories, like C's remove
+fn os_remove(state: &mut LuaState) -> Result<usize> {
+    let name = check_path(state, 1)?;
+    let path = to_path(&name);
+    let res = if path.is_dir() {
+        std::fs::remove_dir(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+    Ok(push_result(state, res, &name))
+}
+
+/// os.rename(oldname, newname)
+fn os_rename(state: &mut LuaState) -> Result<usize> {
+    let from = check_path(state, 1)?;
+    let to = check_path(state, 2)?;
+    let res = std::fs::rename(to_path(&from), to_path(&to));
+    Ok(push_result(state, res, &from))
+}
+
+/// os.tmpname(): the name of a new empty file
+fn os_tmpname(state: &mut LuaState) -> Result<usize> {
+    let template = std::env::temp_dir().join("lua_XXXXXX");
+    let mut buf = CString::new(template.as_os_str().as_bytes())
+        .unwrap()
+        .into_bytes_with_nul();
+    let fd = unsafe { libc::mkstemp(buf.as_mut_ptr() as *mut libc::c_char) };
+    if fd == -1 {
+        return Err(state.error("unable to generate a unique filename".to_string()));
+    }
+    unsafe { libc::close(fd) };
+    buf.pop();
+    let name = state.intern(&buf);
+    state.push(name);
+    Ok(1)
+}
+
+/// os.getenv(varname)
+fn os_getenv(state: &mut LuaState) -> Result<usize> {
+    let name = check_path(state, 1)?;
+    let res = match std::env::var_os(std::ffi::OsStr::from_bytes(&name)) {
+        Some(v) => state.intern(v.as_bytes()),
+        None => TValue::nil(),
+    };
+    state.push(res);
+    Ok(1)
+}
+
+/// os.exit([code])
+fn os_exit(state: &mut LuaState) -> Result<usize> {
+    let code = state.opt_integer(1, 0)?;
+    io::stdout().flush()?;
+    std::process::exit(code as i32)
+}
+
+/// os.clock(): processor time used by the program, in seconds
+fn os_clock(state: &mut LuaState) -> Result<usize> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts) };
+    let secs = ts.tv_sec as LuaNumber + ts.tv_nsec as LuaNumber / 1e9;
+    state.push(TValue::number(secs));
+    Ok(1)
+}
+
+fn set_field(state: &mut LuaState, t: TValue, key: &str, val: TValue) {
+    let Value::Table(t) = t.value() else {
+        unreachable!()
+    };
+    let key = state.global.heap.intern(key.as_bytes());
+    state.global.heap.get_mut(t).set_str(key, val);
+}
+
+/// integer field `key` of the date table in argument 1
+fn get_field(state: &mut LuaState, key: &str, default: Option<i64>) -> Result<i64> {
+    let t = state.arg(1);
+    let k = state.intern(key.as_bytes());
+    let v = state.get_table(t, k, None)?;
+    match (state.to_number(&v), default) {
+        (Some(Value::Integer(n)), _) => Ok(n),
+        (Some(Value::Number(n)), _) => Ok(n as i64),
+        (_, Some(d)) => Ok(d),
+        (_, None) => Err(state.error(format!("field '{key}' missing in date table"))),
+    }
+}
+
+/// os.date([format [, time]]); a format starting with '!' formats in UTC
+fn os_date(state: &mut LuaState) -> Result<usize> {
+    let format = match state.opt_string(1)? {
+        Some(s) => state.str_bytes(s).to_vec(),
+        None => b"%c".to_vec(),
+    };
+    let t = match state.arg(2).value() {
+        Value::Nil => now(),
+        _ => state.check_number(2)? as i64,
+    };
+    let tm = match format.strip_prefix(b"!") {
+        Some(_) => Tm::utc(t),
+        None => Tm::local(t),
+    };
+    let format = format.strip_prefix(b"!").unwrap_or(&format);
+    let Some(tm) = tm else {
+        state.push(TValue::nil());
+        return Ok(1);
+    };
+    if format.starts_with(b"*t") {
+        let res = TValue::table(state.new_table());
+        state.push(res);
+        let fields = [
+            ("sec", tm.sec),
+            ("min", tm.min),
+            ("hour", tm.hour),
+            ("day", tm.day),
+            ("month", tm.month),
+            ("year", tm.year),
+            ("wday", tm.wday + 1),
+            ("yday", tm.yday + 1),
+        ];
+        for (key, v) in fields {
+            set_field(state, res, key, TValue::number(v as LuaNumber));
+        }
+        set_field(state, res, "isdst", TValue::boolean(tm.isdst));
+        return Ok(1);
+    }
+    let res = state.intern(&strftime(format, &tm));
+    state.push(res);
+    Ok(1)
+}
+
+fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+/// os.time([table]): the current time, or the local date of a table whose
+/// fields may be out of range
+fn os_time(state: &mut LuaState) -> Result<usize> {
+    let t = if matches!(state.arg(1).value(), Value::Nil) {
+        Some(now())
+    } else {
+        state.check_table(1)?;
+        let sec = get_field(state, "sec", Some(0))?;
+        let min = get_field(state, "min", Some(0))?;
+        let hour = get_field(state, "hour", Some(12))?;
+        let day = get_field(state, "day", None)?;
+        let month = get_field(state, "month", None)?;
+        let year = get_field(state, "year", None)?;
+        let t = state.arg(1);
+        let k = state.intern(b"isdst");
+        let isdst = state.get_table(t, k, None)?;
+        let isdst = match isdst.value() {
+            Value::Nil => None,
+            _ => Some(!crate::vm::is_false(&isdst)),
+        };
+        mktime(year, month, day, hour, min, sec, isdst)
+    };
+    let res = match t {
+        Some(t) => TValue::number(t as LuaNumber),
+        None => TValue::nil(),
+    };
+    state.push(res);
+    Ok(1)
+}
+
+/// os.difftime(t2 [, t1])
+fn os_difftime(state: &mut LuaState) -> Result<usize> {
+    let t2 = state.check_number(1)?;
+    let t1 = state.opt_number(2, 0.0)?;
+    state.push(TValue::number(t2 - t1));
+    Ok(1)
+}
+
+/// Open the `os` library; functions that `access` denies are left out.
+pub fn open_os(state: &mut LuaState, access: SystemAccess) {
+    let mut funcs: Vec<(&str, NativeFn)> = vec![
+        ("clock", os_clock),
+        ("date", os_date),
+        ("difftime", os_difftime),
+        ("time", os_time),
+    ];
+    if access.write_files {
+        funcs.push(("remove", os_remove));
+        funcs.push(("rename", os_rename));
+        funcs.push(("tmpname", os_tmpname));
+    }
+    if access.env {
+        funcs.push(("getenv", os_getenv));
+    }
+    if access.exit {
+        funcs.push(("exit", os_exit));
+    }
+    state.register_lib("os", &funcs);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::baselib::open_base;
+    use crate::baselib::tests::{call_value, show};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_safe_subset() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        open_os(&mut state, SystemAccess::SAFE);
+        let os = state.get_global("os");
+        for (name, present) in [("date", true), ("exit", false), ("remove", false)] {
+            let key = state.intern(name.as_bytes());
+            let f = state.get_table(os, key, None).unwrap();
+            assert_eq!(!matches!(f.value(), Value::Nil), present, "os.{name}");
+        }
+        let key = state.intern(b"date");
+        let date = state.get_table(os, key, None).unwrap();
+        let format = state.intern(b"!%Y-%m-%d %H:%M:%S");
+        let res = call_value(&mut state, date, &[format, TValue::number(86399.0)]);
+        assert_eq!(show(&state, &res), vec!["1970-01-01 23:59:59"]);
+    }
+
+    #[test]
+    fn test_date_table_and_time() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        open_os(&mut state, SystemAccess::SAFE);
+        let source = "
+            local t = os.date('!*t', 951782400)
+            local same = os.time{year = 2020, month = 13, day = 1, hour = 0}
+                == os.time{year = 2021, month = 1, day = 1, hour = 0}
+            return t.year, t.month, t.day, t.wday, t.yday, t.isdst, same,
+                os.time(os.date('*t', 1700000000))
+        ";
+        let f = state.load_buffer(source.as_bytes(), "=test").unwrap();
+        let res = call_value(&mut state, f, &[]);
+        let res: Vec<String> = res
+            .iter()
+            .map(|v| match v.value() {
+                Value::Boolean(b) => b.to_string(),
+                _ => show(&state, &[*v]).remove(0),
+            })
+            .collect();
+        assert_eq!(
+            res,
+            vec!["2000", "2", "29", "3", "60", "false", "true", "1700000000"]
+        );
+    }
+}
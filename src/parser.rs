@@ -1,5 +1,281 @@
 use full_moon::{Error, ast::Ast};
 
+use crate::vm::LUAI_MAXCCALLS;
+
+/// longest chain of binary operators in an expression
+pub(crate) const MAX_CHAIN: usize = 100_000;
+
 pub fn parse(source: &str) -> Result<Ast, Vec<Error>> {
     full_moon::parse(source)
 }
+
+/// Nesting of a chunk, measured on its tokens: full_moon recurses on every
+/// nested block, bracket and unary operator, and the tree it builds nests
+/// every operand of a chain of binary operators.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Depth {
+    /// deepest nesting of blocks, brackets and unary operators
+    pub(crate) levels: usize,
+    /// most binary operators in one expression
+    pub(crate) chain: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Token {
+    /// name, literal or `...`
+    Operand,
+    /// literal string, which may also be the argument of a call
+    Str,
+    Open,
+    /// `)`, `]`, `}` or `end`
+    Close,
+    /// `until`, ending a block before an expression
+    Until,
+    Binary,
+    Unary,
+    /// `-`, binary or unary depending on what precedes it
+    Minus,
+    /// `.` or `:` before a field or method name
+    Field,
+    Other,
+}
+
+/// Check the nesting of `source` before it is parsed, so that no level the
+/// parser could overflow the stack on goes past LUAI_MAXCCALLS. Nothing is
+/// reported about malformed tokens, which the parser finds. The error is the
+/// line of the first level too many.
+pub(crate) fn check_depth(source: &[u8]) -> Result<Depth, usize> {
+    let mut lex = Lexer {
+        s: source,
+        pos: 0,
+        line: 1,
+    };
+    let mut depth = Depth::default();
+    // operators of the expression and unary operators pending at each level
+    let mut saved: Vec<(usize, usize)> = Vec::new();
+    let (mut chain, mut unary, mut operand) = (0, 0, false);
+    while let Some(token) = lex.next() {
+        let token = match token {
+            Token::Minus if operand => Token::Binary,
+            Token::Minus => Token::Unary,
+            t => t,
+        };
+        match token {
+            // two operands in a row start a new expression
+            Token::Operand if operand => (chain, unary) = (0, 0),
+            Token::Operand | Token::Str => unary = 0,
+            Token::Open => {
+                saved.push((chain, unary));
+                (chain, unary) = (0, 0);
+            }
+            Token::Close | Token::Until => {
+                (chain, _) = saved.pop().unwrap_or_default();
+                unary = 0;
+            }
+            Token::Binary => {
+                chain += 1;
+                depth.chain = depth.chain.max(chain);
+                if chain > MAX_CHAIN {
+                    return Err(lex.line);
+                }
+            }
+            Token::Unary => unary += 1,
+            Token::Field | Token::Minus => {}
+            Token::Other => (chain, unary) = (0, 0),
+        }
+        let levels = saved.len() + saved.iter().map(|&(_, u)| u).sum::<usize>() + unary;
+        depth.levels = depth.levels.max(levels);
+        if levels > LUAI_MAXCCALLS {
+            return Err(lex.line);
+        }
+        operand = matches!(token, Token::Operand | Token::Str | Token::Close);
+    }
+    Ok(depth)
+}
+
+/// The little of llex needed to tell tokens apart.
+struct Lexer<'a> {
+    s: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl Lexer<'_> {
+    fn peek(&self, n: usize) -> u8 {
+        self.s.get(self.pos + n).copied().unwrap_or(0)
+    }
+    /// skip a byte, counting lines
+    fn bump(&mut self) {
+        if self.peek(0) == b'\n' {
+            self.line += 1;
+        }
+        self.pos += 1;
+    }
+    /// level of a long bracket `[==[` at the current position
+    fn long_bracket(&self) -> Option<usize> {
+        let n = self.s[self.pos + 1..]
+            .iter()
+            .take_while(|&&c| c == b'=')
+            .count();
+        (self.peek(n + 1) == b'[').then_some(n)
+    }
+    fn skip_long(&mut self, level: usize) {
+        self.pos += level + 2;
+        while self.pos < self.s.len() {
+            if self.peek(0) == b']'
+                && self.s[self.pos + 1..]
+                    .iter()
+                    .take(level)
+                    .all(|&c| c == b'=')
+                && self.peek(level + 1) == b']'
+            {
+                self.pos += level + 2;
+                return;
+            }
+            self.bump();
+        }
+    }
+    fn skip_string(&mut self, quote: u8) {
+        self.pos += 1;
+        while self.pos < self.s.len() {
+            match self.peek(0) {
+                b'\\' => {
+                    self.pos += 1;
+                    self.bump();
+                }
+                b'\n' => return,
+                c => {
+                    self.pos += 1;
+                    if c == quote {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+    fn next(&mut self) -> Option<Token> {
+        loop {
+            let c = self.peek(0);
+            if self.pos >= self.s.len() {
+                return None;
+            }
+            match c {
+                b'-' if self.peek(1) == b'-' => {
+                    self.pos += 2;
+                    match self.peek(0) {
+                        b'[' if self.long_bracket().is_some() => {
+                            let level = self.long_bracket().unwrap();
+                            self.skip_long(level);
+                        }
+                        _ => {
+                            while self.pos < self.s.len() && self.peek(0) != b'\n' {
+                                self.pos += 1;
+                            }
+                        }
+                    }
+                }
+                c if c.is_ascii_whitespace() => self.bump(),
+                _ => break,
+            }
+        }
+        let c = self.peek(0);
+        let token = match c {
+            b'[' => match self.long_bracket() {
+                Some(level) => {
+                    self.skip_long(level);
+                    return Some(Token::Str);
+                }
+                None => Token::Open,
+            },
+            b'"' | b'\'' => {
+                self.skip_string(c);
+                return Some(Token::Str);
+            }
+            b'(' | b'{' => Token::Open,
+            b')' | b']' | b'}' => Token::Close,
+            b'.' if self.peek(1) == b'.' => {
+                let len = if self.peek(2) == b'.' { 3 } else { 2 };
+                self.pos += len;
+                return Some(if len == 3 {
+                    Token::Operand
+                } else {
+                    Token::Binary
+                });
+            }
+            b'.' if !self.peek(1).is_ascii_digit() => Token::Field,
+            b':' => Token::Field,
+            b'=' | b'~' | b'<' | b'>' if self.peek(1) == b'=' => {
+                self.pos += 2;
+                return Some(Token::Binary);
+            }
+            b'<' | b'>' | b'+' | b'*' | b'/' | b'%' | b'^' => Token::Binary,
+            b'-' => Token::Minus,
+            b'#' => Token::Unary,
+            c if c.is_ascii_digit() || c == b'.' => {
+                // digits, letters and exponent signs, as read_numeral does
+                while self.peek(0).is_ascii_alphanumeric()
+                    || self.peek(0) == b'.'
+                    || (matches!(self.peek(0), b'+' | b'-')
+                        && matches!(self.s[self.pos - 1], b'e' | b'E'))
+                {
+                    self.pos += 1;
+                }
+                return Some(Token::Operand);
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' || c >= 0x80 => {
+                let start = self.pos;
+                while self.peek(0).is_ascii_alphanumeric()
+                    || self.peek(0) == b'_'
+                    || self.peek(0) >= 0x80
+                {
+                    self.pos += 1;
+                }
+                return Some(match &self.s[start..self.pos] {
+                    b"function" | b"do" | b"if" | b"repeat" => Token::Open,
+                    b"end" => Token::Close,
+                    b"until" => Token::Until,
+                    b"and" | b"or" => Token::Binary,
+                    b"not" => Token::Unary,
+                    b"nil" | b"true" | b"false" => Token::Operand,
+                    b"break" | b"else" | b"elseif" | b"for" | b"in" | b"local" | b"return"
+                    | b"then" | b"while" => Token::Other,
+                    _ => Token::Operand,
+                });
+            }
+            _ => Token::Other,
+        };
+        self.pos += 1;
+        Some(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_check_depth() {
+        let depth = |s: &str| check_depth(s.as_bytes());
+        assert_eq!(
+            depth("local x = -(a + b.c[1] * f{1, 2} .. 'x' ^ #t) -- (((\nprint(x)"),
+            Ok(Depth {
+                levels: 3,
+                chain: 4
+            })
+        );
+        assert_eq!(
+            depth("do if x then f(function() return [[ ( ]] end) end end").map(|d| d.levels),
+            Ok(5)
+        );
+        let nested = format!("x = 1\nreturn {}1{}", "{".repeat(300), "}".repeat(300));
+        assert_eq!(depth(&nested), Err(2));
+        assert_eq!(depth(&format!("return {}1", "not ".repeat(201))), Err(1));
+        let chain = format!("x = 1{}\ny = 1{}", " + 1".repeat(500), " + 1".repeat(500));
+        assert_eq!(depth(&chain).map(|d| d.chain), Ok(500));
+        assert_eq!(
+            depth(&format!("x = 1{}", " - 1".repeat(MAX_CHAIN + 1))),
+            Err(1)
+        );
+    }
+}
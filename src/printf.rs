@@ -0,0 +1,285 @@
+//! C `printf` conversions, as used by `string.format` and number-to-string
+//! coercion (`LUAI_NUMFFORMAT`). Output is byte-identical to glibc.
+
+use crate::eval::{LuaInteger, LuaNumber};
+
+const FLAGS: &[u8] = b"-+ #0";
+
+/// A conversion specification `%[flags][width][.precision]conv`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Spec {
+    pub left: bool,
+    pub plus: bool,
+    pub space: bool,
+    pub alt: bool,
+    pub zero: bool,
+    pub width: usize,
+    pub precision: Option<usize>,
+    pub conv: u8,
+}
+
+impl Spec {
+    /// `%.{precision}g`
+    pub fn g(precision: usize) -> Self {
+        Self {
+            precision: Some(precision),
+            conv: b'g',
+            ..Self::default()
+        }
+    }
+
+    /// Parse the specification following a '%' (scanformat in lstrlib.c), with
+    /// the same limits as Lua: at most 5 flags and 2 digits of width and precision.
+    /// Returns it with the number of bytes used; `conv` is 0 at the end of `fmt`.
+    pub fn parse(fmt: &[u8]) -> Result<(Spec, usize), String> {
+        let mut spec = Spec::default();
+        let mut p = 0;
+        while let Some(&c) = fmt.get(p).filter(|c| FLAGS.contains(c)) {
+            match c {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alt = true,
+                _ => spec.zero = true,
+            }
+            p += 1;
+        }
+        if p > FLAGS.len() {
+            return Err("invalid format (repeated flags)".to_string());
+        }
+        let digits = |p: &mut usize| {
+            let mut n = None;
+            for _ in 0..2 {
+                match fmt.get(*p) {
+                    Some(d) if d.is_ascii_digit() => {
+                        n = Some(n.unwrap_or(0) * 10 + (d - b'0') as usize);
+                        *p += 1;
+                    }
+                    _ => break,
+                }
+            }
+            n
+        };
+        spec.width = digits(&mut p).unwrap_or(0);
+        if fmt.get(p) == Some(&b'.') {
+            p += 1;
+            spec.precision = Some(digits(&mut p).unwrap_or(0));
+        }
+        if fmt.get(p).is_some_and(|d| d.is_ascii_digit()) {
+            return Err("invalid format (width or precision too long)".to_string());
+        }
+        spec.conv = fmt.get(p).copied().unwrap_or(0);
+        Ok((spec, (p + 1).min(fmt.len())))
+    }
+
+    /// pad `body` (after a sign or prefix `head`) to the field width
+    fn pad(&self, head: &[u8], body: &[u8], zero_pad: bool) -> Vec<u8> {
+        let len = head.len() + body.len();
+        let fill = self.width.saturating_sub(len);
+        let mut out = Vec::with_capacity(len + fill);
+        if self.left {
+            out.extend_from_slice(head);
+            out.extend_from_slice(body);
+            out.resize(len + fill, b' ');
+        } else if zero_pad {
+            out.extend_from_slice(head);
+            out.resize(head.len() + fill, b'0');
+            out.extend_from_slice(body);
+        } else {
+            out.resize(fill, b' ');
+            out.extend_from_slice(head);
+            out.extend_from_slice(body);
+        }
+        out
+    }
+
+    fn sign(&self, negative: bool) -> &'static [u8] {
+        if negative {
+            b"-"
+        } else if self.plus {
+            b"+"
+        } else if self.space {
+            b" "
+        } else {
+            b""
+        }
+    }
+
+    /// `%c`
+    pub fn format_char(&self, c: u8) -> Vec<u8> {
+        self.pad(b"", &[c], false)
+    }
+
+    /// `%s`; the precision limits the number of bytes taken from `s`
+    pub fn format_str(&self, s: &[u8]) -> Vec<u8> {
+        let s = match self.precision {
+            Some(p) if p < s.len() => &s[..p],
+            _ => s,
+        };
+        self.pad(b"", s, false)
+    }
+
+    /// `%d %i %o %u %x %X`
+    pub fn format_int(&self, n: LuaInteger) -> Vec<u8> {
+        let (negative, mut digits, head): (bool, String, &[u8]) = match self.conv {
+            b'd' | b'i' => (n < 0, n.unsigned_abs().to_string(), b""),
+            b'o' => (false, format!("{:o}", n as u64), b""),
+            b'u' => (false, (n as u64).to_string(), b""),
+            b'x' => (
+                false,
+                format!("{:x}", n as u64),
+                if self.alt && n != 0 { b"0x" } else { b"" },
+            ),
+            _ => (
+                false,
+                format!("{:X}", n as u64),
+                if self.alt && n != 0 { b"0X" } else { b"" },
+            ),
+        };
+        if let Some(p) = self.precision {
+            if p == 0 && n == 0 {
+                digits.clear();
+            }
+            if digits.len() < p {
+                digits = format!("{}{digits}", "0".repeat(p - digits.len()));
+            }
+        }
+        if self.conv == b'o' && self.alt && !digits.starts_with('0') {
+            digits.insert(0, '0');
+        }
+        let mut prefix = self.sign(negative).to_vec();
+        prefix.extend_from_slice(head);
+        let zero_pad = self.zero && self.precision.is_none();
+        self.pad(&prefix, digits.as_bytes(), zero_pad)
+    }
+
+    /// `%e %E %f %F %g %G`
+    pub fn format_float(&self, n: LuaNumber) -> Vec<u8> {
+        let upper = self.conv.is_ascii_uppercase();
+        let sign = self.sign(n.is_sign_negative() && !n.is_nan());
+        if !n.is_finite() {
+            let body = match (n.is_nan(), upper) {
+                (true, false) => "nan",
+                (true, true) => "NAN",
+                (false, false) => "inf",
+                (false, true) => "INF",
+            };
+            return self.pad(sign, body.as_bytes(), false);
+        }
+        let n = n.abs();
+        let precision = self.precision.unwrap_or(6);
+        let mut body = match self.conv.to_ascii_lowercase() {
+            b'e' => fmt_e(n, precision, self.alt),
+            b'f' => fmt_f(n, precision, self.alt),
+            _ => fmt_g(n, precision, self.alt),
+        };
+        if upper {
+            body.make_ascii_uppercase();
+        }
+        self.pad(sign, body.as_bytes(), self.zero)
+    }
+}
+
+/// `%.{precision}f` of a non-negative finite number
+fn fmt_f(n: LuaNumber, precision: usize, alt: bool) -> String {
+    let mut s = format!("{n:.precision$}");
+    if alt && precision == 0 {
+        s.push('.');
+    }
+    s
+}
+
+/// `%.{precision}e` of a non-negative finite number
+fn fmt_e(n: LuaNumber, precision: usize, alt: bool) -> String {
+    let s = format!("{n:.precision$e}");
+    let (mantissa, exp) = s.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let dot = if alt && precision == 0 { "." } else { "" };
+    let sign = if exp < 0 { '-' } else { '+' };
+    format!("{mantissa}{dot}e{sign}{:02}", exp.abs())
+}
+
+/// `%.{precision}g` of a non-negative finite number
+fn fmt_g(n: LuaNumber, precision: usize, alt: bool) -> String {
+    let p = precision.max(1);
+    // exponent of the number rounded to `p` significant digits
+    let e = format!("{n:.*e}", p - 1);
+    let exp: i32 = e.split_once('e').unwrap().1.parse().unwrap();
+    let s = if exp < -4 || exp >= p as i32 {
+        fmt_e(n, p - 1, alt)
+    } else {
+        fmt_f(n, (p as i32 - 1 - exp) as usize, alt)
+    };
+    if alt {
+        return s;
+    }
+    // drop trailing zeros of the fraction
+    let (num, exp) = match s.find('e') {
+        Some(i) => s.split_at(i),
+        None => (s.as_str(), ""),
+    };
+    let num = if num.contains('.') {
+        num.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        num
+    };
+    format!("{num}{exp}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn fmt(spec: &str, n: LuaNumber) -> String {
+        let (spec, _) = Spec::parse(spec.as_bytes()).unwrap();
+        let out = match spec.conv {
+            b'd' | b'i' | b'o' | b'u' | b'x' | b'X' => spec.format_int(n as LuaInteger),
+            _ => spec.format_float(n),
+        };
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_matches_c_printf() {
+        let cases = [
+            ("5.2f", 1.23456, " 1.23"),
+            ("-8.3e", 12345.678, "1.235e+04"),
+            ("08.3f", -1.5, "-001.500"),
+            ("+.0f", 2.5, "+2"),
+            ("#.0f", 3.0, "3."),
+            ("g", 100000.0, "100000"),
+            ("g", 1000000.0, "1e+06"),
+            ("g", 0.0001, "0.0001"),
+            ("g", 0.00001, "1e-05"),
+            ("#g", 1.0, "1.00000"),
+            (".14g", 0.1, "0.1"),
+            (".14g", 1e100, "1e+100"),
+            (".3G", 1e-10, "1E-10"),
+            ("5.1f", f64::INFINITY, "  inf"),
+            ("d", -42.0, "-42"),
+            ("+05d", 42.0, "+0042"),
+            (".3d", 7.0, "007"),
+            ("x", 255.0, "ff"),
+            ("#X", 255.0, "0XFF"),
+            ("#o", 8.0, "010"),
+            ("x", -1.0, "ffffffffffffffff"),
+            ("-5d|", 3.0, "3    "),
+        ];
+        for (spec, n, expected) in cases {
+            assert_eq!(fmt(spec, n), expected, "%{spec}");
+        }
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        assert!(Spec::parse(b"123d").is_err());
+        assert!(Spec::parse(b"1.123f").is_err());
+        assert!(Spec::parse(b"------d").is_err());
+        let (spec, len) = Spec::parse(b"-+ #012.10fxyz").unwrap();
+        assert_eq!(
+            (spec.width, spec.precision, spec.conv, len),
+            (12, Some(10), b'f', 11)
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Handles on Lua values held by the host, stored in the registry with
+//! `reference` so that they stay alive until the handle is dropped.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::api::LUA_REFNIL;
+use crate::conversion::{FromLua, ToLua};
+use crate::error::LuaError;
+use crate::eval::{TValue, Value};
+use crate::vm::LuaState;
+
+/// A Lua value pinned in the registry of the state which made it. Dropping
+/// the key releases the value; the slot is freed the next time the state
+/// creates a key or starts a collection.
+pub struct RegistryKey {
+    reference: i64,
+    /// queue of dropped keys of the owning state, also telling states apart
+    owner: Rc<RefCell<Vec<i64>>>,
+}
+
+impl fmt::Debug for RegistryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RegistryKey({})", self.reference)
+    }
+}
+
+impl Drop for RegistryKey {
+    fn drop(&mut self) {
+        if self.reference != LUA_REFNIL {
+            self.owner.borrow_mut().push(self.reference);
+        }
+    }
+}
+
+impl LuaState {
+    /// Pin `val` in the registry until the returned key is dropped.
+    pub fn create_registry_value(&mut self, val: impl ToLua) -> Result<RegistryKey, LuaError> {
+        let val = val.to_lua(self);
+        self.check_live(&[val])?;
+        Ok(self.registry_key(val))
+    }
+
+    /// `create_registry_value` for a value known to be live
+    pub(crate) fn registry_key(&mut self, val: TValue) -> RegistryKey {
+        self.expire_registry_values();
+        let registry = self.global.registry;
+        RegistryKey {
+            reference: self.reference(registry, val),
+            owner: self.global.dropped_keys.clone(),
+        }
+    }
+
+    /// the value pinned by `key`, converted to `T`
+    pub fn registry_value<T: FromLua>(&mut self, key: &RegistryKey) -> Result<T, LuaError> {
+        let val = self.registry_raw(key)?;
+        T::from_lua(val, self).map_err(|e| self.error_object(e))
+    }
+
+    /// Pin `val` under `key` instead of the value it held.
+    pub fn replace_registry_value(
+        &mut self,
+        key: &mut RegistryKey,
+        val: impl ToLua,
+    ) -> Result<(), LuaError> {
+        self.check_owner(key)?;
+        let val = val.to_lua(self);
+        self.check_live(&[val])?;
+        let registry = self.global.registry;
+        if key.reference == LUA_REFNIL {
+            key.reference = self.reference(registry, val);
+        } else if matches!(val.value(), Value::Nil) {
+            // a nil slot looks free to `reference`: release it for good
+            self.unreference(registry, key.reference);
+            key.reference = LUA_REFNIL;
+        } else {
+            self.global
+                .heap
+                .get_mut(registry)
+                .set_int(key.reference, val);
+        }
+        Ok(())
+    }
+
+    /// Release the value of `key` now rather than at the next chance.
+    pub fn remove_registry_value(&mut self, mut key: RegistryKey) -> Result<(), LuaError> {
+        self.check_owner(&key)?;
+        let registry = self.global.registry;
+        self.unreference(registry, key.reference);
+        key.reference = LUA_REFNIL;
+        Ok(())
+    }
+
+    /// whether `key` was made by this state
+    pub fn owns_registry_value(&self, key: &RegistryKey) -> bool {
+        Rc::ptr_eq(&key.owner, &self.global.dropped_keys)
+    }
+
+    /// free the slots of the keys dropped since the last call
+    pub(crate) fn expire_registry_values(&mut self) {
+        let dropped = std::mem::take(&mut *self.global.dropped_keys.borrow_mut());
+        let registry = self.global.registry;
+        for r in dropped {
+            self.unreference(registry, r);
+        }
+    }
+
+    fn check_owner(&mut self, key: &RegistryKey) -> Result<(), LuaError> {
+        if self.owns_registry_value(key) {
+            Ok(())
+        } else {
+            let msg = "registry key used with a different state";
+            Err(LuaError::new(self.intern(msg.as_bytes()), msg.to_string()))
+        }
+    }
+
+    fn registry_raw(&mut self, key: &RegistryKey) -> Result<TValue, LuaError> {
+        self.check_owner(key)?;
+        if key.reference == LUA_REFNIL {
+            return Ok(TValue::nil());
+        }
+        let registry = self.global.registry;
+        Ok(self.global.heap.get(registry).get_int(key.reference))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iolib::SystemAccess;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_registry_keys() {
+        let mut lua = LuaState::new();
+        lua.open_libs(SystemAccess::SAFE);
+        let f: TValue = lua
+            .exec("local n = 0 return function() n = n + 1 return n end")
+            .unwrap();
+        let key = lua.create_registry_value(f).unwrap();
+        let weak: TValue = lua.exec("return setmetatable({}, {__mode = 'v'})").unwrap();
+        let weak_key = lua.create_registry_value(weak).unwrap();
+        lua.gc_collect().unwrap();
+        let f: TValue = lua.registry_value(&key).unwrap();
+        assert_eq!(lua.call_function::<_, i32>(&f, ()).unwrap(), 1);
+        assert_eq!(lua.call_function::<_, i32>(&f, ()).unwrap(), 2);
+
+        // a dropped key no longer keeps its value alive
+        let weak: TValue = lua.registry_value(&weak_key).unwrap();
+        lua.set_global("weak", weak).unwrap();
+        lua.set_global("f", f).unwrap();
+        lua.exec::<()>("weak[1] = f f = nil").unwrap();
+        drop(key);
+        lua.gc_collect().unwrap();
+        assert!(
+            lua.exec::<Option<TValue>>("return weak[1]")
+                .unwrap()
+                .is_none()
+        );
+        // its slot is reused
+        let key = lua.create_registry_value("again").unwrap();
+        assert_eq!(key.reference, 1);
+        assert_eq!(lua.registry_value::<String>(&key).unwrap(), "again");
+
+        let mut nil_key = lua.create_registry_value(TValue::nil()).unwrap();
+        assert_eq!(lua.registry_value::<Option<i32>>(&nil_key).unwrap(), None);
+        lua.replace_registry_value(&mut nil_key, 5).unwrap();
+        assert_eq!(lua.registry_value::<i32>(&nil_key).unwrap(), 5);
+
+        // a key replaced with nil gives up its slot instead of sharing it
+        let mut k2 = lua.create_registry_value("two").unwrap();
+        lua.replace_registry_value(&mut k2, TValue::nil()).unwrap();
+        let k3 = lua.create_registry_value("three").unwrap();
+        lua.replace_registry_value(&mut k2, "new two").unwrap();
+        assert_eq!(lua.registry_value::<String>(&k3).unwrap(), "three");
+        assert_eq!(lua.registry_value::<String>(&k2).unwrap(), "new two");
+
+        let mut other = LuaState::new();
+        assert!(!other.owns_registry_value(&key));
+        let err = other.registry_value::<String>(&key).unwrap_err();
+        assert_eq!(err.to_string(), "registry key used with a different state");
+    }
+}
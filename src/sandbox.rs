@@ -0,0 +1,442 @@
+//! Isolated globals for untrusted scripts sharing one state.
+//!
+//! Each sandbox gets its own copy of a whitelist of safe library functions,
+//! built once from the standard libraries and kept in the registry as
+//! `_SANDBOX`. Chunks loaded into a sandbox run with its table as function
+//! environment, and so do the closures they create.
+
+use anyhow::Result;
+
+use crate::api::LUA_SIGNATURE;
+use crate::baselib::{load_aux, read_chunk};
+use crate::conversion::{FromLua, FromLuaMulti, ToLua};
+use crate::error::LuaError;
+use crate::eval::{TValue, Value};
+use crate::func::NativeFn;
+use crate::heap::Gc;
+use crate::registry::RegistryKey;
+use crate::table::Table;
+use crate::vm::LuaState;
+
+/// base functions copied into a sandbox
+const SAFE_GLOBALS: &[&str] = &[
+    "_VERSION",
+    "assert",
+    "error",
+    "ipairs",
+    "next",
+    "pairs",
+    "pcall",
+    "print",
+    "rawequal",
+    "rawget",
+    "rawset",
+    "select",
+    "setmetatable",
+    "tonumber",
+    "tostring",
+    "type",
+    "unpack",
+    "xpcall",
+];
+
+/// library functions copied into a sandbox, each library as a fresh table
+const SAFE_LIBS: &[(&str, &[&str])] = &[
+    (
+        "coroutine",
+        &["create", "resume", "running", "status", "wrap", "yield"],
+    ),
+    ("json", &["decode", "encode", "null"]),
+    (
+        "math",
+        &[
+            "abs", "acos", "asin", "atan", "atan2", "ceil", "cos", "cosh", "deg", "exp", "floor",
+            "fmod", "frexp", "huge", "ldexp", "log", "log10", "max", "min", "modf", "pi", "pow",
+            "rad", "random", "sin", "sinh", "sqrt", "tan", "tanh", "type",
+        ],
+    ),
+    ("os", &["clock", "date", "difftime", "time"]),
+    (
+        "string",
+        &[
+            "byte", "char", "find", "format", "gmatch", "gsub", "len", "lower", "match", "rep",
+            "reverse", "sub", "upper",
+        ],
+    ),
+    ("table", &["concat", "insert", "maxn", "remove", "sort"]),
+];
+
+/// functions made for each sandbox, with its globals as upvalue
+const ENV_FUNCS: &[(&str, NativeFn)] = &[
+    ("getfenv", sandbox_getfenv),
+    ("load", sandbox_load),
+    ("loadstring", sandbox_loadstring),
+    ("setfenv", sandbox_setfenv),
+];
+
+/// Globals of sandboxed scripts, made by `create_sandbox`. The table stays
+/// alive as long as the handle.
+#[derive(Debug)]
+pub struct Sandbox {
+    env: RegistryKey,
+}
+
+/// environment of a Lua or native function
+fn function_env(state: &LuaState, f: &TValue) -> Option<Gc<Table>> {
+    match f.value() {
+        Value::LuaClosure(c) => Some(state.global.heap.get(c).env),
+        Value::NativeClosure(c) => Some(state.global.heap.get(c).env),
+        _ => None,
+    }
+}
+
+/// Globals of the sandbox owning the running native, kept as its upvalue:
+/// the caller's environment cannot be trusted, as `pcall` or a coroutine
+/// may stand between the script and the native.
+fn own_env(state: &LuaState) -> Gc<Table> {
+    match state.upvalue(1).value() {
+        Value::Table(t) => t,
+        _ => unreachable!("sandbox function without its globals"),
+    }
+}
+
+/// Compile source code, refusing precompiled chunks, into a function
+/// running in the sandbox.
+fn load_source(state: &mut LuaState, buf: &[u8], chunkname: &str) -> Result<TValue> {
+    if buf.starts_with(LUA_SIGNATURE) {
+        let msg = state.intern(b"attempt to load a binary chunk");
+        return Err(state.error_value(msg));
+    }
+    let f = state.load_buffer(buf, chunkname)?;
+    let env = own_env(state);
+    state.set_fenv(&f, env);
+    Ok(f)
+}
+
+/// load(func [, chunkname]) of a sandbox
+fn sandbox_load(state: &mut LuaState) -> Result<usize> {
+    let chunkname = match state.opt_string(2)? {
+        Some(s) => String::from_utf8_lossy(state.str_bytes(s)).into_owned(),
+        None => "=(load)".to_string(),
+    };
+    if !matches!(
+        state.arg(1).value(),
+        Value::LuaClosure(_) | Value::NativeClosure(_)
+    ) {
+        return Err(state.type_error_arg(1, "function"));
+    }
+    let res = read_chunk(state).and_then(|buf| load_source(state, &buf, &chunkname));
+    Ok(load_aux(state, res))
+}
+
+/// loadstring(s [, chunkname]) of a sandbox
+fn sandbox_loadstring(state: &mut LuaState) -> Result<usize> {
+    let s = state.check_string(1)?;
+    let chunkname = match state.opt_string(2)? {
+        Some(name) => state.str_bytes(name),
+        None => state.str_bytes(s),
+    };
+    let chunkname = String::from_utf8_lossy(chunkname).into_owned();
+    let buf = state.str_bytes(s).to_vec();
+    let res = load_source(state, &buf, &chunkname);
+    Ok(load_aux(state, res))
+}
+
+/// getmetatable(t) of a sandbox: only tables show their metatable, so that
+/// the one shared by all strings stays out of reach
+fn sandbox_getmetatable(state: &mut LuaState) -> Result<usize> {
+    let obj = state.check_any(1)?;
+    let res = match (obj.value(), state.get_metatable(&obj)) {
+        (Value::Table(_), Some(mt)) => {
+            let protected = state.get_metafield(&obj, "__metatable");
+            match protected.value() {
+                Value::Nil => TValue::table(mt),
+                _ => protected,
+            }
+        }
+        _ => TValue::nil(),
+    };
+    state.push(res);
+    Ok(1)
+}
+
+/// the Lua function at argument 1, which must run in the sandbox
+fn check_own_function(state: &mut LuaState) -> Result<TValue> {
+    let f = state.arg(1);
+    if !matches!(f.value(), Value::LuaClosure(_)) {
+        return Err(state.type_error_arg(1, "Lua function"));
+    }
+    if function_env(state, &f) != Some(own_env(state)) {
+        return Err(state.arg_error(1, "function of another environment"));
+    }
+    Ok(f)
+}
+
+/// getfenv([f]) of a sandbox
+fn sandbox_getfenv(state: &mut LuaState) -> Result<usize> {
+    let env = match state.arg(1).value() {
+        Value::Nil => Some(own_env(state)),
+        _ => {
+            let f = check_own_function(state)?;
+            function_env(state, &f)
+        }
+    };
+    state.push(env.map_or(TValue::nil(), TValue::table));
+    Ok(1)
+}
+
+/// setfenv(f, table) of a sandbox
+fn sandbox_setfenv(state: &mut LuaState) -> Result<usize> {
+    let env = state.check_table(2)?;
+    let f = check_own_function(state)?;
+    state.set_fenv(&f, env);
+    state.push(f);
+    Ok(1)
+}
+
+impl LuaState {
+    /// Fresh globals holding the safe part of the libraries opened so far:
+    /// no `io`, `debug`, `package` or file loading, `load` and `loadstring`
+    /// only take source code, and `setfenv`/`getfenv` only apply to
+    /// functions of the same sandbox.
+    pub fn create_sandbox(&mut self) -> Sandbox {
+        let whitelist = self.sandbox_whitelist();
+        let mut entries = Vec::new();
+        let mut key = TValue::nil();
+        while let Ok(Some((k, v))) = self.global.heap.get(whitelist).next(&key) {
+            entries.push((k, v));
+            key = k;
+        }
+        let env = self.new_table();
+        for (k, v) in entries {
+            let v = match v.value() {
+                Value::Table(lib) => TValue::table(self.copy_table(lib)),
+                _ => v,
+            };
+            self.global.heap.get_mut(env).set(k, v).unwrap();
+        }
+        for (name, func) in ENV_FUNCS {
+            let f = self.new_native_closure(*func, vec![TValue::table(env)]);
+            let key = self.global.heap.intern(name.as_bytes());
+            self.global.heap.get_mut(env).set_str(key, f);
+        }
+        let key = self.global.heap.intern(b"_G");
+        self.global
+            .heap
+            .get_mut(env)
+            .set_str(key, TValue::table(env));
+        Sandbox {
+            env: self.registry_key(TValue::table(env)),
+        }
+    }
+
+    /// the globals table of `sandbox`
+    pub fn sandbox_env(&mut self, sandbox: &Sandbox) -> Result<TValue, LuaError> {
+        self.registry_value(&sandbox.env)
+    }
+
+    /// Compile `source` into a function running in `sandbox`. Precompiled
+    /// chunks are refused.
+    pub fn load_sandboxed(
+        &mut self,
+        sandbox: &Sandbox,
+        source: impl AsRef<[u8]>,
+        chunkname: &str,
+    ) -> Result<TValue, LuaError> {
+        let env = self.sandbox_table(sandbox)?;
+        let source = source.as_ref();
+        if source.starts_with(LUA_SIGNATURE) {
+            let msg = "attempt to load a binary chunk";
+            return Err(LuaError::new(self.intern(msg.as_bytes()), msg.to_string()));
+        }
+        let f = self.load_chunk(source, chunkname)?;
+        self.set_fenv(&f, env);
+        Ok(f)
+    }
+
+    /// Run `source` in `sandbox` and convert its results, like `exec`.
+    pub fn exec_sandboxed<R: FromLuaMulti>(
+        &mut self,
+        sandbox: &Sandbox,
+        source: &str,
+    ) -> Result<R, LuaError> {
+        let f = self.load_sandboxed(sandbox, source, source)?;
+        self.call_function(&f, ())
+    }
+
+    /// global variable `name` of `sandbox` converted to `T`
+    pub fn sandbox_global<T: FromLua>(
+        &mut self,
+        sandbox: &Sandbox,
+        name: &str,
+    ) -> Result<T, LuaError> {
+        let env = self.sandbox_table(sandbox)?;
+        let key = self.global.heap.intern(name.as_bytes());
+        let val = self.global.heap.get(env).get_str(key);
+        T::from_lua(val, self).map_err(|e| self.error_object(e))
+    }
+
+    /// Set the global variable `name` of `sandbox`, to hand it host functions
+    /// or data.
+    pub fn set_sandbox_global(
+        &mut self,
+        sandbox: &Sandbox,
+        name: &str,
+        val: impl ToLua,
+    ) -> Result<(), LuaError> {
+        let env = self.sandbox_table(sandbox)?;
+        let val = val.to_lua(self);
+        self.check_live(&[val])?;
+        let key = self.global.heap.intern(name.as_bytes());
+        self.global.heap.get_mut(env).set_str(key, val);
+        Ok(())
+    }
+
+    fn sandbox_table(&mut self, sandbox: &Sandbox) -> Result<Gc<Table>, LuaError> {
+        match self.sandbox_env(sandbox)?.value() {
+            Value::Table(t) => Ok(t),
+            _ => unreachable!("sandbox without a table"),
+        }
+    }
+
+    /// the whitelist copied into every sandbox, built on first use
+    fn sandbox_whitelist(&mut self) -> Gc<Table> {
+        let key = self.global.heap.intern(b"_SANDBOX");
+        let registry = self.global.registry;
+        if let Value::Table(t) = self.global.heap.get(registry).get_str(key).value() {
+            return t;
+        }
+        let whitelist = self.new_table();
+        for name in SAFE_GLOBALS {
+            let v = self.get_global(name);
+            let key = self.global.heap.intern(name.as_bytes());
+            self.global.heap.get_mut(whitelist).set_str(key, v);
+        }
+        for (libname, names) in SAFE_LIBS {
+            let Value::Table(src) = self.get_global(libname).value() else {
+                continue;
+            };
+            let lib = self.new_table();
+            for name in *names {
+                let key = self.global.heap.intern(name.as_bytes());
+                let v = self.global.heap.get(src).get_str(key);
+                self.global.heap.get_mut(lib).set_str(key, v);
+            }
+            let key = self.global.heap.intern(libname.as_bytes());
+            self.global
+                .heap
+                .get_mut(whitelist)
+                .set_str(key, TValue::table(lib));
+        }
+        let name = self.global.heap.intern(b"getmetatable");
+        let f = self.new_native(sandbox_getmetatable);
+        self.global.heap.get_mut(whitelist).set_str(name, f);
+        self.global
+            .heap
+            .get_mut(registry)
+            .set_str(key, TValue::table(whitelist));
+        whitelist
+    }
+
+    /// new table with the entries of `t`
+    fn copy_table(&mut self, t: Gc<Table>) -> Gc<Table> {
+        let copy = self.new_table();
+        let mut key = TValue::nil();
+        while let Ok(Some((k, v))) = self.global.heap.get(t).next(&key) {
+            self.global.heap.get_mut(copy).set(k, v).unwrap();
+            key = k;
+        }
+        copy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iolib::SystemAccess;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_sandbox() {
+        let mut lua = LuaState::new();
+        lua.open_libs(SystemAccess::SAFE);
+        let a = lua.create_sandbox();
+        let b = lua.create_sandbox();
+
+        // globals and libraries are per sandbox
+        lua.exec_sandboxed::<()>(&a, "x = 1 string.rep = nil function f() return x end")
+            .unwrap();
+        assert_eq!(lua.sandbox_global::<i32>(&a, "x").unwrap(), 1);
+        assert_eq!(lua.sandbox_global::<Option<i32>>(&b, "x").unwrap(), None);
+        assert_eq!(lua.global::<Option<i32>>("x").unwrap(), None);
+        assert_eq!(
+            lua.exec_sandboxed::<String>(&b, "return string.rep('a', 3)")
+                .unwrap(),
+            "aaa"
+        );
+        assert_eq!(lua.exec::<String>("return ('b'):rep(2)").unwrap(), "bb");
+        lua.set_sandbox_global(&b, "x", 2).unwrap();
+        assert_eq!(lua.exec_sandboxed::<i32>(&b, "return _G.x").unwrap(), 2);
+
+        // only the safe part of the libraries is there
+        let missing: Vec<bool> = lua
+            .exec_sandboxed(
+                &a,
+                "return {io == nil, debug == nil, require == nil, dofile == nil,
+                 collectgarbage == nil, os.exit == nil, getmetatable('') == nil}",
+            )
+            .unwrap();
+        assert_eq!(missing, vec![true; 7]);
+
+        // loaded chunks share the environment of their loader
+        let n: i32 = lua
+            .exec_sandboxed(&a, "return loadstring('return x + 1')()")
+            .unwrap();
+        assert_eq!(n, 2);
+        let msg: String = lua
+            .exec_sandboxed(&a, "return select(2, loadstring('\\27Lua'))")
+            .unwrap();
+        assert_eq!(msg, "attempt to load a binary chunk");
+
+        // setfenv only reaches the sandbox's own functions
+        let f: TValue = lua.sandbox_global(&a, "f").unwrap();
+        lua.set_sandbox_global(&b, "g", f).unwrap();
+        let err = lua.exec_sandboxed::<()>(&b, "setfenv(g, {})").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"setfenv(g, {})\"]:1: bad argument #1 to 'setfenv' \
+             (function of another environment)"
+        );
+        let err = lua
+            .exec_sandboxed::<()>(&b, "setfenv(print, {})")
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("(Lua function expected, got function)")
+        );
+        let n: i32 = lua
+            .exec_sandboxed(&a, "return setfenv(f, {x = 5})()")
+            .unwrap();
+        assert_eq!(n, 5);
+
+        // pcall, xpcall or a coroutine between the script and the native
+        // does not lend it the environment of the host
+        lua.exec::<()>("function hostfn() return debug end")
+            .unwrap();
+        let hostfn = lua.get_global("hostfn");
+        lua.set_sandbox_global(&a, "hostfn", hostfn).unwrap();
+        let safe: Vec<bool> = lua
+            .exec_sandboxed(
+                &a,
+                "local co = coroutine.wrap(function(f, ...) return select(2, pcall(f, ...)) end)
+                 return {select(2, pcall(getfenv)) == _G,
+                 select(2, pcall(loadstring, 'return debug'))() == nil,
+                 not pcall(setfenv, hostfn, {}),
+                 not pcall(getfenv, hostfn),
+                 select(2, xpcall(getfenv, print)) == _G,
+                 co(getfenv) == _G}",
+            )
+            .unwrap();
+        assert_eq!(safe, vec![true; 6]);
+    }
+}
@@ -0,0 +1,878 @@
+//! Serde bridge: any `Serialize` value becomes a Lua value, and Lua values
+//! deserialize into any `Deserialize` type.
+//!
+//! Sequences become array tables and maps and structs become tables with
+//! their keys; `None` and `()` are nil. Unit enum variants are strings, the
+//! other variants tables with the variant name as only key. When a
+//! deserializer does not say what it expects, a table is read as a sequence
+//! if its keys are 1 to n, with nil holes, and as a map otherwise, and a
+//! number as an integer if it has no fractional part. A sequence ending with
+//! nil also gets its length as field `n`, like the `arg` table of a vararg
+//! function.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
+
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, VariantAccess,
+    Visitor,
+};
+use serde::ser::{self, Serialize};
+
+use crate::error::LuaError;
+use crate::eval::{LuaInteger, LuaNumber, TValue, Value};
+use crate::heap::Gc;
+use crate::table::Table;
+use crate::vm::{LuaState, type_name};
+
+/// largest integer magnitude a Lua number holds exactly
+const MAX_EXACT_INTEGER: u64 = 1 << 53;
+
+/// Error converting between Lua values and serde data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Options of `deserialize_with`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeserializeOptions {
+    /// Fail on a table found again inside itself; otherwise it reads as nil
+    /// there. Tables shared by several fields are always accepted.
+    pub deny_recursive_tables: bool,
+}
+
+impl Default for DeserializeOptions {
+    fn default() -> Self {
+        DeserializeOptions {
+            deny_recursive_tables: true,
+        }
+    }
+}
+
+impl LuaState {
+    /// Lua value of `value`: a table for structs, maps and sequences.
+    pub fn serialize<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> std::result::Result<TValue, LuaError> {
+        value
+            .serialize(Serializer { state: self })
+            .map_err(|e| self.error_object(e.into()))
+    }
+
+    /// `val` deserialized into `T`, failing on recursive tables.
+    pub fn deserialize<T: DeserializeOwned>(
+        &mut self,
+        val: TValue,
+    ) -> std::result::Result<T, LuaError> {
+        self.deserialize_with(val, DeserializeOptions::default())
+    }
+
+    pub fn deserialize_with<T: DeserializeOwned>(
+        &mut self,
+        val: TValue,
+        options: DeserializeOptions,
+    ) -> std::result::Result<T, LuaError> {
+        self.check_live(&[val])?;
+        let visiting = RefCell::new(HashSet::new());
+        let de = Deserializer {
+            state: self,
+            value: val,
+            visiting: &visiting,
+            options,
+        };
+        T::deserialize(de).map_err(|e| self.error_object(e.into()))
+    }
+}
+
+// ---- Rust to Lua ----
+
+struct Serializer<'a> {
+    state: &'a mut LuaState,
+}
+
+fn integer(n: i128) -> Result<TValue> {
+    match LuaInteger::try_from(n) {
+        Ok(n) => Ok(TValue::integer(n)),
+        Err(_) => Err(Error(format!("integer {n} cannot be represented exactly"))),
+    }
+}
+
+impl<'a> Serializer<'a> {
+    fn string(self, bytes: &[u8]) -> TValue {
+        self.state.intern(bytes)
+    }
+    /// `{[variant] = val}`
+    fn variant(state: &mut LuaState, variant: &str, val: TValue) -> TValue {
+        let t = state.new_table();
+        let key = state.global.heap.intern(variant.as_bytes());
+        state.global.heap.get_mut(t).set_str(key, val);
+        TValue::table(t)
+    }
+}
+
+impl<'a> ser::Serializer for Serializer<'a> {
+    type Ok = TValue;
+    type Error = Error;
+    type SerializeSeq = SerializeTable<'a>;
+    type SerializeTuple = SerializeTable<'a>;
+    type SerializeTupleStruct = SerializeTable<'a>;
+    type SerializeTupleVariant = SerializeTable<'a>;
+    type SerializeMap = SerializeTable<'a>;
+    type SerializeStruct = SerializeTable<'a>;
+    type SerializeStructVariant = SerializeTable<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<TValue> {
+        Ok(TValue::boolean(v))
+    }
+    fn serialize_i8(self, v: i8) -> Result<TValue> {
+        integer(v.into())
+    }
+    fn serialize_i16(self, v: i16) -> Result<TValue> {
+        integer(v.into())
+    }
+    fn serialize_i32(self, v: i32) -> Result<TValue> {
+        integer(v.into())
+    }
+    fn serialize_i64(self, v: i64) -> Result<TValue> {
+        integer(v.into())
+    }
+    fn serialize_i128(self, v: i128) -> Result<TValue> {
+        integer(v)
+    }
+    fn serialize_u8(self, v: u8) -> Result<TValue> {
+        integer(v.into())
+    }
+    fn serialize_u16(self, v: u16) -> Result<TValue> {
+        integer(v.into())
+    }
+    fn serialize_u32(self, v: u32) -> Result<TValue> {
+        integer(v.into())
+    }
+    fn serialize_u64(self, v: u64) -> Result<TValue> {
+        integer(v.into())
+    }
+    fn serialize_u128(self, v: u128) -> Result<TValue> {
+        integer(i128::try_from(v).unwrap_or(i128::MAX))
+    }
+    fn serialize_f32(self, v: f32) -> Result<TValue> {
+        Ok(TValue::number(v as LuaNumber))
+    }
+    fn serialize_f64(self, v: f64) -> Result<TValue> {
+        Ok(TValue::number(v))
+    }
+    fn serialize_char(self, v: char) -> Result<TValue> {
+        Ok(self.string(v.encode_utf8(&mut [0; 4]).as_bytes()))
+    }
+    fn serialize_str(self, v: &str) -> Result<TValue> {
+        Ok(self.string(v.as_bytes()))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<TValue> {
+        Ok(self.string(v))
+    }
+    fn serialize_none(self) -> Result<TValue> {
+        Ok(TValue::nil())
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<TValue> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<TValue> {
+        Ok(TValue::nil())
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<TValue> {
+        Ok(TValue::nil())
+    }
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<TValue> {
+        Ok(self.string(variant.as_bytes()))
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<TValue> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<TValue> {
+        let val = value.serialize(Serializer { state: self.state })?;
+        Ok(Serializer::variant(self.state, variant, val))
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<SerializeTable<'a>> {
+        Ok(SerializeTable::new(self.state, None))
+    }
+    fn serialize_tuple(self, _: usize) -> Result<SerializeTable<'a>> {
+        Ok(SerializeTable::new(self.state, None))
+    }
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<SerializeTable<'a>> {
+        Ok(SerializeTable::new(self.state, None))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<SerializeTable<'a>> {
+        Ok(SerializeTable::new(self.state, Some(variant)))
+    }
+    fn serialize_map(self, _: Option<usize>) -> Result<SerializeTable<'a>> {
+        Ok(SerializeTable::new(self.state, None))
+    }
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<SerializeTable<'a>> {
+        Ok(SerializeTable::new(self.state, None))
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<SerializeTable<'a>> {
+        Ok(SerializeTable::new(self.state, Some(variant)))
+    }
+}
+
+/// Table being filled by a sequence, map or struct, wrapped in
+/// `{[variant] = table}` at the end for enum variants.
+struct SerializeTable<'a> {
+    state: &'a mut LuaState,
+    table: Gc<Table>,
+    len: i64,
+    /// whether the last element pushed was nil, to record the length
+    trailing_nil: bool,
+    key: Option<TValue>,
+    variant: Option<&'static str>,
+}
+
+impl<'a> SerializeTable<'a> {
+    fn new(state: &'a mut LuaState, variant: Option<&'static str>) -> Self {
+        let table = state.new_table();
+        SerializeTable {
+            state,
+            table,
+            len: 0,
+            trailing_nil: false,
+            key: None,
+            variant,
+        }
+    }
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let val = value.serialize(Serializer { state: self.state })?;
+        self.len += 1;
+        self.trailing_nil = matches!(val.value(), Value::Nil);
+        self.state
+            .global
+            .heap
+            .get_mut(self.table)
+            .set_int(self.len, val);
+        Ok(())
+    }
+    fn set(&mut self, key: TValue, val: TValue) -> Result<()> {
+        if matches!(key.value(), Value::Nil) {
+            return Err(Error("table index is nil".to_string()));
+        }
+        self.state
+            .global
+            .heap
+            .get_mut(self.table)
+            .set(key, val)
+            .map_err(|e| Error(e.to_string()))
+    }
+    fn field<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<()> {
+        let val = value.serialize(Serializer { state: self.state })?;
+        let key = self.state.intern(name.as_bytes());
+        self.set(key, val)
+    }
+    fn finish(mut self) -> Result<TValue> {
+        if self.trailing_nil {
+            let key = self.state.intern(b"n");
+            self.set(key, TValue::integer(self.len))?;
+        }
+        let t = TValue::table(self.table);
+        Ok(match self.variant {
+            Some(variant) => Serializer::variant(self.state, variant, t),
+            None => t,
+        })
+    }
+}
+
+impl ser::SerializeSeq for SerializeTable<'_> {
+    type Ok = TValue;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+    fn end(self) -> Result<TValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeTable<'_> {
+    type Ok = TValue;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+    fn end(self) -> Result<TValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeTable<'_> {
+    type Ok = TValue;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+    fn end(self) -> Result<TValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeTable<'_> {
+    type Ok = TValue;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+    fn end(self) -> Result<TValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for SerializeTable<'_> {
+    type Ok = TValue;
+    type Error = Error;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(key.serialize(Serializer { state: self.state })?);
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_key before serialize_value");
+        let val = value.serialize(Serializer { state: self.state })?;
+        self.set(key, val)
+    }
+    fn end(self) -> Result<TValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeTable<'_> {
+    type Ok = TValue;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.field(key, value)
+    }
+    fn end(self) -> Result<TValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeTable<'_> {
+    type Ok = TValue;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.field(key, value)
+    }
+    fn end(self) -> Result<TValue> {
+        self.finish()
+    }
+}
+
+// ---- Lua to Rust ----
+
+#[derive(Clone, Copy)]
+struct Deserializer<'de> {
+    state: &'de LuaState,
+    value: TValue,
+    /// tables being deserialized, to detect recursion
+    visiting: &'de RefCell<HashSet<Gc<Table>>>,
+    options: DeserializeOptions,
+}
+
+/// The values `t[1]` to `t[n]` if the other keys of `t` are indices up to
+/// its border `n`, holes read as nil. An integer field `n` at or past the
+/// border gives the length, so that trailing nils are kept. Tables less than
+/// half full are not sequences, as Lua would not keep them in an array.
+fn sequence(state: &LuaState, entries: &[(TValue, TValue)]) -> Option<Vec<TValue>> {
+    let mut len = 0;
+    let mut items = Vec::with_capacity(entries.len());
+    let mut length = None;
+    for (k, v) in entries {
+        match (k.value(), v.value()) {
+            (Value::String(s), Value::Integer(n))
+                if state.global.heap.get(s).as_bytes() == b"n" =>
+            {
+                length = Some(usize::try_from(n).ok()?);
+            }
+            _ => match k.as_number() {
+                Some(i) if i.fract() == 0.0 && i >= 1.0 && i <= u32::MAX as LuaNumber => {
+                    len = len.max(i as usize);
+                    items.push((i as usize, *v));
+                }
+                _ => return None,
+            },
+        }
+    }
+    if let Some(n) = length {
+        if n < len {
+            return None;
+        }
+        len = n;
+    }
+    if len > 2 * items.len().max(1) {
+        return None;
+    }
+    let mut seq = vec![TValue::nil(); len];
+    for (i, v) in items {
+        seq[i - 1] = v;
+    }
+    Some(seq)
+}
+
+impl<'de> Deserializer<'de> {
+    fn with_value(&self, value: TValue) -> Self {
+        Deserializer { value, ..*self }
+    }
+    fn invalid_type(&self, expected: &dyn de::Expected) -> Error {
+        de::Error::invalid_type(de::Unexpected::Other(type_name(&self.value)), expected)
+    }
+    /// Entries of the table `t`, with `t` marked as visited until `f`
+    /// returns. A recursive table reads as nil unless that is denied.
+    fn visit_table<V: Visitor<'de>>(
+        &self,
+        t: Gc<Table>,
+        visitor: V,
+        f: impl FnOnce(V, Vec<(TValue, TValue)>) -> Result<V::Value>,
+    ) -> Result<V::Value> {
+        if !self.visiting.borrow_mut().insert(t) {
+            if self.options.deny_recursive_tables {
+                return Err(Error("recursive table detected".to_string()));
+            }
+            return visitor.visit_unit();
+        }
+        let entries: Vec<(TValue, TValue)> = self.state.global.heap.get(t).entries().collect();
+        let result = f(visitor, entries);
+        self.visiting.borrow_mut().remove(&t);
+        result
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value.value() {
+            Value::Nil => visitor.visit_unit(),
+            Value::Boolean(b) => visitor.visit_bool(b),
+            Value::Integer(n) => visitor.visit_i64(n),
+            Value::Number(n) => {
+                if n.fract() == 0.0 && n.abs() <= MAX_EXACT_INTEGER as LuaNumber {
+                    visitor.visit_i64(n as i64)
+                } else {
+                    visitor.visit_f64(n)
+                }
+            }
+            Value::String(s) => {
+                let bytes = self.state.global.heap.get(s).as_bytes();
+                match std::str::from_utf8(bytes) {
+                    Ok(s) => visitor.visit_borrowed_str(s),
+                    Err(_) => visitor.visit_borrowed_bytes(bytes),
+                }
+            }
+            Value::Table(t) => self.visit_table(t, visitor, |visitor, entries| {
+                match sequence(self.state, &entries) {
+                    Some(items) if !items.is_empty() => {
+                        visitor.visit_seq(SeqDeserializer::new(&self, items))
+                    }
+                    _ => visitor.visit_map(MapDeserializer::new(&self, entries)),
+                }
+            }),
+            _ => Err(Error(format!(
+                "cannot deserialize a {} value",
+                type_name(&self.value)
+            ))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value.value() {
+            Value::Nil => visitor.visit_none(),
+            Value::Table(t) if !self.options.deny_recursive_tables => {
+                // a recursive table reads as nil
+                if self.visiting.borrow().contains(&t) {
+                    visitor.visit_none()
+                } else {
+                    visitor.visit_some(self)
+                }
+            }
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let Value::Table(t) = self.value.value() else {
+            return Err(self.invalid_type(&visitor));
+        };
+        self.visit_table(t, visitor, |visitor, entries| {
+            match sequence(self.state, &entries) {
+                Some(items) => visitor.visit_seq(SeqDeserializer::new(&self, items)),
+                None => Err(Error("table is not a sequence".to_string())),
+            }
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let Value::Table(t) = self.value.value() else {
+            return Err(self.invalid_type(&visitor));
+        };
+        self.visit_table(t, visitor, |visitor, entries| {
+            visitor.visit_map(MapDeserializer::new(&self, entries))
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.value.value() {
+            Value::String(_) => visitor.visit_enum(EnumDeserializer {
+                de: self,
+                variant: self.value,
+                value: None,
+            }),
+            Value::Table(t) => {
+                let entries: Vec<_> = self.state.global.heap.get(t).entries().collect();
+                match entries[..] {
+                    [(variant, value)] => visitor.visit_enum(EnumDeserializer {
+                        de: self,
+                        variant,
+                        value: Some(value),
+                    }),
+                    _ => Err(Error("enum table must have exactly one entry".to_string())),
+                }
+            }
+            _ => Err(self.invalid_type(&"string or table")),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct identifier ignored_any
+    }
+}
+
+struct SeqDeserializer<'de> {
+    de: Deserializer<'de>,
+    items: std::vec::IntoIter<TValue>,
+}
+
+impl<'de> SeqDeserializer<'de> {
+    fn new(de: &Deserializer<'de>, items: Vec<TValue>) -> Self {
+        SeqDeserializer {
+            de: *de,
+            items: items.into_iter(),
+        }
+    }
+}
+
+impl<'de> SeqAccess<'de> for SeqDeserializer<'de> {
+    type Error = Error;
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        match self.items.next() {
+            Some(v) => seed.deserialize(self.de.with_value(v)).map(Some),
+            None => Ok(None),
+        }
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+struct MapDeserializer<'de> {
+    de: Deserializer<'de>,
+    entries: std::vec::IntoIter<(TValue, TValue)>,
+    value: Option<TValue>,
+}
+
+impl<'de> MapDeserializer<'de> {
+    fn new(de: &Deserializer<'de>, entries: Vec<(TValue, TValue)>) -> Self {
+        MapDeserializer {
+            de: *de,
+            entries: entries.into_iter(),
+            value: None,
+        }
+    }
+}
+
+impl<'de> MapAccess<'de> for MapDeserializer<'de> {
+    type Error = Error;
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.entries.next() {
+            Some((k, v)) => {
+                self.value = Some(v);
+                seed.deserialize(self.de.with_value(k)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let v = self
+            .value
+            .take()
+            .expect("next_key_seed before next_value_seed");
+        seed.deserialize(self.de.with_value(v))
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+/// a variant name, with the value of the variant unless it is a unit one
+struct EnumDeserializer<'de> {
+    de: Deserializer<'de>,
+    variant: TValue,
+    value: Option<TValue>,
+}
+
+impl<'de> EnumAccess<'de> for EnumDeserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let variant = seed.deserialize(self.de.with_value(self.variant))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for EnumDeserializer<'de> {
+    type Error = Error;
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        let v = self.value.unwrap_or_else(TValue::nil);
+        seed.deserialize(self.de.with_value(v))
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value> {
+        let v = self.value.unwrap_or_else(TValue::nil);
+        de::Deserializer::deserialize_seq(self.de.with_value(v), visitor)
+    }
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        let v = self.value.unwrap_or_else(TValue::nil);
+        de::Deserializer::deserialize_map(self.de.with_value(v), visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iolib::SystemAccess;
+    use pretty_assertions::assert_eq;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, serde::Serialize, Deserialize)]
+    enum Mode {
+        Fast,
+        Limit(u32),
+        Range { from: i64, to: i64 },
+    }
+
+    #[derive(Debug, PartialEq, serde::Serialize, Deserialize)]
+    struct Config {
+        name: String,
+        ratio: f64,
+        retries: u8,
+        tags: Vec<String>,
+        env: BTreeMap<String, String>,
+        timeout: Option<u32>,
+        modes: Vec<Mode>,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut lua = LuaState::new();
+        lua.open_libs(SystemAccess::SAFE);
+        let config = Config {
+            name: "svc".to_string(),
+            ratio: 0.5,
+            retries: 3,
+            tags: vec!["a".to_string(), "b".to_string()],
+            env: BTreeMap::from([("HOME".to_string(), "/root".to_string())]),
+            timeout: None,
+            modes: vec![Mode::Fast, Mode::Limit(7), Mode::Range { from: -1, to: 2 }],
+        };
+        let val = lua.serialize(&config).unwrap();
+        lua.set_global("config", val).unwrap();
+        let shape: String = lua
+            .exec(
+                "local c = config return table.concat({c.name, #c.tags, c.env.HOME, \
+                 tostring(c.timeout), c.modes[1], c.modes[2].Limit, c.modes[3].Range.to}, ' ')",
+            )
+            .unwrap();
+        assert_eq!(shape, "svc 2 /root nil Fast 7 2");
+        let back: Config = lua.deserialize(val).unwrap();
+        assert_eq!(back, config);
+
+        // nil holes and trailing nils are kept
+        for list in [
+            vec![Some(1), None, Some(3)],
+            vec![Some(1), None],
+            vec![None],
+        ] {
+            let val = lua.serialize(&list).unwrap();
+            assert_eq!(lua.deserialize::<Vec<Option<i32>>>(val).unwrap(), list);
+        }
+
+        // integers keep the integer variant past 2^53
+        let big = lua.serialize(&(i64::MAX - 1)).unwrap();
+        lua.set_global("big", big).unwrap();
+        let kind: String = lua.exec("return math.type(big)").unwrap();
+        assert_eq!(kind, "integer");
+        assert_eq!(lua.deserialize::<i64>(big).unwrap(), i64::MAX - 1);
+        let err = lua.serialize(&u64::MAX).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "integer 18446744073709551615 cannot be represented exactly"
+        );
+    }
+
+    #[test]
+    fn test_deserialize_any() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        #[serde(untagged)]
+        enum Any {
+            Nil,
+            Bool(bool),
+            Int(i64),
+            Float(f64),
+            Str(String),
+            List(Vec<Any>),
+            Map(BTreeMap<String, Any>),
+        }
+        let mut lua = LuaState::new();
+        let val: TValue = lua.exec("return {1, 2.5, 'x', {}, {k = true}}").unwrap();
+        let any: Any = lua.deserialize(val).unwrap();
+        assert_eq!(
+            any,
+            Any::List(vec![
+                Any::Int(1),
+                Any::Float(2.5),
+                Any::Str("x".to_string()),
+                Any::Map(BTreeMap::new()),
+                Any::Map(BTreeMap::from([("k".to_string(), Any::Bool(true))])),
+            ])
+        );
+        let err = lua.deserialize::<u32>(TValue::number(1.5)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid type: floating point `1.5`, expected u32"
+        );
+        let val: TValue = lua.exec("return {1, 2, x = 3}").unwrap();
+        let err = lua.deserialize::<Vec<i32>>(val).unwrap_err();
+        assert_eq!(err.to_string(), "table is not a sequence");
+
+        // recursive tables fail, or read as nil when allowed
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Node {
+            name: String,
+            next: Option<Box<Node>>,
+        }
+        let val: TValue = lua
+            .exec("local a = {name = 'a'} a.next = {name = 'b', next = a} return a")
+            .unwrap();
+        let err = lua.deserialize::<Node>(val).unwrap_err();
+        assert_eq!(err.to_string(), "recursive table detected");
+        let options = DeserializeOptions {
+            deny_recursive_tables: false,
+        };
+        let node: Node = lua.deserialize_with(val, options).unwrap();
+        assert_eq!(node.next.unwrap().next, None);
+        // shared tables are not recursive
+        let val: TValue = lua.exec("local t = {1} return {t, t}").unwrap();
+        assert_eq!(lua.deserialize::<Vec<Vec<u8>>>(val).unwrap(), [[1], [1]]);
+        // a collected value is refused
+        lua.gc_collect().unwrap();
+        let err = lua.deserialize::<Vec<Vec<u8>>>(val).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to use a collected value");
+    }
+}
@@ -0,0 +1,984 @@
+use anyhow::Result;
+
+use crate::eval::{LuaNumber, TValue, Value};
+use crate::printf::Spec;
+use crate::vm::{LUAI_MAXCCALLS, LUAI_MAXCSTACK, LuaState, is_false, type_name};
+
+/// relative string position: negative means back from the end
+fn posrelat(pos: i64, len: usize) -> i64 {
+    let pos = if pos < 0 { pos + len as i64 + 1 } else { pos };
+    pos.max(0)
+}
+
+/// copy of string argument `narg`
+fn check_bytes(state: &mut LuaState, narg: usize) -> Result<Vec<u8>> {
+    let s = state.check_string(narg)?;
+    Ok(state.str_bytes(s).to_vec())
+}
+
+/// string.len(s)
+fn str_len(state: &mut LuaState) -> Result<usize> {
+    let s = state.check_string(1)?;
+    let n = state.str_bytes(s).len();
+    state.push(TValue::number(n as LuaNumber));
+    Ok(1)
+}
+
+/// string.sub(s, i [, j])
+fn str_sub(state: &mut LuaState) -> Result<usize> {
+    let s = check_bytes(state, 1)?;
+    let l = s.len();
+    let start = posrelat(state.check_integer(2)?, l).max(1);
+    let end = posrelat(state.opt_integer(3, -1)?, l).min(l as i64);
+    let res = if start <= end {
+        state.intern(&s[start as usize - 1..end as usize])
+    } else {
+        state.intern(b"")
+    };
+    state.push(res);
+    Ok(1)
+}
+
+/// string.reverse(s)
+fn str_reverse(state: &mut LuaState) -> Result<usize> {
+    let mut s = check_bytes(state, 1)?;
+    s.reverse();
+    let res = state.intern(&s);
+    state.push(res);
+    Ok(1)
+}
+
+/// string.lower(s)
+fn str_lower(state: &mut LuaState) -> Result<usize> {
+    let s = check_bytes(state, 1)?;
+    let res = state.intern(&s.to_ascii_lowercase());
+    state.push(res);
+    Ok(1)
+}
+
+/// string.upper(s)
+fn str_upper(state: &mut LuaState) -> Result<usize> {
+    let s = check_bytes(state, 1)?;
+    let res = state.intern(&s.to_ascii_uppercase());
+    state.push(res);
+    Ok(1)
+}
+
+/// string.rep(s, n)
+fn str_rep(state: &mut LuaState) -> Result<usize> {
+    let s = check_bytes(state, 1)?;
+    let n = state.check_integer(2)?.max(0) as usize;
+    let Some(len) = s.len().checked_mul(n).filter(|&len| len <= MAX_STRING_SIZE) else {
+        return Err(state.error("resulting string too large".to_string()));
+    };
+    state.check_string_length(len)?;
+    let mut buf = Vec::new();
+    if buf.try_reserve_exact(len).is_err() {
+        return Err(state.error("not enough memory".to_string()));
+    }
+    for _ in 0..n {
+        buf.extend_from_slice(&s);
+    }
+    let res = state.intern(&buf);
+    state.push(res);
+    Ok(1)
+}
+
+/// string.byte(s [, i [, j]])
+fn str_byte(state: &mut LuaState) -> Result<usize> {
+    let s = check_bytes(state, 1)?;
+    let l = s.len();
+    let posi = posrelat(state.opt_integer(2, 1)?, l);
+    let pose = posrelat(state.opt_integer(3, posi)?, l).min(l as i64);
+    let posi = posi.max(1);
+    if posi > pose {
+        return Ok(0);
+    }
+    let n = (pose - posi + 1) as usize;
+    if n >= LUAI_MAXCSTACK {
+        return Err(state.error("string slice too long".to_string()));
+    }
+    state.check_stack(n);
+    for &b in &s[posi as usize - 1..pose as usize] {
+        state.push(TValue::number(b as LuaNumber));
+    }
+    Ok(n)
+}
+
+/// string.char(...)
+fn str_char(state: &mut LuaState) -> Result<usize> {
+    let n = state.get_top();
+    let mut bytes = Vec::with_capacity(n);
+    for i in 1..=n {
+        let c = state.check_integer(i)?;
+        if !(0..=255).contains(&c) {
+            return Err(state.arg_error(i, "invalid value"));
+        }
+        bytes.push(c as u8);
+    }
+    let res = state.intern(&bytes);
+    state.push(res);
+    Ok(1)
+}
+
+// ---- pattern matching ----
+
+/// longest string made by string.rep (MAX_INT of luaconf.h)
+const MAX_STRING_SIZE: usize = i32::MAX as usize;
+const L_ESC: u8 = b'%';
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+const LUA_MAXCAPTURES: usize = 32;
+const CAP_UNFINISHED: isize = -1;
+const CAP_POSITION: isize = -2;
+
+/// a capture as found by the matcher
+enum Capture {
+    Str(usize, usize),
+    Position(usize),
+}
+
+/// State of a match of `pat` against `src` (MatchState in lstrlib.c).
+/// Positions are byte offsets; errors are plain messages for `LuaState::error`.
+struct MatchState<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    level: usize,
+    /// (start, length or CAP_*) of each capture
+    capture: [(usize, isize); LUA_MAXCAPTURES],
+    /// remaining recursion budget
+    matchdepth: usize,
+}
+
+type MatchResult = std::result::Result<Option<usize>, String>;
+
+fn is_space(c: u8) -> bool {
+    c == b' ' || (b'\t'..=b'\r').contains(&c)
+}
+
+/// does `c` belong to class `%cl`
+fn match_class(c: u8, cl: u8) -> bool {
+    let res = match cl.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => is_space(c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        b'z' => c == 0,
+        _ => return cl == c,
+    };
+    if cl.is_ascii_uppercase() { !res } else { res }
+}
+
+impl<'a> MatchState<'a> {
+    fn new(src: &'a [u8], pat: &'a [u8]) -> Self {
+        Self {
+            src,
+            pat,
+            level: 0,
+            capture: [(0, 0); LUA_MAXCAPTURES],
+            matchdepth: LUAI_MAXCCALLS,
+        }
+    }
+
+    /// end of the single character class starting at `p`
+    fn class_end(&self, p: usize) -> std::result::Result<usize, String> {
+        let pat = self.pat;
+        let mut p = p;
+        let c = pat[p];
+        p += 1;
+        if c == L_ESC {
+            if p >= pat.len() {
+                return Err("malformed pattern (ends with '%')".to_string());
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if pat.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // look for a ']'
+            loop {
+                if p >= pat.len() {
+                    return Err("malformed pattern (missing ']')".to_string());
+                }
+                let c = pat[p];
+                p += 1;
+                if c == L_ESC && p < pat.len() {
+                    p += 1;
+                }
+                if pat.get(p) == Some(&b']') {
+                    break;
+                }
+            }
+            return Ok(p + 1);
+        }
+        Ok(p)
+    }
+
+    /// does `c` match the set `[...]` between `p` and its closing bracket `ec`
+    fn match_bracket_class(&self, c: u8, p: usize, ec: usize) -> bool {
+        let pat = self.pat;
+        let mut p = p;
+        let mut sig = true;
+        if pat[p + 1] == b'^' {
+            sig = false;
+            p += 1;
+        }
+        p += 1;
+        while p < ec {
+            if pat[p] == L_ESC {
+                p += 1;
+                if match_class(c, pat[p]) {
+                    return sig;
+                }
+            } else if pat[p + 1] == b'-' && p + 2 < ec {
+                if pat[p] <= c && c <= pat[p + 2] {
+                    return sig;
+                }
+                p += 2;
+            } else if pat[p] == c {
+                return sig;
+            }
+            p += 1;
+        }
+        !sig
+    }
+
+    /// does the character at `s` match the class `p..ep`
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let Some(&c) = self.src.get(s) else {
+            return false;
+        };
+        match self.pat[p] {
+            b'.' => true,
+            L_ESC => match_class(c, self.pat[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> MatchResult {
+        if p + 1 >= self.pat.len() {
+            return Err("unbalanced pattern".to_string());
+        }
+        let (b, e) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&b) {
+            return Ok(None);
+        }
+        let mut cont = 1;
+        for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+            if c == e {
+                cont -= 1;
+                if cont == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == b {
+                cont += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> MatchResult {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        // try with the maximum repetitions, then fewer
+        loop {
+            if let Some(res) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(res));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, s: usize, p: usize, ep: usize) -> MatchResult {
+        let mut s = s;
+        loop {
+            if let Some(res) = self.do_match(s, ep + 1)? {
+                return Ok(Some(res));
+            }
+            if self.single_match(s, p, ep) {
+                s += 1;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> MatchResult {
+        if self.level >= LUA_MAXCAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.capture[self.level] = (s, what);
+        self.level += 1;
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.level -= 1;
+        }
+        Ok(res)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> MatchResult {
+        let l = self.capture_to_close()?;
+        self.capture[l].1 = (s - self.capture[l].0) as isize;
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.capture[l].1 = CAP_UNFINISHED;
+        }
+        Ok(res)
+    }
+
+    fn capture_to_close(&self) -> std::result::Result<usize, String> {
+        (0..self.level)
+            .rev()
+            .find(|&l| self.capture[l].1 == CAP_UNFINISHED)
+            .ok_or_else(|| "invalid pattern capture".to_string())
+    }
+
+    fn check_capture(&self, l: u8) -> std::result::Result<usize, String> {
+        let l = l.wrapping_sub(b'1') as usize;
+        if l >= self.level || self.capture[l].1 == CAP_UNFINISHED {
+            return Err("invalid capture index".to_string());
+        }
+        Ok(l)
+    }
+
+    /// back-reference `%l`
+    fn match_capture(&self, s: usize, l: u8) -> MatchResult {
+        let l = self.check_capture(l)?;
+        let (init, len) = self.capture[l];
+        let len = len.max(0) as usize;
+        if self.src.len() - s >= len && self.src[init..init + len] == self.src[s..s + len] {
+            Ok(Some(s + len))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// match `pat[p..]` at `src[s..]`, returning the end of the match
+    fn do_match(&mut self, s: usize, p: usize) -> MatchResult {
+        if self.matchdepth == 0 {
+            return Err("pattern too complex".to_string());
+        }
+        self.matchdepth -= 1;
+        let res = self.match_inner(s, p);
+        self.matchdepth += 1;
+        res
+    }
+
+    fn match_inner(&mut self, s: usize, p: usize) -> MatchResult {
+        let (mut s, mut p) = (s, p);
+        loop {
+            if p == self.pat.len() {
+                return Ok(Some(s));
+            }
+            match (self.pat[p], self.pat.get(p + 1).copied()) {
+                (b'(', Some(b')')) => return self.start_capture(s, p + 2, CAP_POSITION),
+                (b'(', _) => return self.start_capture(s, p + 1, CAP_UNFINISHED),
+                (b')', _) => return self.end_capture(s, p + 1),
+                (b'$', None) => return Ok((s == self.src.len()).then_some(s)),
+                (L_ESC, Some(b'b')) => match self.match_balance(s, p + 2)? {
+                    Some(e) => {
+                        s = e;
+                        p += 4;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+                (L_ESC, Some(b'f')) => {
+                    p += 2;
+                    if self.pat.get(p) != Some(&b'[') {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let ep = self.class_end(p)?;
+                    let prev = if s == 0 { 0 } else { self.src[s - 1] };
+                    let cur = self.src.get(s).copied().unwrap_or(0);
+                    if !self.match_bracket_class(prev, p, ep - 1)
+                        && self.match_bracket_class(cur, p, ep - 1)
+                    {
+                        p = ep;
+                        continue;
+                    }
+                    return Ok(None);
+                }
+                (L_ESC, Some(d)) if d.is_ascii_digit() => match self.match_capture(s, d)? {
+                    Some(e) => {
+                        s = e;
+                        p += 2;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+                _ => {}
+            }
+            // a single character class, possibly followed by a repetition
+            let ep = self.class_end(p)?;
+            let m = self.single_match(s, p, ep);
+            match self.pat.get(ep) {
+                Some(b'?') => {
+                    if m && let Some(res) = self.do_match(s + 1, ep + 1)? {
+                        return Ok(Some(res));
+                    }
+                    p = ep + 1;
+                }
+                Some(b'*') => return self.max_expand(s, p, ep),
+                Some(b'+') => {
+                    return if m {
+                        self.max_expand(s + 1, p, ep)
+                    } else {
+                        Ok(None)
+                    };
+                }
+                Some(b'-') => return self.min_expand(s, p, ep),
+                _ => {
+                    if !m {
+                        return Ok(None);
+                    }
+                    s += 1;
+                    p = ep;
+                }
+            }
+        }
+    }
+
+    /// capture `i` of a match spanning `s..e`; the whole match if there are no captures
+    fn get_capture(&self, i: usize, s: usize, e: usize) -> std::result::Result<Capture, String> {
+        if i >= self.level {
+            if i == 0 {
+                return Ok(Capture::Str(s, e));
+            }
+            return Err("invalid capture index".to_string());
+        }
+        match self.capture[i] {
+            (_, CAP_UNFINISHED) => Err("unfinished capture".to_string()),
+            (init, CAP_POSITION) => Ok(Capture::Position(init + 1)),
+            (init, len) => Ok(Capture::Str(init, init + len as usize)),
+        }
+    }
+
+    /// all captures of a match, or the whole match when `whole` is set and there are none
+    fn get_captures(
+        &self,
+        s: usize,
+        e: usize,
+        whole: bool,
+    ) -> std::result::Result<Vec<Capture>, String> {
+        let n = if self.level == 0 && whole {
+            1
+        } else {
+            self.level
+        };
+        (0..n).map(|i| self.get_capture(i, s, e)).collect()
+    }
+}
+
+fn capture_value(state: &mut LuaState, src: &[u8], cap: &Capture) -> TValue {
+    match *cap {
+        Capture::Str(s, e) => state.intern(&src[s..e]),
+        Capture::Position(pos) => TValue::number(pos as LuaNumber),
+    }
+}
+
+fn push_captures(state: &mut LuaState, src: &[u8], caps: &[Capture]) -> usize {
+    state.check_stack(caps.len());
+    for cap in caps {
+        let v = capture_value(state, src, cap);
+        state.push(v);
+    }
+    caps.len()
+}
+
+/// shared body of string.find and string.match
+fn str_find_aux(state: &mut LuaState, find: bool) -> Result<usize> {
+    let s = check_bytes(state, 1)?;
+    let p = check_bytes(state, 2)?;
+    let init = (posrelat(state.opt_integer(3, 1)?, s.len()) - 1).clamp(0, s.len() as i64) as usize;
+    if find && (!is_false(&state.arg(4)) || !p.iter().any(|c| SPECIALS.contains(c))) {
+        // plain search
+        let pos = if p.is_empty() {
+            Some(0)
+        } else {
+            s[init..].windows(p.len()).position(|w| w == p.as_slice())
+        };
+        if let Some(pos) = pos {
+            let start = init + pos;
+            state.push(TValue::number((start + 1) as LuaNumber));
+            state.push(TValue::number((start + p.len()) as LuaNumber));
+            return Ok(2);
+        }
+    } else {
+        let anchor = p.first() == Some(&b'^');
+        let pat = if anchor { &p[1..] } else { &p[..] };
+        let mut ms = MatchState::new(&s, pat);
+        let mut s1 = init;
+        loop {
+            ms.level = 0;
+            match ms.do_match(s1, 0) {
+                Err(msg) => return Err(state.error(msg)),
+                Ok(Some(e)) => {
+                    return match ms.get_captures(s1, e, !find) {
+                        Err(msg) => Err(state.error(msg)),
+                        Ok(caps) if find => {
+                            state.push(TValue::number((s1 + 1) as LuaNumber));
+                            state.push(TValue::number(e as LuaNumber));
+                            Ok(2 + push_captures(state, &s, &caps))
+                        }
+                        Ok(caps) => Ok(push_captures(state, &s, &caps)),
+                    };
+                }
+                Ok(None) => {}
+            }
+            s1 += 1;
+            if s1 > s.len() || anchor {
+                break;
+            }
+        }
+    }
+    state.push(TValue::nil());
+    Ok(1)
+}
+
+/// string.find(s, pattern [, init [, plain]])
+fn str_find(state: &mut LuaState) -> Result<usize> {
+    str_find_aux(state, true)
+}
+
+/// string.match(s, pattern [, init])
+fn str_match(state: &mut LuaState) -> Result<usize> {
+    str_find_aux(state, false)
+}
+
+/// iterator returned by gmatch; upvalues are the subject, the pattern and the next position
+fn gmatch_aux(state: &mut LuaState) -> Result<usize> {
+    let (s, p) = match (state.upvalue(1).value(), state.upvalue(2).value()) {
+        (Value::String(s), Value::String(p)) => {
+            (state.str_bytes(s).to_vec(), state.str_bytes(p).to_vec())
+        }
+        _ => unreachable!("gmatch without its strings"),
+    };
+    let pos = state.upvalue(3).as_number().unwrap_or(0.0) as usize;
+    let mut ms = MatchState::new(&s, &p);
+    for src in pos..=s.len() {
+        ms.level = 0;
+        let e = match ms.do_match(src, 0) {
+            Ok(Some(e)) => e,
+            Ok(None) => continue,
+            Err(msg) => return Err(state.error(msg)),
+        };
+        // an empty match moves on at least one position
+        let next = if e == src { e + 1 } else { e };
+        state.set_upvalue(3, TValue::number(next as LuaNumber));
+        return match ms.get_captures(src, e, true) {
+            Ok(caps) => Ok(push_captures(state, &s, &caps)),
+            Err(msg) => Err(state.error(msg)),
+        };
+    }
+    Ok(0)
+}
+
+/// string.gmatch(s, pattern)
+fn str_gmatch(state: &mut LuaState) -> Result<usize> {
+    let s = state.check_string(1)?;
+    let p = state.check_string(2)?;
+    let f = state.new_native_closure(
+        gmatch_aux,
+        vec![TValue::string(s), TValue::string(p), TValue::number(0.0)],
+    );
+    state.push(f);
+    Ok(1)
+}
+
+/// expand the replacement string of gsub for the match `s..e`
+fn add_s(
+    state: &mut LuaState,
+    ms: &MatchState,
+    b: &mut Vec<u8>,
+    s: usize,
+    e: usize,
+    news: &[u8],
+) -> Result<()> {
+    let mut i = 0;
+    while i < news.len() {
+        let c = news[i];
+        i += 1;
+        if c != L_ESC {
+            b.push(c);
+            continue;
+        }
+        let c = news.get(i).copied().unwrap_or(0);
+        i += 1;
+        if !c.is_ascii_digit() {
+            b.push(c);
+        } else if c == b'0' {
+            b.extend_from_slice(&ms.src[s..e]);
+        } else {
+            let cap = match ms.get_capture((c - b'1') as usize, s, e) {
+                Ok(cap) => cap,
+                Err(msg) => return Err(state.error(msg)),
+            };
+            let v = capture_value(state, ms.src, &cap);
+            b.extend(state.to_str_bytes(&v).unwrap());
+        }
+    }
+    Ok(())
+}
+
+/// append the replacement for the match `s..e` to `b`
+fn add_value(
+    state: &mut LuaState,
+    ms: &MatchState,
+    b: &mut Vec<u8>,
+    s: usize,
+    e: usize,
+    repl: TValue,
+) -> Result<()> {
+    let caps = match ms.get_captures(s, e, true) {
+        Ok(caps) => caps,
+        Err(msg) => return Err(state.error(msg)),
+    };
+    let v = match repl.value() {
+        Value::Number(_) | Value::Integer(_) | Value::String(_) => {
+            let news = state.to_str_bytes(&repl).unwrap();
+            return add_s(state, ms, b, s, e, &news);
+        }
+        Value::Table(_) => {
+            let key = capture_value(state, ms.src, &caps[0]);
+            state.get_table(repl, key, None)?
+        }
+        _ => {
+            let func = state.top;
+            state.push(repl);
+            push_captures(state, ms.src, &caps);
+            state.call(func, 1)?;
+            state.top -= 1;
+            state.stack[state.top]
+        }
+    };
+    if is_false(&v) {
+        // keep the original text
+        b.extend_from_slice(&ms.src[s..e]);
+    } else if let Some(bytes) = state.to_str_bytes(&v) {
+        b.extend(bytes);
+    } else {
+        let msg = format!("invalid replacement value (a {})", type_name(&v));
+        return Err(state.error(msg));
+    }
+    Ok(())
+}
+
+/// string.gsub(s, pattern, repl [, n])
+fn str_gsub(state: &mut LuaState) -> Result<usize> {
+    let src = check_bytes(state, 1)?;
+    let p = check_bytes(state, 2)?;
+    let repl = state.arg(3);
+    let max_s = state.opt_integer(4, src.len() as i64 + 1)?;
+    if !matches!(
+        repl.value(),
+        Value::Number(_)
+            | Value::Integer(_)
+            | Value::String(_)
+            | Value::Table(_)
+            | Value::LuaClosure(_)
+            | Value::NativeClosure(_)
+    ) {
+        return Err(state.arg_error(3, "string/function/table expected"));
+    }
+    let anchor = p.first() == Some(&b'^');
+    let pat = if anchor { &p[1..] } else { &p[..] };
+    let mut ms = MatchState::new(&src, pat);
+    let mut b = Vec::with_capacity(src.len());
+    let mut s = 0;
+    let mut n = 0;
+    while n < max_s {
+        ms.level = 0;
+        let e = match ms.do_match(s, 0) {
+            Ok(e) => e,
+            Err(msg) => return Err(state.error(msg)),
+        };
+        if let Some(e) = e {
+            n += 1;
+            add_value(state, &ms, &mut b, s, e, repl)?;
+        }
+        match e {
+            Some(e) if e > s => s = e,
+            _ if s < src.len() => {
+                b.push(src[s]);
+                s += 1;
+            }
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    b.extend_from_slice(&src[s..]);
+    state.check_string_length(b.len())?;
+    let res = state.intern(&b);
+    state.push(res);
+    state.push(TValue::number(n as LuaNumber));
+    Ok(2)
+}
+
+/// `s` as a quoted Lua string literal (%q)
+fn add_quoted(b: &mut Vec<u8>, s: &[u8]) {
+    b.push(b'"');
+    for &c in s {
+        match c {
+            b'"' | b'\\' | b'\n' => b.extend_from_slice(&[b'\\', c]),
+            b'\r' => b.extend_from_slice(b"\\r"),
+            0 => b.extend_from_slice(b"\\000"),
+            _ => b.push(c),
+        }
+    }
+    b.push(b'"');
+}
+
+/// string.format(formatstring, ...)
+fn str_format(state: &mut LuaState) -> Result<usize> {
+    let top = state.get_top();
+    let fmt = check_bytes(state, 1)?;
+    let mut b = Vec::with_capacity(fmt.len());
+    let mut arg = 1;
+    let mut i = 0;
+    while i < fmt.len() {
+        let c = fmt[i];
+        i += 1;
+        if c != L_ESC {
+            b.push(c);
+            continue;
+        }
+        if fmt.get(i) == Some(&L_ESC) {
+            b.push(L_ESC);
+            i += 1;
+            continue;
+        }
+        arg += 1;
+        if arg > top {
+            return Err(state.arg_error(arg, "no value"));
+        }
+        let spec = match Spec::parse(&fmt[i..]) {
+            Ok((spec, len)) => {
+                i += len;
+                spec
+            }
+            Err(msg) => return Err(state.error(msg)),
+        };
+        match spec.conv {
+            b'c' => b.extend(spec.format_char(state.check_number(arg)? as i64 as u8)),
+            b'd' | b'i' | b'o' | b'u' | b'x' | b'X' => {
+                b.extend(spec.format_int(state.check_number(arg)? as i64))
+            }
+            b'e' | b'E' | b'f' | b'g' | b'G' => {
+                b.extend(spec.format_float(state.check_number(arg)?))
+            }
+            b'q' => {
+                let s = check_bytes(state, arg)?;
+                add_quoted(&mut b, &s);
+            }
+            b's' => {
+                let s = check_bytes(state, arg)?;
+                // long strings are kept whole when there is no precision
+                if spec.precision.is_none() && s.len() >= 100 {
+                    b.extend(s);
+                } else {
+                    b.extend(spec.format_str(&s));
+                }
+            }
+            conv => {
+                let msg = format!("invalid option '%{}' to 'format'", conv as char);
+                return Err(state.error(msg));
+            }
+        }
+    }
+    state.check_string_length(b.len())?;
+    let res = state.intern(&b);
+    state.push(res);
+    Ok(1)
+}
+
+pub fn open_string(state: &mut LuaState) {
+    let lib = state.register_lib(
+        "string",
+        &[
+            ("byte", str_byte),
+            ("char", str_char),
+            ("find", str_find),
+            ("format", str_format),
+            ("gmatch", str_gmatch),
+            ("gsub", str_gsub),
+            ("len", str_len),
+            ("lower", str_lower),
+            ("match", str_match),
+            ("rep", str_rep),
+            ("reverse", str_reverse),
+            ("sub", str_sub),
+            ("upper", str_upper),
+        ],
+    );
+    // strings index the string table, so that `s:upper()` works
+    let meta = state.new_table();
+    let index = state.intern(b"__index");
+    state
+        .global
+        .heap
+        .get_mut(meta)
+        .set(index, TValue::table(lib))
+        .unwrap();
+    let s = state.intern(b"");
+    state.set_metatable(&s, Some(meta));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::baselib::open_base;
+    use crate::baselib::tests::{call_value, show};
+    use pretty_assertions::assert_eq;
+
+    fn state() -> LuaState {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        open_string(&mut state);
+        state
+    }
+
+    /// string.`name`(args...) with string arguments, results as strings
+    fn string(state: &mut LuaState, name: &str, args: &[&str]) -> Vec<String> {
+        let lib = state.get_global("string");
+        let key = state.intern(name.as_bytes());
+        let f = state.get_table(lib, key, None).unwrap();
+        let args: Vec<TValue> = args.iter().map(|a| state.intern(a.as_bytes())).collect();
+        let res = call_value(state, f, &args);
+        show(state, &res)
+    }
+
+    #[test]
+    fn test_find_and_match() {
+        let mut state = state();
+        let cases: [(&str, &[&str], &[&str]); 9] = [
+            ("find", &["hello world", "o w"], &["5", "7"]),
+            ("find", &["a.b", ".", "1", "1"], &["2", "2"]),
+            ("find", &["hello", "l+"], &["3", "4"]),
+            (
+                "match",
+                &["key = val", "(%w+)%s*=%s*(%w+)"],
+                &["key", "val"],
+            ),
+            ("match", &["f(a(b)c) d", "%b()"], &["(a(b)c)"]),
+            ("match", &["THE quick", "%f[%a]%l+"], &["quick"]),
+            ("match", &["hello", "()ll()"], &["3", "5"]),
+            ("match", &["say 'hi' now", "([\"'])(.-)%1"], &["'", "hi"]),
+            ("match", &["abc", "^b"], &["nil"]),
+        ];
+        for (name, args, expected) in cases {
+            assert_eq!(string(&mut state, name, args), expected, "{name}{args:?}");
+        }
+    }
+
+    #[test]
+    fn test_rep_too_large() {
+        let mut state = state();
+        for n in ["2^62", "2^40", "1e10"] {
+            let (ok, msg): (bool, String) = state
+                .exec(&format!("return pcall(string.rep, 'xy', {n})"))
+                .unwrap();
+            assert!(!ok);
+            assert!(msg.ends_with("resulting string too large"), "{msg}");
+        }
+        let s: String = state.exec("return string.rep('ab', 3)").unwrap();
+        assert_eq!(s, "ababab");
+    }
+
+    #[test]
+    fn test_format() {
+        let mut state = state();
+        let args = ["%5.1f|%-3d|%x|%s|%q", "3.14159", "7", "255", "x", "a\nb\"c"];
+        let res = string(&mut state, "format", &args);
+        assert_eq!(res, vec!["  3.1|7  |ff|x|\"a\\\nb\\\"c\""]);
+        let func = state.top;
+        let lib = state.get_global("string");
+        let key = state.intern(b"format");
+        let format = state.get_table(lib, key, None).unwrap();
+        state.push(format);
+        let fmt = state.intern(b"%d %s");
+        state.push(fmt);
+        state.push(TValue::number(1.0));
+        let err = state.pcall(func, 1, None).unwrap_err();
+        assert_eq!(err.to_string(), "bad argument #3 to '?' (no value)");
+    }
+
+    #[test]
+    fn test_gsub() {
+        let mut state = state();
+        let cases: [(&[&str], &[&str]); 4] = [
+            (&["hello world", "o", "0"], &["hell0 w0rld", "2"]),
+            (&["abc", "%w", "%0%0"], &["aabbcc", "3"]),
+            (
+                &["hello world", "(%w+)", "<%1>", "1"],
+                &["<hello> world", "1"],
+            ),
+            (&["abc", "", "-"], &["-a-b-c-", "4"]),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(string(&mut state, "gsub", args), expected, "{args:?}");
+        }
+        let lib = state.get_global("string");
+        let key = state.intern(b"gsub");
+        let gsub = state.get_table(lib, key, None).unwrap();
+        let t = state.new_table();
+        let (name, bob) = (state.intern(b"name"), state.intern(b"Bob"));
+        state.global.heap.get_mut(t).set(name, bob).unwrap();
+        let s = state.intern(b"$name is $age");
+        let p = state.intern(b"%$(%w+)");
+        let res = call_value(&mut state, gsub, &[s, p, TValue::table(t)]);
+        assert_eq!(show(&state, &res), vec!["Bob is $age", "2"]);
+    }
+
+    #[test]
+    fn test_gmatch_and_errors() {
+        let mut state = state();
+        let s = state.intern(b"one two  three");
+        // s:gmatch goes through the string metatable
+        let key = state.intern(b"gmatch");
+        let gmatch = state.get_table(s, key, None).unwrap();
+        let lib = state.get_global("string");
+        let p = state.intern(b"%a+");
+        let iter = call_value(&mut state, gmatch, &[s, p])[0];
+        let mut words = Vec::new();
+        loop {
+            let res = call_value(&mut state, iter, &[]);
+            if res.is_empty() {
+                break;
+            }
+            words.extend(show(&state, &res));
+        }
+        assert_eq!(words, vec!["one", "two", "three"]);
+
+        let key = state.intern(b"find");
+        let find = state.get_table(lib, key, None).unwrap();
+        for (pat, msg) in [
+            ("%", "malformed pattern (ends with '%')"),
+            ("[a", "malformed pattern (missing ']')"),
+            ("(", "unfinished capture"),
+            ("%1", "invalid capture index"),
+        ] {
+            let func = state.top;
+            state.push(find);
+            let s = state.intern(b"a");
+            state.push(s);
+            let p = state.intern(pat.as_bytes());
+            state.push(p);
+            let err = state.pcall(func, 1, None).unwrap_err();
+            assert_eq!(err.to_string(), msg);
+        }
+    }
+}
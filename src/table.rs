@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, bail};
+
+use crate::eval::{TValue, Value};
+use crate::func::{LuaClosure, NativeClosure};
+use crate::heap::{Gc, LuaString};
+use crate::thread::Thread;
+use crate::userdata::Userdata;
+
+/// Hashable form of a non-nil `TValue`.
+/// Floats with an integral value are normalized to `Integer` so that `t[1]` and `t[1.0]` are the same slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TableKey {
+    Boolean(bool),
+    Integer(i64),
+    Number(u64),
+    String(Gc<LuaString>),
+    Table(Gc<Table>),
+    LuaClosure(Gc<LuaClosure>),
+    NativeClosure(Gc<NativeClosure>),
+    Thread(Gc<Thread>),
+    UserData(Gc<Userdata>),
+}
+
+impl TableKey {
+    pub fn from_value(val: &TValue) -> Result<Option<TableKey>> {
+        let key = match val.value() {
+            Value::Nil => return Ok(None),
+            Value::Boolean(b) => TableKey::Boolean(b),
+            Value::Integer(n) => TableKey::Integer(n),
+            Value::Number(n) => {
+                if n.is_nan() {
+                    bail!("table index is NaN");
+                }
+                if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+                    TableKey::Integer(n as i64)
+                } else {
+                    TableKey::Number(n.to_bits())
+                }
+            }
+            Value::String(s) => TableKey::String(s),
+            Value::Table(t) => TableKey::Table(t),
+            Value::LuaClosure(c) => TableKey::LuaClosure(c),
+            Value::NativeClosure(c) => TableKey::NativeClosure(c),
+            Value::Thread(t) => TableKey::Thread(t),
+            Value::UserData(u) => TableKey::UserData(u),
+        };
+        Ok(Some(key))
+    }
+    pub fn to_value(self) -> TValue {
+        match self {
+            TableKey::Boolean(b) => TValue::boolean(b),
+            TableKey::Integer(n) => TValue::int_or_float(n as i128),
+            TableKey::Number(n) => TValue::number(f64::from_bits(n)),
+            TableKey::String(s) => TValue::string(s),
+            TableKey::Table(t) => TValue::table(t),
+            TableKey::LuaClosure(c) => TValue::lua_closure(c),
+            TableKey::NativeClosure(c) => TValue::native_closure(c),
+            TableKey::Thread(t) => TValue::thread(t),
+            TableKey::UserData(u) => TValue::userdata(u),
+        }
+    }
+}
+
+/// Lua table with an array part for keys `1..=n` and an insertion ordered hash part.
+/// Removed hash entries keep their slot (with a nil value) until the next rehash,
+/// so `next` stays valid while fields are cleared during a traversal.
+#[derive(Debug, Default)]
+pub struct Table {
+    array: Vec<TValue>,
+    node: Vec<(TableKey, TValue)>,
+    index: HashMap<TableKey, usize>,
+    pub metatable: Option<Gc<Table>>,
+}
+
+fn is_nil(val: &TValue) -> bool {
+    matches!(val.value(), Value::Nil)
+}
+
+fn nil() -> TValue {
+    TValue::nil()
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_capacity(narray: usize, nhash: usize) -> Self {
+        Self {
+            array: Vec::with_capacity(narray),
+            node: Vec::with_capacity(nhash),
+            index: HashMap::with_capacity(nhash),
+            metatable: None,
+        }
+    }
+    pub fn get(&self, key: &TValue) -> TValue {
+        match TableKey::from_value(key) {
+            Ok(Some(k)) => self.get_key(&k),
+            _ => nil(),
+        }
+    }
+    pub fn get_int(&self, n: i64) -> TValue {
+        self.get_key(&TableKey::Integer(n))
+    }
+    pub fn get_str(&self, s: Gc<LuaString>) -> TValue {
+        self.get_key(&TableKey::String(s))
+    }
+    fn get_key(&self, key: &TableKey) -> TValue {
+        if let TableKey::Integer(n) = key
+            && *n >= 1
+            && (*n as usize) <= self.array.len()
+        {
+            return self.array[*n as usize - 1];
+        }
+        match self.index.get(key) {
+            Some(&i) => self.node[i].1,
+            None => nil(),
+        }
+    }
+    pub fn set(&mut self, key: TValue, val: TValue) -> Result<()> {
+        match TableKey::from_value(&key)? {
+            Some(k) => {
+                self.set_key(k, val);
+                Ok(())
+            }
+            None => bail!("table index is nil"),
+        }
+    }
+    pub fn set_int(&mut self, n: i64, val: TValue) {
+        self.set_key(TableKey::Integer(n), val);
+    }
+    pub fn set_str(&mut self, s: Gc<LuaString>, val: TValue) {
+        self.set_key(TableKey::String(s), val);
+    }
+    fn set_key(&mut self, key: TableKey, val: TValue) {
+        if let TableKey::Integer(n) = key {
+            if n >= 1 && (n as usize) <= self.array.len() {
+                self.array[n as usize - 1] = val;
+                return;
+            }
+            if n >= 1 && (n as usize) == self.array.len() + 1 && !is_nil(&val) {
+                self.array.push(val);
+                self.remove_node(&key);
+                self.migrate_to_array();
+                return;
+            }
+        }
+        match self.index.get(&key) {
+            Some(&i) => self.node[i].1 = val,
+            None => {
+                if is_nil(&val) {
+                    return;
+                }
+                if self.node.len() == self.node.capacity() {
+                    self.rehash();
+                }
+                self.index.insert(key, self.node.len());
+                self.node.push((key, val));
+            }
+        }
+    }
+    fn remove_node(&mut self, key: &TableKey) {
+        if let Some(&i) = self.index.get(key) {
+            self.node[i].1 = nil();
+        }
+    }
+    /// move keys following the array part out of the hash part
+    fn migrate_to_array(&mut self) {
+        loop {
+            let next = TableKey::Integer(self.array.len() as i64 + 1);
+            match self.index.get(&next) {
+                Some(&i) if !is_nil(&self.node[i].1) => {
+                    let val = self.node[i].1;
+                    self.node[i].1 = nil();
+                    self.array.push(val);
+                }
+                _ => break,
+            }
+        }
+    }
+    /// drop dead entries of the hash part
+    fn rehash(&mut self) {
+        if self.node.iter().all(|(_, v)| !is_nil(v)) {
+            return;
+        }
+        self.node.retain(|(_, v)| !is_nil(v));
+        self.index.clear();
+        for (i, (k, _)) in self.node.iter().enumerate() {
+            self.index.insert(*k, i);
+        }
+    }
+    /// a border of the table (`#t`)
+    pub fn length(&self) -> i64 {
+        let n = self.array.len();
+        if n > 0 && is_nil(&self.array[n - 1]) {
+            // binary search for a border inside the array part
+            let (mut i, mut j) = (0usize, n);
+            while j - i > 1 {
+                let m = (i + j) / 2;
+                if is_nil(&self.array[m - 1]) {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            return i as i64;
+        }
+        let mut j = n as i64;
+        while !is_nil(&self.get_int(j + 1)) {
+            j += 1;
+        }
+        j
+    }
+    /// live entries in traversal order, for the collector
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (TValue, TValue)> + '_ {
+        let array = self
+            .array
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (TableKey::Integer(i as i64 + 1).to_value(), *v));
+        let node = self.node.iter().map(|(k, v)| (k.to_value(), *v));
+        array.chain(node).filter(|(_, v)| !is_nil(v))
+    }
+    pub(crate) fn mem_size(&self) -> usize {
+        self.array.capacity() * std::mem::size_of::<TValue>()
+            + self.node.capacity() * std::mem::size_of::<(TableKey, TValue)>()
+            + self.index.capacity() * std::mem::size_of::<(TableKey, usize)>()
+    }
+    /// entry following `key` in traversal order, `None` at the end of the table
+    pub fn next(&self, key: &TValue) -> Result<Option<(TValue, TValue)>> {
+        let mut i = match TableKey::from_value(key)? {
+            None => 0,
+            Some(TableKey::Integer(n)) if n >= 1 && (n as usize) <= self.array.len() => n as usize,
+            Some(k) => match self.index.get(&k) {
+                Some(&pos) => self.array.len() + pos + 1,
+                None => bail!("invalid key to 'next'"),
+            },
+        };
+        while i < self.array.len() {
+            if !is_nil(&self.array[i]) {
+                return Ok(Some((TValue::integer(i as i64 + 1), self.array[i])));
+            }
+            i += 1;
+        }
+        for (k, v) in self.node.iter().skip(i - self.array.len()) {
+            if !is_nil(v) {
+                return Ok(Some((k.to_value(), *v)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(n: f64) -> TValue {
+        TValue::number(n)
+    }
+
+    #[test]
+    fn test_array_migration_and_length() {
+        let mut t = Table::new();
+        t.set(num(3.0), num(30.0)).unwrap();
+        t.set(num(2.0), num(20.0)).unwrap();
+        assert_eq!(t.length(), 0);
+        t.set(num(1.0), num(10.0)).unwrap();
+        assert_eq!(t.array.len(), 3);
+        assert_eq!(t.length(), 3);
+        t.set(num(3.0), nil()).unwrap();
+        assert_eq!(t.length(), 2);
+        assert!(t.set(nil(), num(1.0)).is_err());
+        assert!(t.set(num(f64::NAN), num(1.0)).is_err());
+    }
+
+    #[test]
+    fn test_next_survives_clearing_fields() {
+        let mut t = Table::new();
+        for i in 1..=2 {
+            t.set_int(i, num(i as f64));
+        }
+        t.set(TValue::boolean(true), num(3.0)).unwrap();
+        let mut key = nil();
+        let mut count = 0;
+        while let Some((k, _)) = t.next(&key).unwrap() {
+            t.set(k, nil()).unwrap();
+            key = k;
+            count += 1;
+        }
+        assert_eq!(count, 3);
+        assert!(t.next(&nil()).unwrap().is_none());
+
+        // keys of the array and hash parts have the same variant
+        t.set_int(1, num(1.0));
+        t.set_int(100, num(2.0));
+        let (k1, _) = t.next(&nil()).unwrap().unwrap();
+        let (k2, _) = t.next(&k1).unwrap().unwrap();
+        assert!(k1.is_integer() && k2.is_integer());
+    }
+}
@@ -0,0 +1,307 @@
+use anyhow::Result;
+
+use crate::eval::{LuaNumber, TValue, Value};
+use crate::heap::Gc;
+use crate::table::Table;
+use crate::vm::{LuaState, is_false};
+
+fn geti(state: &LuaState, t: Gc<Table>, i: i64) -> TValue {
+    state.global.heap.get(t).get_int(i)
+}
+
+fn seti(state: &mut LuaState, t: Gc<Table>, i: i64, v: TValue) {
+    state.global.heap.get_mut(t).set_int(i, v);
+}
+
+/// table argument `narg` and its length (aux_getn)
+fn check_getn(state: &mut LuaState, narg: usize) -> Result<(Gc<Table>, i64)> {
+    let t = state.check_table(narg)?;
+    Ok((t, state.global.heap.get(t).length()))
+}
+
+/// table.getn(t)
+fn tab_getn(state: &mut LuaState) -> Result<usize> {
+    let (_, n) = check_getn(state, 1)?;
+    state.push(TValue::number(n as LuaNumber));
+    Ok(1)
+}
+
+/// table.maxn(t): largest positive numeric key
+fn tab_maxn(state: &mut LuaState) -> Result<usize> {
+    let t = state.check_table(1)?;
+    let max = state
+        .global
+        .heap
+        .get(t)
+        .entries()
+        .filter_map(|(k, _)| k.as_number())
+        .fold(0.0, LuaNumber::max);
+    state.push(TValue::number(max));
+    Ok(1)
+}
+
+/// table.insert(t, [pos,] value)
+fn tab_insert(state: &mut LuaState) -> Result<usize> {
+    let (t, n) = check_getn(state, 1)?;
+    let mut e = n + 1;
+    let pos = match state.get_top() {
+        2 => e,
+        3 => {
+            let pos = state.check_integer(2)?;
+            e = e.max(pos);
+            // move up elements
+            for i in (pos + 1..=e).rev() {
+                let v = geti(state, t, i - 1);
+                seti(state, t, i, v);
+            }
+            pos
+        }
+        _ => return Err(state.error("wrong number of arguments to 'insert'".to_string())),
+    };
+    let v = state.arg(state.get_top());
+    seti(state, t, pos, v);
+    Ok(0)
+}
+
+/// table.remove(t [, pos])
+fn tab_remove(state: &mut LuaState) -> Result<usize> {
+    let (t, e) = check_getn(state, 1)?;
+    let pos = state.opt_integer(2, e)?;
+    if !(1 <= pos && pos <= e) {
+        // position is outside bounds, nothing to remove
+        return Ok(0);
+    }
+    let res = geti(state, t, pos);
+    state.push(res);
+    for i in pos..e {
+        let v = geti(state, t, i + 1);
+        seti(state, t, i, v);
+    }
+    seti(state, t, e, TValue::nil());
+    Ok(1)
+}
+
+/// table.concat(t [, sep [, i [, j]]])
+fn tab_concat(state: &mut LuaState) -> Result<usize> {
+    let sep = match state.opt_string(2)? {
+        Some(s) => state.str_bytes(s).to_vec(),
+        None => Vec::new(),
+    };
+    let t = state.check_table(1)?;
+    let first = state.opt_integer(3, 1)?;
+    let last = match state.arg(4).value() {
+        Value::Nil => state.global.heap.get(t).length(),
+        _ => state.check_integer(4)?,
+    };
+    let mut b = Vec::new();
+    for i in first..=last {
+        let v = geti(state, t, i);
+        match state.to_str_bytes(&v) {
+            Some(s) => b.extend(s),
+            None => {
+                let msg = format!("invalid value (at index {i}) in table for 'concat'");
+                return Err(state.error(msg));
+            }
+        }
+        if i < last {
+            b.extend_from_slice(&sep);
+        }
+    }
+    state.check_string_length(b.len())?;
+    let res = state.intern(&b);
+    state.push(res);
+    Ok(1)
+}
+
+/// `a < b` with the comparator of table.sort (argument 2), or the `<` operator
+fn sort_comp(state: &mut LuaState, a: TValue, b: TValue) -> Result<bool> {
+    let comp = state.arg(2);
+    if matches!(comp.value(), Value::Nil) {
+        return state.less_than(a, b);
+    }
+    let func = state.top;
+    state.push(comp);
+    state.push(a);
+    state.push(b);
+    state.call(func, 1)?;
+    state.top -= 1;
+    Ok(!is_false(&state.stack[state.top]))
+}
+
+fn swap(state: &mut LuaState, t: Gc<Table>, i: i64, j: i64) {
+    let (a, b) = (geti(state, t, i), geti(state, t, j));
+    seti(state, t, i, b);
+    seti(state, t, j, a);
+}
+
+/// Quicksort of `t[l..=u]`, step for step the one of ltablib.c so that
+/// inconsistent comparators give the same results and errors.
+fn aux_sort(state: &mut LuaState, t: Gc<Table>, l: i64, u: i64) -> Result<()> {
+    let (mut l, mut u) = (l, u);
+    let invalid = "invalid order function for sorting";
+    while l < u {
+        // sort elements a[l], a[(l+u)/2] and a[u]
+        if sort_comp(state, geti(state, t, u), geti(state, t, l))? {
+            swap(state, t, l, u);
+        }
+        if u - l == 1 {
+            break;
+        }
+        let mut i = (l + u) / 2;
+        if sort_comp(state, geti(state, t, i), geti(state, t, l))? {
+            swap(state, t, i, l);
+        } else if sort_comp(state, geti(state, t, u), geti(state, t, i))? {
+            swap(state, t, i, u);
+        }
+        if u - l == 2 {
+            break;
+        }
+        // the pivot stays on the stack while the comparator runs
+        let pivot = geti(state, t, i);
+        state.push(pivot);
+        swap(state, t, i, u - 1);
+        // a[l] <= P == a[u-1] <= a[u], only need to sort from l+1 to u-2
+        i = l;
+        let mut j = u - 1;
+        loop {
+            // invariant: a[l..i] <= P <= a[j..u]
+            i += 1;
+            while sort_comp(state, geti(state, t, i), pivot)? {
+                if i > u {
+                    return Err(state.error(invalid.to_string()));
+                }
+                i += 1;
+            }
+            j -= 1;
+            while sort_comp(state, pivot, geti(state, t, j))? {
+                if j < l {
+                    return Err(state.error(invalid.to_string()));
+                }
+                j -= 1;
+            }
+            if j < i {
+                break;
+            }
+            swap(state, t, i, j);
+        }
+        state.top -= 1;
+        // swap pivot (a[u-1]) with a[i]
+        swap(state, t, u - 1, i);
+        // sort the smaller half recursively and loop on the larger one
+        let (lo, hi);
+        if i - l < u - i {
+            (lo, hi) = (l, i - 1);
+            l = i + 1;
+        } else {
+            (lo, hi) = (i + 1, u);
+            u = i - 1;
+        }
+        aux_sort(state, t, lo, hi)?;
+    }
+    Ok(())
+}
+
+/// table.sort(t [, comp])
+fn tab_sort(state: &mut LuaState) -> Result<usize> {
+    let (t, n) = check_getn(state, 1)?;
+    if !matches!(
+        state.arg(2).value(),
+        Value::Nil | Value::LuaClosure(_) | Value::NativeClosure(_)
+    ) {
+        return Err(state.type_error_arg(2, "function"));
+    }
+    state.set_top(2);
+    aux_sort(state, t, 1, n)?;
+    Ok(0)
+}
+
+pub fn open_table(state: &mut LuaState) {
+    state.register_lib(
+        "table",
+        &[
+            ("concat", tab_concat),
+            ("getn", tab_getn),
+            ("insert", tab_insert),
+            ("maxn", tab_maxn),
+            ("remove", tab_remove),
+            ("sort", tab_sort),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::baselib::open_base;
+    use crate::baselib::tests::{call_value, show};
+    use pretty_assertions::assert_eq;
+
+    fn table_fn(state: &mut LuaState, name: &str) -> TValue {
+        let lib = state.get_global("table");
+        let key = state.intern(name.as_bytes());
+        state.get_table(lib, key, None).unwrap()
+    }
+
+    fn list(state: &mut LuaState, items: &[f64]) -> Gc<Table> {
+        let t = state.new_table();
+        for (i, n) in items.iter().enumerate() {
+            seti(state, t, i as i64 + 1, TValue::number(*n));
+        }
+        t
+    }
+
+    fn concat(state: &mut LuaState, t: Gc<Table>) -> String {
+        let f = table_fn(state, "concat");
+        let sep = state.intern(b",");
+        let res = call_value(state, f, &[TValue::table(t), sep]);
+        show(state, &res).remove(0)
+    }
+
+    #[test]
+    fn test_insert_remove_concat() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        open_table(&mut state);
+        let t = list(&mut state, &[1.0, 2.0, 3.0]);
+        let insert = table_fn(&mut state, "insert");
+        call_value(&mut state, insert, &[TValue::table(t), TValue::number(4.0)]);
+        let args = [TValue::table(t), TValue::number(1.0), TValue::number(0.0)];
+        call_value(&mut state, insert, &args);
+        assert_eq!(concat(&mut state, t), "0,1,2,3,4");
+        let remove = table_fn(&mut state, "remove");
+        let res = call_value(&mut state, remove, &[TValue::table(t), TValue::number(2.0)]);
+        assert_eq!(show(&state, &res), vec!["1"]);
+        let res = call_value(&mut state, remove, &[TValue::table(t)]);
+        assert_eq!(show(&state, &res), vec!["4"]);
+        assert_eq!(concat(&mut state, t), "0,2,3");
+        let maxn = table_fn(&mut state, "maxn");
+        seti(&mut state, t, 10, TValue::boolean(true));
+        let res = call_value(&mut state, maxn, &[TValue::table(t)]);
+        assert_eq!(show(&state, &res), vec!["10"]);
+    }
+
+    #[test]
+    fn test_sort() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        open_table(&mut state);
+        let items = [5.0, 3.0, 9.0, 1.0, 7.0, 2.0, 8.0, 6.0, 4.0, 0.0, 3.0];
+        let t = list(&mut state, &items);
+        let sort = table_fn(&mut state, "sort");
+        call_value(&mut state, sort, &[TValue::table(t)]);
+        assert_eq!(concat(&mut state, t), "0,1,2,3,3,4,5,6,7,8,9");
+
+        // a comparator that always says "less" runs off the array
+        fn always(state: &mut LuaState) -> Result<usize> {
+            state.push(TValue::boolean(true));
+            Ok(1)
+        }
+        let comp = state.new_native(always);
+        let func = state.top;
+        state.push(sort);
+        state.push(TValue::table(t));
+        state.push(comp);
+        let err = state.pcall(func, 0, None).unwrap_err();
+        assert_eq!(err.to_string(), "invalid order function for sorting");
+    }
+}
@@ -0,0 +1,344 @@
+use anyhow::Result;
+
+use crate::debug::HookState;
+use crate::error::LuaError;
+use crate::eval::TValue;
+use crate::func::UpVal;
+use crate::heap::Gc;
+use crate::vm::{
+    CallInfo, LUA_MINSTACK, LUA_MULTRET, LUAI_MAXCCALLS, LuaState, PreCall, vm_execute,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadStatus {
+    /// not started yet, or stopped in `yield`
+    Suspended,
+    Running,
+    /// active but not running: it resumed another coroutine
+    Normal,
+    /// finished or stopped by an error
+    Dead,
+}
+
+impl ThreadStatus {
+    /// name as returned by `coroutine.status`
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThreadStatus::Suspended => "suspended",
+            ThreadStatus::Running => "running",
+            ThreadStatus::Normal => "normal",
+            ThreadStatus::Dead => "dead",
+        }
+    }
+}
+
+/// A coroutine with its own stack and `CallInfo` chain.
+/// While a thread runs, its stack lives in the `LuaState` and the thread object
+/// holds empty placeholders.
+pub struct Thread {
+    pub(crate) stack: Vec<TValue>,
+    pub(crate) top: usize,
+    pub(crate) base_ci: Vec<CallInfo>,
+    pub(crate) open_upvals: Vec<Gc<UpVal>>,
+    pub(crate) base_ccalls: usize,
+    pub(crate) hook: HookState,
+    pub(crate) status: ThreadStatus,
+    /// thread that resumed this one, while it runs
+    pub(crate) resumer: Option<Gc<Thread>>,
+}
+
+impl Thread {
+    /// a thread with an empty base frame
+    pub(crate) fn new() -> Self {
+        Self {
+            // slot 0 holds the (absent) function of the base frame
+            stack: vec![TValue::nil(); 1 + 2 * LUA_MINSTACK],
+            top: 1,
+            base_ci: vec![CallInfo {
+                base: 1,
+                func: 0,
+                top: 1 + LUA_MINSTACK,
+                saved_pc: 0,
+                nresults: 0,
+                tailcalls: 0,
+            }],
+            open_upvals: Vec::new(),
+            base_ccalls: 0,
+            hook: HookState::default(),
+            status: ThreadStatus::Suspended,
+            resumer: None,
+        }
+    }
+    pub(crate) fn placeholder(status: ThreadStatus) -> Self {
+        Self {
+            stack: Vec::new(),
+            top: 0,
+            base_ci: Vec::new(),
+            open_upvals: Vec::new(),
+            base_ccalls: 0,
+            hook: HookState::default(),
+            status,
+            resumer: None,
+        }
+    }
+    pub(crate) fn mem_size(&self) -> usize {
+        self.stack.capacity() * std::mem::size_of::<TValue>()
+            + self.base_ci.capacity() * std::mem::size_of::<CallInfo>()
+            + self.open_upvals.capacity() * std::mem::size_of::<Gc<UpVal>>()
+    }
+}
+
+impl LuaState {
+    /// new suspended coroutine which will run `func` on its first resume
+    pub(crate) fn new_thread(&mut self, func: TValue) -> Gc<Thread> {
+        let mut th = Thread::new();
+        th.stack[th.top] = func;
+        th.top += 1;
+        // a new thread inherits the hook of its creator
+        th.hook = HookState {
+            count: self.hook.base_count,
+            allow: true,
+            ..self.hook
+        };
+        self.global.heap.alloc(th)
+    }
+    pub(crate) fn thread_status(&self, th: Gc<Thread>) -> ThreadStatus {
+        self.global.heap.get(th).status
+    }
+    /// exchange the running stack with the one stored in `th`
+    fn swap_thread(&mut self, th: Gc<Thread>) {
+        let t = self.global.heap.get_mut(th);
+        std::mem::swap(&mut self.stack, &mut t.stack);
+        std::mem::swap(&mut self.top, &mut t.top);
+        std::mem::swap(&mut self.base_ci, &mut t.base_ci);
+        std::mem::swap(&mut self.open_upvals, &mut t.open_upvals);
+        std::mem::swap(&mut self.base_ccalls, &mut t.base_ccalls);
+        std::mem::swap(&mut self.hook, &mut t.hook);
+    }
+    /// run `f` with the stack of thread `th` in place of the running one, to
+    /// inspect a thread which is not running
+    pub(crate) fn with_thread<R>(&mut self, th: Gc<Thread>, f: impl FnOnce(&mut Self) -> R) -> R {
+        if th == self.current {
+            return f(self);
+        }
+        let current = self.current;
+        self.swap_thread(current);
+        self.swap_thread(th);
+        let res = f(self);
+        self.swap_thread(th);
+        self.swap_thread(current);
+        res
+    }
+
+    /// Run coroutine `co` until it yields or finishes, passing `args` to it.
+    /// Returns the values given to `yield` or returned by the body; an error
+    /// inside the coroutine kills it and is returned as `Err`.
+    pub(crate) fn resume(
+        &mut self,
+        co: Gc<Thread>,
+        args: &[TValue],
+    ) -> std::result::Result<Vec<TValue>, LuaError> {
+        self.check_live(&[TValue::thread(co)])?;
+        let msg = match self.thread_status(co) {
+            ThreadStatus::Suspended if self.n_ccalls >= LUAI_MAXCCALLS => Some("C stack overflow"),
+            ThreadStatus::Suspended => None,
+            ThreadStatus::Dead => Some("cannot resume dead coroutine"),
+            _ => Some("cannot resume non-suspended coroutine"),
+        };
+        if let Some(msg) = msg {
+            let value = self.intern(msg.as_bytes());
+            return Err(LuaError::new(value, msg.to_string()));
+        }
+        let prev = self.current;
+        let old_ccalls = self.n_ccalls;
+        self.global.heap.get_mut(prev).status = ThreadStatus::Normal;
+        self.swap_thread(prev);
+        self.swap_thread(co);
+        self.current = co;
+        let th = self.global.heap.get_mut(co);
+        th.status = ThreadStatus::Running;
+        th.resumer = Some(prev);
+        self.n_ccalls += 1;
+        self.base_ccalls = self.n_ccalls;
+        self.n_yield = None;
+        for arg in args {
+            self.push(*arg);
+        }
+        let res = match self.resume_inner(args.len()) {
+            Ok(()) => match self.n_yield.take() {
+                Some(n) => {
+                    self.top -= n;
+                    self.global.heap.get_mut(co).status = ThreadStatus::Suspended;
+                    Ok(self.stack[self.top..self.top + n].to_vec())
+                }
+                None => {
+                    let results = self.stack[1..self.top].to_vec();
+                    self.top = 1;
+                    self.global.heap.get_mut(co).status = ThreadStatus::Dead;
+                    Ok(results)
+                }
+            },
+            Err(e) => {
+                self.n_yield = None;
+                self.global.heap.get_mut(co).status = ThreadStatus::Dead;
+                Err(self.error_object(e))
+            }
+        };
+        self.n_ccalls = old_ccalls;
+        self.swap_thread(co);
+        self.global.heap.get_mut(co).resumer = None;
+        self.swap_thread(prev);
+        self.current = prev;
+        self.global.heap.get_mut(prev).status = ThreadStatus::Running;
+        res
+    }
+
+    fn resume_inner(&mut self, nargs: usize) -> Result<()> {
+        let first_arg = self.top - nargs;
+        if self.base_ci.len() == 1 {
+            // first resume: call the body
+            if let PreCall::Lua = self.precall(first_arg - 1, LUA_MULTRET)? {
+                vm_execute(self, 1)?;
+            }
+            return Ok(());
+        }
+        // finish the interrupted call to `yield`, its results are the resume arguments
+        let nresults = self.base_ci.last().unwrap().nresults;
+        self.poscall(first_arg)?;
+        if nresults >= 0 {
+            self.top = self.base_ci.last().unwrap().top;
+        }
+        if self.base_ci.len() > 1 {
+            vm_execute(self, self.base_ci.len() - 1)?;
+        }
+        Ok(())
+    }
+
+    /// Suspend the running coroutine with the top `nresults` values as results
+    /// of `resume`. Must be the return value of a native function (lua_yield).
+    pub(crate) fn yield_(&mut self, nresults: usize) -> Result<usize> {
+        if self.n_ccalls > self.base_ccalls || self.current == self.main_thread {
+            return Err(self
+                .runtime_error("attempt to yield across metamethod/C-call boundary".to_string()));
+        }
+        self.n_yield = Some(nresults);
+        Ok(nresults)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::baselib::open_base;
+    use crate::opcodes::{OpCode, create_abc, create_abx, rk_ask};
+    use crate::vm::tests::{proto, run};
+    use pretty_assertions::assert_eq;
+
+    fn num(n: f64) -> TValue {
+        TValue::number(n)
+    }
+
+    fn as_num(v: &TValue) -> f64 {
+        match v.as_number() {
+            Some(n) => n,
+            None => panic!("not a number: {}", v.value()),
+        }
+    }
+
+    /// function(a) local b = g(a + 1); return b * 2 end, with
+    /// g = function(x) return (coroutine.yield(x)) end
+    fn body(state: &mut LuaState) -> TValue {
+        let coroutine = state.intern(b"coroutine");
+        let yield_ = state.intern(b"yield");
+        let mut g = proto(
+            vec![
+                create_abx(OpCode::OpGetGlobal, 1, 0),
+                create_abc(OpCode::OpGetTable, 1, 1, rk_ask(1)),
+                create_abc(OpCode::OpMove, 2, 0, 0),
+                create_abc(OpCode::OpCall, 1, 2, 2),
+                create_abc(OpCode::OpReturn, 1, 2, 0),
+            ],
+            vec![coroutine, yield_],
+        );
+        g.num_params = 1;
+        g.is_vararg = 0;
+        let g = state.new_lua_closure(Rc::new(g));
+        state.set_global("g", g).unwrap();
+        let gname = state.intern(b"g");
+        let mut f = proto(
+            vec![
+                create_abx(OpCode::OpGetGlobal, 1, 0),
+                create_abc(OpCode::OpAdd, 2, 0, rk_ask(1)),
+                create_abc(OpCode::OpCall, 1, 2, 2),
+                create_abc(OpCode::OpMul, 1, 1, rk_ask(2)),
+                create_abc(OpCode::OpReturn, 1, 2, 0),
+            ],
+            vec![gname, num(1.0), num(2.0)],
+        );
+        f.num_params = 1;
+        f.is_vararg = 0;
+        state.new_lua_closure(Rc::new(f))
+    }
+
+    #[test]
+    fn test_resume_yield_across_nested_calls() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        let f = body(&mut state);
+        let co = state.new_thread(f);
+        assert_eq!(state.thread_status(co), ThreadStatus::Suspended);
+        let res = state.resume(co, &[num(10.0)]).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(as_num(&res[0]), 11.0);
+        assert_eq!(state.thread_status(co), ThreadStatus::Suspended);
+        let res = state.resume(co, &[num(5.0)]).unwrap();
+        assert_eq!(as_num(&res[0]), 10.0);
+        assert_eq!(state.thread_status(co), ThreadStatus::Dead);
+        let err = state.resume(co, &[]).unwrap_err();
+        assert_eq!(err.to_string(), "cannot resume dead coroutine");
+        // the main stack is untouched
+        assert_eq!(state.top, 1);
+        assert_eq!(state.base_ci.len(), 1);
+    }
+
+    #[test]
+    fn test_error_in_coroutine_kills_it() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        let f = body(&mut state);
+        let co = state.new_thread(f);
+        state.resume(co, &[num(1.0)]).unwrap();
+        // b * 2 with a nil b
+        let err = state.resume(co, &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test:4: attempt to perform arithmetic on a nil value"
+        );
+        assert_eq!(state.thread_status(co), ThreadStatus::Dead);
+    }
+
+    #[test]
+    fn test_yield_outside_coroutine() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        let coroutine = state.intern(b"coroutine");
+        let yield_ = state.intern(b"yield");
+        // coroutine.yield()
+        let p = proto(
+            vec![
+                create_abx(OpCode::OpGetGlobal, 0, 0),
+                create_abc(OpCode::OpGetTable, 0, 0, rk_ask(1)),
+                create_abc(OpCode::OpCall, 0, 1, 1),
+                create_abc(OpCode::OpReturn, 0, 1, 0),
+            ],
+            vec![coroutine, yield_],
+        );
+        let err = run(&mut state, p).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "attempt to yield across metamethod/C-call boundary"
+        );
+    }
+}
@@ -1,15 +1,14 @@
-use anyhow::Result;
-use core::panic;
+use anyhow::{Result, bail};
 use std::{
     io::{Cursor, Read},
     vec,
 };
 
 #[derive(Debug, PartialEq)]
-struct Local {
-    name: String,
-    start_line: LuaInt,
-    end_line: LuaInt,
+pub struct Local {
+    pub(crate) name: String,
+    pub(crate) start_line: LuaInt,
+    pub(crate) end_line: LuaInt,
 }
 
 impl Local {
@@ -33,13 +32,13 @@ impl std::fmt::Display for Local {
 }
 
 #[derive(Debug, PartialEq)]
-struct MetaInfo {
-    first_line: LuaInt,
-    last_line: LuaInt,
-    num_upvals: LuaInt,
-    num_params: u8,
-    is_varg: bool,
-    max_stack: u8,
+pub struct MetaInfo {
+    pub(crate) first_line: LuaInt,
+    pub(crate) last_line: LuaInt,
+    pub(crate) num_upvals: u8,
+    pub(crate) num_params: u8,
+    pub(crate) is_varg: u8,
+    pub(crate) max_stack: u8,
 }
 
 impl std::fmt::Display for MetaInfo {
@@ -59,14 +58,14 @@ impl std::fmt::Display for MetaInfo {
 
 #[derive(Debug, PartialEq)]
 pub struct Chunk {
-    name: String,
-    meta_info: MetaInfo,
-    instructions: Vec<u32>,
-    constant_table: Vec<Constant>,
-    protos: Vec<Chunk>,
-    lines: Vec<LuaInt>,
-    locals: Vec<Local>,
-    upvalues: Vec<String>,
+    pub(crate) name: String,
+    pub(crate) meta_info: MetaInfo,
+    pub(crate) instructions: Vec<u32>,
+    pub(crate) constant_table: Vec<Constant>,
+    pub(crate) protos: Vec<Chunk>,
+    pub(crate) lines: Vec<LuaInt>,
+    pub(crate) locals: Vec<Local>,
+    pub(crate) upvalues: Vec<String>,
 }
 
 impl std::fmt::Display for Chunk {
@@ -124,7 +123,9 @@ pub enum Constant {
     Nil,
     Bool(bool),
     Number(f64),
-    String(String),
+    /// integral number literal; only made by the compiler, 5.1 chunks hold doubles
+    Integer(i64),
+    String(Vec<u8>),
 }
 
 impl std::fmt::Display for Constant {
@@ -133,14 +134,15 @@ impl std::fmt::Display for Constant {
             Constant::Nil => write!(f, "Nil"),
             Constant::Bool(b) => write!(f, "Bool({b})"),
             Constant::Number(n) => write!(f, "Number({n})"),
-            Constant::String(s) => write!(f, "String(\"{s}\")"),
+            Constant::Integer(n) => write!(f, "Integer({n})"),
+            Constant::String(s) => write!(f, "String(\"{}\")", String::from_utf8_lossy(s)),
         }
     }
 }
 
 #[derive(Debug, PartialEq)]
 #[repr(u8)]
-enum Endian {
+pub enum Endian {
     BigEndian = 0u8,
     LittleEndian = 1u8,
 }
@@ -156,7 +158,7 @@ impl std::fmt::Display for Endian {
 
 #[derive(Debug, PartialEq)]
 #[repr(u8)]
-enum Integral {
+pub enum Integral {
     FloatingPoint = 0u8,
     IntegralNumber = 1u8,
 }
@@ -172,7 +174,7 @@ impl std::fmt::Display for Integral {
 
 #[derive(Debug, PartialEq)]
 #[repr(C)]
-struct Header {
+pub struct Header {
     signature: [u8; 4], // 0x1b4c7561
     lua_version: u8,    // 0x51
     format_version: u8, // 0x00
@@ -208,13 +210,12 @@ impl std::fmt::Display for Header {
 }
 
 enum SizeT {
-    U0,
     U32(u32),
     U64(u64),
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
-enum LuaInt {
+pub enum LuaInt {
     U32(u32),
     U64(u64),
 }
@@ -267,6 +268,9 @@ impl Undump {
         self.cur.read_exact(&mut inst_size)?;
         self.cur.read_exact(&mut number_size)?;
         self.cur.read_exact(&mut integral)?;
+        if signature != *b"\x1bLua" || lua_version[0] != 0x51 || format_version[0] != 0 {
+            bail!("bad header");
+        }
         Ok(Header {
             signature,
             lua_version: u8::from_be_bytes(lua_version),
@@ -274,7 +278,7 @@ impl Undump {
             endian: match u8::from_be_bytes(endian) {
                 0u8 => Endian::BigEndian,
                 1u8 => Endian::LittleEndian,
-                _ => panic!("Invalid Endian"),
+                _ => bail!("bad header"),
             },
             int_size: u8::from_be_bytes(int_size),
             size_t_size: u8::from_be_bytes(size_t_size),
@@ -283,7 +287,7 @@ impl Undump {
             integral: match u8::from_be_bytes(integral) {
                 0u8 => Integral::FloatingPoint,
                 1u8 => Integral::IntegralNumber,
-                _ => panic!("Invalid Integral"),
+                _ => bail!("bad header"),
             },
         })
     }
@@ -324,6 +328,17 @@ impl Undump {
         }
     }
 
+    /// a `lua_Number` of the size and kind given by the header
+    fn read_number(&mut self, header: &Header) -> Result<f64> {
+        match (&header.integral, header.number_size) {
+            (Integral::FloatingPoint, 4) => Ok(f64::from(self.read_float32(header)?)),
+            (Integral::FloatingPoint, 8) => self.read_float64(header),
+            (Integral::IntegralNumber, 4) => Ok(f64::from(self.read_uint32(header)? as i32)),
+            (Integral::IntegralNumber, 8) => Ok(self.read_uint64(header)? as i64 as f64),
+            _ => bail!("bad header"),
+        }
+    }
+
     fn read_size_t(&mut self, header: &Header) -> Result<SizeT> {
         match header.size_t_size {
             4 => {
@@ -342,28 +357,45 @@ impl Undump {
                     Endian::LittleEndian => Ok(SizeT::U64(u64::from_le_bytes(buf))),
                 }
             }
-            n => {
-                unimplemented!("any size_t is not implemeted... got '{n}'")
-            }
+            _ => bail!("bad header"),
         }
     }
 
     fn read_string(&mut self, header: &Header) -> Result<String> {
+        let bytes = self.read_bytes(header)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn read_bytes(&mut self, header: &Header) -> Result<Vec<u8>> {
         match self.read_size_t(header)? {
-            SizeT::U0 => Ok("".to_string()),
-            SizeT::U32(size) => {
-                let mut string_bytes = vec![0u8; size as usize];
-                self.cur.read_exact(&mut string_bytes)?;
-                let ret = String::from_utf8(string_bytes)?;
-                Ok(ret)
-            }
-            SizeT::U64(size) => {
-                let mut string_bytes = vec![0u8; size as usize];
-                self.cur.read_exact(&mut string_bytes)?;
-                let ret = String::from_utf8(string_bytes)?;
-                Ok(ret)
-            }
+            SizeT::U32(size) => self.read_string_bytes(size as usize),
+            SizeT::U64(size) => self.read_string_bytes(size as usize),
+        }
+    }
+
+    /// dumped strings include the terminating '\0', a size of 0 means NULL
+    fn read_string_bytes(&mut self, size: usize) -> Result<Vec<u8>> {
+        if size as u64 > self.remaining() {
+            bail!("unexpected end");
+        }
+        let mut string_bytes = vec![0u8; size];
+        self.cur.read_exact(&mut string_bytes)?;
+        string_bytes.pop();
+        Ok(string_bytes)
+    }
+
+    /// bytes left to read, bounding the counts found in the chunk
+    fn remaining(&self) -> u64 {
+        self.cur.get_ref().len() as u64 - self.cur.position()
+    }
+
+    /// a count of items, each at least `min_size` bytes long
+    fn read_count(&mut self, header: &Header, min_size: u64) -> Result<usize> {
+        let n = usize::from(self.read_uint(header)?);
+        if n as u64 * min_size > self.remaining() {
+            bail!("unexpected end");
         }
+        Ok(n)
     }
 
     fn read_uint(&mut self, header: &Header) -> Result<LuaInt> {
@@ -384,7 +416,7 @@ impl Undump {
                     Endian::LittleEndian => Ok(LuaInt::U64(u64::from_le_bytes(buf))),
                 }
             }
-            n => unimplemented!("any int size is not implemeted... got '{n}'"),
+            _ => bail!("bad header"),
         }
     }
 
@@ -401,45 +433,45 @@ impl Undump {
         let last_line = self.read_uint(header)?;
         let num_upval = self.read_byte()?;
         let num_params = self.read_byte()?;
-        let is_varg = self.read_byte()? != 0u8;
+        let is_varg = self.read_byte()?;
         let max_stack = self.read_byte()?;
 
         // instructions
-        let num_insts = self.read_uint(header)?;
+        let num_insts = self.read_count(header, 4)?;
         let mut insts = vec![];
-        for _ in 0..usize::from(num_insts) {
+        for _ in 0..num_insts {
             insts.push(self.read_uint32(header)?);
         }
         // constant table
-        let num_consts = self.read_uint(header)?;
+        let num_consts = self.read_count(header, 1)?;
         let mut consts = vec![];
-        for _ in 0..usize::from(num_consts) {
+        for _ in 0..num_consts {
             match self.read_byte()? {
                 0 => consts.push(Constant::Nil),
                 1 => consts.push(Constant::Bool(self.read_byte()? != 0)),
-                3 => consts.push(Constant::Number(self.read_float64(header)?)),
-                4 => consts.push(Constant::String(self.read_string(header)?)),
-                n => panic!("unknown datatype: actually got '{n}'"),
+                3 => consts.push(Constant::Number(self.read_number(header)?)),
+                4 => consts.push(Constant::String(self.read_bytes(header)?)),
+                _ => bail!("bad constant"),
             }
         }
         // proto
-        let num_protos = self.read_uint(header)?;
+        let num_protos = self.read_count(header, 1)?;
         let mut protos = vec![];
-        for _ in 0..usize::from(num_protos) {
+        for _ in 0..num_protos {
             protos.push(self.read_chunk(header)?);
         }
 
         // number_of_lines
-        let num_lines = self.read_uint(header)?;
+        let num_lines = self.read_count(header, 1)?;
         let mut lines = vec![];
-        for _ in 0..usize::from(num_lines) {
+        for _ in 0..num_lines {
             lines.push(self.read_uint(header)?);
         }
 
         // local list
-        let num_locals = self.read_uint(header)?;
+        let num_locals = self.read_count(header, 1)?;
         let mut locals = vec![];
-        for _ in 0..usize::from(num_locals) {
+        for _ in 0..num_locals {
             let name = self.read_string(header)?;
             let start_line = self.read_uint(header)?;
             let end_line = self.read_uint(header)?;
@@ -447,9 +479,9 @@ impl Undump {
         }
 
         // upvalue
-        let num_upvals = self.read_uint(header)?;
+        let num_upval_names = self.read_count(header, 1)?;
         let mut upvals = vec![];
-        for _ in 0..usize::from(num_upvals) {
+        for _ in 0..num_upval_names {
             let name = self.read_string(header)?;
             upvals.push(name);
         }
@@ -458,7 +490,7 @@ impl Undump {
             meta_info: MetaInfo {
                 first_line,
                 last_line,
-                num_upvals,
+                num_upvals: num_upval,
                 num_params,
                 is_varg,
                 max_stack,
@@ -471,24 +503,27 @@ impl Undump {
             upvalues: upvals,
         })
     }
+    /// Errors are "bad header", "bad constant" or "unexpected end", the
+    /// reasons C Lua gives for a malformed precompiled chunk.
     pub fn undump(&mut self) -> Result<(Header, Chunk)> {
-        let header = self.read_header()?;
-        let chunk = self.read_chunk(&header)?;
+        let header = self.read_header().map_err(truncated)?;
+        if header.inst_size != 4
+            || header.number_size != 8
+            || header.integral != Integral::FloatingPoint
+        {
+            bail!("bad header");
+        }
+        let chunk = self.read_chunk(&header).map_err(truncated)?;
         Ok((header, chunk))
     }
+}
 
-    pub fn print(&mut self) {
-        match self.undump() {
-            Ok((h, c)) => {
-                println!("#### Header ####");
-                println!("{}", h);
-                println!("#### Chunks ####");
-                println!("{}", c);
-            }
-            Err(e) => {
-                eprintln!("{e}");
-            }
-        }
+/// io errors of `read_exact` past the end of the input
+fn truncated(e: anyhow::Error) -> anyhow::Error {
+    if e.is::<std::io::Error>() {
+        anyhow::anyhow!("unexpected end")
+    } else {
+        e
     }
 }
 
@@ -510,12 +545,6 @@ mod tests {
         bytecodes.extend(&0x04u8.to_be_bytes()); // inst_size
         bytecodes.extend(&0x08u8.to_be_bytes()); // number_size
         bytecodes.extend(&0x00u8.to_be_bytes()); // integral
-        assert_eq!(true, false);
-        let result = bytecodes
-            .iter()
-            .map(|n| format!("{:02x}", n))
-            .collect::<String>();
-        println!("{}", result);
         let mut undump = Undump::new(bytecodes);
         assert_eq!(
             undump.read_header().unwrap(),
@@ -0,0 +1,546 @@
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::conversion::{BadArgument, FromLua, FromLuaMulti, ToLua, ToLuaMulti, bad_argument};
+use crate::eval::{TValue, Value};
+use crate::heap::Gc;
+use crate::host::{HostFn, host_error, host_function};
+use crate::table::Table;
+use crate::vm::{LuaState, type_name};
+
+/// Full userdata: a host value owned by the Lua heap (Udata in lobject.h).
+/// The value is dropped when the userdata is collected, after its `__gc`
+/// metamethod, if any, has run.
+pub struct Userdata {
+    pub metatable: Option<Gc<Table>>,
+    pub env: Gc<Table>,
+    pub(crate) data: Box<dyn Any>,
+    /// creation order, finalizers run newest first
+    pub(crate) serial: u64,
+    /// already handed to (or found without) a `__gc` metamethod
+    pub(crate) finalized: bool,
+}
+
+/// A Rust type which can be handed to Lua as userdata with
+/// `create_userdata`. Its methods, fields and metamethods are added once per
+/// type, to a metatable shared by all its values.
+pub trait UserData: Sized + 'static {
+    fn add_methods(_methods: &mut UserDataMethods<Self>) {}
+}
+
+/// Methods, fields and metamethods of the userdata of type `T`.
+pub struct UserDataMethods<T> {
+    methods: Vec<(String, HostFn)>,
+    getters: HashMap<Vec<u8>, HostFn>,
+    setters: HashMap<Vec<u8>, HostFn>,
+    meta: Vec<(String, HostFn)>,
+    _type: PhantomData<T>,
+}
+
+/// content of a userdata whose value was dropped by `__gc`
+struct Destroyed;
+
+/// short name of `T` for error messages, e.g. "Point"
+fn short_type_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/// the value of the userdata `val` of type `T`, shared so that it stays
+/// borrowed while Lua code runs
+fn userdata_cell<T: UserData>(state: &LuaState, val: &TValue) -> Result<Rc<RefCell<T>>> {
+    if let Value::UserData(u) = val.value() {
+        if !state.global.heap.is_live(u) {
+            bail!("attempt to use a collected value");
+        }
+        let data = &state.global.heap.get(u).data;
+        if let Some(cell) = data.downcast_ref::<Rc<RefCell<T>>>() {
+            return Ok(cell.clone());
+        }
+        if data.is::<Destroyed>() {
+            bail!("userdata has been destroyed");
+        }
+    }
+    Err(anyhow!(
+        "{} expected, got {}",
+        short_type_name::<T>(),
+        type_name(val)
+    ))
+}
+
+/// first argument as `self`, and the others converted to `A`
+fn method_args<T: UserData, A: FromLuaMulti>(
+    state: &mut LuaState,
+    mut args: Vec<TValue>,
+) -> Result<(Rc<RefCell<T>>, A)> {
+    let this = if args.is_empty() {
+        TValue::nil()
+    } else {
+        args.remove(0)
+    };
+    let cell = userdata_cell::<T>(state, &this).map_err(|e| {
+        anyhow::Error::new(BadArgument {
+            position: 1,
+            message: e.to_string(),
+        })
+    })?;
+    let args = A::from_lua_multi(args, state).map_err(|e| bad_argument(e, 1))?;
+    Ok((cell, args))
+}
+
+fn method<T, A, R, F>(f: F) -> HostFn
+where
+    T: UserData,
+    A: FromLuaMulti,
+    R: ToLuaMulti,
+    F: Fn(&mut LuaState, &T, A) -> Result<R> + 'static,
+{
+    Rc::new(move |state, args| {
+        let (cell, args) = method_args::<T, A>(state, args)?;
+        let this = cell
+            .try_borrow()
+            .map_err(|_| anyhow!("userdata already mutably borrowed"))?;
+        Ok(f(state, &this, args)?.to_lua_multi(state))
+    })
+}
+
+fn method_mut<T, A, R, F>(f: F) -> HostFn
+where
+    T: UserData,
+    A: FromLuaMulti,
+    R: ToLuaMulti,
+    F: Fn(&mut LuaState, &mut T, A) -> Result<R> + 'static,
+{
+    Rc::new(move |state, args| {
+        let (cell, args) = method_args::<T, A>(state, args)?;
+        let mut this = cell
+            .try_borrow_mut()
+            .map_err(|_| anyhow!("userdata already borrowed"))?;
+        Ok(f(state, &mut this, args)?.to_lua_multi(state))
+    })
+}
+
+impl<T: UserData> UserDataMethods<T> {
+    /// method borrowing the value, called as `obj:name(...)`
+    pub fn add_method<A, R, F>(&mut self, name: &str, f: F)
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+        F: Fn(&mut LuaState, &T, A) -> Result<R> + 'static,
+    {
+        self.methods.push((name.to_string(), method(f)));
+    }
+    /// method borrowing the value mutably
+    pub fn add_method_mut<A, R, F>(&mut self, name: &str, f: F)
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+        F: Fn(&mut LuaState, &mut T, A) -> Result<R> + 'static,
+    {
+        self.methods.push((name.to_string(), method_mut(f)));
+    }
+    /// function stored with the methods, called as `obj.name(...)`
+    pub fn add_function<A, R, F>(&mut self, name: &str, f: F)
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+        F: Fn(&mut LuaState, A) -> Result<R> + 'static,
+    {
+        self.methods.push((name.to_string(), host_function(f)));
+    }
+    /// field read as `obj.name`
+    pub fn add_field_getter<R, F>(&mut self, name: &str, f: F)
+    where
+        R: ToLua,
+        F: Fn(&mut LuaState, &T) -> Result<R> + 'static,
+    {
+        let getter = method(move |state, this: &T, ()| f(state, this));
+        self.getters.insert(name.as_bytes().to_vec(), getter);
+    }
+    /// field assigned as `obj.name = value`
+    pub fn add_field_setter<V, F>(&mut self, name: &str, f: F)
+    where
+        V: FromLua,
+        F: Fn(&mut LuaState, &mut T, V) -> Result<()> + 'static,
+    {
+        let setter = method_mut(move |state, this: &mut T, v: V| f(state, this, v));
+        self.setters.insert(name.as_bytes().to_vec(), setter);
+    }
+    /// metamethod such as `__tostring` or `__call`, with the userdata as
+    /// first operand
+    pub fn add_meta_method<A, R, F>(&mut self, name: &str, f: F)
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+        F: Fn(&mut LuaState, &T, A) -> Result<R> + 'static,
+    {
+        self.meta.push((name.to_string(), method(f)));
+    }
+    pub fn add_meta_method_mut<A, R, F>(&mut self, name: &str, f: F)
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+        F: Fn(&mut LuaState, &mut T, A) -> Result<R> + 'static,
+    {
+        self.meta.push((name.to_string(), method_mut(f)));
+    }
+    /// metamethod taking its operands as they are, for binary operators
+    /// where the userdata may be on either side
+    pub fn add_meta_function<A, R, F>(&mut self, name: &str, f: F)
+    where
+        A: FromLuaMulti,
+        R: ToLuaMulti,
+        F: Fn(&mut LuaState, A) -> Result<R> + 'static,
+    {
+        self.meta.push((name.to_string(), host_function(f)));
+    }
+}
+
+/// Getters and setters of a userdata type, upvalue of its `__index` and
+/// `__newindex`.
+struct Fields {
+    getters: HashMap<Vec<u8>, HostFn>,
+    setters: HashMap<Vec<u8>, HostFn>,
+}
+
+fn fields(state: &LuaState, val: TValue) -> Rc<Fields> {
+    match val.value() {
+        Value::UserData(u) => state
+            .global
+            .heap
+            .get(u)
+            .data
+            .downcast_ref::<Rc<Fields>>()
+            .expect("userdata fields")
+            .clone(),
+        _ => unreachable!("userdata fields"),
+    }
+}
+
+/// call `f` with `args` and return its first result, or nil
+fn call_value(state: &mut LuaState, f: TValue, args: &[TValue]) -> Result<TValue> {
+    let func = state.top;
+    state.check_stack(args.len() + 1);
+    state.push(f);
+    for &arg in args {
+        state.push(arg);
+    }
+    state.call(func, 1)?;
+    state.top = func;
+    Ok(state.stack[func])
+}
+
+/// __index: methods, then fields, then the `__index` given by the type
+/// (upvalues: methods table, fields, fallback)
+fn index_userdata(state: &mut LuaState) -> Result<usize> {
+    let (ud, key) = (state.arg(1), state.arg(2));
+    let methods = state.upvalue(1);
+    let mut val = match methods.value() {
+        Value::Table(t) => state.global.heap.get(t).get(&key),
+        _ => TValue::nil(),
+    };
+    if matches!(val.value(), Value::Nil) {
+        let getter = match state.to_str_bytes(&key) {
+            Some(name) if matches!(key.value(), Value::String(_)) => {
+                fields(state, state.upvalue(2)).getters.get(&name).cloned()
+            }
+            _ => None,
+        };
+        let fallback = state.upvalue(3);
+        if let Some(getter) = getter {
+            let results = getter(state, vec![ud]).map_err(|e| host_error(state, e))?;
+            val = results.into_iter().next().unwrap_or_else(TValue::nil);
+        } else if !matches!(fallback.value(), Value::Nil) {
+            val = call_value(state, fallback, &[ud, key])?;
+        }
+    }
+    state.push(val);
+    Ok(1)
+}
+
+/// __newindex: fields, then the `__newindex` given by the type
+/// (upvalues: fields, fallback)
+fn newindex_userdata(state: &mut LuaState) -> Result<usize> {
+    let (ud, key, val) = (state.arg(1), state.arg(2), state.arg(3));
+    let name = match key.value() {
+        Value::String(_) => state.to_str_bytes(&key),
+        _ => None,
+    };
+    let setter = name
+        .as_ref()
+        .and_then(|name| fields(state, state.upvalue(1)).setters.get(name).cloned());
+    let fallback = state.upvalue(2);
+    if let Some(setter) = setter {
+        setter(state, vec![ud, val]).map_err(|e| match e.downcast::<BadArgument>() {
+            Ok(bad) => {
+                let name = String::from_utf8_lossy(name.as_deref().unwrap_or_default());
+                state.error(format!("bad value for field '{name}' ({})", bad.message))
+            }
+            Err(e) => host_error(state, e),
+        })?;
+    } else if !matches!(fallback.value(), Value::Nil) {
+        call_value(state, fallback, &[ud, key, val])?;
+    } else {
+        let msg = match &name {
+            Some(name) => format!(
+                "attempt to set unknown field '{}'",
+                String::from_utf8_lossy(name)
+            ),
+            None => format!("attempt to set a field with a {} key", type_name(&key)),
+        };
+        return Err(state.error(msg));
+    }
+    Ok(0)
+}
+
+/// __gc: run the `__gc` given by the type (upvalue 1), then drop the value.
+/// Lua reaches it through the metatable, so the argument is checked to hold
+/// a `T`.
+fn gc_userdata<T: UserData>(state: &mut LuaState) -> Result<usize> {
+    let ud = state.arg(1);
+    if let Err(e) = userdata_cell::<T>(state, &ud) {
+        return Err(state.arg_error(1, &e.to_string()));
+    }
+    let finalizer = state.upvalue(1);
+    if !matches!(finalizer.value(), Value::Nil) {
+        call_value(state, finalizer, &[ud])?;
+    }
+    if let Value::UserData(u) = ud.value() {
+        state.global.heap.get_mut(u).data = Box::new(Destroyed);
+    }
+    Ok(0)
+}
+
+impl LuaState {
+    /// the metatable shared by the userdata of type `T`, built on first use
+    fn userdata_metatable<T: UserData>(&mut self) -> Gc<Table> {
+        if let Some(&mt) = self.global.userdata_mt.get(&TypeId::of::<T>()) {
+            return mt;
+        }
+        let mut reg = UserDataMethods::<T> {
+            methods: Vec::new(),
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+            meta: Vec::new(),
+            _type: PhantomData,
+        };
+        T::add_methods(&mut reg);
+        let mt = self.new_table();
+        let mut meta = HashMap::new();
+        for (name, f) in reg.meta {
+            let f = self.new_host_function(f);
+            meta.insert(name, f);
+        }
+        let index_fallback = meta.remove("__index").unwrap_or_else(TValue::nil);
+        let newindex_fallback = meta.remove("__newindex").unwrap_or_else(TValue::nil);
+        let finalizer = meta.remove("__gc").unwrap_or_else(TValue::nil);
+        for (name, f) in meta {
+            let key = self.intern(name.as_bytes());
+            self.global
+                .heap
+                .get_mut(mt)
+                .set(key, f)
+                .expect("string key");
+        }
+
+        let methods = self.new_table();
+        for (name, f) in reg.methods {
+            let f = self.new_host_function(f);
+            let key = self.intern(name.as_bytes());
+            self.global
+                .heap
+                .get_mut(methods)
+                .set(key, f)
+                .expect("string key");
+        }
+        let plain_index = reg.getters.is_empty() && matches!(index_fallback.value(), Value::Nil);
+        let has_setters = !reg.setters.is_empty();
+        let fields = Rc::new(Fields {
+            getters: reg.getters,
+            setters: reg.setters,
+        });
+        let fields = TValue::userdata(self.new_userdata(Box::new(fields)));
+        if plain_index {
+            self.set_field(mt, "__index", TValue::table(methods));
+        } else {
+            let index = self.new_native_closure(
+                index_userdata,
+                vec![TValue::table(methods), fields, index_fallback],
+            );
+            self.set_field(mt, "__index", index);
+        }
+        if has_setters || !matches!(newindex_fallback.value(), Value::Nil) {
+            let newindex =
+                self.new_native_closure(newindex_userdata, vec![fields, newindex_fallback]);
+            self.set_field(mt, "__newindex", newindex);
+        }
+        let gc = self.new_native_closure(gc_userdata::<T>, vec![finalizer]);
+        self.set_field(mt, "__gc", gc);
+        self.global.userdata_mt.insert(TypeId::of::<T>(), mt);
+        mt
+    }
+
+    fn set_field(&mut self, t: Gc<Table>, name: &str, val: TValue) {
+        let key = self.global.heap.intern(name.as_bytes());
+        self.global.heap.get_mut(t).set_str(key, val);
+    }
+
+    /// Full userdata owning `data`, with the methods and metamethods of `T`.
+    /// `data` is dropped by `__gc`, or when the state is closed.
+    pub fn create_userdata<T: UserData>(&mut self, data: T) -> TValue {
+        let mt = self.userdata_metatable::<T>();
+        let u = self.new_userdata(Box::new(Rc::new(RefCell::new(data))));
+        self.global.heap.get_mut(u).metatable = Some(mt);
+        TValue::userdata(u)
+    }
+
+    /// Run `f` with a shared borrow of the `T` in userdata `val`. Fails if
+    /// `val` holds no `T` or if the value is borrowed mutably, e.g. by the
+    /// method which is running.
+    pub fn with_userdata<T: UserData, R>(
+        &mut self,
+        val: &TValue,
+        f: impl FnOnce(&mut LuaState, &T) -> Result<R>,
+    ) -> Result<R> {
+        let cell = userdata_cell::<T>(self, val)?;
+        let this = cell
+            .try_borrow()
+            .map_err(|_| anyhow!("userdata already mutably borrowed"))?;
+        f(self, &this)
+    }
+
+    /// Run `f` with a mutable borrow of the `T` in userdata `val`.
+    pub fn with_userdata_mut<T: UserData, R>(
+        &mut self,
+        val: &TValue,
+        f: impl FnOnce(&mut LuaState, &mut T) -> Result<R>,
+    ) -> Result<R> {
+        let cell = userdata_cell::<T>(self, val)?;
+        let mut this = cell
+            .try_borrow_mut()
+            .map_err(|_| anyhow!("userdata already borrowed"))?;
+        f(self, &mut this)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iolib::SystemAccess;
+    use pretty_assertions::assert_eq;
+    use std::cell::Cell;
+
+    struct Point {
+        x: f64,
+        y: f64,
+        dropped: Rc<Cell<bool>>,
+    }
+
+    impl Drop for Point {
+        fn drop(&mut self) {
+            self.dropped.set(true);
+        }
+    }
+
+    impl UserData for Point {
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_field_getter("x", |_, p| Ok(p.x));
+            methods.add_field_setter("x", |_, p, x: f64| {
+                p.x = x;
+                Ok(())
+            });
+            methods.add_method("len", |_, p, ()| Ok((p.x * p.x + p.y * p.y).sqrt()));
+            methods.add_method_mut("scale", |_, p, k: f64| {
+                p.x *= k;
+                p.y *= k;
+                Ok(())
+            });
+            // calls back into Lua while the point is borrowed
+            methods.add_method_mut("apply", |state, _, f: TValue| {
+                state
+                    .call_function::<_, ()>(&f, ())
+                    .map_err(anyhow::Error::new)
+            });
+            methods.add_meta_method("__tostring", |_, p, ()| Ok(format!("({}, {})", p.x, p.y)));
+            methods.add_meta_function("__add", |state, (a, b): (TValue, TValue)| {
+                let (ax, ay) = state.with_userdata(&a, |_, p: &Point| Ok((p.x, p.y)))?;
+                let (bx, by) = state.with_userdata(&b, |_, p: &Point| Ok((p.x, p.y)))?;
+                let dropped = Rc::new(Cell::new(false));
+                Ok(state.create_userdata(Point {
+                    x: ax + bx,
+                    y: ay + by,
+                    dropped,
+                }))
+            });
+        }
+    }
+
+    #[test]
+    fn test_userdata_methods() {
+        let mut lua = LuaState::new();
+        lua.open_libs(SystemAccess::SAFE);
+        let dropped = Rc::new(Cell::new(false));
+        let p = lua.create_userdata(Point {
+            x: 3.0,
+            y: 4.0,
+            dropped: dropped.clone(),
+        });
+        lua.set_global("p", p).unwrap();
+        let r: (f64, String, f64) = lua
+            .exec("local q = p + p; q:scale(0.5); p.x = 6 return p:len() * 0 + p.x, tostring(q), q:len()")
+            .unwrap();
+        assert_eq!(r, (6.0, "(3, 4)".to_string(), 5.0));
+
+        let err = lua.exec::<()>("p.len(1)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"p.len(1)\"]:1: bad argument #1 to 'len' (Point expected, got number)"
+        );
+        let err = lua.exec::<()>("p:scale('big')").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"p:scale('big')\"]:1: bad argument #1 to 'scale' (number expected, got string)"
+        );
+        let err = lua.exec::<()>("p.x = {}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"p.x = {}\"]:1: bad value for field 'x' (number expected, got table)"
+        );
+        let err = lua.exec::<()>("p.y = 1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"p.y = 1\"]:1: attempt to set unknown field 'y'"
+        );
+        let err = lua
+            .exec::<()>("p:apply(function() return p:len() end)")
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("userdata already mutably borrowed"),
+            "{err}"
+        );
+
+        // the __gc reachable from Lua only takes values of its own type
+        let err = lua
+            .exec::<()>("getmetatable(p).__gc(io.stdout)")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"getmetatable(p).__gc(io.stdout)\"]:1: bad argument #1 to '__gc' \
+             (Point expected, got userdata)"
+        );
+        lua.exec::<()>("io.write('')").unwrap();
+
+        // __gc drops the value
+        lua.exec::<()>("p = nil").unwrap();
+        assert!(!dropped.get());
+        lua.gc_collect().unwrap();
+        assert!(dropped.get());
+    }
+}
@@ -1,126 +1,1493 @@
-use core::panic;
-use std::sync::{Arc, Mutex};
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 
-use crate::eval::{LuaNumber, LuaType, TValue, Value};
-use crate::opcodes::{Instruction, as_kbx, as_ra, as_rkb, as_rkc};
-use crate::undump::Constant;
+use anyhow::Result;
 
+use crate::asyncfn::AsyncState;
+use crate::debug::{HookEvent, HookState, MASK_CALL, MASK_COUNT, MASK_LINE, MASK_RET};
+use crate::error::LuaError;
+use crate::eval::{LuaNumber, MAX_EXACT_INTEGER, TValue, Value};
+use crate::func::{
+    LuaClosure, NativeClosure, NativeFn, Proto, UpVal, VARARG_ISVARARG, VARARG_NEEDSARG,
+};
+use crate::heap::{Gc, Heap, LuaString};
+use crate::limits::{Counter, Limits};
+use crate::opcodes::{
+    Instruction, LFIELDS_PER_FLUSH, get_a, get_b, get_bx, get_c, get_sbx, index_k, is_k,
+};
+use crate::printf::Spec;
+use crate::table::Table;
+use crate::thread::{Thread, ThreadStatus};
+use crate::undump::Chunk;
 
-pub struct Proto {
-    constant_table: Vec<Constant>,
-    constant_index: usize,
-}
+/// option for multiple returns in `call`/`pcall`
+pub const LUA_MULTRET: i32 = -1;
+/// maximum depth of nested `CallInfo`s
+pub(crate) const LUAI_MAXCALLS: usize = 20000;
+/// maximum depth of nested Rust calls (metamethods, natives calling Lua)
+pub(crate) const LUAI_MAXCCALLS: usize = 200;
+/// free stack slots guaranteed to a native function
+pub const LUA_MINSTACK: usize = 20;
+/// maximum number of slots a native function can ask for
+pub(crate) const LUAI_MAXCSTACK: usize = 8000;
+/// limit for `__index`/`__newindex` chains
+const MAXTAGLOOP: usize = 100;
 
 pub struct CallInfo {
-    base: usize,
-    func: usize,
-    top: usize,
-    proto: Proto,
+    pub(crate) base: usize,
+    pub(crate) func: usize,
+    pub(crate) top: usize,
+    /// index of the next instruction for Lua functions
+    pub(crate) saved_pc: usize,
+    pub(crate) nresults: i32,
+    /// number of tail calls lost under this entry
+    pub(crate) tailcalls: usize,
 }
-impl CallInfo {
-    pub fn with_consts(constant_table: Vec<Constant>) -> Self {
-        Self {
-            base: 0,
-            func: 0,
-            top: 0,
-            proto: Proto {
-                constant_table,
-                constant_index: 0,
-            },
-        }
-    }
+
+/// Metamethod events, in the order of `TMS` in ltm.h
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagMethod {
+    Index,
+    NewIndex,
+    Gc,
+    Mode,
+    Eq,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Unm,
+    Len,
+    Lt,
+    Le,
+    Concat,
+    Call,
 }
 
-struct GlobalState {
+const TM_NAMES: [&str; 17] = [
+    "__index",
+    "__newindex",
+    "__gc",
+    "__mode",
+    "__eq",
+    "__add",
+    "__sub",
+    "__mul",
+    "__div",
+    "__mod",
+    "__pow",
+    "__unm",
+    "__len",
+    "__lt",
+    "__le",
+    "__concat",
+    "__call",
+];
+
+/// number of basic types which can have a per-type metatable
+const NUM_TAGS: usize = 9;
 
+pub struct GlobalState {
+    pub(crate) heap: Heap,
+    pub(crate) globals: Gc<Table>,
+    /// table for the host to keep values alive
+    pub(crate) registry: Gc<Table>,
+    /// interned metamethod names, indexed by `TagMethod`
+    pub(crate) tm_names: Vec<Gc<LuaString>>,
+    /// metatables for non-table types, indexed by `LuaType`
+    pub(crate) mt: [Option<Gc<Table>>; NUM_TAGS],
+    /// references of the dropped `RegistryKey`s, freed at the next chance
+    pub(crate) dropped_keys: Rc<RefCell<Vec<i64>>>,
+    /// metatables of the userdata made by `create_userdata`, per Rust type
+    pub(crate) userdata_mt: HashMap<TypeId, Gc<Table>>,
+    /// whether the loaders accept precompiled chunks
+    pub(crate) binary_chunks: bool,
+    /// whether `load_file` may read files, from the `SystemAccess` of `open_libs`
+    pub(crate) read_files: bool,
+    pub(crate) limits: Limits,
+    pub(crate) counter: Counter,
+    /// flag set by the `InterruptHandle`s, once one was made
+    pub(crate) interrupt: Option<Arc<AtomicBool>>,
+    pub(crate) async_state: AsyncState,
 }
 
+pub(crate) enum PreCall {
+    /// a Lua frame was pushed and must be run by `vm_execute`
+    Lua,
+    /// a native function was called and its results are in place
+    Native,
+    /// a native function yielded; the running coroutine must be suspended
+    Yield,
+}
+
+/// Execution state. The stack, `CallInfo` chain and open upvalues are those of
+/// the running thread; the other threads keep theirs in their `Thread` object.
 pub struct LuaState {
-    stack: Vec<TValue>,
-    base_ci: Vec<CallInfo>,
-    ci: usize,
-    base: usize,
+    pub(crate) stack: Vec<TValue>,
+    pub(crate) top: usize,
+    pub(crate) base_ci: Vec<CallInfo>,
+    pub(crate) open_upvals: Vec<Gc<UpVal>>,
+    /// `n_ccalls` when the running thread was resumed
+    pub(crate) base_ccalls: usize,
+    pub(crate) n_ccalls: usize,
+    pub(crate) current: Gc<Thread>,
+    pub(crate) main_thread: Gc<Thread>,
+    /// number of values passed to `yield`, set while a coroutine is suspending
+    pub(crate) n_yield: Option<usize>,
+    /// hook of the running thread
+    pub(crate) hook: HookState,
+    pub(crate) global: GlobalState,
+}
+
+impl Default for LuaState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LuaState {
     pub fn new() -> Self {
+        let mut heap = Heap::new();
+        let globals = heap.alloc(Table::new());
+        let registry = heap.alloc(Table::new());
+        let tm_names = TM_NAMES.iter().map(|n| heap.intern(n.as_bytes())).collect();
+        let main_thread = heap.alloc(Thread::placeholder(ThreadStatus::Running));
+        let th = Thread::new();
+        heap.threshold = 4 * heap.total_bytes;
         Self {
-            stack: Vec::new(),
-            base_ci: Vec::new(),
-            ci: 0,
-            base: 0,
+            stack: th.stack,
+            top: th.top,
+            base_ci: th.base_ci,
+            open_upvals: th.open_upvals,
+            base_ccalls: 0,
+            n_ccalls: 0,
+            current: main_thread,
+            main_thread,
+            n_yield: None,
+            hook: HookState::default(),
+            global: GlobalState {
+                heap,
+                globals,
+                registry,
+                tm_names,
+                mt: [None; NUM_TAGS],
+                dropped_keys: Rc::new(RefCell::new(Vec::new())),
+                userdata_mt: HashMap::new(),
+                binary_chunks: true,
+                read_files: true,
+                limits: Limits::default(),
+                counter: Counter::default(),
+                interrupt: None,
+                async_state: AsyncState::default(),
+            },
         }
     }
-    pub fn with_consts(constant_table: Vec<Constant>) -> Self {
-        Self {
-            stack: vec![
-                TValue::new(Value::Nil, LuaType::Nil),
-                TValue::new(Value::Nil, LuaType::Nil),
-                TValue::new(Value::Nil, LuaType::Nil),
-                TValue::new(Value::Nil, LuaType::Nil),
-            ],
-            base_ci: vec![CallInfo::with_consts(constant_table)],
-            ci: 0,
-            base: 0,
+
+    /// make sure there are `n` free slots above `top`
+    pub(crate) fn check_stack(&mut self, n: usize) {
+        let needed = self.top + n;
+        if self.stack.len() < needed {
+            self.stack.resize(needed + LUA_MINSTACK, TValue::nil());
         }
     }
-    fn set_number(&mut self, ra: usize, tval: TValue) {
-        self.stack[ra] = tval.clone();
+    pub(crate) fn push(&mut self, val: TValue) {
+        self.check_stack(1);
+        self.stack[self.top] = val;
+        self.top += 1;
+    }
+    pub(crate) fn intern(&mut self, bytes: &[u8]) -> TValue {
+        TValue::string(self.global.heap.intern(bytes))
+    }
+    pub(crate) fn new_table(&mut self) -> Gc<Table> {
+        self.global.heap.alloc(Table::new())
+    }
+    pub(crate) fn new_native(&mut self, func: NativeFn) -> TValue {
+        self.new_native_closure(func, Vec::new())
+    }
+    /// native function with upvalues, readable through `upvalue`
+    pub(crate) fn new_native_closure(&mut self, func: NativeFn, upvalues: Vec<TValue>) -> TValue {
+        let env = self.global.globals;
+        let c = self.global.heap.alloc(NativeClosure {
+            func,
+            env,
+            upvalues,
+        });
+        TValue::native_closure(c)
+    }
+    /// create the main closure of an undumped chunk
+    pub(crate) fn load(&mut self, chunk: &Chunk) -> TValue {
+        let proto = Proto::from_chunk(chunk, "=?", &mut self.global.heap);
+        self.new_lua_closure(Rc::new(proto))
+    }
+    pub(crate) fn new_lua_closure(&mut self, proto: Rc<Proto>) -> TValue {
+        let env = self.global.globals;
+        let c = self.global.heap.alloc(LuaClosure {
+            proto,
+            upvals: Vec::new(),
+            env,
+        });
+        TValue::lua_closure(c)
+    }
+    pub(crate) fn str_bytes(&self, s: Gc<LuaString>) -> &[u8] {
+        self.global.heap.get(s).as_bytes()
+    }
+    pub(crate) fn tm_name(&self, event: TagMethod) -> Gc<LuaString> {
+        self.global.tm_names[event as usize]
+    }
+
+    pub(crate) fn get_metatable(&self, val: &TValue) -> Option<Gc<Table>> {
+        match val.value() {
+            Value::Table(t) => self.global.heap.get(t).metatable,
+            Value::UserData(u) => self.global.heap.get(u).metatable,
+            _ => self.global.mt[val.lua_type() as usize],
+        }
     }
-    fn set_const(&mut self, ra: usize, kst: usize) {
-        match self.base_ci[self.ci].proto.constant_table[kst] {
-            Constant::Number(n) => {
-                self.stack[ra] = TValue::new(Value::Number(n), LuaType::Number);
+    /// metamethod `event` of `val`, nil if absent
+    pub(crate) fn get_tm_by_obj(&self, val: &TValue, event: TagMethod) -> TValue {
+        match self.get_metatable(val) {
+            Some(mt) => self.global.heap.get(mt).get_str(self.tm_name(event)),
+            None => TValue::nil(),
+        }
+    }
+
+    // ---- upvalues ----
+
+    pub(crate) fn get_upval(&self, uv: Gc<UpVal>) -> TValue {
+        match *self.global.heap.get(uv) {
+            UpVal::Open(th, i) if th == self.current => self.stack[i],
+            UpVal::Open(th, i) => self.global.heap.get(th).stack[i],
+            UpVal::Closed(v) => v,
+        }
+    }
+    pub(crate) fn set_upval(&mut self, uv: Gc<UpVal>, val: TValue) {
+        match *self.global.heap.get(uv) {
+            UpVal::Open(th, i) if th == self.current => self.stack[i] = val,
+            UpVal::Open(th, i) => self.global.heap.get_mut(th).stack[i] = val,
+            UpVal::Closed(_) => *self.global.heap.get_mut(uv) = UpVal::Closed(val),
+        }
+    }
+    fn find_upval(&mut self, level: usize) -> Gc<UpVal> {
+        for uv in self.open_upvals.iter() {
+            if let UpVal::Open(_, i) = self.global.heap.get(*uv)
+                && *i == level
+            {
+                return *uv;
+            }
+        }
+        let uv = self.global.heap.alloc(UpVal::Open(self.current, level));
+        self.open_upvals.push(uv);
+        uv
+    }
+    /// close all open upvalues pointing at or above `level`
+    pub(crate) fn close_upvals(&mut self, level: usize) {
+        let stack = &self.stack;
+        let heap = &mut self.global.heap;
+        self.open_upvals.retain(|uv| {
+            let uv = heap.get_mut(*uv);
+            match uv {
+                UpVal::Open(_, i) if *i >= level => {
+                    *uv = UpVal::Closed(stack[*i]);
+                    false
+                }
+                _ => true,
             }
-            _ => unimplemented!(),
+        });
+    }
+
+    // ---- calls ----
+
+    /// call the function at stack index `func` with the values above it as arguments
+    pub(crate) fn call(&mut self, func: usize, nresults: i32) -> Result<()> {
+        self.n_ccalls += 1;
+        if self.n_ccalls >= LUAI_MAXCCALLS {
+            return Err(self.runtime_error("C stack overflow".to_string()));
+        }
+        if let PreCall::Lua = self.precall(func, nresults)? {
+            vm_execute(self, 1)?;
         }
+        self.n_ccalls -= 1;
+        Ok(())
     }
-    pub fn print_register(&self) {
-        for (i, tval) in self.stack.iter().enumerate() {
-            println!("R[{i:02x}] = {tval}");
+
+    /// Protected call. On error the stack is unwound back to `func`, open
+    /// upvalues are closed and the error object is returned. If `handler` is
+    /// the stack index of a message handler, it is called with the error
+    /// object while the erroring frames are still on the stack.
+    pub(crate) fn pcall(
+        &mut self,
+        func: usize,
+        nresults: i32,
+        handler: Option<usize>,
+    ) -> std::result::Result<(), LuaError> {
+        let old_ci = self.base_ci.len();
+        let old_ccalls = self.n_ccalls;
+        let err = match self.call(func, nresults) {
+            Ok(()) => return Ok(()),
+            Err(e) => self.error_object(e),
+        };
+        if err.is_uncatchable() {
+            self.unwind(func, old_ci, old_ccalls);
+            return Err(err);
+        }
+        let mut err_value = err.value();
+        if let Some(h) = handler {
+            self.n_ccalls = old_ccalls;
+            // keep the dead frames intact below the handler
+            self.top = self
+                .base_ci
+                .iter()
+                .map(|ci| ci.top)
+                .fold(self.top, usize::max);
+            let handler = self.stack[h];
+            self.push(handler);
+            self.push(err_value);
+            let hfunc = self.top - 2;
+            err_value = match self.call(hfunc, 1) {
+                Ok(()) => self.stack[self.top - 1],
+                Err(_) => self.intern(b"error in error handling"),
+            };
+        }
+        self.unwind(func, old_ci, old_ccalls);
+        let message = self.error_message(&err_value);
+        Err(LuaError::new(err_value, message))
+    }
+
+    /// Protected call recording the stack traceback of an error in the
+    /// returned `LuaError`.
+    pub(crate) fn pcall_traceback(
+        &mut self,
+        func: usize,
+        nresults: i32,
+    ) -> std::result::Result<(), LuaError> {
+        let old_ci = self.base_ci.len();
+        let old_ccalls = self.n_ccalls;
+        let err = match self.call(func, nresults) {
+            Ok(()) => return Ok(()),
+            Err(e) => self.error_object(e),
+        };
+        let traceback = self.traceback(0);
+        self.unwind(func, old_ci, old_ccalls);
+        Err(err.with_traceback(traceback))
+    }
+
+    /// drop the frames above `old_ci` after an error, leaving the stack at `func`
+    fn unwind(&mut self, func: usize, old_ci: usize, old_ccalls: usize) {
+        self.close_upvals(func);
+        self.base_ci.truncate(old_ci);
+        self.n_ccalls = old_ccalls;
+        self.top = func;
+    }
+
+    pub(crate) fn precall(&mut self, func: usize, nresults: i32) -> Result<PreCall> {
+        match self.stack[func].value() {
+            Value::LuaClosure(cl) => {
+                let p = self.global.heap.get(cl).proto.clone();
+                self.check_call_depth()?;
+                self.check_stack(p.max_stack);
+                let nargs = self.top - func - 1;
+                let base = if p.is_vararg & VARARG_ISVARARG == 0 {
+                    if nargs > p.num_params {
+                        self.top = func + 1 + p.num_params;
+                    }
+                    func + 1
+                } else {
+                    self.adjust_varargs(&p, nargs)
+                };
+                let top = base + p.max_stack;
+                if self.stack.len() < top {
+                    self.stack.resize(top, TValue::nil());
+                }
+                for slot in self.stack[self.top..top].iter_mut() {
+                    *slot = TValue::nil();
+                }
+                self.base_ci.push(CallInfo {
+                    base,
+                    func,
+                    top,
+                    saved_pc: 0,
+                    nresults,
+                    tailcalls: 0,
+                });
+                self.top = top;
+                if self.hook.mask & MASK_CALL != 0 {
+                    self.call_hook(HookEvent::Call)?;
+                }
+                Ok(PreCall::Lua)
+            }
+            Value::NativeClosure(cl) => {
+                let f = self.global.heap.get(cl).func;
+                self.check_call_depth()?;
+                self.check_stack(LUA_MINSTACK);
+                self.base_ci.push(CallInfo {
+                    base: func + 1,
+                    func,
+                    top: self.top + LUA_MINSTACK,
+                    saved_pc: 0,
+                    nresults,
+                    tailcalls: 0,
+                });
+                if self.hook.mask & MASK_CALL != 0 {
+                    self.call_hook(HookEvent::Call)?;
+                }
+                let n = f(self)?;
+                if self.n_yield.is_some() {
+                    return Ok(PreCall::Yield);
+                }
+                self.poscall(self.top - n)?;
+                Ok(PreCall::Native)
+            }
+            _ => {
+                self.try_func_tm(func)?;
+                self.precall(func, nresults)
+            }
         }
     }
-}
 
-pub fn vm_execute(state: &mut LuaState, insts: Vec<Instruction>) {
-    let mut pc: usize = 0;
-    let k = state.base_ci.first().unwrap().proto.constant_index;
-    loop {
-        match insts[pc] {
-            // Instruction::Move(inst) => {}
-            Instruction::LoadK(inst) => {
-                let ra = as_ra(inst, state.base);
-                let k = as_kbx(inst, state.base);
-                println!("Load {} {}", ra, k);
-                state.set_const(ra, k);
-                pc += 1;
+    /// move the fixed parameters of a vararg function above the actual arguments
+    fn adjust_varargs(&mut self, p: &Proto, nargs: usize) -> usize {
+        for _ in nargs..p.num_params {
+            self.push(TValue::nil());
+        }
+        let nargs = nargs.max(p.num_params);
+        // compatibility `arg` table with the extra arguments
+        let htab = if p.is_vararg & VARARG_NEEDSARG != 0 {
+            let nextra = nargs - p.num_params;
+            let t = self.new_table();
+            let table = self.global.heap.get_mut(t);
+            for i in 0..nextra {
+                table.set_int(i as i64 + 1, self.stack[self.top - nextra + i]);
             }
-            Instruction::Add(inst) => {
-                let ra = as_ra(inst, state.base);
-                let rb = as_rkb(inst, state.base, k);
-                let rc = as_rkc(inst, state.base, k);
-                let nb = TValue::new(Value::Integer(rb as i64), LuaType::Number);
-                let nc = TValue::new(Value::Integer(rc as i64), LuaType::Number);
-                println!("Add {} {} {}", ra, rb, rc);
-                match (nb.lua_type(), nc.lua_type()) {
-                    (Some(LuaType::Number), Some(LuaType::Number)) => {
-                        state.set_number(ra, TValue::new(nb.value() + nc.value(), LuaType::Number));
+            let n = self.global.heap.intern(b"n");
+            self.global
+                .heap
+                .get_mut(t)
+                .set_str(n, TValue::number(nextra as LuaNumber));
+            Some(t)
+        } else {
+            None
+        };
+        let fixed = self.top - nargs;
+        let base = self.top;
+        for i in 0..p.num_params {
+            let v = self.stack[fixed + i];
+            self.push(v);
+            self.stack[fixed + i] = TValue::nil();
+        }
+        if let Some(t) = htab {
+            self.push(TValue::table(t));
+        }
+        base
+    }
+
+    /// replace a non-function at `func` by its `__call` metamethod
+    fn try_func_tm(&mut self, func: usize) -> Result<()> {
+        let f = self.stack[func];
+        let tm = self.get_tm_by_obj(&f, TagMethod::Call);
+        if !matches!(tm.value(), Value::LuaClosure(_) | Value::NativeClosure(_)) {
+            return Err(self.type_error(&f, Some(func), "call"));
+        }
+        self.check_stack(1);
+        for i in (func..self.top).rev() {
+            self.stack[i + 1] = self.stack[i];
+        }
+        self.top += 1;
+        self.stack[func] = tm;
+        Ok(())
+    }
+
+    /// finish a call: move results (starting at `first_result`) to the function slot
+    pub(crate) fn poscall(&mut self, first_result: usize) -> Result<()> {
+        if self.hook.mask & MASK_RET != 0 {
+            self.ret_hooks()?;
+        }
+        let ci = self.base_ci.pop().expect("no call to finish");
+        let mut res = ci.func;
+        let nresults = self.top - first_result;
+        let wanted = if ci.nresults == LUA_MULTRET {
+            nresults
+        } else {
+            ci.nresults as usize
+        };
+        if self.stack.len() < res + wanted {
+            self.stack.resize(res + wanted, TValue::nil());
+        }
+        for i in 0..wanted {
+            self.stack[res] = if i < nresults {
+                self.stack[first_result + i]
+            } else {
+                TValue::nil()
+            };
+            res += 1;
+        }
+        self.top = res;
+        Ok(())
+    }
+
+    /// call metamethod `f(a, b)` and return its first result
+    fn call_tm_res(&mut self, f: TValue, a: TValue, b: TValue) -> Result<TValue> {
+        let func = self.top;
+        self.push(f);
+        self.push(a);
+        self.push(b);
+        self.call(func, 1)?;
+        self.top -= 1;
+        Ok(self.stack[self.top])
+    }
+    /// call metamethod `f(a, b, c)` discarding results
+    fn call_tm(&mut self, f: TValue, a: TValue, b: TValue, c: TValue) -> Result<()> {
+        let func = self.top;
+        self.push(f);
+        self.push(a);
+        self.push(b);
+        self.push(c);
+        self.call(func, 0)
+    }
+
+    // ---- table access ----
+
+    /// `t[key]` with `__index` handling; `t_index` is the stack slot of `t` if any
+    pub(crate) fn get_table(
+        &mut self,
+        t: TValue,
+        key: TValue,
+        t_index: Option<usize>,
+    ) -> Result<TValue> {
+        let mut t = t;
+        let mut t_index = t_index;
+        for _ in 0..MAXTAGLOOP {
+            let tm = if let Value::Table(h) = t.value() {
+                let table = self.global.heap.get(h);
+                let res = table.get(&key);
+                if !matches!(res.value(), Value::Nil) {
+                    return Ok(res);
+                }
+                match table.metatable {
+                    Some(mt) => {
+                        let tm = self
+                            .global
+                            .heap
+                            .get(mt)
+                            .get_str(self.tm_name(TagMethod::Index));
+                        if matches!(tm.value(), Value::Nil) {
+                            return Ok(res);
+                        }
+                        tm
                     }
-                    (_, _) => panic!("not match lua type"),
+                    None => return Ok(res),
+                }
+            } else {
+                let tm = self.get_tm_by_obj(&t, TagMethod::Index);
+                if matches!(tm.value(), Value::Nil) {
+                    return Err(self.type_error(&t, t_index, "index"));
+                }
+                tm
+            };
+            if matches!(tm.value(), Value::LuaClosure(_) | Value::NativeClosure(_)) {
+                return self.call_tm_res(tm, t, key);
+            }
+            t = tm;
+            t_index = None;
+        }
+        Err(self.runtime_error("loop in gettable".to_string()))
+    }
+
+    /// `t[key] = val` with `__newindex` handling
+    pub(crate) fn set_table(
+        &mut self,
+        t: TValue,
+        key: TValue,
+        val: TValue,
+        t_index: Option<usize>,
+    ) -> Result<()> {
+        let mut t = t;
+        let mut t_index = t_index;
+        for _ in 0..MAXTAGLOOP {
+            let tm = if let Value::Table(h) = t.value() {
+                let table = self.global.heap.get(h);
+                let tm = match table.metatable {
+                    Some(mt) if matches!(table.get(&key).value(), Value::Nil) => self
+                        .global
+                        .heap
+                        .get(mt)
+                        .get_str(self.tm_name(TagMethod::NewIndex)),
+                    _ => TValue::nil(),
                 };
-                pc += 1;
+                if matches!(tm.value(), Value::Nil) {
+                    let res = self.global.heap.get_mut(h).set(key, val);
+                    return res.map_err(|e| self.runtime_error(e.to_string()));
+                }
+                tm
+            } else {
+                let tm = self.get_tm_by_obj(&t, TagMethod::NewIndex);
+                if matches!(tm.value(), Value::Nil) {
+                    return Err(self.type_error(&t, t_index, "index"));
+                }
+                tm
+            };
+            if matches!(tm.value(), Value::LuaClosure(_) | Value::NativeClosure(_)) {
+                return self.call_tm(tm, t, key, val);
             }
-            Instruction::GetGlobal(inst) => {
-                unimplemented!()
+            t = tm;
+            t_index = None;
+        }
+        Err(self.runtime_error("loop in settable".to_string()))
+    }
+
+    // ---- arithmetic and comparison ----
+
+    /// numeric value of `val`, converting strings like the VM does
+    pub(crate) fn to_number(&self, val: &TValue) -> Option<Value> {
+        match val.value() {
+            v @ (Value::Integer(_) | Value::Number(_)) => Some(v),
+            Value::String(s) => str2number(self.str_bytes(s)).map(Value::Number),
+            _ => None,
+        }
+    }
+
+    /// string form of a string or number, `None` for other values
+    pub(crate) fn to_str_bytes(&self, val: &TValue) -> Option<Vec<u8>> {
+        match val.value() {
+            Value::String(s) => Some(self.str_bytes(s).to_vec()),
+            // printed like the double it stands for
+            Value::Integer(n) => Some(number2str(n as LuaNumber).into_bytes()),
+            Value::Number(n) => Some(number2str(n).into_bytes()),
+            _ => None,
+        }
+    }
+
+    fn get_bin_tm(&self, a: &TValue, b: &TValue, event: TagMethod) -> TValue {
+        let tm = self.get_tm_by_obj(a, event);
+        if matches!(tm.value(), Value::Nil) {
+            self.get_tm_by_obj(b, event)
+        } else {
+            tm
+        }
+    }
+
+    /// `b op c`; `ib`/`ic` are the stack slots of the operands if they are registers
+    pub(crate) fn arith(
+        &mut self,
+        op: TagMethod,
+        b: TValue,
+        c: TValue,
+        ib: Option<usize>,
+        ic: Option<usize>,
+    ) -> Result<TValue> {
+        if let (Some(x), Some(y)) = (self.to_number(&b), self.to_number(&c)) {
+            return Ok(arith_op(op, x, y));
+        }
+        let tm = self.get_bin_tm(&b, &c, op);
+        if !matches!(tm.value(), Value::Nil) {
+            return self.call_tm_res(tm, b, c);
+        }
+        // blame the second operand if the first one is a number
+        if self.to_number(&b).is_some() {
+            Err(self.type_error(&c, ic, "perform arithmetic on"))
+        } else {
+            Err(self.type_error(&b, ib, "perform arithmetic on"))
+        }
+    }
+
+    /// metamethod shared by both operands of a comparison
+    fn call_order_tm(&mut self, l: TValue, r: TValue, event: TagMethod) -> Result<Option<bool>> {
+        let tm1 = self.get_tm_by_obj(&l, event);
+        if matches!(tm1.value(), Value::Nil) {
+            return Ok(None);
+        }
+        let tm2 = self.get_tm_by_obj(&r, event);
+        if !self.raw_equal(&tm1, &tm2) {
+            return Ok(None);
+        }
+        let res = self.call_tm_res(tm1, l, r)?;
+        Ok(Some(!is_false(&res)))
+    }
+
+    fn order_error(&mut self, l: &TValue, r: &TValue) -> anyhow::Error {
+        let t1 = type_name(l);
+        let t2 = type_name(r);
+        if t1 == t2 {
+            self.runtime_error(format!("attempt to compare two {t1} values"))
+        } else {
+            self.runtime_error(format!("attempt to compare {t1} with {t2}"))
+        }
+    }
+
+    pub(crate) fn less_than(&mut self, l: TValue, r: TValue) -> Result<bool> {
+        match (l.value(), r.value()) {
+            (Value::Integer(a), Value::Integer(b)) => Ok(a < b),
+            (Value::Integer(_) | Value::Number(_), Value::Integer(_) | Value::Number(_)) => {
+                Ok(as_float(l.value()) < as_float(r.value()))
             }
-            Instruction::SetGlobal(inst) => {
-                let ra = as_ra(inst, state.base);
-                println!("SetGlobal {}", ra);
-                pc += 1;
+            (Value::String(a), Value::String(b)) => Ok(self.str_bytes(a) < self.str_bytes(b)),
+            _ => match self.call_order_tm(l, r, TagMethod::Lt)? {
+                Some(res) => Ok(res),
+                None => Err(self.order_error(&l, &r)),
+            },
+        }
+    }
+
+    pub(crate) fn less_equal(&mut self, l: TValue, r: TValue) -> Result<bool> {
+        match (l.value(), r.value()) {
+            (Value::Integer(a), Value::Integer(b)) => Ok(a <= b),
+            (Value::Integer(_) | Value::Number(_), Value::Integer(_) | Value::Number(_)) => {
+                Ok(as_float(l.value()) <= as_float(r.value()))
+            }
+            (Value::String(a), Value::String(b)) => Ok(self.str_bytes(a) <= self.str_bytes(b)),
+            _ => {
+                if let Some(res) = self.call_order_tm(l, r, TagMethod::Le)? {
+                    return Ok(res);
+                }
+                match self.call_order_tm(r, l, TagMethod::Lt)? {
+                    Some(res) => Ok(!res),
+                    None => Err(self.order_error(&l, &r)),
+                }
+            }
+        }
+    }
+
+    /// primitive equality, no metamethods
+    pub(crate) fn raw_equal(&self, a: &TValue, b: &TValue) -> bool {
+        match (a.value(), b.value()) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(x), Value::Boolean(y)) => x == y,
+            (Value::Integer(x), Value::Integer(y)) => x == y,
+            (Value::Integer(_) | Value::Number(_), Value::Integer(_) | Value::Number(_)) => {
+                as_float(a.value()) == as_float(b.value())
             }
-            Instruction::Return(_) => break,
-            _ => unimplemented!(),
+            (Value::String(x), Value::String(y)) => x == y,
+            (Value::Table(x), Value::Table(y)) => x == y,
+            (Value::LuaClosure(x), Value::LuaClosure(y)) => x == y,
+            (Value::NativeClosure(x), Value::NativeClosure(y)) => x == y,
+            (Value::Thread(x), Value::Thread(y)) => x == y,
+            (Value::UserData(x), Value::UserData(y)) => x == y,
+            _ => false,
         }
     }
-    println!("Finish");
+
+    /// `a == b` with `__eq` handling
+    pub(crate) fn equal(&mut self, a: TValue, b: TValue) -> Result<bool> {
+        if self.raw_equal(&a, &b) {
+            return Ok(true);
+        }
+        if let (Value::Table(_), Value::Table(_)) | (Value::UserData(_), Value::UserData(_)) =
+            (a.value(), b.value())
+        {
+            let tm1 = self.get_tm_by_obj(&a, TagMethod::Eq);
+            if matches!(tm1.value(), Value::Nil) {
+                return Ok(false);
+            }
+            let tm2 = self.get_tm_by_obj(&b, TagMethod::Eq);
+            if !self.raw_equal(&tm1, &tm2) {
+                return Ok(false);
+            }
+            let res = self.call_tm_res(tm1, a, b)?;
+            return Ok(!is_false(&res));
+        }
+        Ok(false)
+    }
+
+    /// `#val` for non-table values
+    fn obj_len(&mut self, val: TValue, index: Option<usize>) -> Result<TValue> {
+        match val.value() {
+            Value::Table(t) => Ok(TValue::int_or_float(
+                self.global.heap.get(t).length() as i128
+            )),
+            Value::String(s) => Ok(TValue::int_or_float(self.global.heap.get(s).len() as i128)),
+            _ => {
+                let tm = self.get_tm_by_obj(&val, TagMethod::Len);
+                if matches!(tm.value(), Value::Nil) {
+                    return Err(self.type_error(&val, index, "get length of"));
+                }
+                self.call_tm_res(tm, val, TValue::nil())
+            }
+        }
+    }
+
+    /// concatenate the `total` values ending at stack slot `last`, leaving the result at `last - total + 1`
+    pub(crate) fn concat(&mut self, total: usize, last: usize) -> Result<()> {
+        let mut total = total;
+        let mut last = last;
+        while total > 1 {
+            let top = last + 1;
+            let mut n = 2;
+            let lhs = self.stack[top - 2];
+            let rhs = self.stack[top - 1];
+            match (self.to_str_bytes(&lhs), self.to_str_bytes(&rhs)) {
+                (Some(_), Some(r)) => {
+                    let mut parts = vec![r];
+                    while n <= total {
+                        match self.to_str_bytes(&self.stack[top - n]) {
+                            Some(s) => parts.push(s),
+                            None => break,
+                        }
+                        n += 1;
+                    }
+                    n -= 1;
+                    self.check_string_length(parts.iter().map(|p| p.len()).sum())?;
+                    let bytes: Vec<u8> = parts.into_iter().rev().flatten().collect();
+                    self.stack[top - n] = self.intern(&bytes);
+                }
+                _ => {
+                    let tm = self.get_bin_tm(&lhs, &rhs, TagMethod::Concat);
+                    if matches!(tm.value(), Value::Nil) {
+                        let (culprit, index) = match lhs.value() {
+                            Value::String(_) | Value::Integer(_) | Value::Number(_) => {
+                                (rhs, top - 1)
+                            }
+                            _ => (lhs, top - 2),
+                        };
+                        return Err(self.type_error(&culprit, Some(index), "concatenate"));
+                    }
+                    let saved_top = self.top;
+                    self.top = self.top.max(top);
+                    let res = self.call_tm_res(tm, lhs, rhs)?;
+                    self.top = saved_top;
+                    self.stack[top - 2] = res;
+                }
+            }
+            total -= n - 1;
+            last -= n - 1;
+        }
+        Ok(())
+    }
+
+    // ---- errors ----
+
+    /// Lua error object for any error propagated with `?`
+    pub(crate) fn error_object(&mut self, e: anyhow::Error) -> LuaError {
+        match e.downcast::<LuaError>() {
+            Ok(le) => le,
+            Err(e) => {
+                let msg = e.to_string();
+                let value = self.intern(msg.as_bytes());
+                LuaError::new(value, msg)
+            }
+        }
+    }
+    /// raise `value` as error object
+    pub(crate) fn error_value(&self, value: TValue) -> anyhow::Error {
+        anyhow::Error::new(LuaError::new(value, self.error_message(&value)))
+    }
+    pub(crate) fn error_message(&self, value: &TValue) -> String {
+        match self.to_str_bytes(value) {
+            Some(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            None => format!("(error object is a {} value)", type_name(value)),
+        }
+    }
+    /// error raised by the VM, prefixed with the current position (luaG_runerror)
+    pub(crate) fn runtime_error(&mut self, msg: String) -> anyhow::Error {
+        let msg = match self.where_ci(self.base_ci.len() - 1) {
+            Some(pos) => format!("{pos} {msg}"),
+            None => msg,
+        };
+        let value = self.intern(msg.as_bytes());
+        anyhow::Error::new(LuaError::new(value, msg))
+    }
+
+    /// "attempt to `op` a nil value" with variable names recovered from debug info
+    pub(crate) fn type_error(
+        &mut self,
+        val: &TValue,
+        index: Option<usize>,
+        op: &str,
+    ) -> anyhow::Error {
+        let t = type_name(val);
+        match index.and_then(|i| self.var_info(i)) {
+            Some((kind, name)) => {
+                self.runtime_error(format!("attempt to {op} {kind} '{name}' (a {t} value)"))
+            }
+            None => self.runtime_error(format!("attempt to {op} a {t} value")),
+        }
+    }
+}
+
+pub fn type_name(val: &TValue) -> &'static str {
+    val.lua_type().name()
+}
+
+pub fn is_false(val: &TValue) -> bool {
+    matches!(val.value(), Value::Nil | Value::Boolean(false))
+}
+
+fn as_float(v: Value) -> LuaNumber {
+    match v {
+        Value::Integer(n) => n as LuaNumber,
+        Value::Number(n) => n,
+        _ => 0.0,
+    }
+}
+
+/// string form of a number (`lua_number2str`, "%.14g")
+pub fn number2str(n: LuaNumber) -> String {
+    String::from_utf8(Spec::g(14).format_float(n)).unwrap()
+}
+
+/// convert a string to a number like `lua_str2number`: decimal, exponent or hex notation
+pub fn str2number(bytes: &[u8]) -> Option<LuaNumber> {
+    let s = std::str::from_utf8(bytes).ok()?.trim();
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        let n = u64::from_str_radix(hex, 16).ok()? as LuaNumber;
+        return Some(if neg { -n } else { n });
+    }
+    if s.is_empty()
+        || !s
+            .bytes()
+            .all(|b| b.is_ascii_digit() || b".+-eE".contains(&b))
+    {
+        return None;
+    }
+    s.parse::<LuaNumber>().ok()
+}
+
+fn arith_op(op: TagMethod, x: Value, y: Value) -> TValue {
+    // integers stay integers while the result is exact; a float would
+    // give the same value, so the variant is the only visible difference
+    if let (Value::Integer(a), Value::Integer(b)) = (x, y) {
+        let int = match op {
+            TagMethod::Add => a.checked_add(b),
+            TagMethod::Sub => a.checked_sub(b),
+            // a zero with a negative operand is -0, which only a float holds
+            TagMethod::Mul => a.checked_mul(b).filter(|&n| n != 0 || (a >= 0 && b >= 0)),
+            TagMethod::Mod if b != 0 => {
+                let r = a.wrapping_rem(b);
+                Some(if r != 0 && (r ^ b) < 0 { r + b } else { r })
+            }
+            TagMethod::Unm if a != 0 => a.checked_neg(),
+            _ => None,
+        };
+        if let Some(n) = int.filter(|n| n.abs() <= MAX_EXACT_INTEGER) {
+            return TValue::integer(n);
+        }
+    }
+    let (a, b) = (as_float(x), as_float(y));
+    let n = match op {
+        TagMethod::Add => a + b,
+        TagMethod::Sub => a - b,
+        TagMethod::Mul => a * b,
+        TagMethod::Div => a / b,
+        TagMethod::Mod => a - (a / b).floor() * b,
+        TagMethod::Pow => a.powf(b),
+        TagMethod::Unm => -a,
+        _ => unreachable!("not an arithmetic event"),
+    };
+    TValue::number(n)
+}
+
+/// decode a "floating point byte" table size hint (luaO_fb2int)
+fn fb2int(x: usize) -> usize {
+    let e = (x >> 3) & 31;
+    if e == 0 { x } else { ((x & 7) + 8) << (e - 1) }
+}
+
+pub(crate) fn vm_execute(state: &mut LuaState, nexeccalls: usize) -> Result<()> {
+    let mut nexeccalls = nexeccalls;
+    'reentry: loop {
+        let ci = state.base_ci.last().expect("no running function");
+        let cl = match state.stack[ci.func].value() {
+            Value::LuaClosure(cl) => cl,
+            _ => unreachable!("vm_execute on a non Lua function"),
+        };
+        let proto = state.global.heap.get(cl).proto.clone();
+        let base = ci.base;
+        let mut pc = ci.saved_pc;
+        let mut oldpc = pc;
+        loop {
+            let inst = proto.code[pc];
+            pc += 1;
+            state.base_ci.last_mut().unwrap().saved_pc = pc;
+            state.global.counter.countdown -= 1;
+            if state.global.counter.countdown == 0 {
+                state.check_limits()?;
+            }
+            if state.hook.mask & (MASK_LINE | MASK_COUNT) != 0 {
+                state.trace_exec(&proto, oldpc, pc)?;
+                oldpc = pc;
+            }
+            let ra = base + get_a(inst);
+            let rk = |state: &LuaState, x: usize| -> (TValue, Option<usize>) {
+                if is_k(x) {
+                    (proto.constants[index_k(x)], None)
+                } else {
+                    (state.stack[base + x], Some(base + x))
+                }
+            };
+            match Instruction::from(inst) {
+                Instruction::Move(_) => {
+                    state.stack[ra] = state.stack[base + get_b(inst)];
+                }
+                Instruction::LoadK(_) => {
+                    state.stack[ra] = proto.constants[get_bx(inst)];
+                }
+                Instruction::LoadBool(_) => {
+                    state.stack[ra] = TValue::boolean(get_b(inst) != 0);
+                    if get_c(inst) != 0 {
+                        pc += 1;
+                    }
+                }
+                Instruction::LoadNil(_) => {
+                    for r in ra..=base + get_b(inst) {
+                        state.stack[r] = TValue::nil();
+                    }
+                }
+                Instruction::GetUpval(_) => {
+                    let uv = state.global.heap.get(cl).upvals[get_b(inst)];
+                    state.stack[ra] = state.get_upval(uv);
+                }
+                Instruction::GetGlobal(_) => {
+                    let env = state.global.heap.get(cl).env;
+                    let key = proto.constants[get_bx(inst)];
+                    let env = TValue::table(env);
+                    state.stack[ra] = state.get_table(env, key, None)?;
+                }
+                Instruction::GetTable(_) => {
+                    let rb = base + get_b(inst);
+                    let (key, _) = rk(state, get_c(inst));
+                    state.stack[ra] = state.get_table(state.stack[rb], key, Some(rb))?;
+                }
+                Instruction::SetGlobal(_) => {
+                    let env = state.global.heap.get(cl).env;
+                    let key = proto.constants[get_bx(inst)];
+                    let env = TValue::table(env);
+                    state.set_table(env, key, state.stack[ra], None)?;
+                }
+                Instruction::SetUpval(_) => {
+                    let uv = state.global.heap.get(cl).upvals[get_b(inst)];
+                    state.set_upval(uv, state.stack[ra]);
+                }
+                Instruction::SetTable(_) => {
+                    let (key, _) = rk(state, get_b(inst));
+                    let (val, _) = rk(state, get_c(inst));
+                    state.set_table(state.stack[ra], key, val, Some(ra))?;
+                }
+                Instruction::NewTable(_) => {
+                    let t = Table::with_capacity(fb2int(get_b(inst)), fb2int(get_c(inst)));
+                    let t = state.global.heap.alloc(t);
+                    state.stack[ra] = TValue::table(t);
+                    state.check_gc()?;
+                }
+                Instruction::OpSelf(_) => {
+                    let rb = base + get_b(inst);
+                    let obj = state.stack[rb];
+                    let (key, _) = rk(state, get_c(inst));
+                    state.stack[ra + 1] = obj;
+                    state.stack[ra] = state.get_table(obj, key, Some(rb))?;
+                }
+                Instruction::Add(_)
+                | Instruction::Sub(_)
+                | Instruction::Mul(_)
+                | Instruction::Div(_)
+                | Instruction::Mod(_)
+                | Instruction::Pow(_) => {
+                    let op = match Instruction::from(inst) {
+                        Instruction::Add(_) => TagMethod::Add,
+                        Instruction::Sub(_) => TagMethod::Sub,
+                        Instruction::Mul(_) => TagMethod::Mul,
+                        Instruction::Div(_) => TagMethod::Div,
+                        Instruction::Mod(_) => TagMethod::Mod,
+                        _ => TagMethod::Pow,
+                    };
+                    let (b, ib) = rk(state, get_b(inst));
+                    let (c, ic) = rk(state, get_c(inst));
+                    state.stack[ra] = state.arith(op, b, c, ib, ic)?;
+                }
+                Instruction::Unm(_) => {
+                    let rb = base + get_b(inst);
+                    let b = state.stack[rb];
+                    state.stack[ra] = state.arith(TagMethod::Unm, b, b, Some(rb), Some(rb))?;
+                }
+                Instruction::Not(_) => {
+                    let res = is_false(&state.stack[base + get_b(inst)]);
+                    state.stack[ra] = TValue::boolean(res);
+                }
+                Instruction::Len(_) => {
+                    let rb = base + get_b(inst);
+                    state.stack[ra] = state.obj_len(state.stack[rb], Some(rb))?;
+                }
+                Instruction::Concat(_) => {
+                    let b = get_b(inst);
+                    let c = get_c(inst);
+                    state.concat(c - b + 1, base + c)?;
+                    state.stack[ra] = state.stack[base + b];
+                    state.check_gc()?;
+                }
+                Instruction::Jmp(_) => {
+                    pc = (pc as isize + get_sbx(inst)) as usize;
+                }
+                Instruction::Eq(_) | Instruction::Lt(_) | Instruction::Le(_) => {
+                    let (b, _) = rk(state, get_b(inst));
+                    let (c, _) = rk(state, get_c(inst));
+                    let res = match Instruction::from(inst) {
+                        Instruction::Eq(_) => state.equal(b, c)?,
+                        Instruction::Lt(_) => state.less_than(b, c)?,
+                        _ => state.less_equal(b, c)?,
+                    };
+                    if res == (get_a(inst) != 0) {
+                        pc = (pc as isize + get_sbx(proto.code[pc])) as usize;
+                    }
+                    pc += 1;
+                }
+                Instruction::Test(_) => {
+                    if is_false(&state.stack[ra]) != (get_c(inst) != 0) {
+                        pc = (pc as isize + get_sbx(proto.code[pc])) as usize;
+                    }
+                    pc += 1;
+                }
+                Instruction::TestSet(_) => {
+                    let rb = state.stack[base + get_b(inst)];
+                    if is_false(&rb) != (get_c(inst) != 0) {
+                        state.stack[ra] = rb;
+                        pc = (pc as isize + get_sbx(proto.code[pc])) as usize;
+                    }
+                    pc += 1;
+                }
+                Instruction::Call(_) => {
+                    let b = get_b(inst);
+                    let nresults = get_c(inst) as i32 - 1;
+                    if b != 0 {
+                        state.top = ra + b;
+                    }
+                    match state.precall(ra, nresults)? {
+                        PreCall::Lua => {
+                            nexeccalls += 1;
+                            continue 'reentry;
+                        }
+                        PreCall::Native => {
+                            if nresults >= 0 {
+                                state.top = state.base_ci.last().unwrap().top;
+                            }
+                            state.check_gc()?;
+                        }
+                        PreCall::Yield => return Ok(()),
+                    }
+                }
+                Instruction::TailCall(_) => {
+                    let b = get_b(inst);
+                    if b != 0 {
+                        state.top = ra + b;
+                    }
+                    match state.precall(ra, LUA_MULTRET)? {
+                        PreCall::Lua => {
+                            // put the new frame in place of the previous one
+                            let new = state.base_ci.pop().unwrap();
+                            let old_base = state.base_ci.last().unwrap().base;
+                            state.close_upvals(old_base);
+                            let ci = state.base_ci.last_mut().unwrap();
+                            let func = ci.func;
+                            ci.base = func + (new.base - new.func);
+                            let n = state.top - new.func;
+                            for aux in 0..n {
+                                state.stack[func + aux] = state.stack[new.func + aux];
+                            }
+                            ci.top = func + n;
+                            ci.saved_pc = 0;
+                            ci.tailcalls += 1;
+                            state.top = func + n;
+                            continue 'reentry;
+                        }
+                        PreCall::Native => {}
+                        PreCall::Yield => return Ok(()),
+                    }
+                }
+                Instruction::Return(_) => {
+                    let b = get_b(inst);
+                    if b != 0 {
+                        state.top = ra + b - 1;
+                    }
+                    state.close_upvals(base);
+                    let wanted = state.base_ci.last().unwrap().nresults;
+                    state.poscall(ra)?;
+                    nexeccalls -= 1;
+                    if nexeccalls == 0 {
+                        return Ok(());
+                    }
+                    if wanted != LUA_MULTRET {
+                        state.top = state.base_ci.last().unwrap().top;
+                    }
+                    continue 'reentry;
+                }
+                Instruction::ForLoop(_) => {
+                    let step = state.stack[ra + 2].value();
+                    let idx = arith_op(TagMethod::Add, state.stack[ra].value(), step);
+                    let limit = state.stack[ra + 1].value();
+                    let cont = if as_float(step) > 0.0 {
+                        as_float(idx.value()) <= as_float(limit)
+                    } else {
+                        as_float(limit) <= as_float(idx.value())
+                    };
+                    if cont {
+                        pc = (pc as isize + get_sbx(inst)) as usize;
+                        state.stack[ra] = idx;
+                        state.stack[ra + 3] = idx;
+                    }
+                }
+                Instruction::ForPrep(_) => {
+                    let init = state.to_number(&state.stack[ra]);
+                    let limit = state.to_number(&state.stack[ra + 1]);
+                    let step = state.to_number(&state.stack[ra + 2]);
+                    let (init, limit, step) = match (init, limit, step) {
+                        (None, _, _) => {
+                            return Err(state.runtime_error(
+                                "'for' initial value must be a number".to_string(),
+                            ));
+                        }
+                        (_, None, _) => {
+                            return Err(
+                                state.runtime_error("'for' limit must be a number".to_string())
+                            );
+                        }
+                        (_, _, None) => {
+                            return Err(
+                                state.runtime_error("'for' step must be a number".to_string())
+                            );
+                        }
+                        (Some(i), Some(l), Some(s)) => (i, l, s),
+                    };
+                    state.stack[ra] = arith_op(TagMethod::Sub, init, step);
+                    state.stack[ra + 1] = TValue::from(limit);
+                    state.stack[ra + 2] = TValue::from(step);
+                    pc = (pc as isize + get_sbx(inst)) as usize;
+                }
+                Instruction::TForLoop(_) => {
+                    let cb = ra + 3;
+                    state.stack[cb + 2] = state.stack[ra + 2];
+                    state.stack[cb + 1] = state.stack[ra + 1];
+                    state.stack[cb] = state.stack[ra];
+                    state.top = cb + 3;
+                    state.call(cb, get_c(inst) as i32)?;
+                    state.top = state.base_ci.last().unwrap().top;
+                    if !matches!(state.stack[cb].value(), Value::Nil) {
+                        state.stack[cb - 1] = state.stack[cb];
+                        pc = (pc as isize + get_sbx(proto.code[pc])) as usize;
+                    }
+                    pc += 1;
+                }
+                Instruction::SetList(_) => {
+                    let mut n = get_b(inst);
+                    let mut c = get_c(inst);
+                    if n == 0 {
+                        n = state.top - ra - 1;
+                        state.top = state.base_ci.last().unwrap().top;
+                    }
+                    if c == 0 {
+                        c = proto.code[pc] as usize;
+                        pc += 1;
+                    }
+                    let t = match state.stack[ra].value() {
+                        Value::Table(t) => t,
+                        _ => unreachable!("SETLIST on a non table"),
+                    };
+                    let last = (c - 1) * LFIELDS_PER_FLUSH;
+                    for i in 1..=n {
+                        let val = state.stack[ra + i];
+                        state.global.heap.get_mut(t).set_int((last + i) as i64, val);
+                    }
+                }
+                Instruction::Close(_) => {
+                    state.close_upvals(ra);
+                }
+                Instruction::Closure(_) => {
+                    let p = proto.protos[get_bx(inst)].clone();
+                    let mut upvals = Vec::with_capacity(p.num_upvals);
+                    for _ in 0..p.num_upvals {
+                        let pseudo = proto.code[pc];
+                        pc += 1;
+                        match Instruction::from(pseudo) {
+                            Instruction::GetUpval(_) => {
+                                upvals.push(state.global.heap.get(cl).upvals[get_b(pseudo)]);
+                            }
+                            _ => upvals.push(state.find_upval(base + get_b(pseudo))),
+                        }
+                    }
+                    let env = state.global.heap.get(cl).env;
+                    let ncl = state.global.heap.alloc(LuaClosure {
+                        proto: p,
+                        upvals,
+                        env,
+                    });
+                    state.stack[ra] = TValue::lua_closure(ncl);
+                    state.check_gc()?;
+                }
+                Instruction::VarArg(_) => {
+                    let ci = state.base_ci.last().unwrap();
+                    let n = (ci.base - ci.func - 1).saturating_sub(proto.num_params);
+                    let b = match get_b(inst) {
+                        0 => {
+                            state.top = ra;
+                            state.check_stack(n);
+                            state.top = ra + n;
+                            n
+                        }
+                        b => b - 1,
+                    };
+                    for j in 0..b {
+                        state.stack[ra + j] = if j < n {
+                            state.stack[base - n + j]
+                        } else {
+                            TValue::nil()
+                        };
+                    }
+                }
+                Instruction::Unknown(_) => {
+                    return Err(state.runtime_error(format!("invalid opcode {inst:#010x}")));
+                }
+            }
+            state.base_ci.last_mut().unwrap().saved_pc = pc;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::baselib::open_base;
+    use crate::func::LocVar;
+    use crate::opcodes::{OpCode, create_abc, create_abx, rk_ask};
+    use pretty_assertions::assert_eq;
+
+    pub(crate) fn proto(code: Vec<u32>, constants: Vec<TValue>) -> Proto {
+        Proto {
+            source: "=test".to_string(),
+            max_stack: 8,
+            lineinfo: (1..=code.len()).collect(),
+            code,
+            constants,
+            is_vararg: VARARG_ISVARARG,
+            ..Default::default()
+        }
+    }
+
+    /// run `p` as main chunk and return its results
+    pub(crate) fn run(
+        state: &mut LuaState,
+        p: Proto,
+    ) -> std::result::Result<Vec<TValue>, LuaError> {
+        let main = state.new_lua_closure(Rc::new(p));
+        let func = state.top;
+        state.push(main);
+        state.pcall(func, LUA_MULTRET, None)?;
+        let results = state.stack[func..state.top].to_vec();
+        state.top = func;
+        Ok(results)
+    }
+
+    #[test]
+    fn test_arith_error_names_global() {
+        let mut state = LuaState::new();
+        let x = state.intern(b"x");
+        let one = TValue::number(1.0);
+        // x + 1
+        let p = proto(
+            vec![
+                create_abx(OpCode::OpGetGlobal, 0, 0),
+                create_abc(OpCode::OpAdd, 0, 0, rk_ask(1)),
+                create_abc(OpCode::OpReturn, 0, 1, 0),
+            ],
+            vec![x, one],
+        );
+        let err = run(&mut state, p).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test:2: attempt to perform arithmetic on global 'x' (a nil value)"
+        );
+        // the error object is the message itself
+        assert!(matches!(err.value().value(), Value::String(_)));
+    }
+
+    #[test]
+    fn test_pcall_returns_error_object() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        let pcall = state.intern(b"pcall");
+        let error = state.intern(b"error");
+        // return pcall(error, {})
+        let p = proto(
+            vec![
+                create_abx(OpCode::OpGetGlobal, 0, 0),
+                create_abx(OpCode::OpGetGlobal, 1, 1),
+                create_abc(OpCode::OpNewTable, 2, 0, 0),
+                create_abc(OpCode::OpTailCall, 0, 3, 0),
+                create_abc(OpCode::OpReturn, 0, 0, 0),
+            ],
+            vec![pcall, error],
+        );
+        let results = run(&mut state, p).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[0].value(), Value::Boolean(false)));
+        assert!(matches!(results[1].value(), Value::Table(_)));
+    }
+
+    /// message handler reporting the call depth it runs at
+    fn depth(state: &mut LuaState) -> Result<usize> {
+        let n = state.base_ci.len() as f64;
+        state.push(TValue::number(n));
+        Ok(1)
+    }
+
+    /// local x; local y = x + 1
+    fn failing_local() -> Proto {
+        let one = TValue::number(1.0);
+        let mut f = proto(
+            vec![
+                create_abc(OpCode::OpLoadNil, 0, 0, 0),
+                create_abc(OpCode::OpAdd, 1, 0, rk_ask(0)),
+                create_abc(OpCode::OpReturn, 0, 1, 0),
+            ],
+            vec![one],
+        );
+        f.locvars = vec![LocVar {
+            name: "x".to_string(),
+            startpc: 1,
+            endpc: 3,
+        }];
+        f
+    }
+
+    #[test]
+    fn test_local_name_in_error() {
+        let mut state = LuaState::new();
+        let err = run(&mut state, failing_local()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test:2: attempt to perform arithmetic on local 'x' (a nil value)"
+        );
+    }
+
+    #[test]
+    fn test_xpcall_handler_runs_before_unwinding() {
+        let mut state = LuaState::new();
+        open_base(&mut state);
+        state.register("depth", depth);
+        let xpcall = state.intern(b"xpcall");
+        let depth = state.intern(b"depth");
+        // return xpcall(function() ... end, depth)
+        let mut p = proto(
+            vec![
+                create_abx(OpCode::OpGetGlobal, 0, 0),
+                create_abx(OpCode::OpClosure, 1, 0),
+                create_abx(OpCode::OpGetGlobal, 2, 1),
+                create_abc(OpCode::OpCall, 0, 3, 0),
+                create_abc(OpCode::OpReturn, 0, 0, 0),
+            ],
+            vec![xpcall, depth],
+        );
+        p.protos.push(Rc::new(failing_local()));
+        let results = run(&mut state, p).unwrap();
+        assert!(matches!(results[0].value(), Value::Boolean(false)));
+        // base, main chunk, xpcall, the failing function and the handler itself
+        assert!(matches!(results[1].value(), Value::Number(n) if n == 5.0));
+        assert_eq!(state.base_ci.len(), 1);
+    }
 }